@@ -0,0 +1,138 @@
+//! Admission webhooks for PreviewEnvironments.  The mutating one fills in
+//! `spec.owner` from the user creating the preview, so quotas and
+//! `kubectl preview list --owner me` work without anyone having to set it,
+//! applies the naming script, if there is one, and records who created the
+//! preview for `impersonation`.  The validating one refuses a preview whose
+//! FQDN another preview already has, whose images come from registries
+//! `registries` doesn't allow, or whose creator annotations don't match
+//! who's asking, and new previews during a freeze when `FREEZE_MODE` is
+//! `block`.
+//!
+//! The API server only calls webhooks over TLS, so this needs a serving
+//! certificate; see `admission-webhook.yaml` for the registration.
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::Filter;
+
+use crate::naming::NamingScript;
+use crate::freeze::{self, FreezeMode};
+use crate::{impersonation, profiles, registries, ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// The JSON patch to apply to a preview being created by `username`.
+pub fn owner_patch(object: &JsonValue, username: &str) -> Option<JsonValue> {
+    if object["spec"]["owner"].as_str().map(|owner| !owner.is_empty()).unwrap_or(false) {
+        return None;
+    }
+    Some(json!([{ "op": "add", "path": "/spec/owner", "value": username }]))
+}
+
+// Answer an AdmissionReview.  The only thing refused here is a preview the
+// naming script can't name; otherwise validation is the controller's job.
+fn review(body: JsonValue, naming: Option<&NamingScript>) -> JsonValue {
+    let request = &body["request"];
+    let mut response = json!({
+        "uid": request["uid"],
+        "allowed": true,
+    });
+
+    let mut patch = Vec::new();
+    let username = request["userInfo"]["username"].as_str().unwrap_or_default();
+    if request["operation"] == "CREATE" && !username.is_empty() {
+        if let Some(JsonValue::Array(owner)) = owner_patch(&request["object"], username) {
+            patch.extend(owner);
+        }
+    }
+    if let (true, Some(naming)) = (request["operation"] == "CREATE", naming) {
+        match naming.patch(&request["object"]) {
+            Ok(named) => patch.extend(named),
+            Err(err) => {
+                response["allowed"] = json!(false);
+                response["status"] = json!({ "code": 400, "message": format!("Naming script failed: {}", err) });
+                patch.clear();
+            }
+        }
+    }
+    if request["operation"] == "CREATE" && response["allowed"] == true {
+        let annotated = patch.iter().any(|op| op["path"] == "/metadata/annotations");
+        patch.extend(impersonation::creator_patch(&request["object"], &request["userInfo"], annotated));
+    }
+    if !patch.is_empty() {
+        let patch = serde_json::to_vec(&patch).expect("Failed to serialize JSON patch");
+        response["patchType"] = json!("JSONPatch");
+        response["patch"] = json!(base64::encode(patch));
+    }
+
+    json!({
+        "apiVersion": body["apiVersion"],
+        "kind": "AdmissionReview",
+        "response": response,
+    })
+}
+
+// Answer an AdmissionReview for the validating webhook.  This runs after
+// the mutating one, so it sees the FQDN the naming script chose.
+fn validate(body: JsonValue, resources: &ApiResources) -> JsonValue {
+    let request = &body["request"];
+    let mut response = json!({
+        "uid": request["uid"],
+        "allowed": true,
+    });
+
+    let object = &request["object"];
+    let name = object["metadata"]["name"].as_str().unwrap_or_default();
+    let fqdn = object["spec"]["fqdn"].as_str().unwrap_or_default();
+    if (request["operation"] == "CREATE" || request["operation"] == "UPDATE") && !fqdn.is_empty() {
+        if let Some(holder) = resources.fqdns.holder(fqdn, name) {
+            let message = format!("fqdn {} is already used by preview {}", fqdn, holder);
+            response["allowed"] = json!(false);
+            response["status"] = json!({ "code": 409, "message": message });
+        }
+    }
+    let pe = serde_json::from_value::<KubePreviewEnvironment>(object.clone());
+    if let (true, Ok(pe)) = (request["operation"] == "CREATE" || request["operation"] == "UPDATE", pe) {
+        let allowed = registries::allowed(resources, &profiles::of(resources, &pe));
+        if let Some(message) = registries::check(&allowed, &pe) {
+            response["allowed"] = json!(false);
+            response["status"] = json!({ "code": 403, "message": message });
+        }
+    }
+    if let (true, FreezeMode::Block) = (request["operation"] == "CREATE", resources.reloadable().freeze_mode) {
+        if let Some(window) = freeze::current(resources) {
+            response["allowed"] = json!(false);
+            response["status"] = json!({ "code": 403, "message": window.message() });
+        }
+    }
+    if let Some(message) = impersonation::forged(request) {
+        response["allowed"] = json!(false);
+        response["status"] = json!({ "code": 403, "message": message });
+    }
+
+    json!({
+        "apiVersion": body["apiVersion"],
+        "kind": "AdmissionReview",
+        "response": response,
+    })
+}
+
+/// Serve `POST /mutate` and `POST /validate` over TLS.
+pub async fn serve(
+    addr: SocketAddr,
+    cert_path: String,
+    key_path: String,
+    naming: Option<Arc<NamingScript>>,
+    resources: Arc<ApiResources>,
+) {
+    let mutate = warp::path("mutate")
+        .and(warp::body::json())
+        .map(move |body: JsonValue| warp::reply::json(&review(body, naming.as_deref())));
+    let validate = warp::path("validate")
+        .and(warp::body::json())
+        .map(move |body: JsonValue| warp::reply::json(&validate(body, &resources)));
+    let route = warp::post().and(mutate.or(validate));
+
+    println!("Admission webhook listening on {}", addr);
+    warp::serve(route).tls().cert_path(cert_path).key_path(key_path).run(addr).await;
+}
@@ -0,0 +1,443 @@
+//! Handles on everything the controller reads and writes in the cluster,
+//! and the helpers the rest of it goes through to create, update and delete
+//! things there.
+use chrono::Utc;
+use k8s_openapi::api::apps::v1::{DeploymentSpec, DeploymentStatus};
+use kube::{
+    api::{DeleteParams, ListParams, Object, PostParams, RawApi, Void},
+    Error,
+};
+use serde_json::json;
+use std::sync::{Arc, RwLock};
+use tracing::instrument;
+
+use crate::applied;
+use crate::bluegreen::{self, UpdateStrategy};
+use crate::bus::{self, Bus};
+use crate::client::Client;
+use crate::config::{self, Config, Reloadable};
+use crate::conflicts::{self, Conflict};
+use crate::debounce::Debounce;
+use crate::delivery::{self, DeliveryBackend};
+use crate::dns::DnsProvider;
+use crate::external_secrets::ExternalSecretTemplate;
+use crate::fqdn::FqdnIndex;
+use crate::plugins::Plugins;
+use crate::policy::Opa;
+use crate::profiles::Profiles;
+use crate::requeue::Requeue;
+use crate::statefulsets::{self, WorkloadType};
+use crate::store::Store;
+#[cfg(feature = "vault")]
+use crate::vault::Vault;
+use crate::{egress, fqdn, profiles, rollouts, KubePreviewEnvironment, PreviewEnvironmentStatus};
+
+type Deployment = Object<DeploymentSpec, DeploymentStatus>;
+type JsonValue = serde_json::value::Value;
+
+/// Everything the controller reads and writes, shared between the reconcile
+/// loop and the tasks running alongside it.
+pub struct ApiResources {
+    pub config: Config,
+    pub client: Client,
+    pub previews: RawApi,
+    pub deployments: RawApi,
+    pub stateful_sets: RawApi,
+    pub persistent_volume_claims: RawApi,
+    pub services: RawApi,
+    pub mappings: RawApi,
+    pub tcp_mappings: RawApi,
+    pub hosts: RawApi,
+    pub secrets: RawApi,
+    pub jobs: RawApi,
+    pub cron_jobs: RawApi,
+    pub pods: RawApi,
+    /// Every node, and every pod in every namespace, for `capacity`.
+    pub nodes: RawApi,
+    pub cluster_pods: RawApi,
+    pub config_maps: RawApi,
+    pub pod_monitors: RawApi,
+    pub peer_authentications: RawApi,
+    pub egress_policies: RawApi,
+    pub rollouts: RawApi,
+    pub rollout_strategy: JsonValue,
+    pub pipeline_runs: RawApi,
+    pub gitops: Option<Box<dyn DeliveryBackend>>,
+    pub source_secrets: Option<RawApi>,
+    pub external_secrets: RawApi,
+    pub external_secret_template: Option<Arc<ExternalSecretTemplate>>,
+    #[cfg(feature = "vault")]
+    pub vault: Option<Arc<Vault>>,
+    pub policy: Option<Opa>,
+    pub events: RawApi,
+    pub dns: Option<Arc<dyn DnsProvider>>,
+    pub plugins: Arc<Plugins>,
+    pub fqdns: Arc<FqdnIndex>,
+    /// Teams' settings, as last read.  See `profiles`.
+    pub profiles: Profiles,
+    pub requeue: Requeue,
+    /// Bookkeeping kept across restarts.  See `store`.
+    pub store: Arc<Store>,
+    pub debounce: Debounce,
+    /// What's happening to previews, for the notifier, metrics, audit log
+    /// and events API.  See `bus`.
+    pub bus: Arc<Bus>,
+    /// The current reloadable settings, which may have changed since
+    /// `config` was loaded.
+    pub live: RwLock<Reloadable>,
+}
+
+impl ApiResources {
+    /// Handles on every resource in the controller's namespace, and the
+    /// integrations `config` turns on.
+    pub fn new(config: Config, client: Client) -> Self {
+        let namespace = config.namespace.as_str();
+
+        // Note the resource is the using the plural form defined in the CRD.
+        let previews = RawApi::customResource("previewenvironments")
+            .group("platform9.com")
+            .within(namespace);
+        let mappings = RawApi::customResource("mappings")
+            .group("getambassador.io")
+            .version("v2")
+            .within(namespace);
+        let tcp_mappings = RawApi::customResource("tcpmappings")
+            .group("getambassador.io")
+            .version("v2")
+            .within(namespace);
+        let hosts = RawApi::customResource("hosts")
+            .group("getambassador.io")
+            .version("v2")
+            .within(namespace);
+        let pod_monitors = RawApi::customResource("podmonitors")
+            .group("monitoring.coreos.com")
+            .version("v1")
+            .within(namespace);
+        let rollouts = RawApi::customResource("rollouts")
+            .group("argoproj.io")
+            .version("v1alpha1")
+            .within(namespace);
+        let rollout_strategy = config.loaded.rollout_strategy.clone().unwrap_or_else(rollouts::default_strategy);
+        let pipeline_runs = RawApi::customResource("pipelineruns")
+            .group("tekton.dev")
+            .version("v1beta1")
+            .within(namespace);
+        let peer_authentications = RawApi::customResource("peerauthentications")
+            .group("security.istio.io")
+            .version("v1beta1")
+            .within(namespace);
+        let egress_policies = match config.egress_policy {
+            egress::EgressPolicy::Cilium => RawApi::customResource("ciliumnetworkpolicies")
+                .group("cilium.io")
+                .version("v2")
+                .within(namespace),
+            _ => RawApi::customResource("networkpolicies")
+                .group("networking.k8s.io")
+                .version("v1")
+                .within(namespace),
+        };
+        let source_secrets = config
+            .secret_source_namespace
+            .as_ref()
+            .map(|source_namespace| RawApi::v1Secret().within(source_namespace));
+        let external_secrets = RawApi::customResource("externalsecrets")
+            .group("external-secrets.io")
+            .version("v1beta1")
+            .within(namespace);
+
+        let store = Arc::new(Store::open(&config));
+
+        ApiResources {
+            previews,
+            deployments: RawApi::v1Deployment().within(namespace),
+            stateful_sets: RawApi::v1StatefulSet().within(namespace),
+            persistent_volume_claims: RawApi::v1PersistentVolumeClaim().within(namespace),
+            services: RawApi::v1Service().within(namespace),
+            mappings,
+            tcp_mappings,
+            hosts,
+            secrets: RawApi::v1Secret().within(namespace),
+            jobs: RawApi::v1Job().within(namespace),
+            cron_jobs: RawApi::v1beta1CronJob().within(namespace),
+            pods: RawApi::v1Pod().within(namespace),
+            nodes: RawApi::v1Node(),
+            cluster_pods: RawApi::v1Pod(),
+            config_maps: RawApi::v1ConfigMap().within(namespace),
+            pod_monitors,
+            peer_authentications,
+            egress_policies,
+            rollouts,
+            rollout_strategy,
+            pipeline_runs,
+            gitops: delivery::from_config(&config),
+            source_secrets,
+            external_secrets,
+            external_secret_template: config.loaded.external_secret_template.clone(),
+            #[cfg(feature = "vault")]
+            vault: Vault::from_config(&config).map(Arc::new),
+            policy: Opa::from_config(&config),
+            events: RawApi::v1Event().within(namespace),
+            dns: config.loaded.dns.clone(),
+            plugins: config.loaded.plugins.clone(),
+            fqdns: Arc::new(FqdnIndex::default()),
+            profiles: Profiles::default(),
+            requeue: Requeue::new(store.clone()),
+            store,
+            debounce: Debounce::default(),
+            bus: Arc::new(Bus::default()),
+            live: RwLock::new(config.reloadable.clone()),
+            config,
+            client,
+        }
+    }
+
+    pub fn reloadable(&self) -> Reloadable {
+        self.live.read().unwrap().clone()
+    }
+}
+
+// Delete a single child.  A child that is already gone is exactly what we
+// wanted, so a 404 counts as success.
+#[instrument(skip(resources, api, dp))]
+pub async fn delete_child(resources: &ApiResources, kind: &str, api: &RawApi, name: &str, dp: &DeleteParams) -> Result<(), Error> {
+    let request = api.delete(name, dp)?;
+    match resources.client.request::<Void>(request).await {
+        Ok(_) => Ok(()),
+        Err(Error::Api(ae)) if ae.code == 404 => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+// Attach a Kubernetes Event to the PreviewEnvironment so it shows up in
+// `kubectl describe`.  Failing to record an event is never fatal.
+#[instrument(skip(resources, pe, message))]
+pub async fn record_event(resources: &ApiResources, pe: &KubePreviewEnvironment, event_type: &str, reason: &str, message: &str) {
+    resources.bus.publish(bus::Event::new(pe, event_type, reason, message));
+    let now = Utc::now().to_rfc3339();
+    let event = json!({
+        "apiVersion": "v1",
+        "kind": "Event",
+        "metadata": {
+            "generateName": format!("{}.", pe.metadata.name),
+        },
+        "involvedObject": {
+            "apiVersion": "platform9.com/v1",
+            "kind": "PreviewEnvironment",
+            "name": pe.metadata.name,
+            "namespace": pe.metadata.namespace,
+            "uid": pe.metadata.uid,
+        },
+        "type": event_type,
+        "reason": reason,
+        "message": message,
+        "source": {
+            "component": "preview-controller",
+        },
+        "firstTimestamp": now,
+        "lastTimestamp": now,
+        "count": 1,
+    });
+
+    let data = serde_json::to_vec(&event).expect("Failed to serialize Event json");
+    let result = match resources.events.create(&PostParams::default(), data) {
+        Ok(request) => resources.client.request::<Void>(request).await.map(|_| ()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        println!("Failed to record event for {}: {:?}", pe.metadata.name, err);
+    }
+}
+
+const PROPAGATION_ANNOTATION: &str = "preview.platform9.com/propagation-policy";
+
+// Work out how a child should be deleted.  An annotation naming the kind
+// (`preview.platform9.com/propagation-policy.deployment: Orphan`) wins over
+// one for the whole environment, which wins over the controller default.
+pub fn delete_params(resources: &ApiResources, pe: &KubePreviewEnvironment, kind: &str) -> DeleteParams {
+    let annotations = &pe.metadata.annotations;
+    let policy = annotations
+        .get(&format!("{}.{}", PROPAGATION_ANNOTATION, kind))
+        .or_else(|| annotations.get(PROPAGATION_ANNOTATION))
+        .and_then(|value| {
+            let policy = config::propagation_policy(value);
+            if policy.is_none() {
+                println!("Ignoring invalid propagation policy {:?} on {}", value, pe.metadata.name);
+            }
+            policy
+        })
+        .unwrap_or_else(|| resources.config.propagation_policy.clone());
+
+    DeleteParams {
+        propagation_policy: Some(policy),
+        ..DeleteParams::default()
+    }
+}
+
+#[instrument(skip(resources, mutate))]
+pub async fn set_status<F>(resources: &ApiResources, name: &str, mut mutate: F)
+where
+    F: FnMut(&mut PreviewEnvironmentStatus),
+{
+    let result = resources
+        .client
+        .update_status(&resources.previews, name, |pe: &mut KubePreviewEnvironment| {
+            let mut status = pe.status.take().unwrap_or_default();
+            mutate(&mut status);
+            pe.status = Some(status);
+        })
+        .await;
+    if let Err(err) = result {
+        println!("Failed to update status of {}: {:?}", name, err);
+    }
+}
+
+// Bring the deployment's image in line with the spec.  This goes through
+// the conflict-retrying update so we don't overwrite changes that other
+// controllers (like an HPA) make to the deployment in the meantime.
+#[instrument(skip(resources))]
+pub async fn update_deployment_image(resources: &ApiResources, name: &str, image: &str) {
+    let result = resources
+        .client
+        .update(&resources.deployments, name, |deployment: &mut Deployment| {
+            if let Some(pod_spec) = deployment.spec.template.spec.as_mut() {
+                for container in pod_spec.containers.iter_mut() {
+                    container.image = Some(image.to_string());
+                }
+            }
+        })
+        .await;
+    if let Err(err) = result {
+        println!("Failed to update deployment {}: {:?}", name, err);
+    }
+}
+
+pub async fn create_job(resources: &ApiResources, job_json: &JsonValue) -> Result<(), Error> {
+    let data = serde_json::to_vec(&job_json).expect("Failed to serialize Job json");
+    let request = resources.jobs.create(&PostParams::default(), data)?;
+    resources.client.request::<Void>(request).await.map(|_| ())
+}
+
+// Roll the preview's pods so they pull their image again.  Used when a new
+// image is pushed under the tag a preview is already running.
+pub async fn restart_deployment(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let (name, _) = bluegreen::slots(pe);
+    let restarted_at = Utc::now().to_rfc3339();
+    if pe.spec.strategy == UpdateStrategy::ArgoRollout {
+        return rollouts::restart(resources, pe, &name, &restarted_at).await;
+    }
+    if pe.spec.workload_type == WorkloadType::StatefulSet {
+        match statefulsets::restart(&resources.client, &resources.stateful_sets, &name, &restarted_at).await {
+            Ok(()) => {
+                println!("Restarted statefulset {} for a new image push", name);
+                record_event(resources, pe, "Normal", "Redeployed", "Restarted after a new image was pushed").await;
+            }
+            Err(err) => println!("Failed to restart statefulset {}: {:?}", name, err),
+        }
+        return;
+    }
+    let result = resources
+        .client
+        .update(&resources.deployments, &name, |deployment: &mut Deployment| {
+            let template = &mut deployment.spec.template;
+            template
+                .metadata
+                .get_or_insert_with(Default::default)
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert("kubectl.kubernetes.io/restartedAt".to_string(), restarted_at.clone());
+            // The tag hasn't changed, so make sure the kubelet doesn't reuse
+            // the copy it already has.
+            if let Some(pod_spec) = template.spec.as_mut() {
+                for container in pod_spec.containers.iter_mut() {
+                    container.image_pull_policy = Some("Always".to_string());
+                }
+            }
+        })
+        .await;
+    match result {
+        Ok(_) => {
+            println!("Restarted deployment {} for a new image push", name);
+            record_event(resources, pe, "Normal", "Redeployed", "Restarted after a new image was pushed").await;
+        }
+        Err(err) => println!("Failed to restart deployment {}: {:?}", name, err),
+    }
+}
+
+// Create a child, or do nothing if it's already there.
+pub async fn create_child(resources: &ApiResources, api: &RawApi, child_json: &JsonValue) -> Result<(), Error> {
+    let data = serde_json::to_vec(&applied::stamped(child_json)).expect("Failed to serialize child json");
+    let request = api.create(&PostParams::default(), data)?;
+    match resources.client.request::<Void>(request).await {
+        Ok(_) => Ok(()),
+        Err(Error::Api(ae)) if ae.code == 409 => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// What `apply_child` had to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Applied {
+    Created,
+    Unchanged,
+    Updated,
+    /// Updated, but someone else had changed some of the same fields.
+    Conflicted(Vec<Conflict>),
+}
+
+impl Applied {
+    pub fn conflicts(&self) -> &[Conflict] {
+        match self {
+            Applied::Conflicted(conflicts) => conflicts,
+            _ => &[],
+        }
+    }
+}
+
+// Bring a child in line with the manifest rendered for it: create it if
+// it's missing, and if what's rendered has changed since it was last
+// written, merge the changes in, leaving whatever others have changed
+// about it alone.  See `applied`, and `conflicts` for when both sides
+// have changed the same thing.
+pub async fn apply_child(resources: &ApiResources, api: &RawApi, desired: &JsonValue) -> Result<Applied, Error> {
+    let name = desired["metadata"]["name"].as_str().unwrap_or_default();
+    let live: JsonValue = match resources.client.request(api.get(name)?).await {
+        Ok(live) => live,
+        Err(Error::Api(ae)) if ae.code == 404 => {
+            create_child(resources, api, desired).await?;
+            return Ok(Applied::Created);
+        }
+        Err(err) => return Err(err),
+    };
+    if applied::is_current(&live, desired) {
+        return Ok(Applied::Unchanged);
+    }
+
+    let stamped = applied::stamped(desired);
+    let back_off = resources.config.back_off_on_conflict;
+    let mut found = vec![];
+    let update = resources.client.update(api, name, |live: &mut JsonValue| {
+        let last_applied = applied::last_applied(live);
+        found = last_applied.as_ref().map(|last| conflicts::detect(last, live, &stamped)).unwrap_or_default();
+        let wanted = if back_off { conflicts::yield_to(&stamped, live, &found) } else { stamped.clone() };
+        *live = applied::merge(last_applied.as_ref(), live, &wanted);
+    });
+    update.await?;
+    Ok(if found.is_empty() { Applied::Updated } else { Applied::Conflicted(found) })
+}
+
+// Every preview in the namespace, oldest first.
+pub async fn list_previews(resources: &ApiResources) -> Result<Vec<KubePreviewEnvironment>, Error> {
+    let list: JsonValue = resources.client.request(resources.previews.list(&ListParams::default())?).await?;
+    let mut items: Vec<JsonValue> = list["items"].as_array().cloned().unwrap_or_default();
+    items.sort_by(|a, b| {
+        let created = |pe: &JsonValue| pe["metadata"]["creationTimestamp"].as_str().unwrap_or_default().to_string();
+        created(a).cmp(&created(b))
+    });
+    let mut previews: Vec<KubePreviewEnvironment> =
+        items.into_iter().filter_map(|item| serde_json::from_value(item).ok()).collect();
+    for pe in &mut previews {
+        let profile = profiles::of(resources, pe);
+        fqdn::fill(&resources.config, &profile, pe);
+    }
+    Ok(previews)
+}
@@ -0,0 +1,259 @@
+//! Knowing when a child is already the way the controller would have it,
+//! and changing only what the controller owns when it isn't.  Children are
+//! created with a hash of the manifest rendered for them in the
+//! `preview.platform9.com/applied-hash` annotation, and when they're brought
+//! in line with a changed preview they're only written to if what's rendered
+//! now hashes differently.  An unchanged child costs a read rather than a
+//! write, and its pods aren't rolled because the same manifest was sent
+//! again.
+//!
+//! That's every child of a preview rendered from the controller's own
+//! manifests and run as a Deployment, and the canary's, but not the
+//! one-shot jobs, which are only run once.  Previews delivered through
+//! GitOps or a Jsonnet template, or run as an Argo Rollout, a StatefulSet or
+//! blue-green, only have their image moved on by their own update path, so
+//! the rest of their children stay as they were created.
+//!
+//! The hash is of what the controller rendered, not of the live object, so
+//! it isn't thrown by fields the API server fills in.  It lives on the child
+//! itself, so it carries across restarts without the state store.
+//!
+//! The manifest itself is kept in `preview.platform9.com/last-applied`, the
+//! way `kubectl apply` does it, so a child that has changed is updated with
+//! a three-way merge of what was last applied, what's live and what's
+//! rendered now.  Anything the controller didn't render -- replicas an HPA
+//! scaled to, a sidecar a mesh injected, a cluster IP -- is left as it is,
+//! and so is anything rendered the same as last time.  Lists of named
+//! things, like containers, ports and env, are merged by name.  Children
+//! created before the annotation existed have nothing to merge from, so
+//! everything rendered is written over them once.
+use serde_json::json;
+
+type JsonValue = serde_json::value::Value;
+
+pub const HASH_ANNOTATION: &str = "preview.platform9.com/applied-hash";
+pub const LAST_APPLIED_ANNOTATION: &str = "preview.platform9.com/last-applied";
+
+// `manifest` without anything `stamped` added to it.
+fn rendered(manifest: &JsonValue) -> JsonValue {
+    let mut manifest = manifest.clone();
+    // Not looked up by indexing, which would leave `"annotations": null` in
+    // a manifest that had none, and have it seen as changed once the API
+    // server has dropped it
+    if let Some(annotations) = manifest.pointer_mut("/metadata/annotations").and_then(JsonValue::as_object_mut) {
+        annotations.remove(HASH_ANNOTATION);
+        annotations.remove(LAST_APPLIED_ANNOTATION);
+    }
+    manifest
+}
+
+/// A hash of `manifest`, leaving out anything it's already been stamped
+/// with.  It's the same across builds and platforms, unlike `DefaultHasher`.
+pub fn hash(manifest: &JsonValue) -> String {
+    // FNV-1a over the JSON, whose object keys are always sorted
+    let hash = rendered(manifest).to_string().bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// `manifest` with its hash and itself in annotations, ready to be
+/// written.
+pub fn stamped(manifest: &JsonValue) -> JsonValue {
+    let last_applied = rendered(manifest);
+    let mut stamped = last_applied.clone();
+    if !stamped["metadata"]["annotations"].is_object() {
+        stamped["metadata"]["annotations"] = json!({});
+    }
+    stamped["metadata"]["annotations"][HASH_ANNOTATION] = json!(hash(&last_applied));
+    stamped["metadata"]["annotations"][LAST_APPLIED_ANNOTATION] = json!(last_applied.to_string());
+    stamped
+}
+
+/// Whether `live` was last written from a manifest just like `desired`.
+pub fn is_current(live: &JsonValue, desired: &JsonValue) -> bool {
+    live["metadata"]["annotations"][HASH_ANNOTATION].as_str() == Some(hash(desired).as_str())
+}
+
+/// What was rendered for `live` when it was last written, if it says.
+pub fn last_applied(live: &JsonValue) -> Option<JsonValue> {
+    let last_applied = live["metadata"]["annotations"][LAST_APPLIED_ANNOTATION].as_str()?;
+    serde_json::from_str(last_applied).ok()
+}
+
+/// `live` with `desired` merged into it.  Fields that were in `last` but
+/// aren't in `desired` are removed, fields in neither are someone else's
+/// and kept, and fields `desired` has the same as `last` are left as they
+/// are live.
+pub fn merge(last: Option<&JsonValue>, live: &JsonValue, desired: &JsonValue) -> JsonValue {
+    match (desired, live) {
+        (JsonValue::Object(desired), JsonValue::Object(live)) => {
+            let last = last.and_then(JsonValue::as_object);
+            let mut merged = live.clone();
+            for key in last.into_iter().flat_map(|last| last.keys()) {
+                if !desired.contains_key(key) {
+                    merged.remove(key);
+                }
+            }
+            for (key, value) in desired {
+                let last = last.and_then(|last| last.get(key));
+                match live.get(key) {
+                    Some(live) if last == Some(value) => merged.insert(key.clone(), live.clone()),
+                    Some(live) => merged.insert(key.clone(), merge(last, live, value)),
+                    None => merged.insert(key.clone(), value.clone()),
+                };
+            }
+            JsonValue::Object(merged)
+        }
+        (JsonValue::Array(desired), JsonValue::Array(live)) if named(desired) && named(live) => {
+            let last = last.and_then(JsonValue::as_array).filter(|last| named(last));
+            JsonValue::Array(merge_named(last.map(Vec::as_slice).unwrap_or_default(), live, desired))
+        }
+        _ => desired.clone(),
+    }
+}
+
+// Whether every item in a list is an object with a `name`, like containers,
+// ports, env and volumes.
+fn named(items: &[JsonValue]) -> bool {
+    items.iter().all(|item| item["name"].is_string())
+}
+
+fn find<'a>(items: &'a [JsonValue], name: &JsonValue) -> Option<&'a JsonValue> {
+    items.iter().find(|item| item["name"] == *name)
+}
+
+// Merge lists of named things item by item.  The rendered items come first,
+// in the order they were rendered, followed by anyone else's.
+fn merge_named(last: &[JsonValue], live: &[JsonValue], desired: &[JsonValue]) -> Vec<JsonValue> {
+    let mut merged: Vec<JsonValue> = desired
+        .iter()
+        .map(|item| match find(live, &item["name"]) {
+            Some(live) => merge(find(last, &item["name"]), live, item),
+            None => item.clone(),
+        })
+        .collect();
+    // Whatever we rendered last time and don't now is gone
+    let others = live.iter().filter(|item| find(desired, &item["name"]).is_none() && find(last, &item["name"]).is_none());
+    merged.extend(others.cloned());
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_take_what_changed_on_each_side() {
+        let cases = vec![
+            (
+                "fields removed since the last apply go",
+                Some(json!({ "a": 1, "b": 2 })),
+                json!({ "a": 1, "b": 2, "c": 3 }),
+                json!({ "a": 1 }),
+                json!({ "a": 1, "c": 3 }),
+            ),
+            (
+                "without a last apply nothing is removed",
+                None,
+                json!({ "a": 1, "b": 2 }),
+                json!({ "a": 2 }),
+                json!({ "a": 2, "b": 2 }),
+            ),
+            (
+                "replicas an HPA scaled stay while the rendered ones don't change",
+                Some(json!({ "spec": { "replicas": 1 } })),
+                json!({ "spec": { "replicas": 5 } }),
+                json!({ "spec": { "replicas": 1 } }),
+                json!({ "spec": { "replicas": 5 } }),
+            ),
+            (
+                "rendered changes win",
+                Some(json!({ "spec": { "replicas": 1 } })),
+                json!({ "spec": { "replicas": 5 } }),
+                json!({ "spec": { "replicas": 2 } }),
+                json!({ "spec": { "replicas": 2 } }),
+            ),
+            (
+                "fields filled in by the API server stay",
+                Some(json!({ "spec": { "ports": [{ "name": "http", "port": 80 }] } })),
+                json!({ "spec": { "clusterIP": "10.0.0.1", "ports": [{ "name": "http", "port": 80, "targetPort": 80 }] } }),
+                json!({ "spec": { "ports": [{ "name": "http", "port": 8080 }] } }),
+                json!({ "spec": { "clusterIP": "10.0.0.1", "ports": [{ "name": "http", "port": 8080, "targetPort": 80 }] } }),
+            ),
+            (
+                "lists of unnamed things are replaced",
+                Some(json!({ "args": ["a"] })),
+                json!({ "args": ["a", "b"] }),
+                json!({ "args": ["c"] }),
+                json!({ "args": ["c"] }),
+            ),
+        ];
+
+        for (case, last, live, desired, expected) in cases {
+            assert_eq!(merge(last.as_ref(), &live, &desired), expected, "{}", case);
+        }
+    }
+
+    fn wrap(containers: &[JsonValue]) -> JsonValue {
+        json!({ "containers": containers })
+    }
+
+    #[test]
+    fn named_lists_are_merged_by_name() {
+        let app = |image: &str| json!({ "name": "app", "image": image });
+        let sidecar = json!({ "name": "istio-proxy", "image": "istio/proxyv2" });
+        let mut injected = app("web:1");
+        injected["resources"] = json!({ "requests": { "cpu": "100m" } });
+        let mut updated = injected.clone();
+        updated["image"] = json!("web:2");
+        let cases = vec![
+            (
+                "an injected sidecar and an injected field are kept",
+                vec![app("web:1")],
+                vec![injected.clone(), sidecar.clone()],
+                vec![app("web:2")],
+                vec![updated.clone(), sidecar.clone()],
+            ),
+            (
+                "an item rendered last time and not now goes",
+                vec![app("web:1"), json!({ "name": "worker", "image": "web:1" })],
+                vec![injected.clone(), json!({ "name": "worker", "image": "web:1" }), sidecar.clone()],
+                vec![app("web:2")],
+                vec![updated.clone(), sidecar.clone()],
+            ),
+            (
+                "a new item is added after the rendered ones",
+                vec![],
+                vec![sidecar.clone()],
+                vec![app("web:1")],
+                vec![app("web:1"), sidecar.clone()],
+            ),
+            (
+                "rendered items come in the order rendered",
+                vec![],
+                vec![json!({ "name": "b" }), json!({ "name": "a" })],
+                vec![json!({ "name": "a" }), json!({ "name": "b" })],
+                vec![json!({ "name": "a" }), json!({ "name": "b" })],
+            ),
+        ];
+
+        for (case, last, live, desired, expected) in cases {
+            assert_eq!(merge_named(&last, &live, &desired), expected, "{}", case);
+            assert_eq!(merge(Some(&wrap(&last)), &wrap(&live), &wrap(&desired)), wrap(&expected), "{}", case);
+        }
+    }
+
+    #[test]
+    fn stamped_children_are_current_until_the_rendering_changes() {
+        let manifest = json!({ "kind": "Service", "metadata": { "name": "web" }, "spec": { "type": "ClusterIP" } });
+        let live = stamped(&manifest);
+
+        assert!(is_current(&live, &manifest));
+        assert_eq!(last_applied(&live).unwrap()["spec"], manifest["spec"]);
+        let mut changed = manifest.clone();
+        changed["spec"]["type"] = json!("NodePort");
+        assert!(!is_current(&live, &changed));
+        assert_eq!(hash(&stamped(&live)), hash(&live));
+    }
+}
@@ -0,0 +1,27 @@
+//! An audit log of everything that happens to previews, one JSON object per
+//! line, appended to the file named by `AUDIT_LOG`.  It's kept by following
+//! the event bus, so it sees the same events as the notifier and the events
+//! API and never holds up reconciling.
+use std::fs::OpenOptions;
+use std::io::Write;
+use tokio::sync::broadcast::Receiver;
+
+use crate::bus::{self, Event};
+
+/// Appends every event published on the bus to `path` until the bus goes
+/// away.  The file is opened for each event, so it can be rotated from
+/// under the controller.
+pub async fn run(path: String, mut events: Receiver<Event>) {
+    println!("Writing the audit log to {}", path);
+    while let Some(event) = bus::next(&mut events, "audit log").await {
+        let line = serde_json::to_string(&event).expect("Failed to serialize audit event");
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(err) = written {
+            println!("Failed to write {} for {} to the audit log: {}", event.reason, event.preview, err);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Creating a fleet of previews at once, e.g. one per region for release
+//! testing.  A batch is one manifest with `{axis}` placeholders in it and a
+//! matrix of values for them, and every combination of the values gets a
+//! preview: `region: [us-east, eu-west]` with `customer: [acme, globex]`
+//! makes four.
+//!
+//! The previews are created a few at a time rather than all at once, and
+//! one failing doesn't stop the rest; how each one went is reported back.
+use futures::{stream, StreamExt};
+use kube::api::{Api, PostParams};
+use kube::Error;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// Each axis of a matrix and the values it takes.
+pub type Matrix = BTreeMap<String, Vec<String>>;
+
+/// How many previews are created at once when not told otherwise, and the
+/// most that can be asked for.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+pub const MAX_CONCURRENCY: usize = 32;
+
+pub struct Outcome {
+    pub name: String,
+    pub result: Result<KubePreviewEnvironment, Error>,
+}
+
+/// Every combination of the matrix's values.
+pub fn combinations(matrix: &Matrix) -> Vec<BTreeMap<String, String>> {
+    let mut combinations = vec![BTreeMap::new()];
+    for (axis, values) in matrix {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(axis.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    combinations
+}
+
+/// The manifest for each combination of the matrix's values.  Each preview
+/// has to get its own name, so the template's name needs placeholders for
+/// whatever the matrix varies.
+pub fn manifests(template: &JsonValue, matrix: &Matrix) -> Result<Vec<JsonValue>, String> {
+    if let Some((axis, _)) = matrix.iter().find(|(_, values)| values.is_empty()) {
+        return Err(format!("matrix axis {} has no values", axis));
+    }
+    let manifests: Vec<JsonValue> = combinations(matrix).iter().map(|values| fill(template, values)).collect();
+    let mut names = BTreeSet::new();
+    for manifest in &manifests {
+        let name = manifest["metadata"]["name"].as_str().unwrap_or_default();
+        if name.is_empty() {
+            return Err("the template has no metadata.name".to_string());
+        }
+        if !names.insert(name) {
+            return Err(format!("more than one preview would be named {}, give the name placeholders for the matrix", name));
+        }
+    }
+    Ok(manifests)
+}
+
+// Put each value wherever its `{axis}` placeholder appears in a string.
+// Other braces, like an fqdnTemplate's, are left for the controller.
+fn fill(template: &JsonValue, values: &BTreeMap<String, String>) -> JsonValue {
+    match template {
+        JsonValue::String(text) => {
+            let filled = values.iter().fold(text.clone(), |text, (axis, value)| text.replace(&format!("{{{}}}", axis), value));
+            JsonValue::String(filled)
+        }
+        JsonValue::Array(items) => JsonValue::Array(items.iter().map(|item| fill(item, values)).collect()),
+        JsonValue::Object(fields) => {
+            JsonValue::Object(fields.iter().map(|(key, value)| (key.clone(), fill(value, values))).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+/// Creates the previews, `concurrency` at a time, and says how each went,
+/// in the order they were given.
+pub async fn create(previews: &Api<KubePreviewEnvironment>, manifests: Vec<JsonValue>, concurrency: usize) -> Vec<Outcome> {
+    let pp = PostParams::default();
+    let pp = &pp;
+    stream::iter(manifests)
+        .map(|manifest| async move {
+            let name = manifest["metadata"]["name"].as_str().unwrap_or_default().to_string();
+            let data = serde_json::to_vec(&manifest).expect("Failed to serialize PreviewEnvironment json");
+            let result = previews.create(pp, data).await;
+            Outcome { name, result }
+        })
+        .buffered(concurrency.max(1).min(MAX_CONCURRENCY))
+        .collect()
+        .await
+}
@@ -0,0 +1,410 @@
+// `kubectl` picks up any executable named `kubectl-<name>` on the PATH as a
+// plugin, so installing this binary lets you run `kubectl preview ...`.
+use kube::{
+    api::{Api, DeleteParams, ListParams, LogParams, Object, PatchParams, PostParams, RawApi},
+    client::APIClient,
+    config, Error,
+};
+use k8s_openapi::api::core::v1::{PodSpec, PodStatus};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::BTreeMap;
+use structopt::StructOpt;
+
+use rust_k8s_starter::batch::{self, Matrix};
+use rust_k8s_starter::bluegreen::ROLLBACK_ANNOTATION;
+use rust_k8s_starter::pause::PAUSED_ANNOTATION;
+use rust_k8s_starter::promotion::{FQDN_ANNOTATION, PROMOTE_ANNOTATION};
+use rust_k8s_starter::retry::RETRY_ANNOTATION;
+use rust_k8s_starter::usage::Usage;
+use rust_k8s_starter::KubePreviewEnvironment;
+
+type Pod = Object<PodSpec, PodStatus>;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kubectl-preview", about = "Manage preview environments")]
+struct Opt {
+    /// Namespace the preview environments live in
+    #[structopt(short, long, default_value = "default", global = true)]
+    namespace: String,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Create a new preview environment
+    Create {
+        name: String,
+        #[structopt(long)]
+        image: String,
+        #[structopt(long)]
+        fqdn: String,
+        /// Who the preview belongs to.  Defaults to you.
+        #[structopt(long)]
+        owner: Option<String>,
+    },
+    /// Create a preview environment for every combination of a matrix's values
+    CreateBatch {
+        /// PreviewEnvironment manifest, with {axis} placeholders for the matrix's values
+        #[structopt(short = "f", long)]
+        filename: String,
+        /// An axis of the matrix and its values, e.g. region=us-east,eu-west.  Repeat for more axes.
+        #[structopt(long)]
+        matrix: Vec<String>,
+        /// How many previews to create at once
+        #[structopt(long, default_value = "4")]
+        concurrency: usize,
+    },
+    /// Create a new preview environment with the same spec as an existing one
+    Clone {
+        source: String,
+        name: String,
+        #[structopt(long)]
+        fqdn: String,
+        /// Copy the source's database into the clone as well
+        #[structopt(long)]
+        with_database: bool,
+        /// Who the clone belongs to.  Defaults to you.
+        #[structopt(long)]
+        owner: Option<String>,
+    },
+    /// List preview environments
+    List {
+        /// Only list previews belonging to this owner, or `me` for your own
+        #[structopt(long)]
+        owner: Option<String>,
+    },
+    /// Delete a preview environment
+    Delete { name: String },
+    /// Print the URL a preview environment is served at
+    Url { name: String },
+    /// Print the logs of the pods backing a preview environment
+    Logs { name: String },
+    /// Switch a blue-green preview back to the release before its last update
+    Rollback { name: String },
+    /// Stop the controller from touching a preview environment, to tune it by hand
+    Pause { name: String },
+    /// Let the controller manage a paused preview environment again
+    Resume { name: String },
+    /// Try a failed preview environment again after it has run out of retries
+    Retry { name: String },
+    /// Copy a preview environment into a long-lived namespace such as staging
+    Promote {
+        name: String,
+        /// Namespace to promote it to
+        #[structopt(long)]
+        to: String,
+        /// FQDN the promoted environment is served at
+        #[structopt(long)]
+        fqdn: String,
+    },
+    /// Summarise what preview environments cost, by owner or namespace
+    Report {
+        #[structopt(long, default_value = "owner", possible_values = &["owner", "namespace"])]
+        by: String,
+        /// Include previews in every namespace
+        #[structopt(short = "A", long)]
+        all_namespaces: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let opt = Opt::from_args();
+    let kubeconfig = config::load_kube_config().await?;
+    let client = APIClient::new(kubeconfig);
+
+    let previews: Api<KubePreviewEnvironment> = Api::customResource(client.clone(), "previewenvironments")
+        .group("platform9.com")
+        .within(&opt.namespace);
+
+    match opt.command {
+        Command::Create { name, image, fqdn, owner } => {
+            let mut data = json!({
+                "apiVersion": "platform9.com/v1",
+                "kind": "PreviewEnvironment",
+                "metadata": {
+                    "name": name,
+                    "labels": {
+                        "preview": "true",
+                    }
+                },
+                "spec": {
+                    "image": image,
+                    "fqdn": fqdn,
+                }
+            });
+            // Without an owner the controller's admission webhook records
+            // whoever created it.
+            if let Some(owner) = owner {
+                data["spec"]["owner"] = json!(owner);
+            }
+            let data = serde_json::to_vec(&data).expect("Failed to serialize PreviewEnvironment json");
+            previews.create(&PostParams::default(), data).await?;
+            println!("previewenvironment/{} created", name);
+        }
+        Command::CreateBatch { filename, matrix, concurrency } => {
+            let manifests = batch_manifests(&filename, &matrix);
+            let outcomes = batch::create(&previews, manifests, concurrency).await;
+            let mut failed = 0;
+            for outcome in &outcomes {
+                match &outcome.result {
+                    Ok(_) => println!("previewenvironment/{} created", outcome.name),
+                    Err(Error::Api(ae)) => println!("previewenvironment/{} failed: {}", outcome.name, ae.message),
+                    Err(err) => println!("previewenvironment/{} failed: {:?}", outcome.name, err),
+                }
+                failed += outcome.result.is_err() as usize;
+            }
+            println!("{} created, {} failed", outcomes.len() - failed, failed);
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Clone { source, name, fqdn, with_database, owner } => {
+            // Go through the raw JSON so every field of the spec is copied,
+            // not just the ones this plugin knows about
+            let api = RawApi::customResource("previewenvironments")
+                .group("platform9.com")
+                .within(&opt.namespace);
+            let original: serde_json::Value = client.request(api.get(&source)?).await?;
+            let mut spec = original["spec"].clone();
+            spec["fqdn"] = json!(fqdn);
+            spec["cloneFrom"] = json!({ "name": source });
+            if with_database {
+                spec["cloneFrom"]["database"] = json!({});
+            }
+            // The clone is yours unless you say otherwise, not the original's
+            // owner's
+            if let Some(owner) = owner {
+                spec["owner"] = json!(owner);
+            } else if let Some(spec) = spec.as_object_mut() {
+                spec.remove("owner");
+            }
+            let data = json!({
+                "apiVersion": "platform9.com/v1",
+                "kind": "PreviewEnvironment",
+                "metadata": {
+                    "name": name,
+                    "labels": original["metadata"]["labels"],
+                },
+                "spec": spec,
+            });
+            let data = serde_json::to_vec(&data).expect("Failed to serialize PreviewEnvironment json");
+            previews.create(&PostParams::default(), data).await?;
+            println!("previewenvironment/{} cloned from {}", name, source);
+        }
+        Command::List { owner } => {
+            let owner = match owner.as_deref() {
+                Some("me") => Some(current_user(&client).await?),
+                _ => owner,
+            };
+            let list = previews.list(&ListParams::default()).await?;
+            println!("{:<32} {:<40} {:<24} {:<8} {:<8} {}", "NAME", "IMAGE", "OWNER", "CPU", "MEMORY", "FQDN");
+            for pe in list.items {
+                let pe_owner = pe.spec.owner.unwrap_or_default();
+                if owner.as_ref().map(|owner| owner != &pe_owner).unwrap_or(false) {
+                    continue;
+                }
+                // Usage is only known once metrics-server has seen the pods
+                let usage = pe.status.and_then(|status| status.usage).unwrap_or_else(|| Usage {
+                    cpu: "-".to_string(),
+                    memory: "-".to_string(),
+                });
+                println!(
+                    "{:<32} {:<40} {:<24} {:<8} {:<8} {}",
+                    pe.metadata.name, pe.spec.image, pe_owner, usage.cpu, usage.memory, pe.spec.fqdn
+                );
+            }
+        }
+        Command::Delete { name } => {
+            previews.delete(&name, &DeleteParams::default()).await?;
+            println!("previewenvironment/{} deleted", name);
+        }
+        Command::Url { name } => {
+            let pe = previews.get(&name).await?;
+            println!("https://{}", pe.spec.fqdn);
+        }
+        Command::Logs { name } => {
+            // The controller labels the pods it creates with the name of
+            // the deployment, so we can find them with a label selector.
+            let pods: Api<Pod> = Api::v1Pod(client).within(&opt.namespace);
+            let lp = ListParams {
+                label_selector: Some(format!("app={}-deployment", name)),
+                ..ListParams::default()
+            };
+            for pod in pods.list(&lp).await?.items {
+                let logs = pods.log(&pod.metadata.name, &LogParams::default()).await?;
+                println!("==> {} <==", pod.metadata.name);
+                print!("{}", logs);
+            }
+        }
+        Command::Rollback { name } => {
+            // The controller does the actual work when it sees the annotation
+            let patch = json!({
+                "metadata": {
+                    "annotations": {
+                        ROLLBACK_ANNOTATION: "true",
+                    }
+                }
+            });
+            let patch = serde_json::to_vec(&patch).expect("Failed to serialize rollback patch");
+            previews.patch(&name, &PatchParams::default(), patch).await?;
+            println!("previewenvironment/{} rolling back", name);
+        }
+        Command::Pause { name } => {
+            let patch = json!({
+                "metadata": {
+                    "annotations": {
+                        PAUSED_ANNOTATION: "true",
+                    }
+                }
+            });
+            let patch = serde_json::to_vec(&patch).expect("Failed to serialize pause patch");
+            previews.patch(&name, &PatchParams::default(), patch).await?;
+            println!("previewenvironment/{} paused", name);
+        }
+        Command::Resume { name } => {
+            // A merge patch removes the annotation when it's set to null
+            let patch = json!({
+                "metadata": {
+                    "annotations": {
+                        PAUSED_ANNOTATION: null,
+                    }
+                }
+            });
+            let patch = serde_json::to_vec(&patch).expect("Failed to serialize resume patch");
+            previews.patch(&name, &PatchParams::default(), patch).await?;
+            println!("previewenvironment/{} resumed", name);
+        }
+        Command::Retry { name } => {
+            let patch = json!({
+                "metadata": {
+                    "annotations": {
+                        RETRY_ANNOTATION: "true",
+                    }
+                }
+            });
+            let patch = serde_json::to_vec(&patch).expect("Failed to serialize retry patch");
+            previews.patch(&name, &PatchParams::default(), patch).await?;
+            println!("previewenvironment/{} retrying", name);
+        }
+        Command::Promote { name, to, fqdn } => {
+            // As with rollback, the controller does the copying
+            let patch = json!({
+                "metadata": {
+                    "annotations": {
+                        PROMOTE_ANNOTATION: to,
+                        FQDN_ANNOTATION: fqdn,
+                    }
+                }
+            });
+            let patch = serde_json::to_vec(&patch).expect("Failed to serialize promote patch");
+            previews.patch(&name, &PatchParams::default(), patch).await?;
+            println!("previewenvironment/{} promoting to {}", name, to);
+        }
+        Command::Report { by, all_namespaces } => {
+            let mut api = RawApi::customResource("previewenvironments").group("platform9.com");
+            if !all_namespaces {
+                api = api.within(&opt.namespace);
+            }
+            let list: serde_json::Value = client.request(api.list(&ListParams::default())?).await?;
+            report(&list, &by);
+        }
+    }
+
+    Ok(())
+}
+
+// Read a batch's template and matrix and work out what to create, giving
+// up if they don't make sense.
+fn batch_manifests(filename: &str, axes: &[String]) -> Vec<serde_json::Value> {
+    let template = std::fs::read_to_string(filename).unwrap_or_else(|err| fail(format!("Failed to read {}: {}", filename, err)));
+    let mut template: serde_json::Value =
+        serde_yaml::from_str(&template).unwrap_or_else(|err| fail(format!("Failed to parse {}: {}", filename, err)));
+    if template["apiVersion"].is_null() {
+        template["apiVersion"] = json!("platform9.com/v1");
+        template["kind"] = json!("PreviewEnvironment");
+    }
+    let mut matrix = Matrix::new();
+    for axis in axes {
+        let equals = axis.find('=').unwrap_or_else(|| fail(format!("Expected --matrix axis=value,value, got {}", axis)));
+        let values = axis[equals + 1..].split(',').map(str::trim).filter(|value| !value.is_empty()).map(String::from);
+        matrix.entry(axis[..equals].trim().to_string()).or_default().extend(values);
+    }
+    batch::manifests(&template, &matrix).unwrap_or_else(|err| fail(format!("Invalid batch: {}", err)))
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+#[derive(Default)]
+struct CostTotals {
+    previews: usize,
+    hourly: f64,
+    daily: f64,
+    to_date: f64,
+}
+
+// Total up the controller's cost estimates.  "To date" assumes a preview
+// has cost the same every hour since it was created.
+fn report(list: &serde_json::Value, by: &str) {
+    let now = Utc::now();
+    let mut totals: BTreeMap<String, CostTotals> = BTreeMap::new();
+    let mut overall = CostTotals::default();
+
+    for pe in list["items"].as_array().into_iter().flatten() {
+        let key = match by {
+            "namespace" => pe["metadata"]["namespace"].as_str(),
+            _ => pe["spec"]["owner"].as_str(),
+        };
+        let hourly = pe["status"]["cost"]["hourly"].as_f64().unwrap_or(0.0);
+        let daily = pe["status"]["cost"]["daily"].as_f64().unwrap_or(0.0);
+        let hours = pe["metadata"]["creationTimestamp"]
+            .as_str()
+            .and_then(|created| created.parse::<DateTime<Utc>>().ok())
+            .map(|created| (now - created).num_seconds() as f64 / 3600.0)
+            .unwrap_or(0.0);
+
+        let group = totals.entry(key.unwrap_or("<none>").to_string()).or_default();
+        for row in vec![group, &mut overall] {
+            row.previews += 1;
+            row.hourly += hourly;
+            row.daily += daily;
+            row.to_date += hourly * hours;
+        }
+    }
+
+    println!("{:<40} {:>8} {:>10} {:>10} {:>12}", by.to_uppercase(), "PREVIEWS", "HOURLY", "DAILY", "TO DATE");
+    let print_row = |key: &str, row: &CostTotals| {
+        println!("{:<40} {:>8} {:>10.2} {:>10.2} {:>12.2}", key, row.previews, row.hourly, row.daily, row.to_date);
+    };
+    for (key, row) in &totals {
+        print_row(key, row);
+    }
+    print_row("TOTAL", &overall);
+}
+
+// Ask the API server who we are, the same way `kubectl auth whoami` does,
+// so `--owner me` matches what the admission webhook recorded.
+async fn current_user(client: &APIClient) -> Result<String, Error> {
+    let review = json!({
+        "apiVersion": "authentication.k8s.io/v1",
+        "kind": "SelfSubjectReview",
+    });
+    let request = http::Request::post("/apis/authentication.k8s.io/v1/selfsubjectreviews")
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&review).expect("Failed to serialize SelfSubjectReview json"))
+        .expect("Failed to build SelfSubjectReview request");
+    let review: serde_json::Value = client.request(request).await?;
+    match review["status"]["userInfo"]["username"].as_str() {
+        Some(username) => Ok(username.to_string()),
+        None => {
+            eprintln!("Could not work out who you are; pass --owner <name> instead");
+            std::process::exit(1);
+        }
+    }
+}
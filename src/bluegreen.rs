@@ -0,0 +1,229 @@
+//! Blue-green updates.  Instead of rolling the preview's Deployment in
+//! place, a new image is brought up in a second Deployment and the Service
+//! is only pointed at it once every pod is ready.  The old Deployment is
+//! kept for `BLUE_GREEN_RETENTION_SECONDS` so going back is instant: set
+//! the `preview.platform9.com/rollback` annotation (or run
+//! `kubectl preview rollback`) and the Service flips straight back.
+use chrono::{DateTime, Utc};
+use kube::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{canary, set_status, ApiResources, Children, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+pub const ROLLBACK_ANNOTATION: &str = "preview.platform9.com/rollback";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateStrategy {
+    Rolling,
+    BlueGreen,
+    /// Hand the Deployment over to Argo Rollouts; see `rollouts`.
+    ArgoRollout,
+}
+
+impl Default for UpdateStrategy {
+    fn default() -> Self {
+        UpdateStrategy::Rolling
+    }
+}
+
+/// The Deployment the Service currently sends traffic to, and the other one.
+pub fn slots(pe: &KubePreviewEnvironment) -> (String, String) {
+    let children = Children::of(pe);
+    let active = pe
+        .status
+        .as_ref()
+        .and_then(|status| status.active_deployment.clone())
+        .unwrap_or_else(|| children.deployment.clone());
+    if active == children.deployment {
+        (active, children.green_deployment)
+    } else {
+        (active, children.deployment)
+    }
+}
+
+fn is_ready(deployment: &JsonValue) -> bool {
+    let wanted = deployment["spec"]["replicas"].as_i64().unwrap_or(1);
+    let status = &deployment["status"];
+    status["observedGeneration"].as_i64() >= deployment["metadata"]["generation"].as_i64()
+        && status["updatedReplicas"].as_i64().unwrap_or(0) == wanted
+        && status["availableReplicas"].as_i64().unwrap_or(0) == wanted
+}
+
+fn image_of(deployment: &JsonValue) -> Option<&str> {
+    deployment["spec"]["template"]["spec"]["containers"][0]["image"].as_str()
+}
+
+/// Start moving the preview to `image`.  The switch happens in the
+/// background once the new pods are ready.
+pub async fn deploy(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, image: &str) {
+    let name = pe.metadata.name.clone();
+    let (active, standby) = slots(pe);
+
+    if let Err(err) = prepare(resources, &active, &standby, image).await {
+        let message = format!("Failed to start blue-green update: {}", err);
+        println!("{} {}", name, message);
+        crate::record_event(resources, pe, "Warning", "UpdateFailed", &message).await;
+        return;
+    }
+
+    set_status(resources, &name, |status| {
+        status.phase = Some("Updating".to_string());
+        status.message = Some(format!("Waiting for {} to be ready", standby));
+        status.deploying_image = Some(image.to_string());
+    })
+    .await;
+
+    let resources = resources.clone();
+    let pe = pe.clone();
+    let image = image.to_string();
+    tokio::spawn(async move {
+        switch_when_ready(&resources, &pe, &active, &standby, &image).await;
+    });
+}
+
+// Point the standby Deployment at the new image, creating it from the active
+// one if it's not there.  A standby already running the image (the previous
+// release, when rolling back) is left alone so the switch is immediate.
+async fn prepare(resources: &ApiResources, active: &str, standby: &str, image: &str) -> Result<(), Error> {
+    let deployments = &resources.deployments;
+    match resources.client.request::<JsonValue>(deployments.get(standby)?).await {
+        Ok(existing) if image_of(&existing) == Some(image) && existing["spec"]["replicas"] != 0 => Ok(()),
+        // A retired standby is scaled back up as well as updated
+        Ok(_) => resources
+            .client
+            .update(deployments, standby, |deployment: &mut JsonValue| {
+                for container in deployment["spec"]["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+                    container["image"] = json!(image);
+                }
+                if deployment["spec"]["replicas"] == 0 {
+                    deployment["spec"]["replicas"] = json!(1);
+                }
+            })
+            .await
+            .map(|_: JsonValue| ()),
+        Err(Error::Api(ae)) if ae.code == 404 => {
+            let current: JsonValue = resources.client.request(deployments.get(active)?).await?;
+            let standby_json = canary::deployment_json(&current, standby, image);
+            crate::create_child(resources, deployments, &standby_json).await
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn switch_when_ready(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, active: &str, standby: &str, image: &str) {
+    let name = &pe.metadata.name;
+    let started = Instant::now();
+    loop {
+        let ready = match resources.deployments.get(standby) {
+            Ok(request) => resources.client.request::<JsonValue>(request).await.map(|deployment| is_ready(&deployment)),
+            Err(err) => Err(err),
+        };
+        match ready {
+            Ok(true) => break,
+            Ok(false) if started.elapsed() < resources.config.blue_green_ready_timeout => {}
+            Ok(false) => return crate::reconcile::fail(resources, pe, "UpdateFailed", &format!("{} did not become ready in time", standby)).await,
+            Err(err) => return crate::reconcile::fail(resources, pe, "UpdateFailed", &format!("Failed to check {}: {}", standby, err)).await,
+        }
+        tokio::time::delay_for(POLL_INTERVAL).await;
+    }
+
+    // Flip the Services over to the new pods
+    for service in crate::services::names(pe) {
+        let result = resources
+            .client
+            .update(&resources.services, &service, |service: &mut JsonValue| {
+                service["spec"]["selector"]["app"] = json!(standby);
+            })
+            .await;
+        if let Err(err) = result.map(|_: JsonValue| ()) {
+            return crate::reconcile::fail(resources, pe, "UpdateFailed", &format!("Failed to switch {} to {}: {}", service, standby, err)).await;
+        }
+    }
+
+    println!("Switched {} from {} to {}", name, active, standby);
+    // Keep the old release around for a while in case we need to go back
+    let retention = resources.config.blue_green_retention;
+    let rollback_until = chrono::Duration::from_std(retention).ok().map(|retention| (Utc::now() + retention).to_rfc3339());
+    let previous_image = pe.status.as_ref().and_then(|status| status.image.clone());
+    set_status(resources, name, |status| {
+        status.phase = Some("Ready".to_string());
+        status.message = None;
+        status.image = Some(image.to_string());
+        status.deploying_image = None;
+        status.active_deployment = Some(standby.to_string());
+        status.previous_image = previous_image.clone();
+        status.rollback_until = rollback_until.clone();
+    })
+    .await;
+    resources.requeue.after(name, retention);
+}
+
+/// Scale the old release down once the rollback window is over, or check
+/// back when it will be.  It's kept at zero replicas rather than deleted so
+/// the next update has a Deployment to reuse.
+pub async fn retire_expired(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let rollback_until = pe.status.as_ref().and_then(|status| status.rollback_until.as_deref());
+    let rollback_until = match rollback_until.and_then(|until| DateTime::parse_from_rfc3339(until).ok()) {
+        Some(until) => until.with_timezone(&Utc),
+        None => return,
+    };
+    if let Ok(remaining) = (rollback_until - Utc::now()).to_std() {
+        resources.requeue.after(&pe.metadata.name, remaining);
+        return;
+    }
+
+    let (_, previous) = slots(pe);
+    let result = resources
+        .client
+        .update(&resources.deployments, previous, |deployment: &mut JsonValue| {
+            deployment["spec"]["replicas"] = json!(0);
+        })
+        .await;
+    match result.map(|_: JsonValue| ()) {
+        Ok(()) => {
+            println!("Scaled down {} after the rollback window", previous);
+            set_status(resources, &pe.metadata.name, |status| {
+                status.previous_image = None;
+                status.rollback_until = None;
+            })
+            .await;
+        }
+        // Never got as far as a second release
+        Err(Error::Api(ae)) if ae.code == 404 => {
+            set_status(resources, &pe.metadata.name, |status| status.rollback_until = None).await;
+        }
+        Err(err) => println!("Failed to scale down {}: {:?}", previous, err),
+    }
+}
+
+/// Handle the rollback annotation by pointing the spec back at the previous
+/// image.  The resulting update finds the old release still running and
+/// switches straight to it.
+pub async fn rollback(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let previous_image = pe.status.as_ref().and_then(|status| status.previous_image.clone());
+    let result = resources
+        .client
+        .update(&resources.previews, &pe.metadata.name, |current: &mut KubePreviewEnvironment| {
+            current.metadata.annotations.remove(ROLLBACK_ANNOTATION);
+            if let Some(previous_image) = &previous_image {
+                current.spec.image = previous_image.clone();
+            }
+        })
+        .await;
+    match (result, &previous_image) {
+        (Ok(_), Some(previous_image)) => println!("Rolling {} back to {}", pe.metadata.name, previous_image),
+        (Ok(_), None) => {
+            let message = "Nothing to roll back to";
+            crate::record_event(resources, pe, "Warning", "RollbackFailed", message).await;
+        }
+        (Err(err), _) => println!("Failed to roll back {}: {:?}", pe.metadata.name, err),
+    }
+}
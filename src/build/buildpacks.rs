@@ -0,0 +1,34 @@
+use serde_json::json;
+
+use super::{BuildSpec, ImageBuilder, JsonValue};
+
+/// Builds with Cloud Native Buildpacks, for repos that don't have a
+/// Dockerfile.  The builder image carries the buildpacks and the lifecycle;
+/// `creator` runs detect, build and export in one go and pushes the result.
+pub struct Buildpacks {
+    pub image: String,
+}
+
+impl ImageBuilder for Buildpacks {
+    fn container(&self, build: &BuildSpec, image: &str) -> JsonValue {
+        let app = format!("/workspace/{}", build.context.trim_start_matches('/'));
+        json!({
+            "name": "build",
+            "image": self.image,
+            "command": ["/cnb/lifecycle/creator"],
+            "args": [
+                format!("-app={}", app),
+                image,
+            ],
+            // The lifecycle finds registry credentials the same way the
+            // docker CLI does.
+            "env": [
+                { "name": "DOCKER_CONFIG", "value": "/docker-config" },
+            ],
+            "volumeMounts": [
+                { "name": "workspace", "mountPath": "/workspace" },
+                { "name": "docker-config", "mountPath": "/docker-config" },
+            ],
+        })
+    }
+}
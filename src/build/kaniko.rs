@@ -0,0 +1,28 @@
+use serde_json::json;
+
+use super::{BuildSpec, ImageBuilder, JsonValue};
+
+/// Builds from a Dockerfile with kaniko, which doesn't need a Docker
+/// daemon or any special privileges.
+pub struct Kaniko {
+    pub image: String,
+}
+
+impl ImageBuilder for Kaniko {
+    fn container(&self, build: &BuildSpec, image: &str) -> JsonValue {
+        let context = format!("/workspace/{}", build.context.trim_start_matches('/'));
+        json!({
+            "name": "build",
+            "image": self.image,
+            "args": [
+                format!("--context=dir://{}", context),
+                format!("--dockerfile={}", build.dockerfile),
+                format!("--destination={}", image),
+            ],
+            "volumeMounts": [
+                { "name": "workspace", "mountPath": "/workspace" },
+                { "name": "docker-config", "mountPath": "/kaniko/.docker" },
+            ],
+        })
+    }
+}
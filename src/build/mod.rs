@@ -0,0 +1,181 @@
+//! Builds preview images from source.  A preview that specifies a `build`
+//! instead of (or as well as) an `image` gets a Job that clones the repo,
+//! builds an image with one of the `ImageBuilder`s, and pushes it to the
+//! controller's registry.  Once the Job succeeds the preview is deployed
+//! from the freshly pushed image.
+use kube::{api::RawApi, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+use crate::client::Client;
+use crate::config::Config;
+
+mod buildpacks;
+mod kaniko;
+
+pub use buildpacks::Buildpacks;
+pub use kaniko::Kaniko;
+
+type JsonValue = serde_json::value::Value;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildSpec {
+    /// Git URL of the repository to build.
+    pub git: String,
+    /// Branch, tag or commit to check out.
+    #[serde(rename = "ref", default = "default_ref")]
+    pub git_ref: String,
+    /// Path of the Dockerfile, relative to `context`.
+    #[serde(default = "default_dockerfile")]
+    pub dockerfile: String,
+    /// Directory within the repository to build from.
+    #[serde(default = "default_context")]
+    pub context: String,
+    /// Which builder to use.  `dockerfile` is ignored by buildpacks.
+    #[serde(default)]
+    pub builder: BuilderKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuilderKind {
+    Kaniko,
+    Buildpacks,
+}
+
+impl Default for BuilderKind {
+    fn default() -> Self {
+        BuilderKind::Kaniko
+    }
+}
+
+fn default_ref() -> String {
+    "master".to_string()
+}
+
+fn default_dockerfile() -> String {
+    "Dockerfile".to_string()
+}
+
+fn default_context() -> String {
+    ".".to_string()
+}
+
+/// Something that can turn the source checked out at `/workspace` into an
+/// image and push it.  Registry credentials are mounted from the
+/// `docker-config` volume wherever the builder expects to find them.
+pub trait ImageBuilder: Send + Sync {
+    fn container(&self, build: &BuildSpec, image: &str) -> JsonValue;
+}
+
+/// The builder a preview's build asked for, set up from the controller config.
+pub fn builder_for(build: &BuildSpec, config: &Config) -> Box<dyn ImageBuilder> {
+    match build.builder {
+        BuilderKind::Kaniko => Box::new(Kaniko { image: config.kaniko_image.clone() }),
+        BuilderKind::Buildpacks => Box::new(Buildpacks { image: config.buildpacks_builder_image.clone() }),
+    }
+}
+
+pub enum JobResult {
+    Succeeded,
+    Failed(String),
+}
+
+/// The image a preview's build will push, tagged with the ref it was
+/// built from.
+pub fn image_name(registry: &str, preview: &str, build: &BuildSpec) -> String {
+    let tag: String = build
+        .git_ref
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '-' })
+        .take(128)
+        .collect();
+    format!("{}/{}:{}", registry.trim_end_matches('/'), preview, tag)
+}
+
+pub fn job_json(name: &str, preview: &str, build: &BuildSpec, image: &str, builder: &dyn ImageBuilder, config: &Config) -> JsonValue {
+    let mut volumes = vec![json!({ "name": "workspace", "emptyDir": {} })];
+    if let Some(push_secret) = &config.build_push_secret {
+        volumes.push(json!({
+            "name": "docker-config",
+            "secret": {
+                "secretName": push_secret,
+                "items": [{ "key": ".dockerconfigjson", "path": "config.json" }],
+            }
+        }));
+    } else {
+        volumes.push(json!({ "name": "docker-config", "emptyDir": {} }));
+    }
+
+    json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "spec": {
+            "backoffLimit": 0,
+            "template": {
+                "metadata": {
+                    "labels": {
+                        "preview.platform9.com/name": preview,
+                    }
+                },
+                "spec": {
+                    "restartPolicy": "Never",
+                    "initContainers": [
+                        {
+                            // The repo and ref are passed as environment
+                            // variables so they never get interpreted by the shell.
+                            "name": "clone",
+                            "image": config.git_image,
+                            "command": ["sh", "-c", "git clone \"$GIT_REPO\" /workspace && cd /workspace && git checkout \"$GIT_REF\""],
+                            "env": [
+                                { "name": "GIT_REPO", "value": build.git },
+                                { "name": "GIT_REF", "value": build.git_ref },
+                            ],
+                            "volumeMounts": [{ "name": "workspace", "mountPath": "/workspace" }],
+                        }
+                    ],
+                    "containers": [builder.container(build, image)],
+                    "volumes": volumes,
+                }
+            }
+        }
+    })
+}
+
+/// Polls a Job until it finishes or `timeout` passes.
+pub async fn wait_for_job(client: &Client, jobs: &RawApi, name: &str, timeout: Duration) -> Result<JobResult, Error> {
+    let started = Instant::now();
+    loop {
+        let job: JsonValue = client.request(jobs.get(name)?).await?;
+        let status = &job["status"];
+        if status["succeeded"].as_i64().unwrap_or(0) > 0 {
+            return Ok(JobResult::Succeeded);
+        }
+
+        let failed = status["conditions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|condition| condition["type"] == "Failed" && condition["status"] == "True");
+        if let Some(condition) = failed {
+            let message = condition["message"].as_str().unwrap_or("job failed");
+            return Ok(JobResult::Failed(message.to_string()));
+        }
+
+        if started.elapsed() > timeout {
+            return Ok(JobResult::Failed(format!("job did not finish within {:?}", timeout)));
+        }
+        tokio::time::delay_for(POLL_INTERVAL).await;
+    }
+}
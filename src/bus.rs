@@ -0,0 +1,85 @@
+//! What happens to previews, broadcast inside the controller for whatever
+//! wants to know.  The reconciler publishes an `Event` when a preview is
+//! added, changed or deleted and for every Kubernetes Event it records, and
+//! the notifier, metrics, audit log and server-sent events API each follow
+//! along on a subscription of their own.  Publishing never waits on them, so
+//! a slow webhook or a stuck client can't hold up reconciling, and something
+//! new that wants to follow previews only needs to `subscribe`.
+//!
+//! A subscriber that falls more than `CAPACITY` events behind misses the
+//! oldest of them, which it's told about, rather than holding everyone else
+//! up.
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, RecvError, Sender};
+
+use crate::KubePreviewEnvironment;
+
+// How far behind a subscriber can get before it starts missing events.
+const CAPACITY: usize = 1024;
+
+/// Something that happened to a preview.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub preview: String,
+    pub namespace: Option<String>,
+    /// `Normal` or `Warning`, as for Kubernetes Events.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// `Added`, `Modified` or `Deleted` for changes to the preview itself,
+    /// otherwise the reason of the Kubernetes Event recorded for it, e.g.
+    /// `InvalidSpec`.
+    pub reason: String,
+    pub message: String,
+    /// RFC 3339.
+    pub time: String,
+}
+
+impl Event {
+    pub fn new(pe: &KubePreviewEnvironment, event_type: &str, reason: &str, message: &str) -> Self {
+        Event {
+            preview: pe.metadata.name.clone(),
+            namespace: pe.metadata.namespace.clone(),
+            event_type: event_type.to_string(),
+            reason: reason.to_string(),
+            message: message.to_string(),
+            time: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+pub struct Bus {
+    sender: Sender<Event>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Bus { sender }
+    }
+}
+
+impl Bus {
+    /// Tell every subscriber.  Nobody listening is fine.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Every event published from now on.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// The next event for `subscriber`, carrying on past any it fell too far
+/// behind to see.  `None` once the bus is gone.
+pub async fn next(receiver: &mut Receiver<Event>, subscriber: &str) -> Option<Event> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(missed)) => println!("The {} fell behind and missed {} preview events", subscriber, missed),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
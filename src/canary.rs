@@ -0,0 +1,59 @@
+//! Canary deployments.  A preview with `spec.canary` runs a second
+//! Deployment of another image next to the main one, and Ambassador sends
+//! `weight` percent of the preview's traffic to it, so two builds can be
+//! compared on one URL.
+//!
+//! The canary children are derived from the main ones so they pick up the
+//! same secrets, resources, labels and mesh settings.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CanarySpec {
+    pub image: String,
+    /// Percentage of requests sent to the canary.
+    pub weight: u8,
+}
+
+// Only keep what the API server needs from a manifest's metadata, so a
+// live object can be used as a template.
+fn template_metadata(manifest: &JsonValue, name: &str) -> JsonValue {
+    json!({
+        "name": name,
+        "labels": manifest["metadata"]["labels"],
+    })
+}
+
+/// The canary Deployment, based on the main one.
+pub fn deployment_json(main: &JsonValue, name: &str, image: &str) -> JsonValue {
+    let mut spec = main["spec"].clone();
+    spec["selector"] = json!({ "matchLabels": { "app": name } });
+    spec["template"]["metadata"]["labels"]["app"] = json!(name);
+    for container in spec["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+        container["image"] = json!(image);
+    }
+
+    json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": template_metadata(main, name),
+        "spec": spec,
+    })
+}
+
+/// The canary Mapping.  It matches the same host and prefix as the main
+/// Mapping, and Ambassador gives the main one whatever weight is left.
+pub fn mapping_json(main: &JsonValue, name: &str, service: &str, weight: u8) -> JsonValue {
+    let mut spec = main["spec"].clone();
+    spec["service"] = json!(service);
+    spec["weight"] = json!(weight);
+
+    json!({
+        "apiVersion": main["apiVersion"],
+        "kind": "Mapping",
+        "metadata": template_metadata(main, name),
+        "spec": spec,
+    })
+}
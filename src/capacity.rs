@@ -0,0 +1,180 @@
+//! Holding previews back until the cluster has room for them.  Without it a
+//! preview that doesn't fit is created anyway, and its pods sit Pending
+//! until something else goes away, which can be never.
+//!
+//! With `CHECK_CAPACITY` on, a preview is only created once each of its
+//! pods fits on some node: in what the node can allocate, less what the
+//! pods already on it request, after room has been found for pods that are
+//! still waiting for a node.  Pods request what their `resources` say,
+//! their limits when they don't say, and nothing when there are neither.
+//! A preview's pods are its replicas, and as many again for its canary if
+//! it has one.  Its Jobs, CronJobs and shared services don't ask for
+//! anything, so they aren't counted.  One that doesn't fit is put in the
+//! `Queued` phase with a message saying what it's waiting for, and looked
+//! at again every `CAPACITY_RETRY_INTERVAL_SECONDS` and whenever another
+//! preview is deleted.
+//!
+//! It's an estimate, and the message says so.  Taints, affinity and the
+//! like aren't taken into account, and neither are pods that have been
+//! asked for but not created yet, or sidecars a mesh injects, whose
+//! requests only the mesh knows, so it can still be wrong in both
+//! directions.  Reading nodes and pods across the cluster needs a
+//! ClusterRole; see `generate-rbac`.
+use kube::api::ListParams;
+use kube::Error;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cost::parse_quantity;
+use crate::{profiles, ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// CPU in cores and memory in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Resources {
+    pub cpu: f64,
+    pub memory: f64,
+}
+
+impl Resources {
+    /// The CPU and memory in a map of quantities, like a node's
+    /// `allocatable`.
+    pub fn of(quantities: &JsonValue) -> Self {
+        Resources { cpu: quantity(quantities, "cpu").unwrap_or(0.0), memory: quantity(quantities, "memory").unwrap_or(0.0) }
+    }
+
+    // A container's requests, falling back to its limits the way the API
+    // server does
+    fn of_container(requirements: &JsonValue) -> Self {
+        let requested = |name| quantity(&requirements["requests"], name).or_else(|| quantity(&requirements["limits"], name));
+        Resources { cpu: requested("cpu").unwrap_or(0.0), memory: requested("memory").unwrap_or(0.0) }
+    }
+
+    // What all of a pod's containers request
+    fn of_pod(pod: &JsonValue) -> Self {
+        let mut total = Resources::default();
+        for container in pod["spec"]["containers"].as_array().into_iter().flatten() {
+            let requests = Resources::of_container(&container["resources"]);
+            total.cpu += requests.cpu;
+            total.memory += requests.memory;
+        }
+        total
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cpu <= 0.0 && self.memory <= 0.0
+    }
+
+    fn fits(&self, other: &Resources) -> bool {
+        other.cpu <= self.cpu && other.memory <= self.memory
+    }
+
+    fn take(&mut self, other: &Resources) {
+        self.cpu -= other.cpu;
+        self.memory -= other.memory;
+    }
+}
+
+fn quantity(quantities: &JsonValue, name: &str) -> Option<f64> {
+    quantities[name].as_str().and_then(parse_quantity)
+}
+
+impl fmt::Display for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mebibytes = self.memory / (1024.0 * 1024.0);
+        write!(f, "{}m CPU and {}Mi memory", (self.cpu * 1000.0).round(), mebibytes.round())
+    }
+}
+
+/// What each of `pe`'s pods requests.
+pub fn requested(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Resources {
+    let default_resources = profiles::of(resources, pe).default_resources.or_else(|| resources.reloadable().default_resources);
+    match pe.spec.resources.as_ref().or(default_resources.as_ref()) {
+        Some(requirements) => Resources::of_container(&serde_json::to_value(requirements).unwrap_or_default()),
+        None => Resources::default(),
+    }
+}
+
+/// How many pods `pe` runs: its replicas, and its canary's.
+pub fn pods(pe: &KubePreviewEnvironment) -> usize {
+    let replicas = pe.spec.replicas.max(0) as usize;
+    match pe.spec.canary {
+        // The canary Deployment is a copy of the main one
+        Some(_) => replicas * 2,
+        None => replicas,
+    }
+}
+
+/// Why the cluster hasn't room for `pe` now, or `None` if it has.
+pub async fn shortage(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Result<Option<String>, Error> {
+    let per_pod = requested(resources, pe);
+    let replicas = pods(pe);
+    if per_pod.is_empty() || replicas == 0 {
+        return Ok(None);
+    }
+
+    let nodes: JsonValue = resources.client.request(resources.nodes.list(&ListParams::default())?).await?;
+    let mut free: BTreeMap<String, Resources> = nodes["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|node| schedulable(node))
+        .map(|node| {
+            let name = node["metadata"]["name"].as_str().unwrap_or_default().to_string();
+            (name, Resources::of(&node["status"]["allocatable"]))
+        })
+        .collect();
+
+    let pods: JsonValue = resources.client.request(resources.cluster_pods.list(&ListParams::default())?).await?;
+    let mut waiting = vec![];
+    for pod in pods["items"].as_array().into_iter().flatten() {
+        // Finished pods don't hold on to anything
+        if pod["status"]["phase"] == "Succeeded" || pod["status"]["phase"] == "Failed" {
+            continue;
+        }
+        let requests = Resources::of_pod(pod);
+        match pod["spec"]["nodeName"].as_str() {
+            Some(node) => {
+                if let Some(node) = free.get_mut(node) {
+                    node.take(&requests);
+                }
+            }
+            None => waiting.push(requests),
+        }
+    }
+
+    // Pods already waiting for a node get first pick, then each of the
+    // preview's goes on the first node it fits on
+    let mut place = |requests: &Resources| match free.values_mut().find(|node| node.fits(requests)) {
+        Some(node) => {
+            node.take(requests);
+            true
+        }
+        None => false,
+    };
+    for requests in &waiting {
+        place(requests);
+    }
+    let placed = (0..replicas).take_while(|_| place(&per_pod)).count();
+    if placed == replicas {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "Waiting for room in the cluster: {} of its {} pods, each requesting {}, don't fit on any node \
+         (going by requests alone, without injected sidecars, taints or affinity)",
+        replicas - placed,
+        replicas,
+        per_pod
+    )))
+}
+
+// Whether new pods can go on the node
+fn schedulable(node: &JsonValue) -> bool {
+    let ready = node["status"]["conditions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|condition| condition["type"] == "Ready" && condition["status"] == "True");
+    ready && node["spec"]["unschedulable"] != true
+}
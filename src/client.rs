@@ -0,0 +1,475 @@
+use async_trait::async_trait;
+use http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use kube::{
+    api::{PostParams, RawApi},
+    config::{create_client_builder, ConfigOptions, Configuration},
+    Error, ErrorResponse,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use tracing::instrument;
+
+use crate::config::Config;
+use crate::{conflicts, impersonation, metrics};
+
+// How many times a request is retried when the API server tells us to
+// back off, and the longest we are willing to wait for any one retry.
+const MAX_RETRIES: u32 = 5;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// How many times a read-modify-write is retried when someone else updated
+// the object between our read and our write.
+const MAX_CONFLICT_RETRIES: u32 = 10;
+
+/// Where the client's requests go: the API server, or a stand-in for it
+/// in tests (see `fake`).
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, request: http::Request<Vec<u8>>) -> Result<http::Response<String>, Error>;
+}
+
+// The API server, over the kubeconfig's connection.  `APIClient` hides the
+// response headers from us, so we send requests ourselves using the same
+// underlying HTTP client.
+struct Http {
+    client: reqwest::Client,
+    base_path: String,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl Transport for Http {
+    async fn send(&self, request: http::Request<Vec<u8>>) -> Result<http::Response<String>, Error> {
+        let (parts, body) = request.into_parts();
+        let uri = format!("{}{}", self.base_path, parts.uri);
+        let response = self
+            .client
+            .request(parts.method, &uri)
+            .headers(parts.headers)
+            .body(body)
+            .timeout(self.timeout)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut reply = http::Response::new(response.text().await?);
+        *reply.status_mut() = status;
+        *reply.headers_mut() = headers;
+        Ok(reply)
+    }
+}
+
+/// The API server, over the kubeconfig's connection, giving up on any one
+/// request after `timeout`.
+pub fn transport(config: Configuration, timeout: Duration) -> Arc<dyn Transport> {
+    Arc::new(Http { client: config.client, base_path: config.base_path, timeout })
+}
+
+/// Every request the controller makes passes through this client.  It rate
+/// limits requests, which keeps a burst of events (say, a bot opening 200
+/// PRs) from hammering the API server, and waits out `429`/`503` responses
+/// instead of failing.
+#[derive(Clone)]
+pub struct Client {
+    transport: Arc<dyn Transport>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Client {
+    /// `timeout` is how long any one request can take before it's given up
+    /// on, so a dead connection can't hold up the controller for good.
+    pub fn new(config: Configuration, qps: f64, burst: u32, timeout: Duration) -> Self {
+        Client::with_transport(transport(config, timeout), qps, burst)
+    }
+
+    /// A client that sends its requests over `transport`, e.g. to record
+    /// them (see `vcr`) or to a fake API server.
+    pub fn with_transport(transport: Arc<dyn Transport>, qps: f64, burst: u32) -> Self {
+        Client { transport, limiter: Arc::new(RateLimiter::new(qps, burst)) }
+    }
+
+    #[instrument(skip(self, request), fields(method = %request.method(), uri = %request.uri()))]
+    pub async fn request<T: DeserializeOwned>(&self, request: http::Request<Vec<u8>>) -> Result<T, Error> {
+        let (parts, body) = request.into_parts();
+        let mut headers = parts.headers.clone();
+        for (name, value) in impersonation::headers(&parts.method, &parts.uri.to_string()) {
+            headers.append(name, HeaderValue::from_str(&value).map_err(http::Error::from)?);
+        }
+        let uri = with_field_manager(&parts.method, &parts.uri)?;
+
+        let mut attempt = 0;
+        loop {
+            self.limiter.acquire().await;
+            let mut request = http::Request::new(body.clone());
+            *request.method_mut() = parts.method.clone();
+            *request.uri_mut() = uri.clone();
+            *request.headers_mut() = headers.clone();
+            let response = self.transport.send(request).await?;
+
+            let status = response.status();
+            let overloaded = status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE;
+            if overloaded && attempt < MAX_RETRIES {
+                let delay = retry_after(response.headers(), SystemTime::now()).unwrap_or(DEFAULT_RETRY_AFTER);
+                println!("API server responded {}, retrying {} {} in {:?}", status, parts.method, parts.uri, delay);
+                metrics::RETRIED_REQUESTS.inc();
+                tokio::time::delay_for(delay).await;
+                attempt += 1;
+                continue;
+            }
+
+            let text = response.into_body();
+            if status.is_client_error() || status.is_server_error() {
+                return Err(api_error(&text, status));
+            }
+            return Ok(serde_json::from_str(&text)?);
+        }
+    }
+
+    /// Reads the latest copy of an object, lets `mutate` change it, and
+    /// writes it back.  The write carries the `resourceVersion` we read, so
+    /// if anyone else wrote in between the API server answers `409 Conflict`
+    /// and we start over from a fresh read instead of clobbering them.
+    /// Nothing is written if `mutate` leaves the object as it was.
+    pub async fn update<K, F>(&self, api: &RawApi, name: &str, mutate: F) -> Result<K, Error>
+    where
+        K: Serialize + DeserializeOwned,
+        F: FnMut(&mut K),
+    {
+        self.read_modify_write(api, name, mutate, false).await
+    }
+
+    /// Same as `update`, but writes through the `/status` subresource.
+    pub async fn update_status<K, F>(&self, api: &RawApi, name: &str, mutate: F) -> Result<K, Error>
+    where
+        K: Serialize + DeserializeOwned,
+        F: FnMut(&mut K),
+    {
+        self.read_modify_write(api, name, mutate, true).await
+    }
+
+    async fn read_modify_write<K, F>(&self, api: &RawApi, name: &str, mut mutate: F, status: bool) -> Result<K, Error>
+    where
+        K: Serialize + DeserializeOwned,
+        F: FnMut(&mut K),
+    {
+        let pp = PostParams::default();
+        let mut attempt = 0;
+        loop {
+            let mut object: K = self.request(api.get(name)?).await?;
+            let before = serde_json::to_vec(&object)?;
+            mutate(&mut object);
+
+            // Nothing to write if nothing changed, which saves a request
+            // on every reconcile that finds things as they should be
+            let data = serde_json::to_vec(&object)?;
+            if data == before {
+                return Ok(object);
+            }
+            let request = if status {
+                api.replace_status(name, &pp, data)?
+            } else {
+                api.replace(name, &pp, data)?
+            };
+
+            match self.request(request).await {
+                Err(Error::Api(ae)) if ae.code == 409 && attempt < MAX_CONFLICT_RETRIES => {
+                    println!("Conflict updating {}, retrying with the latest version", name);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Connection to the API server from the kubeconfig, with the controller's
+/// network settings: how long to wait to connect, TCP keepalives so a
+/// connection that silently died is noticed, an explicit proxy, and extra
+/// CAs to trust, e.g. for a proxy that intercepts TLS.  `HTTP_PROXY`,
+/// `HTTPS_PROXY` and `NO_PROXY` in the environment are honoured as well.
+///
+/// Request timeouts are left to `Client`, since the watch holds its
+/// requests open for as long as the API server lets it.
+pub async fn kube_config(config: &Config) -> Result<Configuration, String> {
+    let (mut builder, loader) = create_client_builder(ConfigOptions::default()).await.map_err(|err| err.to_string())?;
+    builder = builder.connect_timeout(config.kube_connect_timeout);
+    if config.kube_tcp_keepalive.as_secs() > 0 {
+        builder = builder.tcp_keepalive(config.kube_tcp_keepalive);
+    }
+    if let Some(proxy) = &config.kube_proxy {
+        let proxy = reqwest::Proxy::all(proxy).map_err(|err| format!("Invalid KUBE_PROXY {}: {}", proxy, err))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = &config.kube_ca_bundle {
+        let bundle = std::fs::read(path).map_err(|err| format!("Failed to read KUBE_CA_BUNDLE {}: {}", path, err))?;
+        for certificate in pem_certificates(&bundle) {
+            let certificate =
+                reqwest::Certificate::from_pem(&certificate).map_err(|err| format!("Invalid certificate in {}: {}", path, err))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    let client = builder.build().map_err(|err| err.to_string())?;
+    Ok(Configuration::new(loader.cluster.server, client))
+}
+
+// Each certificate in a PEM bundle, which `Certificate::from_pem` only
+// reads the first of.
+fn pem_certificates(bundle: &[u8]) -> Vec<Vec<u8>> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let bundle = String::from_utf8_lossy(bundle);
+    let mut pems = Vec::new();
+    let mut rest = bundle.as_ref();
+    while let Some(end) = rest.find(END) {
+        pems.push(rest[..end + END.len()].trim().as_bytes().to_vec());
+        rest = &rest[end + END.len()..];
+    }
+    pems
+}
+
+/// Reads the `Retry-After` header, which is either a number of seconds or
+/// an HTTP date, counting from `now`.
+fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            date.duration_since(now).unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+// Tag writes with the controller's field manager, so `managedFields` on
+// what it writes say who wrote it.  See `conflicts`.
+fn with_field_manager(method: &Method, uri: &Uri) -> Result<Uri, Error> {
+    if ![Method::POST, Method::PUT, Method::PATCH].contains(method) {
+        return Ok(uri.clone());
+    }
+    let uri = uri.to_string();
+    let separator = match uri.find('?') {
+        Some(at) if at + 1 < uri.len() => "&",
+        Some(_) => "",
+        None => "?",
+    };
+    let tagged = format!("{}{}fieldManager={}", uri, separator, conflicts::FIELD_MANAGER);
+    Ok(tagged.parse::<Uri>().map_err(http::Error::from)?)
+}
+
+// Same handling `APIClient` uses: prefer the `Status` object the API
+// server sent back, and make one up if the body isn't one.
+fn api_error(text: &str, status: StatusCode) -> Error {
+    match serde_json::from_str::<ErrorResponse>(text) {
+        Ok(response) => Error::Api(response),
+        Err(_) => Error::Api(ErrorResponse {
+            status: status.to_string(),
+            code: status.as_u16(),
+            message: text.to_string(),
+            reason: "Failed to parse error data".to_string(),
+        }),
+    }
+}
+
+/// A token bucket: it holds up to `burst` tokens and refills at `qps`
+/// tokens per second.  Each request takes one token, waiting for a refill
+/// when the bucket is empty.
+pub struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(qps: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            qps,
+            burst,
+            bucket: Mutex::new(Bucket { tokens: burst, last_refill: Instant::now() }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        let mut throttled = false;
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.qps;
+                bucket.tokens = (bucket.tokens + refill).min(self.burst);
+                bucket.last_refill = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.qps)
+            };
+
+            if !throttled {
+                metrics::THROTTLED_REQUESTS.inc();
+                throttled = true;
+            }
+            metrics::THROTTLED_SECONDS.inc_by(wait.as_secs_f64());
+            tokio::time::delay_for(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake::FakeApi;
+
+    type JsonValue = serde_json::value::Value;
+
+    // Whether the limiter hands out a token without waiting.  The clock is
+    // paused in these tests, so only `advance` refills the bucket.
+    fn acquired(limiter: &RateLimiter) -> bool {
+        limiter.acquire().now_or_never().is_some()
+    }
+
+    #[tokio::test]
+    async fn a_full_bucket_serves_its_burst_without_waiting() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new(1.0, 5);
+
+        for _ in 0..5 {
+            assert!(acquired(&limiter));
+        }
+        assert!(!acquired(&limiter));
+    }
+
+    #[tokio::test]
+    async fn an_empty_bucket_waits_for_a_refill() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new(10.0, 1);
+        assert!(acquired(&limiter));
+
+        // One token comes back every 100ms
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(!acquired(&limiter));
+        tokio::time::advance(Duration::from_millis(60)).await;
+        assert!(acquired(&limiter));
+        assert!(!acquired(&limiter));
+    }
+
+    #[tokio::test]
+    async fn refills_stop_at_the_burst() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new(100.0, 2);
+        assert!(acquired(&limiter) && acquired(&limiter));
+        // Long enough for 20 tokens, but the bucket only holds 2
+        tokio::time::advance(Duration::from_millis(200)).await;
+
+        assert!(acquired(&limiter) && acquired(&limiter));
+        assert!(!acquired(&limiter));
+    }
+
+    fn client() -> (Arc<FakeApi>, Client, RawApi) {
+        let api = Arc::new(FakeApi::default());
+        let client = Client::with_transport(api.clone(), 1000.0, 1000);
+        (api, client, RawApi::v1ConfigMap().within("default"))
+    }
+
+    fn failure(code: u16, reason: &str) -> JsonValue {
+        json!({ "status": "Failure", "message": "try again", "reason": reason, "code": code })
+    }
+
+    #[tokio::test]
+    async fn overloaded_responses_are_retried() {
+        tokio::time::pause();
+        let (api, client, config_maps) = client();
+        let data = serde_json::to_vec(&json!({ "metadata": { "name": "web" } })).unwrap();
+        api.apply(config_maps.create(&PostParams::default(), data).unwrap());
+        let get = config_maps.get("web").unwrap();
+        api.respond(&get, 429, failure(429, "TooManyRequests"));
+        api.respond(&get, 503, failure(503, "ServiceUnavailable"));
+
+        let started = Instant::now();
+        let result: Result<JsonValue, Error> = client.request(get).await;
+
+        assert_eq!(result.unwrap()["metadata"]["name"], "web");
+        assert_eq!(api.requests().len(), 3);
+        // Waiting the default second between each, since neither said otherwise
+        assert!(started.elapsed() >= DEFAULT_RETRY_AFTER * 2);
+    }
+
+    #[tokio::test]
+    async fn overloaded_responses_are_retried_only_so_often() {
+        tokio::time::pause();
+        let (api, client, config_maps) = client();
+        let get = config_maps.get("web").unwrap();
+        for _ in 0..=MAX_RETRIES {
+            api.respond(&get, 429, failure(429, "TooManyRequests"));
+        }
+
+        let result: Result<JsonValue, Error> = client.request(get).await;
+
+        assert!(matches!(result, Err(Error::Api(ae)) if ae.code == 429));
+        assert_eq!(api.requests().len(), MAX_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn conflicting_writes_are_retried_only_so_often() {
+        let (api, client, config_maps) = client();
+        let data = serde_json::to_vec(&json!({ "metadata": { "name": "web" } })).unwrap();
+        api.apply(config_maps.create(&PostParams::default(), data).unwrap());
+        let replace = config_maps.replace("web", &PostParams::default(), vec![]).unwrap();
+        for _ in 0..=MAX_CONFLICT_RETRIES {
+            api.respond(&replace, 409, failure(409, "Conflict"));
+        }
+
+        let update = client.update(&config_maps, "web", |config_map: &mut JsonValue| config_map["data"] = json!({ "a": "b" }));
+
+        assert!(matches!(update.await, Err(Error::Api(ae)) if ae.code == 409));
+        let writes = api.requests().into_iter().filter(|request| request.method == Method::PUT).count();
+        assert_eq!(writes, MAX_CONFLICT_RETRIES as usize + 1);
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_is_seconds_or_a_date() {
+        let now = SystemTime::now();
+        assert_eq!(retry_after(&headers("3"), now), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after(&headers(" 0 "), now), Some(Duration::from_secs(0)));
+
+        // HTTP dates only go down to the second
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:30 GMT"), now), Some(Duration::from_secs(30)));
+        // Already past
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:27:00 GMT"), now), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn retry_after_is_capped_and_ignored_when_unreadable() {
+        let now = SystemTime::now();
+        assert_eq!(retry_after(&headers("3600"), now), Some(MAX_RETRY_AFTER));
+        assert_eq!(retry_after(&headers("soon"), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn a_zero_burst_still_allows_one_request() {
+        let limiter = RateLimiter::new(1.0, 0);
+
+        assert_eq!(limiter.burst, 1.0);
+    }
+}
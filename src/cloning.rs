@@ -0,0 +1,84 @@
+//! Previews cloned from another one, for reproducing a bug against a known
+//! state.  `kubectl preview clone` copies the source preview's spec under a
+//! new name and FQDN and records where it came from in `cloneFrom`.
+//!
+//! With `cloneFrom.database` set the controller also copies the source's
+//! database into the clone's, using a Job that dumps one and restores into
+//! the other.  The Job gets the clone's secrets as they are and the source's
+//! with a `SOURCE_` prefix, so by default it runs
+//! `pg_dump "$SOURCE_DATABASE_URL" | psql "$DATABASE_URL"`.  It's tracked
+//! with the preview's other Jobs in the `JobsComplete` condition.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::jobs::JobSpec;
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// Name of the Job that copies the database, among the preview's Jobs.
+pub const DATABASE_JOB: &str = "clone-database";
+
+const SOURCE_PREFIX: &str = "SOURCE_";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CloneSpec {
+    /// The preview this one was cloned from.
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database: Option<DatabaseClone>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DatabaseClone {
+    #[serde(default = "default_image")]
+    pub image: String,
+    /// Replaces the default `pg_dump | psql`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+}
+
+fn default_image() -> String {
+    "postgres:15".to_string()
+}
+
+fn default_command() -> Vec<String> {
+    let script = "pg_dump --no-owner --clean --if-exists \"$SOURCE_DATABASE_URL\" | psql \"$DATABASE_URL\"";
+    vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+}
+
+/// The Job that copies the source's database, if the preview asked for one.
+pub fn database_job(pe: &KubePreviewEnvironment) -> Option<JobSpec> {
+    let database = pe.spec.clone_from.as_ref()?.database.as_ref()?;
+    let command = if database.command.is_empty() {
+        default_command()
+    } else {
+        database.command.clone()
+    };
+    Some(JobSpec {
+        name: DATABASE_JOB.to_string(),
+        image: database.image.clone(),
+        command,
+        // A half-finished restore is as good as none, so don't keep trying
+        backoff_limit: 0,
+    })
+}
+
+/// Give the rendered database Job the source preview's secrets as well,
+/// prefixed with `SOURCE_`.
+pub fn add_source(job: &mut JsonValue, source_deployment: &JsonValue) {
+    let source_env = source_deployment["spec"]["template"]["spec"]["containers"][0]["envFrom"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|mut env_from| {
+            env_from["prefix"] = json!(SOURCE_PREFIX);
+            env_from
+        });
+    let container = &mut job["spec"]["template"]["spec"]["containers"][0];
+    match container["envFrom"].as_array_mut() {
+        Some(existing) => existing.extend(source_env),
+        None => container["envFrom"] = json!(source_env.collect::<Vec<_>>()),
+    }
+}
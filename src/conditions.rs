@@ -0,0 +1,46 @@
+//! Standard Kubernetes conditions on the preview's status, for things that
+//! are easier to follow as a set of true/false facts than through `phase`.
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    /// `True`, `False` or `Unknown`.
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_transition_time: Option<String>,
+}
+
+/// Set a condition, adding it if it isn't there yet.  The transition time
+/// only moves when the status actually changes.
+pub fn set(conditions: &mut Vec<Condition>, condition_type: &str, status: &str, reason: &str, message: Option<String>) {
+    let now = Utc::now().to_rfc3339();
+    match conditions.iter_mut().find(|condition| condition.condition_type == condition_type) {
+        Some(condition) => {
+            if condition.status != status {
+                condition.status = status.to_string();
+                condition.last_transition_time = Some(now);
+            }
+            condition.reason = Some(reason.to_string());
+            condition.message = message;
+        }
+        None => conditions.push(Condition {
+            condition_type: condition_type.to_string(),
+            status: status.to_string(),
+            reason: Some(reason.to_string()),
+            message,
+            last_transition_time: Some(now),
+        }),
+    }
+}
+
+pub fn is_true(conditions: &[Condition], condition_type: &str) -> bool {
+    conditions.iter().any(|condition| condition.condition_type == condition_type && condition.status == "True")
+}
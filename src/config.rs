@@ -0,0 +1,832 @@
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::api::PropagationPolicy;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::creation::PartialFailure;
+use crate::delivery;
+use crate::dns::{self, DnsProvider};
+use crate::external_secrets::ExternalSecretTemplate;
+use crate::naming::NamingScript;
+use crate::plugins::Plugins;
+use crate::rollouts;
+use crate::egress::{Destination, EgressPolicy};
+use crate::fqdn;
+use crate::freeze::{FreezeMode, FreezeWindow};
+use crate::mesh::Mesh;
+use crate::pod_security;
+use crate::scheduling::Spread;
+use crate::tcp::PortRange;
+
+type JsonValue = serde_json::value::Value;
+
+/// Controller settings.  Each can be set with an environment variable, so
+/// they can be set straight from the Deployment manifest, a command line
+/// flag or an entry in a YAML or TOML config file.  See `Config::load`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub namespace: String,
+    pub grpc_addr: SocketAddr,
+    pub metrics_addr: SocketAddr,
+
+    /// Sustained requests per second allowed against the API server.
+    pub qps: f64,
+    /// Requests allowed to go through at once before `qps` kicks in.
+    pub burst: u32,
+    /// How long to wait for a connection to the API server, and for any one
+    /// request to it.
+    pub kube_connect_timeout: Duration,
+    pub kube_request_timeout: Duration,
+    /// Interval between TCP keepalives on connections to the API server, or
+    /// zero for none.
+    pub kube_tcp_keepalive: Duration,
+    /// Proxy for every request to the API server, for networks where the
+    /// `HTTPS_PROXY` the rest of the controller uses won't do.
+    pub kube_proxy: Option<String>,
+    /// PEM file of extra CAs to trust for the API server.
+    pub kube_ca_bundle: Option<String>,
+    /// How long the watch on previews can go quiet before it's assumed to
+    /// have stalled and is started again.  See `watch`.
+    pub watch_stall_timeout: Duration,
+    /// File to record every request to the API server and its response
+    /// to, for playing back in tests.  See `vcr`.
+    pub vcr_record: Option<String>,
+    /// How long a preview has to go unchanged before a change to it is
+    /// reconciled, so a burst of changes is reconciled once.  Zero
+    /// reconciles every change straight away.  See `debounce`.
+    pub debounce: Duration,
+    /// Directory to keep reconcile bookkeeping in across restarts.  Kept in
+    /// memory only when unset.  See `store`.
+    pub state_dir: Option<String>,
+    /// Leave fields of a child someone else has changed as they have them,
+    /// rather than changing them back.  See `conflicts`.
+    pub back_off_on_conflict: bool,
+
+    /// Where to post notifications about previews, and the reasons besides
+    /// warnings that are worth one.  See `notifier`.
+    pub notify_url: Option<String>,
+    pub notify_reasons: Vec<String>,
+    /// File to append an audit log of everything that happens to previews
+    /// to.  See `audit`.
+    pub audit_log: Option<String>,
+    /// Where to serve the live feed of preview events.  Disabled when
+    /// unset.  See `sse`.
+    pub events_addr: Option<SocketAddr>,
+
+    /// How child resources are deleted when their PreviewEnvironment is.
+    /// Individual environments can override this with an annotation.
+    pub propagation_policy: PropagationPolicy,
+
+    /// Which `DnsProvider` manages records for preview FQDNs, if any.
+    pub dns_provider: Option<String>,
+    /// Where preview FQDNs should point, usually the ingress load balancer.
+    pub dns_target: Option<String>,
+    pub cloudflare_api_token: Option<String>,
+    pub cloudflare_zone_id: Option<String>,
+    pub route53_hosted_zone_id: Option<String>,
+
+    /// How previews get TLS certificates.  See `TlsMode`.
+    pub tls_mode: TlsMode,
+    /// Contact address for Let's Encrypt in `acme` mode.
+    pub acme_email: Option<String>,
+    /// Secret holding a certificate like `*.previews.example.com` that
+    /// every preview shares in `wildcard` mode.
+    pub wildcard_tls_secret: Option<String>,
+
+    /// Namespace that Secrets listed in `copySecrets` are copied from.
+    pub secret_source_namespace: Option<String>,
+    /// How often copied Secrets are checked against their source.
+    pub secret_sync_interval: Duration,
+
+    /// File holding the `ExternalSecret` spec generated for every preview.
+    pub external_secret_template: Option<String>,
+
+    /// Vault server to mint per-preview database credentials from.
+    pub vault_addr: Option<String>,
+    /// A static Vault token.  When unset the controller logs in with its
+    /// service account using `vault_auth_role`.
+    pub vault_token: Option<String>,
+    pub vault_auth_role: Option<String>,
+    /// Where the database secrets engine is mounted, and the role used to
+    /// mint credentials unless a preview names its own.
+    pub vault_database_mount: String,
+    pub vault_database_role: Option<String>,
+    pub vault_renew_interval: Duration,
+
+    /// Registry (and path) that images built from source are pushed to,
+    /// e.g. `registry.example.com/previews`.
+    pub build_registry: Option<String>,
+    /// `kubernetes.io/dockerconfigjson` Secret with push credentials.
+    pub build_push_secret: Option<String>,
+    pub build_timeout: Duration,
+    pub kaniko_image: String,
+    /// Buildpacks builder image, e.g. one of the Paketo builders.
+    pub buildpacks_builder_image: String,
+    pub git_image: String,
+
+    /// Where to listen for registry push webhooks.  Disabled when unset.
+    pub webhook_addr: Option<SocketAddr>,
+    /// Shared secret registries must pass as `?token=`.
+    pub webhook_token: Option<String>,
+
+    /// Most critical vulnerabilities an image may have.  Images aren't
+    /// scanned at all when unset.
+    pub scan_max_critical: Option<usize>,
+    pub scan_timeout: Duration,
+    pub trivy_image: String,
+
+    /// OPA to check rendered children against.  No policy is enforced
+    /// when unset.
+    pub opa_url: Option<String>,
+    pub opa_policy_path: String,
+
+    /// Registries and repositories previews' images may come from, e.g.
+    /// `ghcr.io/acme`.  Anywhere when empty.  See `registries`.
+    pub allowed_registries: Vec<String>,
+
+    /// Caps on concurrent previews.  Previews over a cap wait in a queue,
+    /// and are looked at again this often.  See `quota`.
+    pub max_previews_per_owner: Option<usize>,
+    pub max_previews_per_namespace: Option<usize>,
+    pub quota_retry_interval: Duration,
+    /// Queue previews the cluster hasn't room for, rather than leave their
+    /// pods Pending, and how often to look for room again.  See `capacity`.
+    pub check_capacity: bool,
+    pub capacity_retry_interval: Duration,
+
+    /// The label naming a preview's team, whose profile overrides some of
+    /// these settings for it, and how often profiles are read.  See
+    /// `profiles`.
+    pub team_label: String,
+    pub profile_refresh_interval: Duration,
+    /// How long previews live before they're deleted.  Forever when unset.
+    pub preview_ttl: Option<Duration>,
+
+    /// Where to serve the mutating admission webhook.  Disabled when unset.
+    pub admission_addr: Option<SocketAddr>,
+    pub admission_tls_cert: String,
+    pub admission_tls_key: String,
+    /// Rhai script the admission webhook names new previews with.  See
+    /// `naming`.
+    pub naming_script: Option<String>,
+    /// Template for the FQDN of previews that don't set one, e.g.
+    /// `{name}.{namespace}.previews.example.com`.  See `fqdn`.
+    pub fqdn_template: Option<String>,
+    /// Create each preview's children as the user who created the preview,
+    /// so the audit log shows who they're for.  See `impersonation`.
+    pub impersonate_creator: bool,
+
+    /// Prices used to estimate what each preview costs to run.
+    pub cost_per_cpu_hour: f64,
+    pub cost_per_gb_hour: f64,
+    /// How often to refresh each preview's usage from metrics-server.
+    pub usage_interval: Duration,
+    /// How often to refresh the list of each preview's children in its
+    /// status.  See `inventory`.
+    pub child_status_interval: Duration,
+
+    /// Grafana that per-preview dashboards are provisioned for.  No
+    /// dashboards are created when unset.
+    pub grafana_url: Option<String>,
+    /// Label the Grafana sidecar looks for on dashboard ConfigMaps.
+    pub grafana_dashboard_label: String,
+    /// Name of the Loki datasource in Grafana, for log links.
+    pub loki_datasource: String,
+
+    /// Service mesh previews join unless they say otherwise.
+    pub mesh: Mesh,
+
+    /// How long a preview has to be created in full, and what becomes of
+    /// it if it isn't.  See `creation`.
+    pub creation_timeout: Duration,
+    pub partial_failure: PartialFailure,
+
+    /// How long a blue-green update waits for the new pods, and how long
+    /// the old release is kept for rolling back to.
+    pub blue_green_ready_timeout: Duration,
+    pub blue_green_retention: Duration,
+
+    /// YAML file with the Argo Rollouts `strategy` used for previews with
+    /// `strategy: argoRollout`.
+    pub rollout_strategy_template: Option<String>,
+    pub rollout_status_interval: Duration,
+
+    /// GitOps tool that deploys previews with a `source`.  Defaults to the
+    /// first one the controller was built with.
+    pub delivery_backend: Option<String>,
+    /// Where ArgoCD Applications are created, the AppProject they belong
+    /// to, and the cluster they deploy to.
+    pub argocd_namespace: String,
+    pub argocd_project: String,
+    pub argocd_destination_server: String,
+    /// How often Flux checks a preview's source for changes.
+    pub flux_interval: String,
+
+    /// How long a preview's Tekton pipeline may run before it's failed.
+    pub pipeline_timeout: Duration,
+    /// How long a preview's one-shot Jobs may run before they're failed.
+    pub job_timeout: Duration,
+
+    /// Ambassador listener ports handed out to TCP previews.
+    pub tcp_port_range: PortRange,
+
+    /// Labels copied from each PreviewEnvironment to everything generated
+    /// for it.  A trailing `*` matches by prefix.
+    pub propagate_labels: Vec<String>,
+
+    /// How multi-replica previews are spread out, unless they say
+    /// otherwise, and over what.
+    pub spread: Spread,
+    pub spread_topology_key: String,
+
+    /// PriorityClass for preview pods.  When a value is given as well the
+    /// controller creates the PriorityClass itself at startup.
+    pub priority_class: Option<String>,
+    pub priority_class_value: Option<i32>,
+
+    /// RuntimeClass for previews that don't ask for one.
+    pub runtime_class: Option<String>,
+
+    /// Pod Security Standard the preview's pods are checked against before
+    /// they're created.
+    pub pod_security_level: Option<pod_security::Level>,
+
+    /// Whether previews' outbound connections are restricted, and the
+    /// external services they're still allowed to reach.
+    pub egress_policy: EgressPolicy,
+    pub egress_allowlist: Vec<Destination>,
+
+    /// Where database snapshots are uploaded to, e.g. `s3://bucket/previews`,
+    /// the image that takes them, and a Secret with the credentials it
+    /// needs.
+    pub snapshot_location: Option<String>,
+    pub snapshot_image: Option<String>,
+    pub snapshot_credentials_secret: Option<String>,
+
+    /// The fewest replicas a promoted environment runs with.
+    pub promote_replicas: i32,
+
+    /// Controller-wide read-only mode for cluster upgrades and the like.
+    /// Nothing is created, changed or deleted, but previews are still
+    /// watched and their status kept up to date.  Everything missed is
+    /// caught up on when the controller restarts without it.
+    pub maintenance: bool,
+    /// Start without checking the cluster has what the controller needs.
+    /// See `preflight`.
+    pub skip_preflight: bool,
+
+    /// WebAssembly modules that get to change every preview's manifests
+    /// before they're created.  See `plugins`.
+    pub plugins: Vec<String>,
+
+    /// How often to look for resources left behind by deleted previews.
+    pub sweep_interval: Duration,
+
+    /// The config file the settings were read from, if any, and how often
+    /// it's checked for changes.
+    pub config_file: Option<String>,
+    pub reload_interval: Duration,
+
+    /// The settings that can change without a restart, as they were at
+    /// startup.  Use `ApiResources::reloadable` for the current values.
+    pub reloadable: Reloadable,
+
+    /// What's built from the settings.
+    pub loaded: Loaded,
+}
+
+/// The integrations the settings select, built by `Config::load` so that
+/// settings they can't be built from are reported with the rest, and built
+/// only the once.
+#[derive(Clone, Default)]
+pub struct Loaded {
+    /// The provider `DNS_PROVIDER` selects.
+    pub dns: Option<Arc<dyn DnsProvider>>,
+    /// The template `EXTERNAL_SECRET_TEMPLATE` names.
+    pub external_secret_template: Option<Arc<ExternalSecretTemplate>>,
+    /// The strategy `ROLLOUT_STRATEGY_TEMPLATE` names.
+    pub rollout_strategy: Option<JsonValue>,
+    /// The script `NAMING_SCRIPT` names, compiled.
+    pub naming_script: Option<Arc<NamingScript>>,
+    /// The plugins `PLUGINS` lists, compiled.
+    pub plugins: Arc<Plugins>,
+}
+
+// Only the settings say anything about what's loaded, so this is left out
+// of what `reload` compares
+impl fmt::Debug for Loaded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Loaded")
+    }
+}
+
+/// Settings that are safe to change while the controller is running,
+/// because nothing has been set up around them.  They're picked up from the
+/// config file when it changes; see `reload`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloadable {
+    /// Requests and limits for previews that don't set `resources`.
+    pub default_resources: Option<ResourceRequirements>,
+    /// Failed attempts in a row after which a preview is left alone until
+    /// its spec changes or it's asked to retry.
+    pub max_failures: u32,
+    /// Only report what the sweeper would delete.
+    pub sweep_dry_run: bool,
+    /// When no previews are created, and what becomes of them instead.
+    /// See `freeze`.
+    pub freeze_windows: Vec<FreezeWindow>,
+    pub freeze_mode: FreezeMode,
+}
+
+impl Reloadable {
+    /// What's different in `new`, one line per setting.
+    pub fn changes(&self, new: &Reloadable) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.default_resources != new.default_resources {
+            changes.push(format!("default resources {:?} -> {:?}", self.default_resources, new.default_resources));
+        }
+        if self.max_failures != new.max_failures {
+            changes.push(format!("MAX_FAILURES {} -> {}", self.max_failures, new.max_failures));
+        }
+        if self.sweep_dry_run != new.sweep_dry_run {
+            changes.push(format!("SWEEP_DRY_RUN {} -> {}", self.sweep_dry_run, new.sweep_dry_run));
+        }
+        if self.freeze_windows != new.freeze_windows {
+            changes.push(format!("FREEZE_WINDOWS {:?} -> {:?}", self.freeze_windows, new.freeze_windows));
+        }
+        if self.freeze_mode != new.freeze_mode {
+            changes.push(format!("FREEZE_MODE {:?} -> {:?}", self.freeze_mode, new.freeze_mode));
+        }
+        changes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsMode {
+    /// No Ambassador `Host` is created; TLS is handled outside the controller.
+    None,
+    /// Each preview gets its own `Host` with a certificate from Let's Encrypt.
+    Acme,
+    /// Every preview's `Host` references the same wildcard certificate,
+    /// which keeps us clear of Let's Encrypt's rate limits.
+    Wildcard,
+}
+
+impl FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(TlsMode::None),
+            "acme" => Ok(TlsMode::Acme),
+            "wildcard" => Ok(TlsMode::Wildcard),
+            _ => Err(format!("unknown TLS mode {:?}", value)),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings from `args` (the controller's command line, without
+    /// the program name), the environment and the config file, in that order
+    /// of precedence, and checks they make sense together.  Every problem
+    /// found is reported at once rather than one per restart.
+    pub fn load<I: IntoIterator<Item = String>>(args: I) -> Result<Self, ConfigError> {
+        let src = Sources::new(args)?;
+        let mut config = Config {
+            namespace: src.or("WATCH_NAMESPACE", "default".to_string()),
+            grpc_addr: src.or("GRPC_ADDR", "0.0.0.0:50051".parse().unwrap()),
+            metrics_addr: src.or("METRICS_ADDR", "0.0.0.0:9090".parse().unwrap()),
+            qps: src.or("KUBE_QPS", 5.0),
+            burst: src.or("KUBE_BURST", 10),
+            kube_connect_timeout: Duration::from_secs(src.or("KUBE_CONNECT_TIMEOUT_SECONDS", 10)),
+            kube_request_timeout: Duration::from_secs(src.or("KUBE_REQUEST_TIMEOUT_SECONDS", 60)),
+            kube_tcp_keepalive: Duration::from_secs(src.or("KUBE_TCP_KEEPALIVE_SECONDS", 30)),
+            kube_proxy: src.opt("KUBE_PROXY"),
+            kube_ca_bundle: src.opt("KUBE_CA_BUNDLE"),
+            watch_stall_timeout: Duration::from_secs(src.or("WATCH_STALL_SECONDS", 300)),
+            vcr_record: src.opt("VCR_RECORD"),
+            debounce: Duration::from_secs(src.or("DEBOUNCE_SECONDS", 2)),
+            state_dir: src.opt("STATE_DIR"),
+            back_off_on_conflict: src.or("BACK_OFF_ON_CONFLICT", false),
+            notify_url: src.opt("NOTIFY_URL"),
+            notify_reasons: src.list("NOTIFY_REASONS"),
+            audit_log: src.opt("AUDIT_LOG"),
+            events_addr: src.parse("EVENTS_ADDR"),
+            propagation_policy: src.with("DELETE_PROPAGATION", PropagationPolicy::Background, |value| {
+                propagation_policy(value).ok_or_else(|| "expected Foreground, Background or Orphan".to_string())
+            }),
+            dns_provider: src.opt("DNS_PROVIDER"),
+            dns_target: src.opt("DNS_TARGET"),
+            cloudflare_api_token: src.opt("CLOUDFLARE_API_TOKEN"),
+            cloudflare_zone_id: src.opt("CLOUDFLARE_ZONE_ID"),
+            route53_hosted_zone_id: src.opt("ROUTE53_HOSTED_ZONE_ID"),
+            tls_mode: src.or("TLS_MODE", TlsMode::None),
+            acme_email: src.opt("ACME_EMAIL"),
+            wildcard_tls_secret: src.opt("WILDCARD_TLS_SECRET"),
+            secret_source_namespace: src.opt("SECRET_SOURCE_NAMESPACE"),
+            secret_sync_interval: Duration::from_secs(src.or("SECRET_SYNC_INTERVAL_SECONDS", 60)),
+            external_secret_template: src.opt("EXTERNAL_SECRET_TEMPLATE"),
+            vault_addr: src.opt("VAULT_ADDR"),
+            vault_token: src.opt("VAULT_TOKEN"),
+            vault_auth_role: src.opt("VAULT_AUTH_ROLE"),
+            vault_database_mount: src.or("VAULT_DATABASE_MOUNT", "database".to_string()),
+            vault_database_role: src.opt("VAULT_DATABASE_ROLE"),
+            vault_renew_interval: Duration::from_secs(src.or("VAULT_RENEW_INTERVAL_SECONDS", 300)),
+            build_registry: src.opt("BUILD_REGISTRY"),
+            build_push_secret: src.opt("BUILD_PUSH_SECRET"),
+            build_timeout: Duration::from_secs(src.or("BUILD_TIMEOUT_SECONDS", 1800)),
+            kaniko_image: src.or("KANIKO_IMAGE", "gcr.io/kaniko-project/executor:latest".to_string()),
+            buildpacks_builder_image: src.or("BUILDPACKS_BUILDER_IMAGE", "paketobuildpacks/builder:base".to_string()),
+            git_image: src.or("GIT_IMAGE", "alpine/git:latest".to_string()),
+            webhook_addr: src.parse("WEBHOOK_ADDR"),
+            webhook_token: src.opt("WEBHOOK_TOKEN"),
+            scan_max_critical: src.parse("SCAN_MAX_CRITICAL"),
+            scan_timeout: Duration::from_secs(src.or("SCAN_TIMEOUT_SECONDS", 600)),
+            trivy_image: src.or("TRIVY_IMAGE", "aquasec/trivy:latest".to_string()),
+            opa_url: src.opt("OPA_URL"),
+            opa_policy_path: src.or("OPA_POLICY_PATH", "preview/deny".to_string()),
+            allowed_registries: src.list("ALLOWED_REGISTRIES"),
+            max_previews_per_owner: src.parse("MAX_PREVIEWS_PER_OWNER"),
+            max_previews_per_namespace: src.parse("MAX_PREVIEWS_PER_NAMESPACE"),
+            quota_retry_interval: Duration::from_secs(src.or("QUOTA_RETRY_INTERVAL_SECONDS", 60)),
+            check_capacity: src.or("CHECK_CAPACITY", false),
+            capacity_retry_interval: Duration::from_secs(src.or("CAPACITY_RETRY_INTERVAL_SECONDS", 60)),
+            team_label: src.or("TEAM_LABEL", "team".to_string()),
+            profile_refresh_interval: Duration::from_secs(src.or("PROFILE_REFRESH_INTERVAL_SECONDS", 30)),
+            preview_ttl: src.parse("PREVIEW_TTL_SECONDS").map(Duration::from_secs),
+            admission_addr: src.parse("ADMISSION_ADDR"),
+            admission_tls_cert: src.or("ADMISSION_TLS_CERT", "/tls/tls.crt".to_string()),
+            admission_tls_key: src.or("ADMISSION_TLS_KEY", "/tls/tls.key".to_string()),
+            naming_script: src.opt("NAMING_SCRIPT"),
+            fqdn_template: src.opt("FQDN_TEMPLATE"),
+            impersonate_creator: src.or("IMPERSONATE_CREATOR", false),
+            cost_per_cpu_hour: src.or("COST_PER_CPU_HOUR", 0.0316),
+            cost_per_gb_hour: src.or("COST_PER_GB_HOUR", 0.0042),
+            usage_interval: Duration::from_secs(src.or("USAGE_INTERVAL_SECONDS", 60)),
+            child_status_interval: Duration::from_secs(src.or("CHILD_STATUS_INTERVAL_SECONDS", 30)),
+            grafana_url: src.opt("GRAFANA_URL"),
+            grafana_dashboard_label: src.or("GRAFANA_DASHBOARD_LABEL", "grafana_dashboard".to_string()),
+            loki_datasource: src.or("LOKI_DATASOURCE", "Loki".to_string()),
+            mesh: src.or("MESH", Mesh::None),
+            creation_timeout: Duration::from_secs(src.or("CREATION_TIMEOUT_SECONDS", 600)),
+            partial_failure: src.or("PARTIAL_FAILURE", PartialFailure::Degrade),
+            blue_green_ready_timeout: Duration::from_secs(src.or("BLUE_GREEN_READY_TIMEOUT_SECONDS", 600)),
+            blue_green_retention: Duration::from_secs(src.or("BLUE_GREEN_RETENTION_SECONDS", 3600)),
+            rollout_strategy_template: src.opt("ROLLOUT_STRATEGY_TEMPLATE"),
+            rollout_status_interval: Duration::from_secs(src.or("ROLLOUT_STATUS_INTERVAL_SECONDS", 15)),
+            delivery_backend: src.opt("DELIVERY_BACKEND"),
+            argocd_namespace: src.or("ARGOCD_NAMESPACE", "argocd".to_string()),
+            argocd_project: src.or("ARGOCD_PROJECT", "default".to_string()),
+            argocd_destination_server: src.or("ARGOCD_DESTINATION_SERVER", "https://kubernetes.default.svc".to_string()),
+            flux_interval: src.or("FLUX_INTERVAL", "5m".to_string()),
+            pipeline_timeout: Duration::from_secs(src.or("PIPELINE_TIMEOUT_SECONDS", 3600)),
+            job_timeout: Duration::from_secs(src.or("JOB_TIMEOUT_SECONDS", 3600)),
+            tcp_port_range: src.or("TCP_PORT_RANGE", PortRange { first: 30000, last: 30999 }),
+            propagate_labels: src.list("PROPAGATE_LABELS"),
+            spread: src.or("SPREAD", Spread::None),
+            spread_topology_key: src.or("SPREAD_TOPOLOGY_KEY", "kubernetes.io/hostname".to_string()),
+            priority_class: src.opt("PRIORITY_CLASS"),
+            priority_class_value: src.parse("PRIORITY_CLASS_VALUE"),
+            runtime_class: src.opt("RUNTIME_CLASS"),
+            pod_security_level: src.parse("POD_SECURITY_LEVEL"),
+            egress_policy: src.or("EGRESS_POLICY", EgressPolicy::Open),
+            egress_allowlist: src.parsed_list("EGRESS_ALLOWLIST"),
+            snapshot_location: src.opt("SNAPSHOT_LOCATION"),
+            snapshot_image: src.opt("SNAPSHOT_IMAGE"),
+            snapshot_credentials_secret: src.opt("SNAPSHOT_CREDENTIALS_SECRET"),
+            promote_replicas: src.or("PROMOTE_REPLICAS", 2),
+            maintenance: src.or("MAINTENANCE_MODE", false),
+            skip_preflight: src.or("SKIP_PREFLIGHT", false),
+            plugins: src.list("PLUGINS"),
+            sweep_interval: Duration::from_secs(src.or("SWEEP_INTERVAL_SECONDS", 3600)),
+            config_file: src.file_path.clone(),
+            reload_interval: Duration::from_secs(src.or("RELOAD_INTERVAL_SECONDS", 10)),
+            reloadable: Reloadable {
+                default_resources: default_resources(|key| src.opt(key)),
+                max_failures: src.or("MAX_FAILURES", 5),
+                sweep_dry_run: src.or("SWEEP_DRY_RUN", false),
+                freeze_windows: src.parsed_list("FREEZE_WINDOWS"),
+                freeze_mode: src.or("FREEZE_MODE", FreezeMode::Queue),
+            },
+            loaded: Loaded::default(),
+        };
+
+        let unknown: Vec<String> = src.unused().iter().map(|key| format!("unknown setting {} (from {})", key, src.origin(key))).collect();
+        let mut errors = src.errors.into_inner();
+        errors.extend(config.validate());
+        errors.extend(config.build());
+        errors.extend(unknown);
+        if !errors.is_empty() {
+            return Err(ConfigError::Invalid(errors));
+        }
+        Ok(config)
+    }
+
+    /// Settings that are fine on their own but not together.
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.tls_mode == TlsMode::Wildcard && self.wildcard_tls_secret.is_none() {
+            errors.push("WILDCARD_TLS_SECRET is required when TLS_MODE is wildcard".to_string());
+        }
+        if self.tls_mode == TlsMode::Acme && self.acme_email.is_none() {
+            errors.push("ACME_EMAIL is required when TLS_MODE is acme, for Let's Encrypt to get in touch".to_string());
+        }
+        let backends: Vec<&str> = delivery::backends().iter().map(|(name, _)| *name).collect();
+        if let Some(backend) = self.delivery_backend.as_deref().filter(|backend| !backends.contains(backend)) {
+            errors.push(format!("unknown DELIVERY_BACKEND {:?}, this build supports: {}", backend, supported(&backends)));
+        }
+        if self.dns_provider.is_some() && self.dns_target.is_none() {
+            errors.push("DNS_TARGET is required when DNS_PROVIDER is set".to_string());
+        }
+        if self.naming_script.is_some() && self.admission_addr.is_none() {
+            errors.push("ADMISSION_ADDR is required when NAMING_SCRIPT is set, since the webhook runs it".to_string());
+        }
+        if self.impersonate_creator && self.admission_addr.is_none() {
+            errors.push("ADMISSION_ADDR is required when IMPERSONATE_CREATOR is set, since the webhook records them".to_string());
+        }
+        if let Some(Err(err)) = self.fqdn_template.as_deref().map(fqdn::check_template) {
+            errors.push(err);
+        }
+        if self.state_dir.is_some() && !cfg!(feature = "store") {
+            errors.push("STATE_DIR is set but the controller was built without the store feature".to_string());
+        }
+        if self.vault_addr.is_some() && !cfg!(feature = "vault") {
+            errors.push("VAULT_ADDR is set but the controller was built without the vault feature".to_string());
+        }
+        if self.vault_addr.is_some() && self.vault_token.is_none() && self.vault_auth_role.is_none() {
+            errors.push("VAULT_TOKEN or VAULT_AUTH_ROLE is required when VAULT_ADDR is set".to_string());
+        }
+        if self.priority_class_value.is_some() && self.priority_class.is_none() {
+            errors.push("PRIORITY_CLASS is required when PRIORITY_CLASS_VALUE is set".to_string());
+        }
+        if self.kube_connect_timeout.as_secs() == 0 || self.kube_request_timeout.as_secs() == 0 {
+            errors.push("KUBE_CONNECT_TIMEOUT_SECONDS and KUBE_REQUEST_TIMEOUT_SECONDS must be at least 1".to_string());
+        }
+        if self.watch_stall_timeout.as_secs() == 0 {
+            errors.push("WATCH_STALL_SECONDS must be at least 1".to_string());
+        }
+        if self.qps <= 0.0 {
+            errors.push("KUBE_QPS must be greater than zero".to_string());
+        }
+        if self.reloadable.max_failures == 0 {
+            errors.push("MAX_FAILURES must be at least 1".to_string());
+        }
+        errors
+    }
+
+    /// Builds what the settings select into `loaded`, which is the only way
+    /// to know their settings work.
+    fn build(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        match dns::from_config(self) {
+            Ok(provider) => self.loaded.dns = provider.map(Arc::from),
+            Err(err) => errors.push(err),
+        }
+        if let Some(path) = &self.external_secret_template {
+            match ExternalSecretTemplate::load(path) {
+                Ok(template) => self.loaded.external_secret_template = Some(Arc::new(template)),
+                Err(err) => errors.push(err),
+            }
+        }
+        if let Some(path) = &self.rollout_strategy_template {
+            match rollouts::load_strategy(path) {
+                Ok(strategy) => self.loaded.rollout_strategy = Some(strategy),
+                Err(err) => errors.push(err),
+            }
+        }
+        if let Some(path) = &self.naming_script {
+            match NamingScript::load(path) {
+                Ok(script) => self.loaded.naming_script = Some(Arc::new(script)),
+                Err(err) => errors.push(err),
+            }
+        }
+        match Plugins::load(&self.plugins) {
+            Ok(plugins) => self.loaded.plugins = Arc::new(plugins),
+            Err(err) => errors.push(err),
+        }
+        errors
+    }
+}
+
+/// Requests and limits for previews that don't set their own, from the
+/// `DEFAULT_CPU_REQUEST` and like settings `setting` finds.  Profiles have
+/// them too.
+pub fn default_resources(setting: impl Fn(&str) -> Option<String>) -> Option<ResourceRequirements> {
+    let quantities = |cpu: &str, memory: &str| {
+        let mut quantities = BTreeMap::new();
+        for (name, key) in [("cpu", cpu), ("memory", memory)].iter() {
+            if let Some(value) = setting(key) {
+                quantities.insert(name.to_string(), Quantity(value));
+            }
+        }
+        Some(quantities).filter(|quantities| !quantities.is_empty())
+    };
+    let requests = quantities("DEFAULT_CPU_REQUEST", "DEFAULT_MEMORY_REQUEST");
+    let limits = quantities("DEFAULT_CPU_LIMIT", "DEFAULT_MEMORY_LIMIT");
+    if requests.is_none() && limits.is_none() {
+        return None;
+    }
+    Some(ResourceRequirements { requests, limits })
+}
+
+// The integrations built in, for error messages.
+pub(crate) fn supported(names: &[&str]) -> String {
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Failed to parse config file {0}: {1}")]
+    Parse(String, String),
+    #[error("{0}")]
+    Flag(String),
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+/// Where settings come from.  Every setting has one name, the environment
+/// variable it's always been read from, e.g. `TLS_MODE`.  On the command
+/// line it's written `--tls-mode`, and in the config file `tls_mode` or, in
+/// a section, `mode` under `tls`.
+struct Sources {
+    flags: BTreeMap<String, String>,
+    file: BTreeMap<String, String>,
+    file_path: Option<String>,
+    // What's been looked up, so typos in the file or flags can be caught
+    read: RefCell<BTreeSet<String>>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Sources {
+    fn new<I: IntoIterator<Item = String>>(args: I) -> Result<Self, ConfigError> {
+        let mut flags = BTreeMap::new();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                return Err(ConfigError::Flag(format!("Unexpected argument {:?}", arg)));
+            }
+            let flag = &arg[2..];
+            let (name, value) = match flag.find('=') {
+                Some(eq) => (&flag[..eq], flag[eq + 1..].to_string()),
+                // A flag on its own, like `--maintenance-mode`, turns it on
+                None => match args.peek() {
+                    Some(next) if !next.starts_with("--") => (flag, args.next().unwrap()),
+                    _ => (flag, "true".to_string()),
+                },
+            };
+            flags.insert(key(name), value);
+        }
+
+        // The file is named on the command line or in the environment
+        let file_path = flags.remove("CONFIG").or_else(|| std::env::var("CONFIG_FILE").ok()).filter(|path| !path.is_empty());
+        let mut file = BTreeMap::new();
+        if let Some(path) = &file_path {
+            let contents = std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+            let value: JsonValue = if path.ends_with(".toml") {
+                toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.clone(), err.to_string()))?
+            } else {
+                serde_yaml::from_str(&contents).map_err(|err| ConfigError::Parse(path.clone(), err.to_string()))?
+            };
+            if !value.is_object() && !value.is_null() {
+                return Err(ConfigError::Parse(path.clone(), "expected a mapping of settings".to_string()));
+            }
+            flatten("", &value, &mut file);
+        }
+
+        Ok(Sources {
+            flags,
+            file,
+            file_path,
+            read: RefCell::new(BTreeSet::new()),
+            errors: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// The raw value of a setting, flags first, then the environment, then
+    /// the file.  Empty values count as unset.
+    fn get(&self, key: &str) -> Option<(String, String)> {
+        self.read.borrow_mut().insert(key.to_string());
+        if let Some(value) = self.flags.get(key) {
+            return Some((value.clone(), self.origin(key)));
+        }
+        if let Ok(value) = std::env::var(key) {
+            return Some((value, format!("environment variable {}", key)));
+        }
+        self.file.get(key).map(|value| (value.clone(), self.origin(key)))
+    }
+
+    fn origin(&self, key: &str) -> String {
+        if self.flags.contains_key(key) {
+            format!("flag --{}", key.to_lowercase().replace('_', "-"))
+        } else {
+            format!("config file {}", self.file_path.as_deref().unwrap_or_default())
+        }
+    }
+
+    /// Flags and file entries that don't match any setting.
+    fn unused(&self) -> Vec<String> {
+        let read = self.read.borrow();
+        self.flags.keys().chain(self.file.keys()).filter(|key| !read.contains(*key)).cloned().collect()
+    }
+
+    /// Parses a setting with `parse`, noting the problem and falling back to
+    /// `default` if it's invalid.
+    fn with<T>(&self, key: &str, default: T, parse: impl Fn(&str) -> Result<T, String>) -> T {
+        match self.get(key).filter(|(value, _)| !value.is_empty()) {
+            Some((value, origin)) => parse(&value).unwrap_or_else(|err| {
+                self.errors.borrow_mut().push(format!("invalid value {:?} for {} (from {}): {}", value, key, origin, err));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    fn or<T: FromStr>(&self, key: &str, default: T) -> T
+    where
+        T::Err: Display,
+    {
+        self.with(key, default, |value| value.parse().map_err(|err: T::Err| err.to_string()))
+    }
+
+    fn opt(&self, key: &str) -> Option<String> {
+        self.get(key).map(|(value, _)| value).filter(|value| !value.is_empty())
+    }
+
+    /// A comma separated list, empty when unset.
+    fn list(&self, key: &str) -> Vec<String> {
+        self.opt(key)
+            .map(|value| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    fn parsed_list<T: FromStr>(&self, key: &str) -> Vec<T>
+    where
+        T::Err: Display,
+    {
+        self.with(key, Vec::new(), |value| {
+            value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(|item| item.parse().map_err(|err: T::Err| err.to_string())).collect()
+        })
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.with(key, None, |value| value.parse().map(Some).map_err(|err: T::Err| err.to_string()))
+    }
+}
+
+// `--tls-mode` and `tls_mode` are both TLS_MODE.
+fn key(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+// Sections in the file are prefixes, so `vault: {addr: ...}` is VAULT_ADDR.
+// Lists become comma separated, like they'd be written in the environment.
+fn flatten(prefix: &str, value: &JsonValue, settings: &mut BTreeMap<String, String>) {
+    match value {
+        JsonValue::Object(map) => {
+            for (name, value) in map {
+                let name = if prefix.is_empty() { key(name) } else { format!("{}_{}", prefix, key(name)) };
+                flatten(&name, value, settings);
+            }
+        }
+        JsonValue::Array(items) => {
+            let items: Vec<String> = items.iter().map(scalar).collect();
+            settings.insert(prefix.to_string(), items.join(","));
+        }
+        value => {
+            settings.insert(prefix.to_string(), scalar(value));
+        }
+    }
+}
+
+fn scalar(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(value) => value.clone(),
+        JsonValue::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+pub fn propagation_policy(value: &str) -> Option<PropagationPolicy> {
+    match value.to_lowercase().as_str() {
+        "foreground" => Some(PropagationPolicy::Foreground),
+        "background" => Some(PropagationPolicy::Background),
+        "orphan" => Some(PropagationPolicy::Orphan),
+        _ => None,
+    }
+}
@@ -0,0 +1,290 @@
+//! Noticing when something else keeps changing what the controller owns of
+//! a child.  A GitOps tool, an admission webhook or a person with kubectl
+//! changing the image or the replicas of a preview's Deployment will have it
+//! changed back on the next update, and they'll change it again, and the two
+//! will fight for as long as both are running.
+//!
+//! The three-way merge children are updated with (see `applied`) already
+//! leaves alone fields someone else changed, as long as the controller
+//! hasn't changed what it renders for them.  When it has, both sides want
+//! the field and one has to win.  Before a child is updated, the fields the
+//! controller last applied are compared with what's live and what's
+//! rendered now, and the child's `managedFields` say who the other side
+//! is: every write the controller makes is tagged with the
+//! `preview-controller` field manager, so whichever other manager owns the
+//! field now is the culprit.  The preview gets a `Conflicted` condition
+//! naming them and the fields.
+//!
+//! By default the controller's value wins.  With `BACK_OFF_ON_CONFLICT` the
+//! fields are left as the other side has them, and only the condition says
+//! so.  Values the API server normalises (`1000m` CPU read back as `1`,
+//! say) show up as conflicts with no manager named, so render them the way
+//! the API server would.
+use serde_json::json;
+
+use crate::{conditions, set_status, ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// The field manager every write the controller makes is tagged with.
+pub const FIELD_MANAGER: &str = "preview-controller";
+
+/// Type of the condition set while someone else is changing the children.
+pub const CONDITION: &str = "Conflicted";
+
+/// A field the controller applied that someone else has since changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// Which child, e.g. `Deployment web-canary`.
+    pub child: String,
+    /// Where, e.g. `spec.template.spec.containers[app].image`.
+    pub field: String,
+    /// Who has it now, or `None` when `managedFields` doesn't say.
+    pub manager: Option<String>,
+    // Where the field is within the object, for putting it back
+    path: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    /// An item of a list of named things, like a container.
+    Named(String),
+}
+
+/// The fields in `last` that someone else has changed in `live` and that
+/// `desired` changes too, and who the someone else is.
+pub fn detect(last: &JsonValue, live: &JsonValue, desired: &JsonValue) -> Vec<Conflict> {
+    let mut changed = vec![];
+    walk(last, live, &mut vec![], &mut changed);
+    let (kind, name) = (live["kind"].as_str().unwrap_or_default(), live["metadata"]["name"].as_str().unwrap_or_default());
+    let child = format!("{} {}", kind, name);
+    changed
+        .into_iter()
+        .filter(|path| locate(desired, path) != locate(last, path))
+        .map(|path| Conflict {
+            child: child.clone(),
+            field: field_name(&path),
+            manager: manager(live, &path),
+            path,
+        })
+        .collect()
+}
+
+fn walk(last: &JsonValue, live: &JsonValue, path: &mut Vec<Segment>, changed: &mut Vec<Vec<Segment>>) {
+    match last {
+        JsonValue::Object(fields) if live.is_object() => {
+            for (key, value) in fields {
+                path.push(Segment::Key(key.clone()));
+                walk(value, &live[key], path, changed);
+                path.pop();
+            }
+        }
+        JsonValue::Array(items) if live.is_array() && !items.is_empty() && items.iter().all(|item| item["name"].is_string()) => {
+            for item in items {
+                let name = item["name"].as_str().unwrap_or_default();
+                let live_item = live.as_array().and_then(|live| live.iter().find(|live| live["name"] == item["name"]));
+                path.push(Segment::Named(name.to_string()));
+                walk(item, live_item.unwrap_or(&JsonValue::Null), path, changed);
+                path.pop();
+            }
+        }
+        _ if last != live => changed.push(path.clone()),
+        _ => {}
+    }
+}
+
+fn field_name(path: &[Segment]) -> String {
+    let mut name = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if name.is_empty() => name.push_str(key),
+            Segment::Key(key) => name.push_str(&format!(".{}", key)),
+            Segment::Named(item) => name.push_str(&format!("[{}]", item)),
+        }
+    }
+    name
+}
+
+// Whoever else's `managedFields` entry covers the field.  Those are written
+// as `{"f:spec": {"f:replicas": {}}}`, with named list items as
+// `k:{"name":"app"}`.
+fn manager(live: &JsonValue, path: &[Segment]) -> Option<String> {
+    let entries = live["metadata"]["managedFields"].as_array()?;
+    entries
+        .iter()
+        .filter(|entry| entry["manager"].as_str() != Some(FIELD_MANAGER))
+        .find(|entry| {
+            let mut fields = &entry["fieldsV1"];
+            for segment in path {
+                let key = match segment {
+                    Segment::Key(key) => format!("f:{}", key),
+                    Segment::Named(name) => format!("k:{}", json!({ "name": name })),
+                };
+                fields = &fields[key.as_str()];
+            }
+            !fields.is_null()
+        })
+        .and_then(|entry| entry["manager"].as_str().map(String::from))
+}
+
+/// `desired` with the conflicting fields as they are in `live`, so applying
+/// it leaves them be.
+pub fn yield_to(desired: &JsonValue, live: &JsonValue, conflicts: &[Conflict]) -> JsonValue {
+    let mut desired = desired.clone();
+    for conflict in conflicts {
+        if let (Some(target), Some(value)) = (locate_mut(&mut desired, &conflict.path), locate(live, &conflict.path)) {
+            *target = value.clone();
+        }
+    }
+    desired
+}
+
+fn locate<'a>(object: &'a JsonValue, path: &[Segment]) -> Option<&'a JsonValue> {
+    path.iter().try_fold(object, |object, segment| match segment {
+        Segment::Key(key) => object.get(key),
+        Segment::Named(name) => object.as_array()?.iter().find(|item| item["name"] == name.as_str()),
+    })
+}
+
+fn locate_mut<'a>(object: &'a mut JsonValue, path: &[Segment]) -> Option<&'a mut JsonValue> {
+    path.iter().try_fold(object, |object, segment| match segment {
+        Segment::Key(key) => object.get_mut(key),
+        Segment::Named(name) => object.as_array_mut()?.iter_mut().find(|item| item["name"] == name.as_str()),
+    })
+}
+
+/// Set or clear the preview's `Conflicted` condition after its children
+/// were updated.
+pub async fn report(resources: &ApiResources, pe: &KubePreviewEnvironment, conflicts: &[Conflict]) {
+    let status = pe.status.clone().unwrap_or_default();
+    if conflicts.is_empty() && !conditions::is_true(&status.conditions, CONDITION) {
+        return;
+    }
+    let (condition_status, reason, message) = if conflicts.is_empty() {
+        ("False", "Resolved", None)
+    } else {
+        let changes: Vec<String> = conflicts
+            .iter()
+            .map(|conflict| {
+                let manager = conflict.manager.as_deref().unwrap_or("someone else");
+                format!("{} changed {} of {}", manager, conflict.field, conflict.child)
+            })
+            .collect();
+        let reason = if resources.config.back_off_on_conflict { "BackedOff" } else { "Overwritten" };
+        ("True", reason, Some(changes.join("; ")))
+    };
+    if let Some(message) = &message {
+        println!("{} {}: {}", pe.metadata.name, reason, message);
+    }
+    set_status(resources, &pe.metadata.name, |status| {
+        conditions::set(&mut status.conditions, CONDITION, condition_status, reason, message.clone());
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(image: &str, replicas: i64) -> JsonValue {
+        json!({
+            "kind": "Deployment",
+            "metadata": { "name": "web" },
+            "spec": {
+                "replicas": replicas,
+                "template": { "spec": { "containers": [{ "name": "app", "image": image }] } },
+            },
+        })
+    }
+
+    // `deployment` as someone else has written to it, according to its
+    // managedFields.
+    fn written(mut deployment: JsonValue, entries: JsonValue) -> JsonValue {
+        deployment["metadata"]["managedFields"] = entries;
+        deployment
+    }
+
+    fn image_owner(manager: &str) -> JsonValue {
+        let container = format!("k:{}", json!({ "name": "app" }));
+        let containers = json!({ container: { "f:image": {} } });
+        json!({ "manager": manager, "fieldsV1": { "f:spec": { "f:template": { "f:spec": { "f:containers": containers } } } } })
+    }
+
+    fn replicas_owner(manager: &str) -> JsonValue {
+        json!({ "manager": manager, "fieldsV1": { "f:spec": { "f:replicas": {} } } })
+    }
+
+    #[test]
+    fn fields_both_sides_changed_are_conflicts() {
+        let image = "spec.template.spec.containers[app].image";
+        let cases = vec![
+            ("nothing changed", deployment("web:1", 1), deployment("web:1", 1), vec![]),
+            ("only the controller changed the image", deployment("web:1", 1), deployment("web:2", 1), vec![]),
+            (
+                "both changed the image",
+                written(deployment("web:3", 1), json!([image_owner(FIELD_MANAGER), image_owner("argocd")])),
+                deployment("web:2", 1),
+                vec![(image, Some("argocd"))],
+            ),
+            (
+                "someone else changed the image the controller left alone",
+                written(deployment("web:3", 1), json!([image_owner("argocd")])),
+                deployment("web:1", 1),
+                vec![],
+            ),
+            (
+                "an HPA scaled the replicas the controller changed",
+                written(deployment("web:1", 5), json!([replicas_owner("kube-controller-manager")])),
+                deployment("web:1", 2),
+                vec![("spec.replicas", Some("kube-controller-manager"))],
+            ),
+            (
+                "managedFields don't say who",
+                written(deployment("web:3", 1), json!([image_owner(FIELD_MANAGER)])),
+                deployment("web:2", 1),
+                vec![(image, None)],
+            ),
+        ];
+
+        let last = deployment("web:1", 1);
+        for (case, live, desired, expected) in cases {
+            let conflicts = detect(&last, &live, &desired);
+            let found: Vec<(&str, Option<&str>)> =
+                conflicts.iter().map(|conflict| (conflict.field.as_str(), conflict.manager.as_deref())).collect();
+            assert_eq!(found, expected, "{}", case);
+            assert!(conflicts.iter().all(|conflict| conflict.child == "Deployment web"), "{}", case);
+        }
+    }
+
+    #[test]
+    fn removed_fields_and_items_are_conflicts_too() {
+        let last = deployment("web:1", 1);
+        let mut live = last.clone();
+        live["spec"]["template"]["spec"]["containers"] = json!([]);
+        live["spec"].as_object_mut().unwrap().remove("replicas");
+
+        let conflicts = detect(&last, &live, &deployment("web:2", 2));
+        let fields: Vec<&str> = conflicts.iter().map(|conflict| conflict.field.as_str()).collect();
+        assert_eq!(fields, vec!["spec.replicas", "spec.template.spec.containers[app]"]);
+    }
+
+    #[test]
+    fn yielding_keeps_the_live_values_of_conflicting_fields() {
+        let last = deployment("web:1", 1);
+        let live = written(deployment("web:3", 5), json!([image_owner("argocd"), replicas_owner("kube-controller-manager")]));
+        let mut desired = deployment("web:2", 2);
+        desired["spec"]["template"]["spec"]["containers"][0]["env"] = json!([{ "name": "MODE", "value": "preview" }]);
+
+        let conflicts = detect(&last, &live, &desired);
+        let yielded = yield_to(&desired, &live, &conflicts);
+
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(yielded["spec"]["replicas"], 5);
+        assert_eq!(yielded["spec"]["template"]["spec"]["containers"][0]["image"], "web:3");
+        // Whatever didn't conflict is still as rendered
+        let env = &yielded["spec"]["template"]["spec"]["containers"][0]["env"];
+        assert_eq!(env, &desired["spec"]["template"]["spec"]["containers"][0]["env"]);
+        assert_eq!(yield_to(&desired, &live, &[]), desired);
+    }
+}
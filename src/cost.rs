@@ -0,0 +1,60 @@
+//! Rough running cost of a preview, worked out from the resource requests
+//! of its deployment and the controller's per-CPU and per-GB prices.  It's
+//! an estimate for spotting expensive previews, not a bill.
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CostEstimate {
+    pub hourly: f64,
+    pub daily: f64,
+}
+
+/// Estimate the cost of running a rendered Deployment.
+pub fn estimate(deployment: &JsonValue, config: &Config) -> CostEstimate {
+    let replicas = deployment["spec"]["replicas"].as_f64().unwrap_or(1.0);
+    let containers = deployment["spec"]["template"]["spec"]["containers"].as_array().into_iter().flatten();
+
+    let (mut cpu, mut memory) = (0.0, 0.0);
+    for container in containers {
+        let requests = &container["resources"]["requests"];
+        cpu += requests["cpu"].as_str().and_then(parse_quantity).unwrap_or(0.0);
+        memory += requests["memory"].as_str().and_then(parse_quantity).unwrap_or(0.0);
+    }
+
+    let gigabytes = memory / 1_000_000_000.0;
+    let hourly = replicas * (cpu * config.cost_per_cpu_hour + gigabytes * config.cost_per_gb_hour);
+    // Round to a hundredth of a cent so the status doesn't churn on noise
+    let round = |value: f64| (value * 10_000.0).round() / 10_000.0;
+    CostEstimate { hourly: round(hourly), daily: round(hourly * 24.0) }
+}
+
+/// Parse a Kubernetes quantity such as `250m`, `1.5`, `512Mi` or `2G`.
+pub fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let suffixes: [(&str, f64); 13] = [
+        ("Ki", 1024.0),
+        ("Mi", 1024.0 * 1024.0),
+        ("Gi", 1024.0 * 1024.0 * 1024.0),
+        ("Ti", 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("Pi", 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("Ei", 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("n", 1e-9),
+        ("u", 1e-6),
+        ("m", 1e-3),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+    ];
+    for (suffix, multiplier) in suffixes.iter() {
+        if let Some(number) = quantity.strip_suffix(suffix) {
+            return number.parse::<f64>().ok().map(|number| number * multiplier);
+        }
+    }
+    // Plain numbers, including exponent forms like `1e3`
+    quantity.parse().ok()
+}
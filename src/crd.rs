@@ -0,0 +1,273 @@
+//! The PreviewEnvironment custom resource: the spec users write and the
+//! status the controller keeps up to date.  `preview-environment-crd.yaml`
+//! has the schema the API server checks them against, and needs to be kept
+//! in step with these.
+use k8s_openapi::api::core::v1::{HostAlias, PodDNSConfig, PodSecurityContext, ResourceRequirements, SecurityContext};
+use kube::api::Object;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::bluegreen::UpdateStrategy;
+use crate::build::BuildSpec;
+use crate::canary::CanarySpec;
+use crate::cloning::CloneSpec;
+use crate::conditions::Condition;
+use crate::cost::CostEstimate;
+use crate::credentials::GeneratedSecret;
+use crate::cronjobs::CronJobSpec;
+use crate::delivery::SourceSpec;
+use crate::dependencies::Dependency;
+use crate::jobs::JobSpec;
+use crate::jsonnet::JsonnetSpec;
+use crate::mesh::Mesh;
+use crate::monitoring::MetricsSpec;
+use crate::ports::{PortSpec, Protocol, RouteSpec};
+use crate::scheduling::Spread;
+use crate::services::{ServiceMode, ServiceType, SessionAffinity};
+use crate::shared::SharedService;
+use crate::snapshot::{SnapshotSpec, SnapshotStatus};
+use crate::statefulsets::{VolumeClaimSpec, WorkloadType};
+use crate::tcp::TcpSpec;
+use crate::tekton::{PipelineRunStatus, PipelineSpec};
+use crate::{inventory, rollouts, usage};
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewEnvironment {
+    /// Image to deploy.  May be left out when `build` is given.
+    #[serde(default)]
+    pub image: String,
+    /// Host the preview is served on.  Made from the FQDN template when
+    /// left out.
+    #[serde(default)]
+    pub fqdn: String,
+    /// Template for `fqdn`, overriding the controller's `FQDN_TEMPLATE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fqdn_template: Option<String>,
+    /// Labels for everything generated for the preview, e.g. a team or
+    /// cost centre.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Annotations for everything generated for the preview.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Who the preview belongs to, for quotas.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Number of pods to run.
+    #[serde(default = "default_replicas")]
+    pub replicas: i32,
+    /// How to spread multiple replicas across nodes, overriding the
+    /// controller's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spread: Option<Spread>,
+    /// RuntimeClass to run the pods under, e.g. `gvisor` for untrusted
+    /// branches, overriding the controller's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_class_name: Option<String>,
+    /// Node OS the image is built for, e.g. `windows`.
+    #[serde(default, rename = "nodeOS", skip_serializing_if = "Option::is_none")]
+    pub node_os: Option<String>,
+    /// CPU architecture the image is built for, e.g. `arm64`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// CPU and memory for the preview's container.  Falls back to the
+    /// controller's `DEFAULT_CPU_REQUEST` and friends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceRequirements>,
+    /// Overrides for the pod's hardened security context.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod_security_context: Option<PodSecurityContext>,
+    /// Overrides for the hardened security context of the containers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security_context: Option<SecurityContext>,
+    /// Extra nameservers and search domains for the pods.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_config: Option<PodDNSConfig>,
+    /// Entries added to the pods' `/etc/hosts`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_aliases: Vec<HostAlias>,
+    /// Run the preview as a Deployment or, for apps that need stable
+    /// identities and storage, a StatefulSet.
+    #[serde(default)]
+    pub workload_type: WorkloadType,
+    /// Storage for each pod of a StatefulSet.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volume_claim_templates: Vec<VolumeClaimSpec>,
+    /// Named ports the app listens on.  Port 80 when there are none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<PortSpec>,
+    /// How the preview's Service is exposed.  Traffic through Ambassador
+    /// works with any of them.
+    #[serde(default)]
+    pub service_type: ServiceType,
+    /// Pin each client to one pod, for apps with sticky sessions.
+    #[serde(default)]
+    pub session_affinity: SessionAffinity,
+    /// What the app speaks.  gRPC services are routed over HTTP/2.
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Path prefixes and the ports they're routed to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteSpec>,
+    /// Expose a raw TCP port through Ambassador, alongside the HTTP routes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<TcpSpec>,
+    /// Whether the preview gets a normal Service, a headless one, or both.
+    #[serde(default)]
+    pub service_mode: ServiceMode,
+    /// How image changes are rolled out.
+    #[serde(default)]
+    pub strategy: UpdateStrategy,
+    /// Argo Rollouts strategy, overriding the controller's template.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout_strategy: Option<JsonValue>,
+    /// A second image to send some of the preview's traffic to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanarySpec>,
+    /// Service mesh to join, overriding the controller's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<Mesh>,
+    /// Where the app serves Prometheus metrics, if it does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsSpec>,
+    /// Have the GitOps backend deploy this chart instead of the controller
+    /// applying its own manifests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceSpec>,
+    /// Render the preview from a Jsonnet template instead of the built-in
+    /// manifests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jsonnet: Option<JsonnetSpec>,
+    /// Build the image from source instead of deploying `image` directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildSpec>,
+    /// Other previews this one needs, which have to be Ready before it's
+    /// created.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<Dependency>,
+    /// Services shared with other previews of the same repo instead of
+    /// run for this preview alone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_services: Vec<SharedService>,
+    /// The preview this one was cloned from, and whether to copy its
+    /// database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clone_from: Option<CloneSpec>,
+    /// Save the preview's database to object storage before it's deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<SnapshotSpec>,
+    /// Scheduled tasks that run alongside the preview.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cron_jobs: Vec<CronJobSpec>,
+    /// Jobs to run once each time the preview is created.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<JobSpec>,
+    /// Tekton Pipeline to run against the preview once it's Ready.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<PipelineSpec>,
+    /// Secrets to copy in from the controller's source namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub copy_secrets: Vec<String>,
+    /// Random passwords and API keys to generate for the preview.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub generated_secrets: Vec<GeneratedSecret>,
+}
+
+fn default_replicas() -> i32 {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewEnvironmentStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The image currently deployed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// The git ref the deployed image was built from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub built_ref: Option<String>,
+    /// The git ref of the most recent build, whether or not it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_ref: Option<String>,
+    /// The most recent image to be scanned, whether or not it passed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scanned_image: Option<String>,
+    /// What the preview costs to run, going by its resource requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<CostEstimate>,
+    /// The Deployment the Service points at, for blue-green updates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_deployment: Option<String>,
+    /// The image a blue-green update was most recently started for, whether
+    /// or not it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deploying_image: Option<String>,
+    /// The image that can be rolled back to while the old release is kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_image: Option<String>,
+    /// When the previous blue-green release is scaled down, ending the
+    /// window for an instant rollback.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollback_until: Option<String>,
+    /// Failed attempts in a row.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failures: Option<u32>,
+    /// When the preview is given up on if it still hasn't been created in
+    /// full.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_deadline: Option<String>,
+    /// Progress of the Argo Rollout, when there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout: Option<rollouts::RolloutStatus>,
+    /// The canary that's currently deployed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanarySpec>,
+    /// Ambassador listener port given to the preview's TCP service.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_port: Option<u16>,
+    /// Where the TCP service can be reached, as `host:port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_address: Option<String>,
+    /// Grafana dashboard for the preview's pods.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dashboard_url: Option<String>,
+    /// Grafana Explore link to the preview's logs in Loki.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logs_url: Option<String>,
+    /// The most recent run of the preview's pipeline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline_run: Option<PipelineRunStatus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    /// Every child of the preview and how it's doing.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resources: Vec<inventory::ChildStatus>,
+    /// What the preview is actually using, according to metrics-server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<usage::Usage>,
+    /// Where the preview was last promoted to, as `namespace/name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub promoted_to: Option<String>,
+    /// The snapshot taken while the preview was being deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<SnapshotStatus>,
+    /// The generation of the spec that was last acted on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// Replicas the preview's workload was last scaled to, for the scale
+    /// subresource.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replicas: Option<i32>,
+    /// Label selector for the preview's pods, for the scale subresource.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+}
+
+pub type KubePreviewEnvironment = Object<PreviewEnvironment, PreviewEnvironmentStatus>;
@@ -0,0 +1,119 @@
+//! Previews that can't be created in full.  Creating a preview means
+//! creating a dozen or so children one after another, and any of them can
+//! fail after the ones before it were created -- a Mapping CRD that isn't
+//! installed, a webhook that rejects a Host, a quota.  Rather than stop at
+//! the first failure and leave half a preview behind, every child is
+//! attempted, and the preview fails with what did and didn't get created.
+//! It's retried with the usual backoff, and each attempt picks up where the
+//! last left off, since children that already exist are left as they are.
+//!
+//! A preview has `CREATION_TIMEOUT_SECONDS` from its first attempt to be
+//! created in full (or until it's out of retries, if that's sooner).  After
+//! that it's given up on, the way `PARTIAL_FAILURE` says:
+//!
+//! - `degrade`, the default, leaves what was created running, with phase
+//!   `Degraded` and a `Degraded` condition listing each child and how
+//!   creating it went.
+//! - `rollback` deletes everything that was created for the preview and
+//!   marks it `Failed`, so nothing half-made is left running.
+//!
+//! Either way it's left alone until its spec changes or it's retried.
+use chrono::{DateTime, Utc};
+use kube::api::RawApi;
+use std::str::FromStr;
+
+use crate::api::create_child;
+use crate::config::Config;
+use crate::{conditions, ApiResources, KubePreviewEnvironment, PreviewEnvironmentStatus};
+
+type JsonValue = serde_json::value::Value;
+
+/// Type of the condition set on a preview that was given up on with some
+/// of its children missing.
+pub const CONDITION: &str = "Degraded";
+
+/// Type of the condition listing what couldn't be deleted when a preview
+/// was rolled back.
+pub const CLEANUP_CONDITION: &str = "CleanupFailed";
+
+/// What becomes of a preview that still isn't created in full by its
+/// deadline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartialFailure {
+    /// Keep what was created, and say what's missing.
+    Degrade,
+    /// Delete what was created.
+    RollBack,
+}
+
+impl FromStr for PartialFailure {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "degrade" => Ok(PartialFailure::Degrade),
+            "rollback" => Ok(PartialFailure::RollBack),
+            _ => Err(format!("unknown partial failure handling {:?}, expected degrade or rollback", value)),
+        }
+    }
+}
+
+/// How creating one child went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub kind: String,
+    pub name: String,
+    pub error: Option<String>,
+}
+
+/// Creates each child in turn, carrying on past any that fail.  Children
+/// that already exist count as created.
+pub async fn create_all(resources: &ApiResources, children: &[(&RawApi, &JsonValue)]) -> Vec<Outcome> {
+    let mut outcomes = vec![];
+    for (api, manifest) in children {
+        let kind = manifest["kind"].as_str().unwrap_or_default().to_string();
+        let name = manifest["metadata"]["name"].as_str().unwrap_or_default().to_string();
+        let error = create_child(resources, api, manifest).await.err().map(|err| err.to_string());
+        if let Some(error) = &error {
+            println!("Failed to create {} {}: {}", kind, name, error);
+        }
+        outcomes.push(Outcome { kind, name, error });
+    }
+    outcomes
+}
+
+pub fn failed(outcomes: &[Outcome]) -> bool {
+    outcomes.iter().any(|outcome| outcome.error.is_some())
+}
+
+/// Every child and how it went, e.g. `Deployment web created; Mapping web
+/// failed: ...`.
+pub fn summary(outcomes: &[Outcome]) -> String {
+    let children: Vec<String> = outcomes
+        .iter()
+        .map(|outcome| match &outcome.error {
+            Some(error) => format!("{} {} failed: {}", outcome.kind, outcome.name, error),
+            None => format!("{} {} created", outcome.kind, outcome.name),
+        })
+        .collect();
+    children.join("; ")
+}
+
+/// When the preview has to be created in full by: the deadline its first
+/// attempt set, or one starting now if this is its first attempt.
+pub fn deadline(pe: &KubePreviewEnvironment, config: &Config) -> DateTime<Utc> {
+    let deadline = pe.status.as_ref().and_then(|status| status.create_deadline.as_deref());
+    match deadline.and_then(|deadline| DateTime::parse_from_rfc3339(deadline).ok()) {
+        Some(deadline) => deadline.with_timezone(&Utc),
+        None => Utc::now() + chrono::Duration::from_std(config.creation_timeout).unwrap_or_else(|_| chrono::Duration::zero()),
+    }
+}
+
+/// Forget the deadline and clear the condition, once the preview has been
+/// created in full or is about to be tried afresh.
+pub fn clear(status: &mut PreviewEnvironmentStatus, reason: &str) {
+    status.create_deadline = None;
+    if conditions::is_true(&status.conditions, CONDITION) {
+        conditions::set(&mut status.conditions, CONDITION, "False", reason, None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Generates random passwords and API keys for a preview and stores them in
+//! a `<preview>-generated` Secret, which the preview sees as environment
+//! variables.  Values are generated once, when the Secret is first
+//! created, so they stay stable for the life of the preview.
+use kube::{
+    api::{PostParams, RawApi},
+    Error,
+};
+use rand::{rngs::OsRng, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use crate::client::Client;
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+/// One generated value, declared in the spec as e.g.
+/// `{ name: ADMIN_PASSWORD, length: 24, charset: alphanumeric }`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeneratedSecret {
+    /// Environment variable (and Secret key) the value is exposed as.
+    pub name: String,
+    #[serde(default = "default_length")]
+    pub length: usize,
+    /// `alphanumeric`, `hex`, `numeric` or `symbols`.  Anything else is
+    /// taken as the literal set of characters to pick from.
+    #[serde(default = "default_charset")]
+    pub charset: String,
+}
+
+fn default_length() -> usize {
+    32
+}
+
+fn default_charset() -> String {
+    "alphanumeric".to_string()
+}
+
+const ALPHANUMERIC: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+fn alphabet(charset: &str) -> Vec<char> {
+    match charset {
+        "alphanumeric" => ALPHANUMERIC.chars().collect(),
+        "hex" => "0123456789abcdef".chars().collect(),
+        "numeric" => "0123456789".chars().collect(),
+        "symbols" => format!("{}{}", ALPHANUMERIC, "!#$%&()*+,-.:;<=>?@[]^_{|}~").chars().collect(),
+        custom => custom.chars().collect(),
+    }
+}
+
+/// A random string drawn from the OS's secure random number generator.
+pub fn generate(length: usize, charset: &str) -> String {
+    let alphabet = alphabet(charset);
+    if alphabet.is_empty() {
+        return String::new();
+    }
+    (0..length).map(|_| *alphabet.choose(&mut OsRng).unwrap()).collect()
+}
+
+pub fn secret_name(preview: &str) -> String {
+    names::child(preview, "generated")
+}
+
+/// Creates the preview's generated Secret.  If it already exists the
+/// existing values are kept.
+pub async fn create(client: &Client, secrets: &RawApi, preview: &str, generated: &[GeneratedSecret]) -> Result<(), Error> {
+    let values: BTreeMap<&str, String> = generated
+        .iter()
+        .map(|secret| (secret.name.as_str(), generate(secret.length, &secret.charset)))
+        .collect();
+
+    let data = json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": secret_name(preview),
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "type": "Opaque",
+        "stringData": values,
+    });
+    let request = secrets.create(&PostParams::default(), serde_json::to_vec(&data)?)?;
+    match client.request::<JsonValue>(request).await {
+        Ok(_) => Ok(()),
+        Err(Error::Api(ae)) if ae.code == 409 => Ok(()),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,69 @@
+//! Scheduled tasks that belong to a preview, such as a nightly data
+//! refresh.  They're created alongside the preview's Deployment, get the
+//! same secrets, and are removed with the rest of the preview.
+use kube::{
+    api::{ListParams, RawApi},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::client::Client;
+use crate::jobs::task_pod_spec;
+use crate::labels::{name_value, NAME_LABEL};
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CronJobSpec {
+    /// Unique within the preview; the CronJob is named `<preview>-<name>`.
+    pub name: String,
+    /// Cron schedule, e.g. `0 3 * * *`.
+    pub schedule: String,
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+}
+
+pub fn name(preview: &str, cron_job: &CronJobSpec) -> String {
+    names::child(preview, &cron_job.name)
+}
+
+pub fn cron_job_json(preview: &str, cron_job: &CronJobSpec, deployment: &JsonValue) -> JsonValue {
+    let pod_spec = task_pod_spec(&cron_job.name, &cron_job.image, &cron_job.command, "OnFailure", deployment);
+    json!({
+        "apiVersion": "batch/v1beta1",
+        "kind": "CronJob",
+        "metadata": {
+            "name": name(preview, cron_job),
+            "labels": {
+                "preview": "true",
+                NAME_LABEL: name_value(preview),
+            }
+        },
+        "spec": {
+            "schedule": cron_job.schedule,
+            // A slow refresh shouldn't pile up behind itself
+            "concurrencyPolicy": "Forbid",
+            "jobTemplate": {
+                "spec": {
+                    "template": {
+                        "spec": pod_spec,
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Removes every CronJob belonging to the preview.
+pub async fn remove(client: &Client, cron_jobs: &RawApi, preview: &str) -> Result<(), Error> {
+    let lp = ListParams {
+        label_selector: Some(format!("{}={}", NAME_LABEL, name_value(preview))),
+        ..ListParams::default()
+    };
+    client.request::<JsonValue>(cron_jobs.delete_collection(&lp)?).await?;
+    Ok(())
+}
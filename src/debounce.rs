@@ -0,0 +1,80 @@
+//! Waiting for a preview to settle before reconciling a change to it.  CI
+//! tends to change a preview several times in a row -- the image, then the
+//! fqdn, then its labels -- and reconciling each of those in turn races
+//! through states nobody asked for.  So a Modified event is held until the
+//! preview has been left alone for `DEBOUNCE_SECONDS`, and only the last
+//! one is reconciled.  A preview that never stops changing is still
+//! reconciled every `MAX_QUIET_PERIODS` quiet periods, so it can't be put
+//! off forever.
+//!
+//! Like `requeue`, this lives in memory.  Anything held when the controller
+//! stops is caught up on by the reconcile of every preview on startup.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::DEBOUNCED_EVENTS;
+use crate::reconcile::reconcile;
+use crate::{impersonation, ApiResources, KubePreviewEnvironment};
+
+// How often held changes are checked for ones that have settled.
+const TICK: Duration = Duration::from_millis(250);
+
+// The longest a change is held, in quiet periods, however busy the preview.
+const MAX_QUIET_PERIODS: u32 = 10;
+
+struct Held {
+    first: Instant,
+    last: Instant,
+    pe: KubePreviewEnvironment,
+}
+
+#[derive(Default)]
+pub struct Debounce {
+    held: Mutex<BTreeMap<String, Held>>,
+}
+
+impl Debounce {
+    /// Hold the latest state of a changed preview, replacing whatever was
+    /// held for it before.
+    pub fn hold(&self, pe: KubePreviewEnvironment) {
+        let now = Instant::now();
+        let mut held = self.held.lock().unwrap();
+        match held.get_mut(&pe.metadata.name) {
+            Some(earlier) => {
+                DEBOUNCED_EVENTS.inc();
+                earlier.last = now;
+                earlier.pe = pe;
+            }
+            None => {
+                held.insert(pe.metadata.name.clone(), Held { first: now, last: now, pe });
+            }
+        }
+    }
+
+    /// Drop whatever is held for a preview, e.g. because it's been deleted.
+    pub fn forget(&self, name: &str) {
+        self.held.lock().unwrap().remove(name);
+    }
+
+    fn take_settled(&self, quiet: Duration) -> Vec<KubePreviewEnvironment> {
+        let now = Instant::now();
+        let mut held = self.held.lock().unwrap();
+        let settled: Vec<String> = held
+            .iter()
+            .filter(|(_, held)| now >= held.last + quiet || now >= held.first + quiet * MAX_QUIET_PERIODS)
+            .map(|(name, _)| name.clone())
+            .collect();
+        settled.iter().filter_map(|name| held.remove(name)).map(|held| held.pe).collect()
+    }
+}
+
+pub async fn run(resources: Arc<ApiResources>) {
+    loop {
+        tokio::time::delay_for(TICK).await;
+        for pe in resources.debounce.take_settled(resources.config.debounce) {
+            println!("Settled PreviewEnvironment name: {}", pe.metadata.name);
+            impersonation::as_creator(&resources.config, &pe, reconcile(&resources, &pe, false)).await;
+        }
+    }
+}
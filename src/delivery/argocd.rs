@@ -0,0 +1,105 @@
+use async_trait::async_trait;
+use kube::{api::RawApi, Error};
+use serde_json::json;
+
+use super::{DeliveryBackend, Release};
+use crate::client::Client;
+use crate::config::Config;
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+/// Finalizer that has ArgoCD delete what it synced when the Application goes.
+const RESOURCES_FINALIZER: &str = "resources-finalizer.argocd.argoproj.io";
+
+/// One ArgoCD `Application` per preview.  Applications live in ArgoCD's
+/// namespace rather than the preview's, so their names include the preview
+/// namespace to keep them apart.
+pub struct ArgoCd {
+    applications: RawApi,
+    project: String,
+    destination_server: String,
+}
+
+impl ArgoCd {
+    pub fn new(config: &Config) -> Self {
+        ArgoCd {
+            applications: RawApi::customResource("applications")
+                .group("argoproj.io")
+                .version("v1alpha1")
+                .within(&config.argocd_namespace),
+            project: config.argocd_project.clone(),
+            destination_server: config.argocd_destination_server.clone(),
+        }
+    }
+}
+
+fn application_name(preview: &str, namespace: &str) -> String {
+    names::slug(&format!("{}-{}", namespace, preview), names::MAX_LABEL)
+}
+
+#[async_trait]
+impl DeliveryBackend for ArgoCd {
+    fn render(&self, release: &Release<'_>) -> Vec<JsonValue> {
+        let source = release.source;
+        let helm = json!({
+            "values": serde_yaml::to_string(&super::helm_values(release)).expect("Failed to serialize Helm values"),
+        });
+        let mut app_source = json!({
+            "repoURL": source.repo_url,
+            "targetRevision": source.target_revision,
+            "helm": helm,
+        });
+        if let Some(path) = &source.path {
+            app_source["path"] = json!(path);
+        }
+        if let Some(chart) = &source.chart {
+            app_source["chart"] = json!(chart);
+        }
+
+        vec![json!({
+            "apiVersion": "argoproj.io/v1alpha1",
+            "kind": "Application",
+            "metadata": {
+                "name": application_name(release.preview, release.namespace),
+                "labels": {
+                    "preview": "true",
+                    "preview.platform9.com/name": release.preview,
+                },
+                "finalizers": [RESOURCES_FINALIZER],
+            },
+            "spec": {
+                "project": self.project,
+                "source": app_source,
+                "destination": {
+                    "server": self.destination_server,
+                    "namespace": release.namespace,
+                },
+                "syncPolicy": {
+                    "automated": { "prune": true, "selfHeal": true },
+                },
+            },
+        })]
+    }
+
+    async fn apply(&self, client: &Client, manifests: &[JsonValue]) -> Result<(), Error> {
+        for manifest in manifests {
+            super::create(client, &self.applications, manifest).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_image(&self, client: &Client, release: &Release<'_>) -> Result<(), Error> {
+        let values = serde_yaml::to_string(&super::helm_values(release)).expect("Failed to serialize Helm values");
+        client
+            .update(&self.applications, &application_name(release.preview, release.namespace), |application: &mut JsonValue| {
+                application["spec"]["source"]["helm"]["values"] = json!(values);
+            })
+            .await
+            .map(|_: JsonValue| ())
+    }
+
+    async fn delete(&self, client: &Client, preview: &str, namespace: &str) -> Result<(), Error> {
+        super::delete(client, &self.applications, &application_name(preview, namespace)).await
+    }
+}
@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use kube::{api::RawApi, Error};
+use serde_json::json;
+
+use super::{DeliveryBackend, Release};
+use crate::client::Client;
+use crate::config::Config;
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+/// A Flux `HelmRelease` per preview, with a `GitRepository` or
+/// `HelmRepository` for it to pull the chart from.  Both live in the
+/// preview namespace.
+pub struct Flux {
+    git_repositories: RawApi,
+    helm_repositories: RawApi,
+    helm_releases: RawApi,
+    interval: String,
+}
+
+impl Flux {
+    pub fn new(config: &Config) -> Self {
+        let namespace = config.namespace.as_str();
+        Flux {
+            git_repositories: RawApi::customResource("gitrepositories")
+                .group("source.toolkit.fluxcd.io")
+                .version("v1beta2")
+                .within(namespace),
+            helm_repositories: RawApi::customResource("helmrepositories")
+                .group("source.toolkit.fluxcd.io")
+                .version("v1beta2")
+                .within(namespace),
+            helm_releases: RawApi::customResource("helmreleases")
+                .group("helm.toolkit.fluxcd.io")
+                .version("v2beta1")
+                .within(namespace),
+            interval: config.flux_interval.clone(),
+        }
+    }
+}
+
+fn source_name(preview: &str) -> String {
+    names::child(preview, "source")
+}
+
+fn release_name(preview: &str) -> String {
+    names::child(preview, "release")
+}
+
+fn metadata(name: &str, preview: &str) -> JsonValue {
+    json!({
+        "name": name,
+        "labels": {
+            "preview": "true",
+            "preview.platform9.com/name": preview,
+        }
+    })
+}
+
+#[async_trait]
+impl DeliveryBackend for Flux {
+    fn render(&self, release: &Release<'_>) -> Vec<JsonValue> {
+        let source = release.source;
+        let source_name = source_name(release.preview);
+        // HEAD means whatever the repository's default is: the default
+        // branch of a git repository, or the latest chart version
+        let revision = Some(source.target_revision.as_str()).filter(|revision| *revision != "HEAD");
+
+        let (source_json, chart) = match &source.chart {
+            Some(chart) => {
+                let repository = json!({
+                    "apiVersion": "source.toolkit.fluxcd.io/v1beta2",
+                    "kind": "HelmRepository",
+                    "metadata": metadata(&source_name, release.preview),
+                    "spec": { "url": source.repo_url, "interval": self.interval },
+                });
+                let mut chart = json!({
+                    "chart": chart,
+                    "sourceRef": { "kind": "HelmRepository", "name": source_name },
+                });
+                if let Some(version) = revision {
+                    chart["version"] = json!(version);
+                }
+                (repository, chart)
+            }
+            None => {
+                let mut repository = json!({
+                    "apiVersion": "source.toolkit.fluxcd.io/v1beta2",
+                    "kind": "GitRepository",
+                    "metadata": metadata(&source_name, release.preview),
+                    "spec": { "url": source.repo_url, "interval": self.interval },
+                });
+                if let Some(branch) = revision {
+                    repository["spec"]["ref"] = json!({ "branch": branch });
+                }
+                let chart = json!({
+                    "chart": source.path.as_deref().unwrap_or("."),
+                    "sourceRef": { "kind": "GitRepository", "name": source_name },
+                });
+                (repository, chart)
+            }
+        };
+
+        let helm_release = json!({
+            "apiVersion": "helm.toolkit.fluxcd.io/v2beta1",
+            "kind": "HelmRelease",
+            "metadata": metadata(&release_name(release.preview), release.preview),
+            "spec": {
+                "interval": self.interval,
+                "chart": { "spec": chart },
+                "values": super::helm_values(release),
+            },
+        });
+        vec![source_json, helm_release]
+    }
+
+    async fn apply(&self, client: &Client, manifests: &[JsonValue]) -> Result<(), Error> {
+        for manifest in manifests {
+            let api = match manifest["kind"].as_str() {
+                Some("GitRepository") => &self.git_repositories,
+                Some("HelmRepository") => &self.helm_repositories,
+                _ => &self.helm_releases,
+            };
+            super::create(client, api, manifest).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_image(&self, client: &Client, release: &Release<'_>) -> Result<(), Error> {
+        let values = super::helm_values(release);
+        client
+            .update(&self.helm_releases, &release_name(release.preview), |helm_release: &mut JsonValue| {
+                helm_release["spec"]["values"] = values.clone();
+            })
+            .await
+            .map(|_: JsonValue| ())
+    }
+
+    async fn delete(&self, client: &Client, preview: &str, _namespace: &str) -> Result<(), Error> {
+        // The release goes first so Flux uninstalls the chart while it can
+        // still find it
+        super::delete(client, &self.helm_releases, &release_name(preview)).await?;
+        super::delete(client, &self.git_repositories, &source_name(preview)).await?;
+        super::delete(client, &self.helm_repositories, &source_name(preview)).await
+    }
+}
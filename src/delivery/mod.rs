@@ -0,0 +1,131 @@
+//! GitOps delivery.  A preview with a `source` isn't applied by the
+//! controller directly: its chart is handed to a GitOps tool, chosen with
+//! `DELIVERY_BACKEND`, which syncs it into the preview namespace.  The
+//! controller still owns the preview's lifecycle -- DNS, secrets, status
+//! and deletion -- and passes the image and FQDN in as Helm values.
+//! Previews without a `source` are always applied directly.
+use async_trait::async_trait;
+use kube::{
+    api::{DeleteParams, PostParams, RawApi, Void},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::client::Client;
+use crate::config::Config;
+
+#[cfg(feature = "argocd")]
+mod argocd;
+#[cfg(feature = "flux")]
+mod flux;
+
+#[cfg(feature = "argocd")]
+pub use argocd::ArgoCd;
+#[cfg(feature = "flux")]
+pub use flux::Flux;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceSpec {
+    /// Git repository or Helm chart repository.
+    #[serde(rename = "repoURL")]
+    pub repo_url: String,
+    /// Directory of the chart within a git repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Chart name, when `repoURL` is a chart repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chart: Option<String>,
+    /// Branch or tag of a git repository, or the chart version.
+    #[serde(default = "default_target_revision")]
+    pub target_revision: String,
+    /// Extra Helm values for the chart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<JsonValue>,
+}
+
+fn default_target_revision() -> String {
+    "HEAD".to_string()
+}
+
+/// Everything a backend needs to know to deploy one preview.
+pub struct Release<'a> {
+    pub preview: &'a str,
+    pub namespace: &'a str,
+    pub source: &'a SourceSpec,
+    pub image: &'a str,
+    pub fqdn: &'a str,
+}
+
+#[async_trait]
+pub trait DeliveryBackend: Send + Sync {
+    /// The resources that deploy the release, so they can be labelled and
+    /// checked against policy before anything is created.
+    fn render(&self, release: &Release<'_>) -> Vec<JsonValue>;
+
+    /// Creates what `render` returned.  Resources that already exist are
+    /// left alone.
+    async fn apply(&self, client: &Client, manifests: &[JsonValue]) -> Result<(), Error>;
+
+    /// Moves a deployed release to `release.image`.
+    async fn update_image(&self, client: &Client, release: &Release<'_>) -> Result<(), Error>;
+
+    /// Removes everything created for the preview.  Removing a preview that
+    /// was never delivered this way is not an error.
+    async fn delete(&self, client: &Client, preview: &str, namespace: &str) -> Result<(), Error>;
+}
+
+type Factory = fn(&Config) -> Box<dyn DeliveryBackend>;
+
+/// The backends this controller was built with, by the name
+/// `DELIVERY_BACKEND` selects them with.  Each is behind a cargo feature of
+/// the same name.
+pub fn backends() -> Vec<(&'static str, Factory)> {
+    let mut backends: Vec<(&'static str, Factory)> = Vec::new();
+    #[cfg(feature = "argocd")]
+    backends.push(("argocd", |config| Box::new(ArgoCd::new(config))));
+    #[cfg(feature = "flux")]
+    backends.push(("flux", |config| Box::new(Flux::new(config))));
+    backends
+}
+
+/// Builds the backend selected by `DELIVERY_BACKEND`, or the first one
+/// built in when it's unset.  `None` when the controller was built without
+/// any.
+pub fn from_config(config: &Config) -> Option<Box<dyn DeliveryBackend>> {
+    let mut backends = backends().into_iter();
+    let (_, factory) = match &config.delivery_backend {
+        Some(name) => backends
+            .find(|(backend, _)| *backend == name.as_str())
+            .unwrap_or_else(|| panic!("Unknown DELIVERY_BACKEND: {}", name)),
+        None => backends.next()?,
+    };
+    Some(factory(config))
+}
+
+/// The image and FQDN the chart is given, on top of the preview's own values.
+fn helm_values(release: &Release<'_>) -> JsonValue {
+    let mut values = release.source.values.clone().unwrap_or_else(|| serde_json::json!({}));
+    values["image"] = serde_json::json!(release.image);
+    values["fqdn"] = serde_json::json!(release.fqdn);
+    values
+}
+
+async fn create(client: &Client, api: &RawApi, manifest: &JsonValue) -> Result<(), Error> {
+    let data = serde_json::to_vec(manifest).expect("Failed to serialize delivery json");
+    match client.request::<Void>(api.create(&PostParams::default(), data)?).await {
+        Ok(_) => Ok(()),
+        Err(Error::Api(ae)) if ae.code == 409 => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+async fn delete(client: &Client, api: &RawApi, name: &str) -> Result<(), Error> {
+    match client.request::<Void>(api.delete(name, &DeleteParams::default())?).await {
+        Ok(_) => Ok(()),
+        Err(Error::Api(ae)) if ae.code == 404 => Ok(()),
+        Err(err) => Err(err),
+    }
+}
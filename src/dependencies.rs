@@ -0,0 +1,81 @@
+//! Previews that depend on other previews, such as a frontend that talks to
+//! a preview of its API.  A preview with `dependsOn` waits until everything
+//! it depends on is Ready before it's created, and is handed each
+//! dependency's URL in an environment variable (`API_URL` for a dependency
+//! named `api`, unless `env` says otherwise).  Deleting a dependency doesn't
+//! take its dependents down with it, but they're warned through an Event and
+//! the `DependenciesReady` condition.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// Phase of a preview waiting on its dependencies.
+pub const WAITING: &str = "WaitingForDependencies";
+
+/// Type of the condition that tracks the preview's dependencies.
+pub const CONDITION: &str = "DependenciesReady";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Dependency {
+    /// Another PreviewEnvironment in the same namespace.
+    pub name: String,
+    /// The environment variable its URL is passed in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+}
+
+impl Dependency {
+    pub fn env_name(&self) -> String {
+        match &self.env {
+            Some(env) => env.clone(),
+            None => format!("{}_URL", self.name.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_")),
+        }
+    }
+}
+
+pub fn is_waiting(pe: &KubePreviewEnvironment) -> bool {
+    pe.status.as_ref().and_then(|status| status.phase.as_deref()) == Some(WAITING)
+}
+
+fn is_ready(pe: &KubePreviewEnvironment) -> bool {
+    pe.status.as_ref().and_then(|status| status.phase.as_deref()) == Some("Ready")
+}
+
+pub fn depends_on(pe: &KubePreviewEnvironment, name: &str) -> bool {
+    pe.spec.depends_on.iter().any(|dependency| dependency.name == name)
+}
+
+/// The dependencies of `pe` that aren't Ready yet, or don't exist at all.
+pub fn unready(pe: &KubePreviewEnvironment, previews: &[KubePreviewEnvironment]) -> Vec<String> {
+    pe.spec
+        .depends_on
+        .iter()
+        .filter(|dependency| !previews.iter().any(|other| other.metadata.name == dependency.name && is_ready(other)))
+        .map(|dependency| dependency.name.clone())
+        .collect()
+}
+
+/// Pass each dependency's URL to the deployment's containers.
+pub fn inject(deployment: &mut JsonValue, pe: &KubePreviewEnvironment, previews: &[KubePreviewEnvironment]) {
+    let env: Vec<JsonValue> = pe
+        .spec
+        .depends_on
+        .iter()
+        .filter_map(|dependency| {
+            let other = previews.iter().find(|other| other.metadata.name == dependency.name)?;
+            Some(json!({ "name": dependency.env_name(), "value": format!("https://{}", other.spec.fqdn) }))
+        })
+        .collect();
+    if env.is_empty() {
+        return;
+    }
+    for container in deployment["spec"]["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+        match container["env"].as_array_mut() {
+            Some(existing) => existing.extend(env.iter().cloned()),
+            None => container["env"] = json!(env),
+        }
+    }
+}
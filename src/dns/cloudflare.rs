@@ -0,0 +1,105 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{DnsError, DnsProvider, DnsRecord};
+
+const API_BASE: &str = "https://api.cloudflare.com/client/v4";
+
+/// Manages records in a single Cloudflare zone using an API token with
+/// `Zone.DNS` edit permission.
+pub struct Cloudflare {
+    http: reqwest::Client,
+    token: String,
+    zone_id: String,
+}
+
+#[derive(Deserialize)]
+struct Response<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: String,
+}
+
+impl Cloudflare {
+    pub fn new(token: String, zone_id: String) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .build()
+            .map_err(|err| format!("failed to create the Cloudflare HTTP client: {}", err))?;
+        Ok(Cloudflare { http, token, zone_id })
+    }
+
+    fn records_url(&self) -> String {
+        format!("{}/zones/{}/dns_records", API_BASE, self.zone_id)
+    }
+
+    async fn find_records(&self, fqdn: &str) -> Result<Vec<Record>, DnsError> {
+        let response = self
+            .http
+            .get(&self.records_url())
+            .bearer_auth(&self.token)
+            .query(&[("name", fqdn)])
+            .send()
+            .await?;
+        Ok(check(response.json().await?)?.unwrap_or_default())
+    }
+}
+
+// Cloudflare reports failures in the body rather than the status code.
+fn check<T>(response: Response<T>) -> Result<Option<T>, DnsError> {
+    if response.success {
+        return Ok(response.result);
+    }
+    let errors: Vec<String> = response
+        .errors
+        .iter()
+        .map(|e| format!("{} ({})", e.message, e.code))
+        .collect();
+    Err(DnsError::Provider(errors.join(", ")))
+}
+
+#[async_trait]
+impl DnsProvider for Cloudflare {
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), DnsError> {
+        let body = json!({
+            "type": record.record_type.as_str(),
+            "name": record.fqdn,
+            "content": record.target,
+            "ttl": 1,
+            "proxied": false,
+        });
+
+        let request = match self.find_records(&record.fqdn).await?.first() {
+            Some(existing) => self.http.put(&format!("{}/{}", self.records_url(), existing.id)),
+            None => self.http.post(&self.records_url()),
+        };
+        let response = request.bearer_auth(&self.token).json(&body).send().await?;
+        check::<serde_json::Value>(response.json().await?)?;
+        Ok(())
+    }
+
+    async fn delete_record(&self, fqdn: &str) -> Result<(), DnsError> {
+        for record in self.find_records(fqdn).await? {
+            let response = self
+                .http
+                .delete(&format!("{}/{}", self.records_url(), record.id))
+                .bearer_auth(&self.token)
+                .send()
+                .await?;
+            check::<serde_json::Value>(response.json().await?)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Manages DNS records for preview FQDNs on clusters that don't run
+//! external-dns.  Each provider implements `DnsProvider`; the controller
+//! only ever talks to the trait.
+use async_trait::async_trait;
+use std::net::Ipv4Addr;
+use thiserror::Error;
+
+use crate::config::Config;
+
+#[cfg(feature = "cloudflare")]
+mod cloudflare;
+#[cfg(feature = "route53")]
+mod route53;
+
+#[cfg(feature = "cloudflare")]
+pub use cloudflare::Cloudflare;
+#[cfg(feature = "route53")]
+pub use route53::Route53;
+
+#[derive(Error, Debug)]
+pub enum DnsError {
+    #[error("DNS provider request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("DNS provider returned an error: {0}")]
+    Provider(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordType {
+    A,
+    Cname,
+}
+
+impl RecordType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RecordType::A => "A",
+            RecordType::Cname => "CNAME",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsRecord {
+    pub fqdn: String,
+    pub record_type: RecordType,
+    pub target: String,
+}
+
+impl DnsRecord {
+    /// An `A` record when the target is an IP address, otherwise a `CNAME`.
+    pub fn new(fqdn: &str, target: &str) -> Self {
+        let record_type = match target.parse::<Ipv4Addr>() {
+            Ok(_) => RecordType::A,
+            Err(_) => RecordType::Cname,
+        };
+        DnsRecord { fqdn: fqdn.to_string(), record_type, target: target.to_string() }
+    }
+}
+
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Creates the record, or points an existing record at the new target.
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), DnsError>;
+
+    /// Removes every record for `fqdn`.  Removing a record that doesn't
+    /// exist is not an error.
+    async fn delete_record(&self, fqdn: &str) -> Result<(), DnsError>;
+}
+
+// Builds a provider, or says what's wrong with its settings.
+type Factory = fn(&Config) -> Result<Box<dyn DnsProvider>, String>;
+
+/// The providers this controller was built with, by the name `DNS_PROVIDER`
+/// selects them with.  Each is behind a cargo feature of the same name.
+pub fn providers() -> Vec<(&'static str, Factory)> {
+    let mut providers: Vec<(&'static str, Factory)> = Vec::new();
+    #[cfg(feature = "cloudflare")]
+    providers.push(("cloudflare", |config| match (&config.cloudflare_api_token, &config.cloudflare_zone_id) {
+        (Some(token), Some(zone_id)) => Ok(Box::new(Cloudflare::new(token.clone(), zone_id.clone())?)),
+        _ => Err("CLOUDFLARE_API_TOKEN and CLOUDFLARE_ZONE_ID are required when DNS_PROVIDER is cloudflare".to_string()),
+    }));
+    #[cfg(feature = "route53")]
+    providers.push(("route53", |config| match &config.route53_hosted_zone_id {
+        Some(zone_id) => Ok(Box::new(Route53::new(zone_id.clone())?)),
+        None => Err("ROUTE53_HOSTED_ZONE_ID is required when DNS_PROVIDER is route53".to_string()),
+    }));
+    providers
+}
+
+/// Builds the provider selected by `DNS_PROVIDER`, if any.  `Config::load`
+/// does, and keeps it in `Loaded::dns`.
+pub fn from_config(config: &Config) -> Result<Option<Box<dyn DnsProvider>>, String> {
+    let name = match config.dns_provider.as_deref() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let providers = providers();
+    match providers.iter().find(|(provider, _)| *provider == name) {
+        Some((_, factory)) => factory(config).map(Some),
+        None => {
+            let names: Vec<&str> = providers.iter().map(|(name, _)| *name).collect();
+            Err(format!("unknown DNS_PROVIDER {:?}, this build supports: {}", name, crate::config::supported(&names)))
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use rusoto_core::{credential::AutoRefreshingProvider, HttpClient, Region};
+use rusoto_route53::{
+    Change, ChangeBatch, ChangeResourceRecordSetsRequest, ListResourceRecordSetsRequest, ResourceRecord,
+    ResourceRecordSet, Route53 as Route53Api, Route53Client,
+};
+use rusoto_sts::WebIdentityProvider;
+
+use super::{DnsError, DnsProvider, DnsRecord};
+
+const TTL: i64 = 60;
+
+/// Manages records in a single Route53 hosted zone.
+///
+/// On EKS the controller's service account can be bound to an IAM role
+/// (IRSA), in which case EKS mounts a web identity token we exchange for
+/// credentials.  Otherwise the usual `AWS_ACCESS_KEY_ID` and
+/// `AWS_SECRET_ACCESS_KEY` variables are used, typically populated from a
+/// Secret in the controller's Deployment.
+pub struct Route53 {
+    client: Route53Client,
+    hosted_zone_id: String,
+}
+
+impl Route53 {
+    pub fn new(hosted_zone_id: String) -> Result<Self, String> {
+        let region = Region::default();
+        let client = if std::env::var("AWS_WEB_IDENTITY_TOKEN_FILE").is_ok() {
+            let credentials = AutoRefreshingProvider::new(WebIdentityProvider::from_k8s_env())
+                .map_err(|err| format!("failed to set up IRSA credentials for Route53: {}", err))?;
+            let http = HttpClient::new().map_err(|err| format!("failed to create the Route53 HTTP client: {}", err))?;
+            Route53Client::new_with(http, credentials, region)
+        } else {
+            Route53Client::new(region)
+        };
+        Ok(Route53 { client, hosted_zone_id })
+    }
+
+    // Route53 hands names back fully qualified with a trailing dot.
+    async fn find_record_sets(&self, fqdn: &str) -> Result<Vec<ResourceRecordSet>, DnsError> {
+        let name = format!("{}.", fqdn.trim_end_matches('.'));
+        let request = ListResourceRecordSetsRequest {
+            hosted_zone_id: self.hosted_zone_id.clone(),
+            start_record_name: Some(name.clone()),
+            ..ListResourceRecordSetsRequest::default()
+        };
+        let response = self
+            .client
+            .list_resource_record_sets(request)
+            .await
+            .map_err(|err| DnsError::Provider(err.to_string()))?;
+
+        // The listing starts at our name but carries on through the rest of
+        // the zone, so keep only the sets that are actually ours.
+        Ok(response
+            .resource_record_sets
+            .into_iter()
+            .filter(|set| set.name == name && (set.type_ == "A" || set.type_ == "CNAME"))
+            .collect())
+    }
+
+    async fn change(&self, action: &str, record_sets: Vec<ResourceRecordSet>) -> Result<(), DnsError> {
+        if record_sets.is_empty() {
+            return Ok(());
+        }
+        let changes = record_sets
+            .into_iter()
+            .map(|resource_record_set| Change { action: action.to_string(), resource_record_set })
+            .collect();
+        let request = ChangeResourceRecordSetsRequest {
+            hosted_zone_id: self.hosted_zone_id.clone(),
+            change_batch: ChangeBatch {
+                changes,
+                comment: Some("Managed by the preview environment controller".to_string()),
+            },
+        };
+        self.client
+            .change_resource_record_sets(request)
+            .await
+            .map_err(|err| DnsError::Provider(err.to_string()))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Route53 {
+    async fn upsert_record(&self, record: &DnsRecord) -> Result<(), DnsError> {
+        let record_set = ResourceRecordSet {
+            name: record.fqdn.clone(),
+            type_: record.record_type.as_str().to_string(),
+            ttl: Some(TTL),
+            resource_records: Some(vec![ResourceRecord { value: record.target.clone() }]),
+            ..ResourceRecordSet::default()
+        };
+        self.change("UPSERT", vec![record_set]).await
+    }
+
+    async fn delete_record(&self, fqdn: &str) -> Result<(), DnsError> {
+        // A DELETE has to match the existing record set exactly, so send
+        // back what Route53 gave us.
+        let record_sets = self.find_record_sets(fqdn).await?;
+        self.change("DELETE", record_sets).await
+    }
+}
@@ -0,0 +1,216 @@
+//! Restricts where previews can connect to.  With `EGRESS_POLICY` set, each
+//! preview gets a policy that allows DNS, anything inside the cluster, and
+//! the approved external services listed in `EGRESS_ALLOWLIST`, such as the
+//! staging payment sandbox, and nothing else.
+//!
+//! Allowlist entries are host names or CIDRs, with an optional port:
+//! `sandbox.payments.example.com:443,10.20.0.0/16`.  A plain NetworkPolicy
+//! can only match addresses, so host names are resolved when the preview is
+//! created and the policy is only as current as that lookup.  Cilium's
+//! `toFQDNs` follows DNS as it changes and also accepts wildcards like
+//! `*.example.com`, so it's the better choice where it's available.
+use serde_json::json;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::labels::{name_value, NAME_LABEL};
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EgressPolicy {
+    /// Previews can connect anywhere.
+    Open,
+    NetworkPolicy,
+    Cilium,
+}
+
+impl FromStr for EgressPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "open" | "none" => Ok(EgressPolicy::Open),
+            "networkpolicy" => Ok(EgressPolicy::NetworkPolicy),
+            "cilium" => Ok(EgressPolicy::Cilium),
+            _ => Err(format!("unknown egress policy {:?}", value)),
+        }
+    }
+}
+
+/// An approved destination outside the cluster.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Destination {
+    fn is_cidr(&self) -> bool {
+        let address = self.host.split('/').next().unwrap_or_default();
+        address.parse::<IpAddr>().is_ok()
+    }
+
+    fn cidr(&self) -> String {
+        if self.host.contains('/') {
+            self.host.clone()
+        } else {
+            host_cidr(self.host.parse().expect("Destination is not an address"))
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (host, port) = match value.rfind(':') {
+            // IPv6 addresses have colons of their own
+            Some(colon) if !value[..colon].contains(':') => {
+                let port = value[colon + 1..].parse().map_err(|_| format!("invalid port in {:?}", value))?;
+                (&value[..colon], Some(port))
+            }
+            _ => (value, None),
+        };
+        if host.is_empty() {
+            return Err(format!("invalid egress destination {:?}", value));
+        }
+        Ok(Destination { host: host.to_string(), port })
+    }
+}
+
+fn host_cidr(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => format!("{}/32", address),
+        IpAddr::V6(address) => format!("{}/128", address),
+    }
+}
+
+// Where in-cluster DNS lives, which every preview needs to reach
+fn dns_peer() -> JsonValue {
+    json!({
+        "namespaceSelector": {},
+        "podSelector": { "matchLabels": { "k8s-app": "kube-dns" } },
+    })
+}
+
+/// The egress policy for a preview's pods, or `None` when egress is open.
+pub async fn policy_json(mode: EgressPolicy, name: &str, preview: &str, allowlist: &[Destination]) -> Option<JsonValue> {
+    match mode {
+        EgressPolicy::Open => None,
+        EgressPolicy::NetworkPolicy => Some(network_policy_json(name, preview, allowlist).await),
+        EgressPolicy::Cilium => Some(cilium_policy_json(name, preview, allowlist)),
+    }
+}
+
+async fn network_policy_json(name: &str, preview: &str, allowlist: &[Destination]) -> JsonValue {
+    let mut rules = vec![
+        json!({
+            "to": [dns_peer()],
+            "ports": [{ "protocol": "UDP", "port": 53 }, { "protocol": "TCP", "port": 53 }],
+        }),
+        // Pods and services anywhere in the cluster
+        json!({ "to": [{ "namespaceSelector": {} }] }),
+    ];
+
+    for destination in allowlist {
+        let cidrs = if destination.is_cidr() {
+            vec![destination.cidr()]
+        } else {
+            resolve(&destination.host).await
+        };
+        if cidrs.is_empty() {
+            continue;
+        }
+        let mut rule = json!({
+            "to": cidrs.iter().map(|cidr| json!({ "ipBlock": { "cidr": cidr } })).collect::<Vec<_>>(),
+        });
+        if let Some(port) = destination.port {
+            rule["ports"] = json!([{ "protocol": "TCP", "port": port }]);
+        }
+        rules.push(rule);
+    }
+
+    json!({
+        "apiVersion": "networking.k8s.io/v1",
+        "kind": "NetworkPolicy",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+            }
+        },
+        "spec": {
+            "podSelector": { "matchLabels": { NAME_LABEL: name_value(preview) } },
+            "policyTypes": ["Egress"],
+            "egress": rules,
+        }
+    })
+}
+
+// Every address `host` currently resolves to.  A host that doesn't resolve
+// is left out rather than failing the preview.
+async fn resolve(host: &str) -> Vec<String> {
+    match tokio::net::lookup_host((host, 0)).await {
+        Ok(addresses) => {
+            let mut cidrs: Vec<String> = addresses.map(|address| host_cidr(address.ip())).collect();
+            cidrs.sort();
+            cidrs.dedup();
+            cidrs
+        }
+        Err(err) => {
+            println!("Failed to resolve egress destination {}: {:?}", host, err);
+            vec![]
+        }
+    }
+}
+
+fn cilium_policy_json(name: &str, preview: &str, allowlist: &[Destination]) -> JsonValue {
+    let mut rules = vec![
+        // Cilium only learns names through its DNS proxy, so lookups have to
+        // go through it
+        json!({
+            "toEndpoints": [{
+                "matchLabels": {
+                    "k8s:io.kubernetes.pod.namespace": "kube-system",
+                    "k8s:k8s-app": "kube-dns",
+                }
+            }],
+            "toPorts": [{
+                "ports": [{ "port": "53", "protocol": "ANY" }],
+                "rules": { "dns": [{ "matchPattern": "*" }] },
+            }],
+        }),
+        json!({ "toEntities": ["cluster"] }),
+    ];
+
+    for destination in allowlist {
+        let mut rule = if destination.is_cidr() {
+            json!({ "toCIDR": [destination.cidr()] })
+        } else if destination.host.contains('*') {
+            json!({ "toFQDNs": [{ "matchPattern": destination.host }] })
+        } else {
+            json!({ "toFQDNs": [{ "matchName": destination.host }] })
+        };
+        if let Some(port) = destination.port {
+            rule["toPorts"] = json!([{ "ports": [{ "port": port.to_string(), "protocol": "TCP" }] }]);
+        }
+        rules.push(rule);
+    }
+
+    json!({
+        "apiVersion": "cilium.io/v2",
+        "kind": "CiliumNetworkPolicy",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+            }
+        },
+        "spec": {
+            "endpointSelector": { "matchLabels": { NAME_LABEL: name_value(preview) } },
+            "egress": rules,
+        }
+    })
+}
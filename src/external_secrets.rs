@@ -0,0 +1,73 @@
+//! Generates an External Secrets Operator `ExternalSecret` for each preview
+//! so it pulls credentials from the organisation's secret store.
+//!
+//! The template is the `spec` of an `ExternalSecret` in YAML, read from the
+//! file named by `EXTERNAL_SECRET_TEMPLATE`.  `{{name}}`, `{{namespace}}`
+//! and `{{fqdn}}` are replaced with the preview's values, e.g.:
+//!
+//! ```yaml
+//! refreshInterval: 1h
+//! secretStoreRef:
+//!   name: vault
+//!   kind: ClusterSecretStore
+//! dataFrom:
+//!   - extract:
+//!       key: previews/{{name}}
+//! ```
+use serde_json::json;
+
+type JsonValue = serde_json::value::Value;
+
+pub struct ExternalSecretTemplate {
+    raw: String,
+}
+
+impl ExternalSecretTemplate {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let raw =
+            std::fs::read_to_string(path).map_err(|err| format!("Failed to read ExternalSecret template {}: {}", path, err))?;
+        let template = ExternalSecretTemplate { raw };
+
+        // Render once up front so a broken template fails at startup rather
+        // than on the first preview.
+        template
+            .render("example", "example", "example.com", "example", "example")
+            .map_err(|err| format!("{} ({})", err, path))?;
+        Ok(template)
+    }
+
+    /// Renders the full `ExternalSecret` named `object_name`.  Whatever the
+    /// template says, the Secret it produces is always called `secret_name`
+    /// so the controller knows what to mount.  The preview's values can still
+    /// make YAML of it that doesn't parse.
+    pub fn render(
+        &self,
+        name: &str,
+        namespace: &str,
+        fqdn: &str,
+        object_name: &str,
+        secret_name: &str,
+    ) -> Result<JsonValue, String> {
+        let rendered = self
+            .raw
+            .replace("{{name}}", name)
+            .replace("{{namespace}}", namespace)
+            .replace("{{fqdn}}", fqdn);
+        let mut spec: JsonValue =
+            serde_yaml::from_str(&rendered).map_err(|err| format!("Invalid ExternalSecret template: {}", err))?;
+        spec["target"]["name"] = json!(secret_name);
+
+        Ok(json!({
+            "apiVersion": "external-secrets.io/v1beta1",
+            "kind": "ExternalSecret",
+            "metadata": {
+                "name": object_name,
+                "labels": {
+                    "preview": "true",
+                    "preview.platform9.com/name": name,
+                }
+            },
+            "spec": spec,
+        }))
+    }
+}
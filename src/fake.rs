@@ -0,0 +1,453 @@
+//! A stand-in for the API server, so the reconcile logic can be tested
+//! without a cluster.  `FakeApi` plugs into `Client` in place of the real
+//! connection and keeps objects in memory, doing roughly what the API
+//! server would with each request: creates, reads, lists, updates, merge
+//! patches and deletes, with `resourceVersion` conflicts and `generation`
+//! bumps on spec changes.  Every request is recorded for tests to make
+//! assertions about, and a canned response can be queued for any request
+//! to replay a failure the store wouldn't produce by itself.
+//!
+//! It's only as clever as the controller needs: list selectors only match
+//! labels with `=`, `!=` or existence, there's no admission or defaulting,
+//! nothing is garbage collected, and access reviews allow everything.
+//!
+//! `Harness` wires a `FakeApi` into a whole controller for tests of
+//! `handle` and `reconcile`.  It's only built for tests, or with the
+//! `test-support` feature, which the integration tests turn on.
+use async_trait::async_trait;
+use chrono::Utc;
+use http::{HeaderMap, Method, StatusCode};
+use kube::api::{PostParams, RawApi};
+use kube::Error;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::client::{Client, Transport};
+use crate::config::Config;
+use crate::reconcile;
+use crate::{ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// A request the controller made, and how it was answered.
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: Method,
+    pub path: String,
+    pub query: String,
+    pub headers: HeaderMap,
+    pub body: JsonValue,
+    pub status: StatusCode,
+}
+
+struct Canned {
+    method: Method,
+    path: String,
+    status: StatusCode,
+    body: JsonValue,
+}
+
+#[derive(Default)]
+pub struct FakeApi {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Keyed by the object's path, e.g. `/api/v1/namespaces/default/services/web`
+    objects: BTreeMap<String, JsonValue>,
+    canned: Vec<Canned>,
+    recorded: Vec<Recorded>,
+    version: u64,
+}
+
+// Where a request is aimed: a collection, an object in it, or one of the
+// object's subresources.
+struct Target {
+    collection: String,
+    name: Option<String>,
+    subresource: Option<String>,
+}
+
+impl FakeApi {
+    /// Answer the next request with the same method and path as `request`
+    /// with `status` and `body`, instead of from the store.
+    pub fn respond(&self, request: &http::Request<Vec<u8>>, status: u16, body: JsonValue) {
+        self.state.lock().unwrap().canned.push(Canned {
+            method: request.method().clone(),
+            path: request.uri().path().to_string(),
+            status: StatusCode::from_u16(status).expect("Invalid status code"),
+            body,
+        });
+    }
+
+    /// Every request made so far, oldest first.
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.lock().unwrap().recorded.clone()
+    }
+
+    /// Forget the requests made so far.
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().recorded.clear();
+    }
+
+    /// The names of the objects created of a resource, e.g. `deployments`,
+    /// in the order they were created.
+    pub fn created(&self, resource: &str) -> Vec<String> {
+        self.succeeded(Method::POST, resource, |request| request.body["metadata"]["name"].as_str().map(String::from))
+    }
+
+    /// The names of the objects deleted of a resource.
+    pub fn deleted(&self, resource: &str) -> Vec<String> {
+        self.succeeded(Method::DELETE, resource, |request| parse(&request.path).and_then(|target| target.name))
+    }
+
+    fn succeeded(&self, method: Method, resource: &str, name: impl Fn(&Recorded) -> Option<String>) -> Vec<String> {
+        let suffix = format!("/{}", resource);
+        self.requests()
+            .iter()
+            .filter(|request| request.method == method && request.status.is_success())
+            .filter(|request| parse(&request.path).map_or(false, |target| target.collection.ends_with(&suffix)))
+            .filter_map(name)
+            .collect()
+    }
+
+    /// Answers `request` from the store without recording it, for setting
+    /// up a test.
+    pub fn apply(&self, request: http::Request<Vec<u8>>) -> (StatusCode, JsonValue) {
+        let (parts, body) = request.into_parts();
+        let body = serde_json::from_slice(&body).unwrap_or(JsonValue::Null);
+        let query = parts.uri.query().unwrap_or_default();
+        self.state.lock().unwrap().serve(&parts.method, parts.uri.path(), query, &body)
+    }
+}
+
+#[async_trait]
+impl Transport for FakeApi {
+    async fn send(&self, request: http::Request<Vec<u8>>) -> Result<http::Response<String>, Error> {
+        let (parts, body) = request.into_parts();
+        let body: JsonValue = if body.is_empty() { JsonValue::Null } else { serde_json::from_slice(&body)? };
+        let path = parts.uri.path().to_string();
+        let query = parts.uri.query().unwrap_or_default().to_string();
+
+        let (status, reply) = {
+            let mut state = self.state.lock().unwrap();
+            let canned = state.canned.iter().position(|canned| canned.method == parts.method && canned.path == path);
+            let (status, reply) = match canned {
+                Some(index) => {
+                    let canned = state.canned.remove(index);
+                    (canned.status, canned.body)
+                }
+                None => state.serve(&parts.method, &path, &query, &body),
+            };
+            state.recorded.push(Recorded { method: parts.method, path, query, headers: parts.headers, body, status });
+            (status, reply)
+        };
+
+        let mut response = http::Response::new(reply.to_string());
+        *response.status_mut() = status;
+        Ok(response)
+    }
+}
+
+impl State {
+    fn serve(&mut self, method: &Method, path: &str, query: &str, body: &JsonValue) -> (StatusCode, JsonValue) {
+        let target = match parse(path) {
+            Some(target) => target,
+            None => return failure(StatusCode::NOT_FOUND, "NotFound", &format!("no such path {}", path)),
+        };
+        let key = target.name.as_ref().map(|name| format!("{}/{}", target.collection, name));
+        match (method, key, target.subresource.as_deref()) {
+            (&Method::GET, Some(key), None) => match self.objects.get(&key) {
+                Some(object) => (StatusCode::OK, object.clone()),
+                None => not_found(&key),
+            },
+            (&Method::GET, None, _) => {
+                let items = self.matching(&target.collection, query);
+                (StatusCode::OK, json!({ "metadata": { "resourceVersion": self.version.to_string() }, "items": items }))
+            }
+            (&Method::POST, None, _) if target.collection.ends_with("/selfsubjectaccessreviews") => {
+                let mut review = body.clone();
+                review["status"] = json!({ "allowed": true });
+                (StatusCode::CREATED, review)
+            }
+            (&Method::POST, None, _) => self.create(&target, body),
+            (&Method::PUT, Some(key), subresource) => self.replace(&key, body, subresource == Some("status")),
+            (&Method::PATCH, Some(key), _) => match self.objects.get(&key).cloned() {
+                Some(mut object) => {
+                    merge(&mut object, body);
+                    object["metadata"]["resourceVersion"] = JsonValue::Null;
+                    self.replace(&key, &object, false)
+                }
+                None => not_found(&key),
+            },
+            (&Method::DELETE, Some(key), None) => match self.objects.remove(&key) {
+                Some(object) => (StatusCode::OK, object),
+                None => not_found(&key),
+            },
+            (&Method::DELETE, None, _) => {
+                let items = self.matching(&target.collection, query);
+                for item in &items {
+                    let name = item["metadata"]["name"].as_str().unwrap_or_default();
+                    self.objects.remove(&format!("{}/{}", target.collection, name));
+                }
+                (StatusCode::OK, json!({ "items": items }))
+            }
+            _ => failure(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", &format!("{} {} isn't supported", method, path)),
+        }
+    }
+
+    fn create(&mut self, target: &Target, body: &JsonValue) -> (StatusCode, JsonValue) {
+        let mut object = body.clone();
+        let name = match (object["metadata"]["name"].as_str(), object["metadata"]["generateName"].as_str()) {
+            (Some(name), _) if !name.is_empty() => name.to_string(),
+            (_, Some(prefix)) => format!("{}{:05}", prefix, self.version + 1),
+            _ => return failure(StatusCode::UNPROCESSABLE_ENTITY, "Invalid", "metadata.name is required"),
+        };
+        let key = format!("{}/{}", target.collection, name);
+        if self.objects.contains_key(&key) {
+            return failure(StatusCode::CONFLICT, "AlreadyExists", &format!("{} already exists", key));
+        }
+        self.version += 1;
+        let metadata = &mut object["metadata"];
+        metadata["name"] = json!(name);
+        metadata["resourceVersion"] = json!(self.version.to_string());
+        metadata["uid"] = json!(format!("fake-uid-{}", self.version));
+        metadata["generation"] = json!(1);
+        metadata["creationTimestamp"] = json!(Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        if let Some(namespace) = namespace(&target.collection) {
+            metadata["namespace"] = json!(namespace);
+        }
+        self.objects.insert(key, object.clone());
+        (StatusCode::CREATED, object)
+    }
+
+    // Replace an object, or just its status.  Like the API server, a write
+    // carrying a `resourceVersion` that isn't the latest is refused.
+    fn replace(&mut self, key: &str, body: &JsonValue, status: bool) -> (StatusCode, JsonValue) {
+        let current = match self.objects.get(key) {
+            Some(current) => current.clone(),
+            None => return not_found(key),
+        };
+        let version = &body["metadata"]["resourceVersion"];
+        if !version.is_null() && version != &current["metadata"]["resourceVersion"] {
+            return failure(StatusCode::CONFLICT, "Conflict", &format!("{} has been modified", key));
+        }
+
+        let mut object = current.clone();
+        if status {
+            object["status"] = body["status"].clone();
+        } else {
+            object = body.clone();
+            object["metadata"]["uid"] = current["metadata"]["uid"].clone();
+            object["metadata"]["creationTimestamp"] = current["metadata"]["creationTimestamp"].clone();
+            object["status"] = current["status"].clone();
+            let generation = current["metadata"]["generation"].as_i64().unwrap_or(1);
+            let changed = body["spec"] != current["spec"];
+            object["metadata"]["generation"] = json!(if changed { generation + 1 } else { generation });
+        }
+        self.version += 1;
+        object["metadata"]["resourceVersion"] = json!(self.version.to_string());
+        self.objects.insert(key.to_string(), object.clone());
+        (StatusCode::OK, object)
+    }
+
+    // The objects in a collection that match the query's label selector.
+    fn matching(&self, collection: &str, query: &str) -> Vec<JsonValue> {
+        let selector = query
+            .split('&')
+            .find(|param| param.starts_with("labelSelector="))
+            .map(|param| decode(&param["labelSelector=".len()..]))
+            .unwrap_or_default();
+        self.objects
+            .iter()
+            .filter(|(key, _)| in_collection(collection, key))
+            .map(|(_, object)| object)
+            .filter(|object| selects(&selector, &object["metadata"]["labels"]))
+            .cloned()
+            .collect()
+    }
+}
+
+// Split a path like `/apis/apps/v1/namespaces/default/deployments/web/scale`
+// into the collection, the object's name and the subresource.
+fn parse(path: &str) -> Option<Target> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let prefix = match segments.first() {
+        Some(&"api") => 2,
+        Some(&"apis") => 3,
+        _ => return None,
+    };
+    let mut rest = segments.get(prefix..)?;
+    let mut collection = segments[..prefix].to_vec();
+    if rest.len() >= 3 && rest[0] == "namespaces" {
+        collection.extend_from_slice(&rest[..2]);
+        rest = &rest[2..];
+    }
+    collection.push(*rest.first()?);
+    Some(Target {
+        collection: format!("/{}", collection.join("/")),
+        name: rest.get(1).map(|name| name.to_string()),
+        subresource: rest.get(2).map(|subresource| subresource.to_string()),
+    })
+}
+
+// Whether the object stored at `key` is in `collection`.  Like the API
+// server, a namespaced resource without a namespace is every namespace's.
+fn in_collection(collection: &str, key: &str) -> bool {
+    let parent = match key.rfind('/') {
+        Some(slash) => &key[..slash],
+        None => return false,
+    };
+    if parent == collection {
+        return true;
+    }
+    let mut segments: Vec<&str> = parent.split('/').collect();
+    match segments.iter().position(|segment| *segment == "namespaces") {
+        Some(index) if index + 3 == segments.len() => {
+            segments.drain(index..index + 2);
+            segments.join("/") == collection
+        }
+        _ => false,
+    }
+}
+
+fn namespace(collection: &str) -> Option<String> {
+    let segments: Vec<&str> = collection.split('/').collect();
+    let index = segments.iter().position(|segment| *segment == "namespaces")?;
+    segments.get(index + 1).filter(|_| index + 2 < segments.len()).map(|namespace| namespace.to_string())
+}
+
+// RFC 7386: objects are merged key by key, nulls remove keys, and anything
+// else replaces what was there.
+fn merge(target: &mut JsonValue, patch: &JsonValue) {
+    match patch {
+        JsonValue::Object(fields) => {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in fields {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge(target.entry(key.clone()).or_insert(JsonValue::Null), value);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+// Whether labels match a selector like `app=web,tier!=db,preview`.
+fn selects(selector: &str, labels: &JsonValue) -> bool {
+    selector.split(',').map(str::trim).filter(|term| !term.is_empty()).all(|term| {
+        if let Some(index) = term.find("!=") {
+            labels[&term[..index]].as_str() != Some(&term[index + 2..])
+        } else if let Some(index) = term.find('=') {
+            let value = term[index + 1..].trim_start_matches('=');
+            labels[&term[..index]].as_str() == Some(value)
+        } else {
+            labels.get(term).is_some()
+        }
+    })
+}
+
+// Undo the percent-encoding of a query parameter.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = value.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn not_found(key: &str) -> (StatusCode, JsonValue) {
+    failure(StatusCode::NOT_FOUND, "NotFound", &format!("{} not found", key))
+}
+
+// A `Status` like the API server sends back when it refuses a request.
+fn failure(status: StatusCode, reason: &str, message: &str) -> (StatusCode, JsonValue) {
+    let body = json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": status.as_u16(),
+    });
+    (status, body)
+}
+
+/// A controller wired to a `FakeApi`, for testing how it handles events.
+pub struct Harness {
+    pub api: Arc<FakeApi>,
+    pub resources: Arc<ApiResources>,
+}
+
+impl Harness {
+    /// A controller configured by `flags`, as they'd be given on its
+    /// command line, with an empty fake API server.  Debouncing is off
+    /// unless the flags turn it on, so every event is acted on as it's
+    /// handled.
+    pub fn new(flags: &[&str]) -> Self {
+        let mut args = vec!["--debounce-seconds=0".to_string()];
+        args.extend(flags.iter().map(|flag| flag.to_string()));
+        let config = Config::load(args).expect("Invalid test config");
+        let api = Arc::new(FakeApi::default());
+        let client = Client::with_transport(api.clone(), 1e6, 1000);
+        Harness { resources: Arc::new(ApiResources::new(config, client)), api }
+    }
+
+    /// Stores a new preview with `spec`, as if someone had just created it,
+    /// and returns it as the watch would deliver it.
+    pub fn preview(&self, name: &str, spec: JsonValue) -> KubePreviewEnvironment {
+        let manifest = json!({
+            "apiVersion": "platform9.com/v1",
+            "kind": "PreviewEnvironment",
+            "metadata": { "name": name, "labels": { "preview": "true" } },
+            "spec": spec,
+        });
+        serde_json::from_value(self.insert(&self.resources.previews, manifest)).expect("Invalid PreviewEnvironment")
+    }
+
+    /// Stores an object, as if someone other than the controller had
+    /// created it, and returns it as stored.
+    pub fn insert(&self, api: &RawApi, object: JsonValue) -> JsonValue {
+        let data = serde_json::to_vec(&object).expect("Failed to serialize object json");
+        let (status, stored) = self.api.apply(api.create(&PostParams::default(), data).expect("Failed to build request"));
+        assert!(status.is_success(), "Failed to create {}: {}", object["metadata"]["name"], stored);
+        stored
+    }
+
+    /// The preview as it's stored now, e.g. to hand over in a Modified
+    /// event after the controller has changed its status.
+    pub fn current(&self, name: &str) -> KubePreviewEnvironment {
+        let stored = self.get(&self.resources.previews, name).unwrap_or_else(|| panic!("No preview named {}", name));
+        serde_json::from_value(stored).expect("Invalid PreviewEnvironment")
+    }
+
+    /// An object as it's stored now, if there is one.
+    pub fn get(&self, api: &RawApi, name: &str) -> Option<JsonValue> {
+        let (status, object) = self.api.apply(api.get(name).expect("Failed to build request"));
+        Some(object).filter(|_| status.is_success())
+    }
+
+    /// Handles an event the way the watch would.
+    pub async fn handle(&self, event: kube::api::WatchEvent<KubePreviewEnvironment>) {
+        reconcile::handle(&self.resources, event).await;
+    }
+}
@@ -0,0 +1,150 @@
+//! One preview per FQDN.  Two previews with the same FQDN would get two
+//! Ambassador Mappings for the same host, and traffic would go to either
+//! without anyone being told.  The controller keeps an index of which
+//! preview claims which FQDN, built from the previews it sees, and the first
+//! to claim an FQDN keeps it: a later preview asking for it fails with the
+//! `FqdnUnique` condition until the FQDN is free again.  The validating
+//! webhook checks the same index, so with it installed a conflicting
+//! preview is refused outright.
+//!
+//! A preview can leave `fqdn` out and have it made from a template instead:
+//! its own `fqdnTemplate`, its team's (see `profiles`) or the controller's
+//! `FQDN_TEMPLATE`, such as `{name}.{namespace}.previews.example.com`.  The
+//! template can use `{name}`, `{namespace}` and `{owner}`, and whatever
+//! they're replaced with is made fit for a DNS name: lowercased, with
+//! anything else turned into dashes, and shortened with a hash if it's too
+//! long.  The FQDN is worked out again whenever the preview is read rather
+//! than saved, so changing the controller's template moves every preview
+//! that uses it.
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::config::Config;
+use crate::profiles::Profile;
+use crate::{names, KubePreviewEnvironment};
+
+/// Type of the condition that reports an FQDN conflict.
+pub const CONDITION: &str = "FqdnUnique";
+
+#[derive(Default)]
+pub struct FqdnIndex {
+    claims: RwLock<BTreeMap<String, Claim>>,
+    next: RwLock<u64>,
+}
+
+// Claims are ordered by when they were first made, which is creation order
+// for previews that existed at startup since they're listed oldest first.
+struct Claim {
+    fqdn: String,
+    order: u64,
+}
+
+impl FqdnIndex {
+    /// Records the FQDN `pe` wants.  A preview keeps its place until it
+    /// asks for a different FQDN.
+    pub fn claim(&self, pe: &KubePreviewEnvironment) {
+        let fqdn = pe.spec.fqdn.to_lowercase();
+        let mut claims = self.claims.write().unwrap();
+        if claims.get(&pe.metadata.name).map(|claim| claim.fqdn == fqdn).unwrap_or(false) {
+            return;
+        }
+        let mut next = self.next.write().unwrap();
+        claims.insert(pe.metadata.name.clone(), Claim { fqdn, order: *next });
+        *next += 1;
+    }
+
+    /// Forgets the preview `name`, returning the other previews that wanted
+    /// its FQDN.
+    pub fn release(&self, name: &str) -> Vec<String> {
+        let mut claims = self.claims.write().unwrap();
+        let released = match claims.remove(name) {
+            Some(claim) => claim,
+            None => return vec![],
+        };
+        claims
+            .iter()
+            .filter(|(_, claim)| claim.fqdn == released.fqdn)
+            .map(|(other, _)| other.clone())
+            .collect()
+    }
+
+    /// The preview holding `fqdn`, unless that's `name` itself.  A preview
+    /// that hasn't claimed anything yet comes after every other.
+    pub fn holder(&self, fqdn: &str, name: &str) -> Option<String> {
+        let fqdn = fqdn.to_lowercase();
+        let claims = self.claims.read().unwrap();
+        let own = claims.get(name).map(|claim| claim.order).unwrap_or(u64::MAX);
+        claims
+            .iter()
+            .filter(|(other, claim)| other.as_str() != name && claim.fqdn == fqdn && claim.order < own)
+            .min_by_key(|(_, claim)| claim.order)
+            .map(|(other, _)| other.clone())
+    }
+}
+
+/// Checks a template only uses placeholders there are values for.
+pub fn check_template(template: &str) -> Result<(), String> {
+    expand(template, |_| Some(String::new())).map(|_| ())
+}
+
+/// The FQDN `pe` gets from its own template, or failing that its team's or
+/// the controller's.
+pub fn from_template(config: &Config, profile: &Profile, pe: &KubePreviewEnvironment) -> Result<String, String> {
+    let template = pe
+        .spec
+        .fqdn_template
+        .as_deref()
+        .or_else(|| profile.fqdn_template.as_deref())
+        .or_else(|| config.fqdn_template.as_deref())
+        .ok_or("fqdn is required when there's no FQDN template")?;
+    let namespace = pe.metadata.namespace.clone().unwrap_or_else(|| config.namespace.clone());
+    let fqdn = expand(template, |placeholder| match placeholder {
+        "name" => Some(pe.metadata.name.clone()),
+        "namespace" => Some(namespace.clone()),
+        "owner" => Some(pe.spec.owner.clone().unwrap_or_default()),
+        _ => None,
+    })?;
+    let fqdn = sanitize(&fqdn);
+    if fqdn.is_empty() {
+        return Err(format!("FQDN template {:?} gives an empty FQDN", template));
+    }
+    Ok(fqdn)
+}
+
+/// Gives `pe` the FQDN from its template if it didn't set one.  A template
+/// that doesn't work leaves it empty, and `from_template` says why.
+pub fn fill(config: &Config, profile: &Profile, pe: &mut KubePreviewEnvironment) {
+    if pe.spec.fqdn.is_empty() {
+        if let Ok(fqdn) = from_template(config, profile, pe) {
+            pe.spec.fqdn = fqdn;
+        }
+    }
+}
+
+// Replace each `{placeholder}` in `template`.  A value can't add labels of
+// its own, so any dots in it become dashes.
+fn expand(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| format!("unclosed {{ in FQDN template {:?}", template))? + start;
+        let placeholder = &rest[start + 1..end];
+        let value =
+            value(placeholder).ok_or_else(|| format!("unknown placeholder {{{}}} in FQDN template {:?}", placeholder, template))?;
+        expanded.push_str(&value.replace('.', "-"));
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Make each label of `fqdn` one a DNS name can have.
+fn sanitize(fqdn: &str) -> String {
+    let labels: Vec<String> = fqdn
+        .split('.')
+        .map(|label| names::slug(label, names::MAX_LABEL))
+        .filter(|label| !label.is_empty())
+        .collect();
+    labels.join(".")
+}
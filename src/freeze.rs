@@ -0,0 +1,140 @@
+//! Freeze windows, for times the cluster should be left alone, like a
+//! release weekend.  `FREEZE_WINDOWS` lists them as `<start>/<end>` in RFC
+//! 3339, optionally followed by what the freeze is for:
+//!
+//! ```yaml
+//! freeze_windows:
+//!   - 2026-11-27T00:00:00Z/2026-11-30T08:00:00Z Black Friday
+//! ```
+//!
+//! While one is on, new previews aren't created.  What happens to them
+//! instead is up to `FREEZE_MODE`:
+//!
+//! - `queue`, the default, holds them in the `Frozen` phase and creates
+//!   them once the freeze is over.
+//! - `block` fails them, and the admission webhook refuses them, so
+//!   they'll have to be created again afterwards.
+//!
+//! Either way their status says which freeze it is and when it ends.
+//! Previews that already exist carry on being updated, but none are deleted
+//! for outliving their TTL until the freeze is over.  Both settings are
+//! picked up from the config file without a restart.
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{record_event, set_status, ApiResources, KubePreviewEnvironment};
+
+/// Phase of a preview waiting for a freeze to end before it's created.
+pub const FROZEN: &str = "Frozen";
+
+/// A time during which no previews are created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FreezeWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub reason: Option<String>,
+}
+
+impl FreezeWindow {
+    /// What to tell the owners of previews it holds up.
+    pub fn message(&self) -> String {
+        let reason = self.reason.as_ref().map(|reason| format!(" ({})", reason)).unwrap_or_default();
+        format!("Creating previews is frozen until {}{}", self.end.to_rfc3339(), reason)
+    }
+}
+
+impl FromStr for FreezeWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (window, reason) = match value.find(char::is_whitespace) {
+            Some(i) => (&value[..i], Some(value[i..].trim().to_string())),
+            None => (value, None),
+        };
+        let mut times = window.splitn(2, '/');
+        let mut time = |which| match times.next().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(time)) => Ok(time.with_timezone(&Utc)),
+            _ => Err(format!("invalid freeze window {:?}, its {} isn't an RFC 3339 time", value, which)),
+        };
+        let (start, end) = (time("start")?, time("end")?);
+        if end <= start {
+            return Err(format!("invalid freeze window {:?}, it ends before it starts", value));
+        }
+        Ok(FreezeWindow { start, end, reason })
+    }
+}
+
+/// What becomes of previews created during a freeze.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FreezeMode {
+    /// Create them once it's over.
+    Queue,
+    /// Fail them.
+    Block,
+}
+
+impl FromStr for FreezeMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "queue" => Ok(FreezeMode::Queue),
+            "block" => Ok(FreezeMode::Block),
+            _ => Err(format!("unknown freeze mode {:?}, expected queue or block", value)),
+        }
+    }
+}
+
+/// The freeze on at `now`, if any.  Of overlapping ones, the one that
+/// lasts longest.
+pub fn at(windows: &[FreezeWindow], now: DateTime<Utc>) -> Option<&FreezeWindow> {
+    windows.iter().filter(|window| window.start <= now && now < window.end).max_by_key(|window| window.end)
+}
+
+/// The freeze on now, if any.
+pub fn current(resources: &ApiResources) -> Option<FreezeWindow> {
+    at(&resources.reloadable().freeze_windows, Utc::now()).cloned()
+}
+
+/// Whether the preview is waiting for a freeze to end to be created.
+pub fn is_held(pe: &KubePreviewEnvironment) -> bool {
+    pe.status.as_ref().and_then(|status| status.phase.as_deref()) == Some(FROZEN)
+}
+
+/// Don't create the preview during `window`: hold it until the window
+/// ends, or fail it, depending on `FREEZE_MODE`.
+pub async fn hold(resources: &ApiResources, pe: &KubePreviewEnvironment, window: &FreezeWindow) {
+    let name = &pe.metadata.name;
+    match resources.reloadable().freeze_mode {
+        FreezeMode::Queue => {
+            let message = format!("{}; this preview will be created then", window.message());
+            println!("Holding {}: {}", name, message);
+            if !is_held(pe) {
+                record_event(resources, pe, "Normal", "Frozen", &message).await;
+            }
+            set_status(resources, name, |status| {
+                status.phase = Some(FROZEN.to_string());
+                status.message = Some(message.clone());
+            })
+            .await;
+            // Checked at least hourly, so a window that's cut short or
+            // dropped doesn't hold it up for long
+            let remaining = (window.end - Utc::now()).to_std().unwrap_or_default();
+            resources.requeue.after(name, remaining.min(Duration::from_secs(3600)));
+        }
+        FreezeMode::Block => {
+            let message = format!("{}; create this preview again after then", window.message());
+            println!("Not creating {}: {}", name, message);
+            record_event(resources, pe, "Warning", "Frozen", &message).await;
+            let generation = pe.metadata.generation;
+            set_status(resources, name, |status| {
+                status.phase = Some("Failed".to_string());
+                status.message = Some(message.clone());
+                status.observed_generation = generation;
+            })
+            .await;
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! A Grafana dashboard for each preview, showing just that preview's pods.
+//! Dashboards are delivered as ConfigMaps for the Grafana sidecar to pick
+//! up, so the controller never needs Grafana credentials; the sidecar has
+//! to be watching the preview namespace for the label set in
+//! `GRAFANA_DASHBOARD_LABEL`.
+use serde_json::json;
+
+use crate::config::Config;
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+/// Grafana dashboard UIDs are limited to 40 characters.
+pub fn uid(preview: &str) -> String {
+    names::slug(&format!("preview-{}", preview), 40)
+}
+
+/// Where the preview's dashboard can be found, if we know where Grafana is.
+pub fn url(config: &Config, preview: &str) -> Option<String> {
+    let grafana_url = config.grafana_url.as_ref()?;
+    Some(format!("{}/d/{}/{}", grafana_url.trim_end_matches('/'), uid(preview), preview))
+}
+
+/// A Grafana Explore link that opens the logs of the preview's pods in Loki.
+pub fn explore_url(config: &Config, namespace: &str, deployment: &str) -> Option<String> {
+    let grafana_url = config.grafana_url.as_ref()?;
+    let left = json!({
+        "datasource": config.loki_datasource,
+        "queries": [{ "refId": "A", "expr": format!("{{namespace=\"{}\", pod=~\"{}-.*\"}}", namespace, deployment) }],
+        "range": { "from": "now-1h", "to": "now" },
+    });
+    let explore = format!("{}/explore", grafana_url.trim_end_matches('/'));
+    reqwest::Url::parse_with_params(&explore, &[("left", left.to_string())])
+        .ok()
+        .map(String::from)
+}
+
+fn panel(id: u32, title: &str, expr: String, unit: &str, x: u32, y: u32) -> JsonValue {
+    json!({
+        "id": id,
+        "title": title,
+        "type": "timeseries",
+        "datasource": "${datasource}",
+        "gridPos": { "x": x, "y": y, "w": 12, "h": 8 },
+        "fieldConfig": { "defaults": { "unit": unit } },
+        "targets": [{ "expr": expr, "legendFormat": "{{pod}}" }],
+    })
+}
+
+pub fn dashboard_json(preview: &str, namespace: &str, deployment: &str) -> JsonValue {
+    let pods = format!("namespace=\"{}\", pod=~\"{}-.*\"", namespace, deployment);
+    json!({
+        "uid": uid(preview),
+        "title": format!("Preview: {}", preview),
+        "tags": ["preview"],
+        "timezone": "browser",
+        "schemaVersion": 27,
+        "refresh": "30s",
+        "time": { "from": "now-1h", "to": "now" },
+        "templating": {
+            "list": [{ "name": "datasource", "type": "datasource", "query": "prometheus" }]
+        },
+        "panels": [
+            panel(1, "CPU", format!("sum by (pod) (rate(container_cpu_usage_seconds_total{{{}, container!=\"\"}}[5m]))", pods), "short", 0, 0),
+            panel(2, "Memory", format!("sum by (pod) (container_memory_working_set_bytes{{{}, container!=\"\"}})", pods), "bytes", 12, 0),
+            panel(3, "Restarts", format!("sum by (pod) (kube_pod_container_status_restarts_total{{{}}})", pods), "short", 0, 8),
+            panel(4, "Network received", format!("sum by (pod) (rate(container_network_receive_bytes_total{{{}}}[5m]))", pods), "Bps", 12, 8),
+        ],
+    })
+}
+
+pub fn config_map_json(name: &str, preview: &str, namespace: &str, deployment: &str, config: &Config) -> JsonValue {
+    let dashboard = dashboard_json(preview, namespace, deployment);
+    json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+                config.grafana_dashboard_label.as_str(): "1",
+            }
+        },
+        "data": {
+            format!("{}.json", name): dashboard.to_string(),
+        }
+    })
+}
@@ -0,0 +1,226 @@
+use futures::prelude::*;
+use kube::{
+    api::{Api, DeleteParams, Informer, ListParams, PatchParams, PostParams, RawApi, WatchEvent},
+    client::APIClient,
+    Error,
+};
+use serde_json::json;
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::batch::{self, Matrix};
+use crate::promotion::{FQDN_ANNOTATION, PROMOTE_ANNOTATION};
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+pub mod proto {
+    tonic::include_proto!("preview");
+}
+
+use proto::preview_environments_server::{PreviewEnvironments, PreviewEnvironmentsServer};
+use proto::watch_event::Type as EventType;
+
+pub struct PreviewService {
+    client: APIClient,
+    namespace: String,
+    previews: Api<KubePreviewEnvironment>,
+}
+
+impl PreviewService {
+    pub fn new(client: APIClient, namespace: &str) -> Self {
+        let previews = Api::customResource(client.clone(), "previewenvironments")
+            .group("platform9.com")
+            .within(namespace);
+        PreviewService { client, namespace: namespace.to_string(), previews }
+    }
+}
+
+pub async fn serve(addr: SocketAddr, client: APIClient, namespace: String) {
+    println!("gRPC API listening on {}", addr);
+    Server::builder()
+        .add_service(PreviewEnvironmentsServer::new(PreviewService::new(client, &namespace)))
+        .serve(addr)
+        .await
+        .expect("gRPC server failed");
+}
+
+// The PreviewEnvironment a create request asks for.
+fn manifest(req: &proto::CreateRequest) -> JsonValue {
+    let mut data = json!({
+        "apiVersion": "platform9.com/v1",
+        "kind": "PreviewEnvironment",
+        "metadata": {
+            "name": req.name,
+            "labels": {
+                "preview": "true",
+            }
+        },
+        "spec": {
+            "image": req.image,
+            "fqdn": req.fqdn,
+        }
+    });
+    // Leave the owner out when it isn't given so the admission webhook
+    // can fill it in.
+    if !req.owner.is_empty() {
+        data["spec"]["owner"] = json!(req.owner);
+    }
+    data
+}
+
+fn to_proto(pe: KubePreviewEnvironment) -> proto::Environment {
+    proto::Environment {
+        name: pe.metadata.name,
+        namespace: pe.metadata.namespace.unwrap_or_default(),
+        image: pe.spec.image,
+        fqdn: pe.spec.fqdn,
+        owner: pe.spec.owner.unwrap_or_default(),
+    }
+}
+
+// Map the Kubernetes API error codes onto their closest gRPC equivalents
+// so clients can tell "not found" apart from "the cluster is on fire".
+fn to_status(err: Error) -> Status {
+    match err {
+        Error::Api(ae) if ae.code == 404 => Status::not_found(ae.message),
+        Error::Api(ae) if ae.code == 409 => Status::already_exists(ae.message),
+        Error::Api(ae) if ae.code == 422 => Status::invalid_argument(ae.message),
+        err => Status::internal(format!("{:?}", err)),
+    }
+}
+
+#[tonic::async_trait]
+impl PreviewEnvironments for PreviewService {
+    #[tracing::instrument(skip(self, request))]
+    async fn create(&self, request: Request<proto::CreateRequest>) -> Result<Response<proto::Environment>, Status> {
+        let req = request.into_inner();
+        let data = serde_json::to_vec(&manifest(&req)).expect("Failed to serialize PreviewEnvironment json");
+        let pe = self.previews.create(&PostParams::default(), data).await.map_err(to_status)?;
+        Ok(Response::new(to_proto(pe)))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn create_batch(
+        &self,
+        request: Request<proto::CreateBatchRequest>,
+    ) -> Result<Response<proto::CreateBatchResponse>, Status> {
+        let req = request.into_inner();
+        let template = req.template.ok_or_else(|| Status::invalid_argument("template is required"))?;
+        let matrix: Matrix = req.matrix.into_iter().map(|axis| (axis.name, axis.values)).collect();
+        let manifests = batch::manifests(&manifest(&template), &matrix).map_err(Status::invalid_argument)?;
+        let concurrency = match req.concurrency {
+            0 => batch::DEFAULT_CONCURRENCY,
+            concurrency => concurrency as usize,
+        };
+        let results = batch::create(&self.previews, manifests, concurrency)
+            .await
+            .into_iter()
+            .map(|outcome| match outcome.result {
+                Ok(pe) => proto::BatchResult { name: outcome.name, environment: Some(to_proto(pe)), error: String::new() },
+                Err(err) => proto::BatchResult {
+                    name: outcome.name,
+                    environment: None,
+                    error: to_status(err).message().to_string(),
+                },
+            })
+            .collect();
+        Ok(Response::new(proto::CreateBatchResponse { results }))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn get(&self, request: Request<proto::GetRequest>) -> Result<Response<proto::Environment>, Status> {
+        let pe = self.previews.get(&request.into_inner().name).await.map_err(to_status)?;
+        Ok(Response::new(to_proto(pe)))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn list(&self, request: Request<proto::ListRequest>) -> Result<Response<proto::ListResponse>, Status> {
+        let owner = request.into_inner().owner;
+        let list = self.previews.list(&ListParams::default()).await.map_err(to_status)?;
+        let environments = list
+            .items
+            .into_iter()
+            .filter(|pe| owner.is_empty() || pe.spec.owner.as_deref() == Some(owner.as_str()))
+            .map(to_proto)
+            .collect();
+        Ok(Response::new(proto::ListResponse { environments }))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn delete(&self, request: Request<proto::DeleteRequest>) -> Result<Response<proto::DeleteResponse>, Status> {
+        self.previews
+            .delete(&request.into_inner().name, &DeleteParams::default())
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(proto::DeleteResponse {}))
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn promote(&self, request: Request<proto::PromoteRequest>) -> Result<Response<proto::Environment>, Status> {
+        let req = request.into_inner();
+        if req.namespace.is_empty() || req.fqdn.is_empty() {
+            return Err(Status::invalid_argument("namespace and fqdn are required"));
+        }
+        // The controller picks the annotations up and does the copying
+        let patch = json!({
+            "metadata": {
+                "annotations": {
+                    PROMOTE_ANNOTATION: req.namespace,
+                    FQDN_ANNOTATION: req.fqdn,
+                }
+            }
+        });
+        let patch = serde_json::to_vec(&patch).expect("Failed to serialize promote patch");
+        let pe = self.previews.patch(&req.name, &PatchParams::default(), patch).await.map_err(to_status)?;
+        Ok(Response::new(to_proto(pe)))
+    }
+
+    type WatchStream = mpsc::Receiver<Result<proto::WatchEvent, Status>>;
+
+    #[tracing::instrument(skip(self, _request))]
+    async fn watch(&self, _request: Request<proto::WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        // Every watcher gets its own Informer so a slow client can't hold
+        // up the controller's event loop.
+        let resource = RawApi::customResource("previewenvironments")
+            .group("platform9.com")
+            .within(&self.namespace);
+        let informer: Informer<KubePreviewEnvironment> = Informer::raw(self.client.clone(), resource).init().await.map_err(to_status)?;
+        let (mut tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            loop {
+                let mut events = match informer.poll().await {
+                    Ok(events) => events.boxed(),
+                    Err(err) => {
+                        let _ = tx.send(Err(to_status(err))).await;
+                        return;
+                    }
+                };
+                while let Some(event) = events.next().await {
+                    let message = match event {
+                        Ok(WatchEvent::Added(pe)) => Ok(watch_event(EventType::Added, pe)),
+                        Ok(WatchEvent::Modified(pe)) => Ok(watch_event(EventType::Modified, pe)),
+                        Ok(WatchEvent::Deleted(pe)) => Ok(watch_event(EventType::Deleted, pe)),
+                        Ok(WatchEvent::Error(err)) => Err(Status::internal(err.message)),
+                        Err(err) => Err(to_status(err)),
+                    };
+                    // The receiver is gone once the client disconnects.
+                    if tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+}
+
+fn watch_event(kind: EventType, pe: KubePreviewEnvironment) -> proto::WatchEvent {
+    proto::WatchEvent {
+        r#type: kind as i32,
+        environment: Some(to_proto(pe)),
+    }
+}
@@ -0,0 +1,117 @@
+//! Creating a preview's children as the person who created the preview, so
+//! the cluster's audit log says who they're for instead of only naming the
+//! controller's service account.  Turned on with `IMPERSONATE_CREATOR`.
+//!
+//! The admission webhook records who created each preview in annotations,
+//! and while a preview is reconciled the controller's creates carry
+//! `Impersonate-User` and `Impersonate-Group` headers for them.  Everything
+//! else -- reads, updates, deletes, Events and the preview's own status --
+//! is still done as the controller.  The webhook also refuses previews whose
+//! annotations don't match who's actually asking, so nobody can be framed
+//! for someone else's preview; that's why this needs `ADMISSION_ADDR`.
+//!
+//! The controller's service account needs the `impersonate` verb on `users`
+//! and `groups`, and the people it impersonates need to be allowed to
+//! create whatever their previews are made of.
+use http::Method;
+use serde_json::json;
+use std::future::Future;
+
+use crate::config::Config;
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// Who created the preview, as the API server told the webhook.
+pub const USER_ANNOTATION: &str = "preview.platform9.com/created-by";
+/// Their groups, comma separated.
+pub const GROUPS_ANNOTATION: &str = "preview.platform9.com/created-by-groups";
+
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+tokio::task_local! {
+    static CREATOR: Option<Identity>;
+}
+
+/// Who `pe` was created by, if the controller acts as them.
+pub fn creator(config: &Config, pe: &KubePreviewEnvironment) -> Option<Identity> {
+    if !config.impersonate_creator {
+        return None;
+    }
+    let user = pe.metadata.annotations.get(USER_ANNOTATION).filter(|user| !user.is_empty())?;
+    let groups = pe.metadata.annotations.get(GROUPS_ANNOTATION).map(String::as_str).unwrap_or_default();
+    Some(Identity {
+        user: user.clone(),
+        groups: groups.split(',').filter(|group| !group.is_empty()).map(String::from).collect(),
+    })
+}
+
+/// Runs `work` for `pe`, with any children it creates created as the
+/// preview's creator.
+pub async fn as_creator<F: Future>(config: &Config, pe: &KubePreviewEnvironment, work: F) -> F::Output {
+    CREATOR.scope(creator(config, pe), work).await
+}
+
+/// Headers to send with a request, given what it is and who it's for.
+pub fn headers(method: &Method, uri: &str) -> Vec<(&'static str, String)> {
+    if method != Method::POST || uri.contains("/events") {
+        return vec![];
+    }
+    let identity = match CREATOR.try_with(|creator| creator.clone()) {
+        Ok(Some(identity)) => identity,
+        _ => return vec![],
+    };
+    let mut headers = vec![("Impersonate-User", identity.user)];
+    headers.extend(identity.groups.into_iter().map(|group| ("Impersonate-Group", group)));
+    headers
+}
+
+/// JSON patch operations recording who's creating `object`.  `annotated`
+/// says whether earlier operations in the same patch already gave it
+/// annotations.
+pub fn creator_patch(object: &JsonValue, user_info: &JsonValue, annotated: bool) -> Vec<JsonValue> {
+    let values = [(USER_ANNOTATION, username(user_info)), (GROUPS_ANNOTATION, groups(user_info))];
+    if !annotated && !object["metadata"]["annotations"].is_object() {
+        let annotations: serde_json::Map<String, JsonValue> =
+            values.iter().map(|(key, value)| (key.to_string(), json!(value))).collect();
+        return vec![json!({ "op": "add", "path": "/metadata/annotations", "value": annotations })];
+    }
+    values
+        .iter()
+        .map(|(key, value)| {
+            let path = format!("/metadata/annotations/{}", key.replace('~', "~0").replace('/', "~1"));
+            json!({ "op": "add", "path": path, "value": value })
+        })
+        .collect()
+}
+
+/// Why the annotations on a preview being created or changed can't be
+/// trusted, if they can't.
+pub fn forged(request: &JsonValue) -> Option<String> {
+    let annotation = |object: &JsonValue, key: &str| object["metadata"]["annotations"][key].as_str().map(String::from);
+    let object = &request["object"];
+    let keys = [USER_ANNOTATION, GROUPS_ANNOTATION];
+    let forged = match request["operation"].as_str() {
+        Some("CREATE") => {
+            let actual = [username(&request["userInfo"]), groups(&request["userInfo"])];
+            keys.iter().zip(actual.iter()).any(|(key, actual)| annotation(object, key).map_or(false, |value| &value != actual))
+        }
+        Some("UPDATE") => keys.iter().any(|key| annotation(object, key) != annotation(&request["oldObject"], key)),
+        _ => false,
+    };
+    Some(format!("{} and {} are set by the controller and can't be changed", USER_ANNOTATION, GROUPS_ANNOTATION))
+        .filter(|_| forged)
+}
+
+fn username(user_info: &JsonValue) -> String {
+    user_info["username"].as_str().unwrap_or_default().to_string()
+}
+
+fn groups(user_info: &JsonValue) -> String {
+    let groups: Vec<&str> = user_info["groups"].as_array().into_iter().flatten().filter_map(JsonValue::as_str).collect();
+    groups.join(",")
+}
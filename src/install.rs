@@ -0,0 +1,210 @@
+//! Everything it takes to run the controller in-cluster, printed by
+//! `generate-install` as YAML ready for `kubectl apply -f -`: the
+//! PreviewEnvironment CRD, the RBAC from `rbac`, the config file in a
+//! ConfigMap, a Deployment running the controller, a Service for its ports
+//! and, when `ADMISSION_ADDR` is set, the admission webhook registrations.
+//!
+//! It takes the same flags and config file as the controller itself.  The
+//! flags are passed on to the Deployment as they are, and the config file is
+//! mounted from the ConfigMap, so changing it reloads the controller the
+//! same as it would anywhere else.  Settings from the environment aren't
+//! carried over, and neither are files the config names, like
+//! `NAMING_SCRIPT`.  It has two flags of its own: `--image`, the image to
+//! run, and `--cluster-wide`, which it passes on to `generate-rbac`.
+//!
+//! The CRD and webhook registrations come from the
+//! `preview-environment-crd.yaml` and `admission-webhook.yaml` next to the
+//! code, so they're always the ones this version of the controller was
+//! built with.  The webhooks need a serving certificate in the
+//! `preview-controller-tls` Secret, and its CA in their `caBundle`.
+use serde_json::json;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::config::{Config, ConfigError};
+use crate::rbac::{self, Scope};
+
+type JsonValue = serde_json::value::Value;
+
+/// The subcommand that prints the install manifests rather than running the
+/// controller.
+pub const COMMAND: &str = "generate-install";
+
+/// The Secret holding the admission webhooks' serving certificate.
+pub const TLS_SECRET: &str = "preview-controller-tls";
+
+// Where the config file is mounted in the controller's pod
+const CONFIG_DIR: &str = "/etc/preview-controller";
+
+const CRD: &str = include_str!("../preview-environment-crd.yaml");
+const WEBHOOKS: &str = include_str!("../admission-webhook.yaml");
+
+/// What to install, besides the controller's config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub image: String,
+    pub scope: Scope,
+    /// The flags to run the controller with, less `--config`.
+    pub args: Vec<String>,
+    /// The config file's name and contents.
+    pub config_file: Option<(String, String)>,
+}
+
+/// The YAML for `generate-install`'s arguments: the controller's own flags,
+/// plus `--image` and `--cluster-wide`.
+pub fn generate(args: &[String]) -> Result<String, ConfigError> {
+    let mut options = Options {
+        image: format!("{}:{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        scope: Scope::Namespaced,
+        args: vec![],
+        config_file: None,
+    };
+    let mut controller_args = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cluster-wide" => options.scope = Scope::ClusterWide,
+            "--image" => options.image = args.next().cloned().unwrap_or_default(),
+            _ if arg.starts_with("--image=") => options.image = arg["--image=".len()..].to_string(),
+            _ => controller_args.push(arg.clone()),
+        }
+    }
+    if options.image.is_empty() {
+        return Err(ConfigError::Flag("--image needs a value".to_string()));
+    }
+
+    let config = Config::load(controller_args.clone())?;
+    if let Some(path) = &config.config_file {
+        let contents = std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+        let name = Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or("config.yaml");
+        options.config_file = Some((name.to_string(), contents));
+    }
+    // The pod gets the config file from the ConfigMap rather than this path
+    let mut controller_args = controller_args.into_iter();
+    while let Some(arg) = controller_args.next() {
+        match arg.as_str() {
+            "--config" => {
+                controller_args.next();
+            }
+            _ if arg.starts_with("--config=") => {}
+            _ => options.args.push(arg),
+        }
+    }
+
+    let documents: Vec<String> = manifests(&config, &options)
+        .iter()
+        .map(|manifest| serde_yaml::to_string(manifest).expect("Failed to serialize install yaml"))
+        .collect();
+    Ok(documents.join("\n"))
+}
+
+/// Everything to apply, in the order it should be applied.
+pub fn manifests(config: &Config, options: &Options) -> Vec<JsonValue> {
+    let mut manifests = vec![serde_yaml::from_str(CRD).expect("Failed to parse the bundled CRD")];
+    manifests.extend(rbac::manifests(config, options.scope));
+    if let Some((name, contents)) = &options.config_file {
+        manifests.push(config_map_json(config, name, contents));
+    }
+    manifests.push(deployment_json(config, options));
+    manifests.push(service_json(config));
+    if let Some(admission_addr) = config.admission_addr {
+        manifests.extend(webhooks_json(config, admission_addr));
+    }
+    manifests
+}
+
+// Every port the controller listens on, by name
+fn ports(config: &Config) -> Vec<(&'static str, u16)> {
+    let mut ports = vec![("grpc", config.grpc_addr.port()), ("metrics", config.metrics_addr.port())];
+    let optional = [("admission", config.admission_addr), ("webhook", config.webhook_addr), ("events", config.events_addr)];
+    ports.extend(optional.iter().filter_map(|(name, addr)| addr.map(|addr| (*name, addr.port()))));
+    ports
+}
+
+fn config_map_json(config: &Config, name: &str, contents: &str) -> JsonValue {
+    json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": { "name": format!("{}-config", rbac::NAME), "namespace": config.namespace },
+        "data": { name: contents },
+    })
+}
+
+fn deployment_json(config: &Config, options: &Options) -> JsonValue {
+    let labels = json!({ "app": rbac::NAME });
+    let mut args = options.args.clone();
+    let mut volumes = vec![];
+    let mut mounts = vec![];
+    if let Some((name, _)) = &options.config_file {
+        args.push(format!("--config={}/{}", CONFIG_DIR, name));
+        volumes.push(json!({ "name": "config", "configMap": { "name": format!("{}-config", rbac::NAME) } }));
+        mounts.push(json!({ "name": "config", "mountPath": CONFIG_DIR, "readOnly": true }));
+    }
+    if config.admission_addr.is_some() {
+        volumes.push(json!({ "name": "tls", "secret": { "secretName": TLS_SECRET } }));
+        mounts.push(json!({ "name": "tls", "mountPath": config.admission_tls_cert, "subPath": "tls.crt", "readOnly": true }));
+        mounts.push(json!({ "name": "tls", "mountPath": config.admission_tls_key, "subPath": "tls.key", "readOnly": true }));
+    }
+    // The store only has to outlive the container, not the pod
+    if let Some(state_dir) = &config.state_dir {
+        volumes.push(json!({ "name": "state", "emptyDir": {} }));
+        mounts.push(json!({ "name": "state", "mountPath": state_dir }));
+    }
+    let ports: Vec<JsonValue> =
+        ports(config).iter().map(|(name, port)| json!({ "name": name, "containerPort": port })).collect();
+
+    json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": { "name": rbac::NAME, "namespace": config.namespace, "labels": labels },
+        "spec": {
+            "replicas": 1,
+            "selector": { "matchLabels": labels },
+            "template": {
+                "metadata": { "labels": labels },
+                "spec": {
+                    "serviceAccountName": rbac::NAME,
+                    "containers": [{
+                        "name": "controller",
+                        "image": options.image,
+                        "args": args,
+                        "ports": ports,
+                        "volumeMounts": mounts,
+                    }],
+                    "volumes": volumes,
+                },
+            },
+        },
+    })
+}
+
+fn service_json(config: &Config) -> JsonValue {
+    let ports: Vec<JsonValue> = ports(config)
+        .iter()
+        .map(|(name, port)| json!({ "name": name, "port": port, "targetPort": name }))
+        .collect();
+    json!({
+        "apiVersion": "v1",
+        "kind": "Service",
+        "metadata": { "name": rbac::NAME, "namespace": config.namespace },
+        "spec": { "selector": { "app": rbac::NAME }, "ports": ports },
+    })
+}
+
+// The registrations in `admission-webhook.yaml`, pointed at this
+// controller's Service
+fn webhooks_json(config: &Config, admission_addr: SocketAddr) -> Vec<JsonValue> {
+    WEBHOOKS
+        .split("\n---\n")
+        .map(|document| {
+            let mut registration: JsonValue = serde_yaml::from_str(document).expect("Failed to parse the bundled webhooks");
+            for webhook in registration["webhooks"].as_array_mut().into_iter().flatten() {
+                let service = &mut webhook["clientConfig"]["service"];
+                service["name"] = json!(rbac::NAME);
+                service["namespace"] = json!(config.namespace);
+                service["port"] = json!(admission_addr.port());
+            }
+            registration
+        })
+        .collect()
+}
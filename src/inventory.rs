@@ -0,0 +1,215 @@
+//! Every child of a preview, listed in its status as `resources`, so
+//! `kubectl get preview web -o yaml` shows what the controller made for it
+//! and how each piece is doing: when it was created, when the controller
+//! last wrote to it, whether it's ready, and what's wrong with it, if
+//! anything.
+//!
+//! The list is rebuilt from the cluster every
+//! `CHILD_STATUS_INTERVAL_SECONDS`, finding children by their
+//! `preview.platform9.com/name` label.  When the controller last wrote to a
+//! child comes from the `preview-controller` entries in its
+//! `managedFields`, and what's wrong with it from its own conditions, e.g. a
+//! Deployment past its progress deadline.  A child that couldn't be created
+//! is listed with the error from the last attempt until it has been.
+use chrono::Utc;
+use kube::{
+    api::{ListParams, RawApi},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::conflicts::FIELD_MANAGER;
+use crate::creation::Outcome;
+use crate::egress::EgressPolicy;
+use crate::labels::{name_value, NAME_LABEL};
+use crate::ApiResources;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChildStatus {
+    pub kind: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// When the controller last wrote to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    /// Whether it's ready, as far as anything of its kind can be.
+    #[serde(default)]
+    pub ready: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Note how creating each child went, ahead of the next refresh.
+pub fn record(children: &mut Vec<ChildStatus>, outcomes: &[Outcome]) {
+    let now = Utc::now().to_rfc3339();
+    for outcome in outcomes {
+        let index = match children.iter().position(|child| child.kind == outcome.kind && child.name == outcome.name) {
+            Some(index) => index,
+            None => {
+                children.push(ChildStatus { kind: outcome.kind.clone(), name: outcome.name.clone(), ..ChildStatus::default() });
+                children.len() - 1
+            }
+        };
+        let child = &mut children[index];
+        match &outcome.error {
+            Some(error) => child.last_error = Some(error.clone()),
+            None => {
+                child.created.get_or_insert_with(|| now.clone());
+                child.last_error = None;
+            }
+        }
+    }
+}
+
+/// What a child of `kind` says about itself.  Items in a list don't have
+/// a `kind` of their own.
+pub fn status_of(kind: &str, child: &JsonValue) -> ChildStatus {
+    ChildStatus {
+        kind: kind.to_string(),
+        name: child["metadata"]["name"].as_str().unwrap_or_default().to_string(),
+        created: child["metadata"]["creationTimestamp"].as_str().map(String::from),
+        updated: last_written(child),
+        ready: is_ready(kind, child),
+        last_error: problem(child),
+    }
+}
+
+// The last time the controller wrote to the child.  The times are all
+// RFC 3339 in UTC, so the latest sorts last.
+fn last_written(child: &JsonValue) -> Option<String> {
+    let entries = child["metadata"]["managedFields"].as_array()?;
+    let written = entries.iter().filter(|entry| entry["manager"] == FIELD_MANAGER);
+    written.filter_map(|entry| entry["time"].as_str()).max().map(String::from)
+}
+
+fn condition<'a>(child: &'a JsonValue, condition_type: &str) -> Option<&'a JsonValue> {
+    child["status"]["conditions"].as_array()?.iter().find(|condition| condition["type"] == condition_type)
+}
+
+fn is_ready(kind: &str, child: &JsonValue) -> bool {
+    let status = &child["status"];
+    match kind {
+        "Deployment" | "StatefulSet" => {
+            let wanted = child["spec"]["replicas"].as_i64().unwrap_or(1);
+            let current = status["observedGeneration"].as_i64() >= child["metadata"]["generation"].as_i64();
+            current && status["readyReplicas"].as_i64().unwrap_or(0) >= wanted
+        }
+        "Rollout" => status["phase"] == "Healthy",
+        "Job" => status["succeeded"].as_i64().unwrap_or(0) > 0,
+        "Host" => status["state"].as_str().map_or(true, |state| state == "Ready"),
+        // Anything else is ready once it exists, unless it says otherwise
+        _ => condition(child, "Ready").map_or(true, |ready| ready["status"] == "True"),
+    }
+}
+
+// Conditions that mean something's wrong, and the status that says so.
+const PROBLEMS: [(&str, &str); 4] =
+    [("ReplicaFailure", "True"), ("Failed", "True"), ("Progressing", "False"), ("Ready", "False")];
+
+fn problem(child: &JsonValue) -> Option<String> {
+    let (condition, _) = PROBLEMS
+        .iter()
+        .filter_map(|(condition_type, bad)| Some((condition(child, condition_type)?, bad)))
+        .find(|(condition, bad)| condition["status"] == **bad)?;
+    let message = condition["message"].as_str().or_else(|| condition["reason"].as_str()).unwrap_or_default();
+    Some(message.to_string())
+}
+
+/// The children found now, plus any that have yet to be created and what
+/// stopped them.
+pub fn merge(found: &[ChildStatus], recorded: &[ChildStatus]) -> Vec<ChildStatus> {
+    let mut merged = found.to_vec();
+    let missing = recorded
+        .iter()
+        .filter(|child| child.last_error.is_some())
+        .filter(|child| !found.iter().any(|found| found.kind == child.kind && found.name == child.name));
+    merged.extend(missing.cloned());
+    merged
+}
+
+fn kinds(resources: &ApiResources) -> Vec<(&'static str, &RawApi)> {
+    let egress = match resources.config.egress_policy {
+        EgressPolicy::Cilium => "CiliumNetworkPolicy",
+        _ => "NetworkPolicy",
+    };
+    vec![
+        ("ExternalSecret", &resources.external_secrets),
+        (egress, &resources.egress_policies),
+        ("Deployment", &resources.deployments),
+        ("StatefulSet", &resources.stateful_sets),
+        ("Rollout", &resources.rollouts),
+        ("Service", &resources.services),
+        ("Mapping", &resources.mappings),
+        ("TCPMapping", &resources.tcp_mappings),
+        ("Host", &resources.hosts),
+        ("ConfigMap", &resources.config_maps),
+        ("PodMonitor", &resources.pod_monitors),
+        ("CronJob", &resources.cron_jobs),
+        ("Job", &resources.jobs),
+        ("PeerAuthentication", &resources.peer_authentications),
+    ]
+}
+
+/// Every preview's children, by the preview's `NAME_LABEL` value.
+pub async fn find(resources: &ApiResources) -> BTreeMap<String, Vec<ChildStatus>> {
+    let lp = ListParams {
+        label_selector: Some("preview=true".to_string()),
+        ..ListParams::default()
+    };
+    let mut found: BTreeMap<String, Vec<ChildStatus>> = BTreeMap::new();
+    for (kind, api) in kinds(resources) {
+        let list: Result<JsonValue, Error> = match api.list(&lp) {
+            Ok(request) => resources.client.request(request).await,
+            Err(err) => Err(err),
+        };
+        let list = match list {
+            Ok(list) => list,
+            // Not every cluster has every CRD
+            Err(Error::Api(ae)) if ae.code == 404 => continue,
+            Err(err) => {
+                println!("Failed to list {}s for child status: {:?}", kind, err);
+                continue;
+            }
+        };
+        for item in list["items"].as_array().into_iter().flatten() {
+            if let Some(preview) = item["metadata"]["labels"][NAME_LABEL].as_str() {
+                found.entry(preview.to_string()).or_default().push(status_of(kind, item));
+            }
+        }
+    }
+    found
+}
+
+/// Periodically refreshes every preview's `resources`.  Status is only
+/// written when something has changed.
+pub async fn refresh(resources: Arc<ApiResources>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let found = find(&resources).await;
+        let previews = match crate::list_previews(&resources).await {
+            Ok(previews) => previews,
+            Err(err) => {
+                println!("Failed to list previews for child status: {:?}", err);
+                continue;
+            }
+        };
+        for pe in previews {
+            let recorded = pe.status.as_ref().map(|status| status.resources.clone()).unwrap_or_default();
+            let current = merge(found.get(&name_value(&pe.metadata.name)).map(Vec::as_slice).unwrap_or_default(), &recorded);
+            if current != recorded {
+                crate::set_status(&resources, &pe.metadata.name, |status| {
+                    status.resources = current.clone();
+                })
+                .await;
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! One-shot Jobs that run each time a preview is created -- seeding a
+//! database, warming a cache and so on.  Unlike a lifecycle hook they don't
+//! hold the preview up: it goes Ready straight away and the Jobs' progress
+//! is tracked in the `JobsComplete` condition.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::cloning;
+use crate::names;
+use crate::labels::{name_value, NAME_LABEL};
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// Type of the condition that tracks the preview's Jobs.
+pub const CONDITION: &str = "JobsComplete";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct JobSpec {
+    /// Unique within the preview; the Job is named `<preview>-job-<name>`.
+    pub name: String,
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// Retries before the Job is counted as failed.
+    #[serde(default = "default_backoff_limit")]
+    pub backoff_limit: u32,
+}
+
+fn default_backoff_limit() -> u32 {
+    3
+}
+
+/// Every Job the preview runs: those in its spec, plus copying the database
+/// when it's a clone.
+pub fn for_preview(pe: &KubePreviewEnvironment) -> Vec<JobSpec> {
+    pe.spec.jobs.iter().cloned().chain(cloning::database_job(pe)).collect()
+}
+
+pub fn name(preview: &str, job: &JobSpec) -> String {
+    names::child(preview, &format!("job-{}", job.name))
+}
+
+/// Pod spec for a task run alongside the preview.  It gets the same pull
+/// secrets, environment, priority, runtime, security contexts and DNS
+/// settings as the preview's own pods in `deployment`.
+pub fn task_pod_spec(name: &str, image: &str, command: &[String], restart_policy: &str, deployment: &JsonValue) -> JsonValue {
+    let pod_spec = &deployment["spec"]["template"]["spec"];
+    let mut container = json!({
+        "name": name,
+        "image": image,
+        "envFrom": pod_spec["containers"][0]["envFrom"].as_array().cloned().unwrap_or_default(),
+    });
+    if pod_spec["containers"][0]["securityContext"].is_object() {
+        container["securityContext"] = pod_spec["containers"][0]["securityContext"].clone();
+    }
+    if !command.is_empty() {
+        container["command"] = json!(command);
+    }
+    let mut task = json!({
+        "restartPolicy": restart_policy,
+        "imagePullSecrets": pod_spec["imagePullSecrets"].as_array().cloned().unwrap_or_default(),
+        "containers": [container],
+    });
+    let shared = ["priorityClassName", "runtimeClassName", "securityContext", "dnsConfig", "hostAliases"];
+    for field in &shared {
+        if !pod_spec[*field].is_null() {
+            task[*field] = pod_spec[*field].clone();
+        }
+    }
+    task
+}
+
+pub fn job_json(preview: &str, job: &JobSpec, deployment: &JsonValue) -> JsonValue {
+    json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {
+            "name": name(preview, job),
+            "labels": {
+                "preview": "true",
+                NAME_LABEL: name_value(preview),
+            }
+        },
+        "spec": {
+            "backoffLimit": job.backoff_limit,
+            "template": {
+                "spec": task_pod_spec(&job.name, &job.image, &job.command, "Never", deployment),
+            }
+        }
+    })
+}
@@ -0,0 +1,131 @@
+//! Jsonnet templates, for teams whose previews don't fit the built-in
+//! manifests and who'd rather not run a GitOps tool for Helm.  A preview
+//! with `jsonnet` names a ConfigMap holding the template, which the
+//! controller evaluates and applies as the preview's children in place of
+//! its own.  Needs the `jsonnet` cargo feature.
+//!
+//! The template is given `std.extVar("name")`, `"namespace"`, `"image"`
+//! and `"fqdn"` as strings, and the whole spec as `std.extVar("spec")`.  It
+//! evaluates to an array of manifests, or a single one.  Only kinds the
+//! controller already manages can be used, and everything is labelled like
+//! any other child so it can be found again to clean up.
+use kube::api::{ListParams, PatchParams, RawApi};
+use kube::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{create_child, delete_child, delete_params};
+use crate::labels::{name_value, NAME_LABEL};
+use crate::{ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonnetSpec {
+    /// ConfigMap in the preview's namespace holding the template.
+    pub config_map: String,
+    #[serde(default = "default_key")]
+    pub key: String,
+}
+
+fn default_key() -> String {
+    "main.jsonnet".to_string()
+}
+
+/// The template `jsonnet` points at.
+pub async fn template(resources: &ApiResources, jsonnet: &JsonnetSpec) -> Result<String, String> {
+    let request = resources.config_maps.get(&jsonnet.config_map).map_err(|err| err.to_string())?;
+    let config_map: JsonValue = resources.client.request(request).await.map_err(|err| match err {
+        Error::Api(ae) if ae.code == 404 => format!("ConfigMap {} not found", jsonnet.config_map),
+        err => err.to_string(),
+    })?;
+    config_map["data"][&jsonnet.key]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| format!("ConfigMap {} has no {}", jsonnet.config_map, jsonnet.key))
+}
+
+/// Evaluates `template` for the preview running `image`.
+#[cfg(feature = "jsonnet")]
+pub fn render(template: &str, pe: &KubePreviewEnvironment, namespace: &str, image: &str) -> Result<Vec<JsonValue>, String> {
+    let spec = serde_json::to_string(&pe.spec).expect("Failed to serialize PreviewEnvironment spec");
+    let mut vm = jsonnet::JsonnetVm::new();
+    vm.ext_var("name", &pe.metadata.name);
+    vm.ext_var("namespace", namespace);
+    vm.ext_var("image", image);
+    vm.ext_var("fqdn", &pe.spec.fqdn);
+    vm.ext_code("spec", &spec);
+    let output = vm.evaluate_snippet(&pe.metadata.name, template).map_err(|err| err.to_string())?;
+
+    match serde_json::from_str(&output).map_err(|err| err.to_string())? {
+        JsonValue::Array(manifests) => Ok(manifests),
+        manifest @ JsonValue::Object(_) => Ok(vec![manifest]),
+        _ => Err("the template must evaluate to a manifest or an array of them".to_string()),
+    }
+}
+
+#[cfg(not(feature = "jsonnet"))]
+pub fn render(_template: &str, _pe: &KubePreviewEnvironment, _namespace: &str, _image: &str) -> Result<Vec<JsonValue>, String> {
+    Err("the controller was built without the jsonnet feature".to_string())
+}
+
+/// The kinds a template can produce, and where they go.
+fn apis(resources: &ApiResources) -> [(&'static str, &RawApi); 9] {
+    [
+        ("Deployment", &resources.deployments),
+        ("StatefulSet", &resources.stateful_sets),
+        ("Service", &resources.services),
+        ("Mapping", &resources.mappings),
+        ("TCPMapping", &resources.tcp_mappings),
+        ("Host", &resources.hosts),
+        ("ConfigMap", &resources.config_maps),
+        ("Job", &resources.jobs),
+        ("CronJob", &resources.cron_jobs),
+    ]
+}
+
+pub fn api_for<'a>(resources: &'a ApiResources, kind: &str) -> Option<&'a RawApi> {
+    apis(resources).iter().find(|(name, _)| *name == kind).map(|(_, api)| *api)
+}
+
+/// Creates `manifest`, or brings it in line with the template if it's
+/// already there.
+pub async fn apply(resources: &ApiResources, api: &RawApi, manifest: &JsonValue) -> Result<(), Error> {
+    create_child(resources, api, manifest).await?;
+    let name = manifest["metadata"]["name"].as_str().unwrap_or_default();
+    let data = serde_json::to_vec(manifest).expect("Failed to serialize template json");
+    resources.client.request::<JsonValue>(api.patch(name, &PatchParams::default(), data)?).await?;
+    Ok(())
+}
+
+/// Deletes everything the template created for `pe`, whatever the template
+/// says now.
+pub async fn remove(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Vec<String> {
+    let lp = ListParams {
+        label_selector: Some(format!("{}={}", NAME_LABEL, name_value(&pe.metadata.name))),
+        ..ListParams::default()
+    };
+    let mut failures = Vec::new();
+    for (kind, api) in apis(resources).iter() {
+        let list: Result<JsonValue, Error> = match api.list(&lp) {
+            Ok(request) => resources.client.request(request).await,
+            Err(err) => Err(err),
+        };
+        let list = match list {
+            Ok(list) => list,
+            Err(err) => {
+                failures.push(format!("{}s: {}", kind, err));
+                continue;
+            }
+        };
+        let kind = kind.to_lowercase();
+        for item in list["items"].as_array().into_iter().flatten() {
+            let name = item["metadata"]["name"].as_str().unwrap_or_default();
+            let dp = delete_params(resources, pe, &kind);
+            if let Err(err) = delete_child(resources, &kind, api, name, &dp).await {
+                failures.push(format!("{} {}: {}", kind, name, err));
+            }
+        }
+    }
+    failures
+}
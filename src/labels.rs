@@ -0,0 +1,105 @@
+//! Labels every resource the controller generates carries, so dashboards,
+//! log queries and `kubectl get -l` can all slice by preview, owner and
+//! commit the same way.  Labels and annotations from the preview's spec are
+//! passed through to everything as well, along with any of the preview's
+//! own labels named in `PROPAGATE_LABELS`.
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::{names, quota};
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+pub const NAME_LABEL: &str = "preview.platform9.com/name";
+pub const OWNER_LABEL: &str = "preview.platform9.com/owner";
+pub const COMMIT_LABEL: &str = "preview.platform9.com/commit";
+
+/// Set on a preview to record the commit it's running when the image was
+/// built elsewhere.
+pub const COMMIT_ANNOTATION: &str = "preview.platform9.com/commit";
+
+/// Selectors match pods on this, so the spec can't override it.
+const APP_LABEL: &str = "app";
+
+/// Whether a label on the preview itself should be copied to its
+/// resources.  Entries ending in `*` match by prefix, so `example.com/*`
+/// covers every label under that domain.
+fn propagated(key: &str, config: &Config) -> bool {
+    config.propagate_labels.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    })
+}
+
+/// The standard labels for a preview's resources, on top of any from its
+/// spec and those propagated from its own metadata.
+pub fn for_preview(pe: &KubePreviewEnvironment, config: &Config) -> BTreeMap<String, String> {
+    let inherited = pe.metadata.labels.iter().filter(|(key, _)| propagated(key, config));
+    let mut labels: BTreeMap<String, String> = inherited
+        .chain(pe.spec.labels.iter())
+        .filter(|(key, _)| key.as_str() != APP_LABEL)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    labels.insert("preview".to_string(), "true".to_string());
+    labels.insert(NAME_LABEL.to_string(), name_value(&pe.metadata.name));
+    if let Some(owner) = quota::owner(pe) {
+        labels.insert(OWNER_LABEL.to_string(), label_value(owner));
+    }
+
+    let commit = match &pe.spec.build {
+        Some(build) => Some(build.git_ref.as_str()),
+        None => pe.metadata.annotations.get(COMMIT_ANNOTATION).map(String::as_str),
+    };
+    if let Some(commit) = commit {
+        labels.insert(COMMIT_LABEL.to_string(), label_value(commit));
+    }
+    labels
+}
+
+/// Add `labels` to a rendered manifest.  Workloads get them on their pod
+/// template too, so they show up on the pods and in their logs.
+pub fn stamp(manifest: &mut JsonValue, labels: &BTreeMap<String, String>) {
+    for (key, value) in labels {
+        manifest["metadata"]["labels"][key] = JsonValue::String(value.clone());
+        if manifest["spec"]["template"].is_object() {
+            manifest["spec"]["template"]["metadata"]["labels"][key] = JsonValue::String(value.clone());
+        }
+        // CronJobs keep their pod template a level further down
+        if manifest["spec"]["jobTemplate"]["spec"]["template"].is_object() {
+            manifest["spec"]["jobTemplate"]["spec"]["template"]["metadata"]["labels"][key] = JsonValue::String(value.clone());
+        }
+    }
+}
+
+/// Add `annotations` to a rendered manifest and, for workloads, its pod
+/// template.
+pub fn annotate(manifest: &mut JsonValue, annotations: &BTreeMap<String, String>) {
+    for (key, value) in annotations {
+        manifest["metadata"]["annotations"][key] = JsonValue::String(value.clone());
+        if manifest["spec"]["template"].is_object() {
+            manifest["spec"]["template"]["metadata"]["annotations"][key] = JsonValue::String(value.clone());
+        }
+        if manifest["spec"]["jobTemplate"]["spec"]["template"].is_object() {
+            manifest["spec"]["jobTemplate"]["spec"]["template"]["metadata"]["annotations"][key] = JsonValue::String(value.clone());
+        }
+    }
+}
+
+/// The `NAME_LABEL` value for the preview `name`, which is shortened like
+/// its children's names when it's too long for a label.
+pub fn name_value(name: &str) -> String {
+    names::slug(name, names::MAX_LABEL)
+}
+
+/// Squash an arbitrary string into something Kubernetes accepts as a label
+/// value: at most 63 alphanumerics, `-`, `_` or `.`, starting and ending
+/// with an alphanumeric.
+pub fn label_value(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '-' })
+        .take(63)
+        .collect();
+    value.trim_matches(|c: char| !c.is_ascii_alphanumeric()).to_string()
+}
@@ -0,0 +1,92 @@
+//! A Kubernetes controller that turns PreviewEnvironment resources into
+//! running preview environments.  The controller binary is a thin wrapper
+//! around what's here, so the reconcile logic can be tested on its own and
+//! shared with the plugin and anything else that needs it.
+pub mod admission;
+pub mod api;
+pub mod applied;
+pub mod audit;
+pub mod batch;
+pub mod bluegreen;
+pub mod build;
+pub mod bus;
+pub mod canary;
+pub mod capacity;
+pub mod client;
+pub mod cloning;
+pub mod conditions;
+pub mod config;
+pub mod conflicts;
+pub mod cost;
+pub mod creation;
+pub mod crd;
+pub mod credentials;
+pub mod cronjobs;
+pub mod debounce;
+pub mod delivery;
+pub mod dependencies;
+pub mod dns;
+pub mod egress;
+pub mod external_secrets;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fake;
+pub mod fqdn;
+pub mod freeze;
+pub mod grafana;
+pub mod grpc;
+pub mod impersonation;
+pub mod install;
+pub mod inventory;
+pub mod jobs;
+pub mod jsonnet;
+pub mod labels;
+pub mod mesh;
+pub mod metrics;
+pub mod monitoring;
+pub mod naming;
+pub mod names;
+pub mod notifier;
+pub mod pause;
+pub mod plugins;
+pub mod pod_security;
+pub mod policy;
+pub mod ports;
+pub mod preflight;
+pub mod profiles;
+pub mod promotion;
+pub mod quota;
+pub mod rbac;
+pub mod reconcile;
+pub mod registries;
+pub mod reload;
+pub mod requeue;
+pub mod resources;
+pub mod retry;
+pub mod rollouts;
+pub mod routing;
+pub mod scale;
+pub mod scan;
+pub mod scheduling;
+pub mod secrets;
+pub mod security;
+pub mod services;
+pub mod shared;
+pub mod snapshot;
+pub mod sse;
+pub mod statefulsets;
+pub mod store;
+pub mod sweeper;
+pub mod tcp;
+pub mod tekton;
+pub mod telemetry;
+pub mod usage;
+pub mod validation;
+pub mod vcr;
+#[cfg(feature = "vault")]
+pub mod vault;
+pub mod watch;
+pub mod webhook;
+
+pub use api::{apply_child, create_child, delete_child, list_previews, record_event, restart_deployment, set_status, ApiResources};
+pub use crd::{KubePreviewEnvironment, PreviewEnvironment, PreviewEnvironmentStatus};
+pub use resources::{deployment_manifest, service_manifest, to_json, Children};
@@ -1,204 +1,162 @@
-use futures::prelude::*;
 use kube::{
-    api::{Api, Informer, Object, RawApi, Void, WatchEvent, DeleteParams, PostParams},
+    api::{PostParams, RawApi, Void},
     client::APIClient,
-    config, Error,
+    Error,
 };
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use k8s_openapi::api::{
-    apps::v1::{DeploymentSpec, DeploymentStatus},
-    core::v1::{ServiceSpec, ServiceStatus},
+use std::sync::Arc;
+
+use rust_k8s_starter::client::{self, Client};
+use rust_k8s_starter::config::Config;
+use rust_k8s_starter::reconcile::reconcile_existing;
+use rust_k8s_starter::{
+    admission, audit, debounce, grpc, install, inventory, metrics, notifier, preflight, profiles, rbac, reload, requeue,
+    rollouts, scheduling, secrets, sse, sweeper, telemetry, usage, vcr, watch, webhook, ApiResources,
 };
-type Deployment = Object<DeploymentSpec, DeploymentStatus>;
-type Service = Object<ServiceSpec, ServiceStatus>;
-type JsonValue = serde_json::value::Value;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PreviewEnvironment {
-    pub image: String,
-    pub fqdn: String,
-}
-type KubePreviewEnvironment = Object<PreviewEnvironment, Void>;
-
-struct ApiResources {
-    client: APIClient,
-    deployments: Api<Deployment>,
-    services: Api<Service>,
-    mappings: RawApi,
-}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let namespace = "default";
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // Print the RBAC the controller needs, or everything needed to install
+    // it, instead of running it
+    let generated = match args.first().map(String::as_str) {
+        Some(rbac::COMMAND) => Some(rbac::generate(&args[1..])),
+        Some(install::COMMAND) => Some(install::generate(&args[1..])),
+        _ => None,
+    };
+    if let Some(generated) = generated {
+        let yaml = generated.unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        });
+        println!("{}", yaml);
+        return Ok(());
+    }
+
+    let config = Config::load(args.clone()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    });
+    let namespace = config.namespace.as_str();
+
+    // Spans are only exported when an OTLP collector has been configured.
+    let _telemetry = telemetry::init();
 
     // Attempt to load the kubeconfig.  If kubectl is working with
     // a default config this should work fine.  When deployed inside
     // a pod, it will use the in-cluster config from service account.
-    let kubeconfig = config::load_kube_config().await?;
-
-    let client = APIClient::new(kubeconfig);
-
-    // Describe the resource you want to watch.  Note the resource is
-    // the using the plural form defined in the CRD.
-    let resource = RawApi::customResource("previewenvironments")
-        .group("platform9.com")
-        .within(namespace);
-
-    let informer = Informer::raw(client.clone(), resource).init().await?;
-    let deployments = Api::v1Deployment(client.clone()).within(namespace);
-    let services = Api::v1Service(client.clone()).within(namespace);
-
-    let mappings = RawApi::customResource("mappings")
-        .group("getambassador.io")
-        .version("v2")
-        .within(namespace);
-    let resources = ApiResources { deployments, services, mappings, client };
-
-    println!("Controller initialized and waiting for changes...");
-
-    loop {
-        // There's a bit of advanced Rust features going on here due
-        // to lots of async streams, futures, and values typed as Option.
-        let mut previews_stream = informer.poll().await?.boxed();
-        while let Some(event) = previews_stream.next().await {
-            handle(&resources, event?).await;
-        }
+    // Timeouts, keepalives, proxy and CAs come from our own config.
+    let kubeconfig = client::kube_config(&config).await.unwrap_or_else(|err| {
+        eprintln!("Failed to load kubeconfig: {}", err);
+        std::process::exit(1);
+    });
+
+    let api_client = APIClient::new(kubeconfig.clone());
+
+    // Every call the controller makes goes through a rate limited client
+    // so a flood of events can't overwhelm the API server.
+    let mut transport = client::transport(kubeconfig, config.kube_request_timeout);
+    if let Some(path) = &config.vcr_record {
+        println!("Recording API requests to {}", path);
+        transport = Arc::new(vcr::Recorder::new(transport, path));
     }
-}
-
-fn json_for_deployment(name: &str) -> JsonValue {
-    json!({
-        "apiVersion": "apps/v1",
-        "kind": "Deployment",
-        "metadata": {
-            "name": name,
-            "labels": {
-                "preview": "true",
-            }
-        },
-        "spec": {
-            "replicas": 1,
-            "selector": {
-                "matchLabels": {
-                    "app": name,
-                }
-            },
-            "template": {
-                "metadata": {
-                    "labels": {
-                        "app": name,
-                    }
-                },
-                "spec": {
-                    "containers": [
-                        {
-                            "name": name,
-                            "image": "nginx"
-                        }
-                    ]
-                }
-            }
-        }
-    })
-}
-
-fn json_for_service(name: &str) -> JsonValue {
-    json!({
-        "apiVersion": "v1",
-        "kind": "Service",
-        "metadata": {
-            "name": name,
-            "labels": {
-                "preview": "true",
+    let client = Client::with_transport(transport, config.qps, config.burst);
+    let resources = Arc::new(ApiResources::new(config.clone(), client.clone()));
+
+    // Make sure the cluster has everything we need before going any further
+    if !config.skip_preflight {
+        let problems = preflight::run(&resources).await;
+        if !problems.is_empty() {
+            eprintln!("The controller can't run in this cluster yet:");
+            for problem in &problems {
+                eprintln!("  - {}", problem);
             }
-        },
-        "spec": {
-            "selector": {
-                "app": name,
-            },
-            "ports": [
-                {
-                    "protocol": "TCP",
-                    "port": 80,
-                }
-            ]
+            std::process::exit(1);
         }
-    })
-}
+    }
 
-fn json_for_mapping(name: &str, host: &str, service: &str) -> JsonValue {
-    json!({
-        "apiVersion": "getambassador.io/v2",
-        "kind": "Mapping",
-        "metadata": {
-            "name": name,
-            "labels": {
-                "preview": "true",
-            }
-        },
-        "spec": {
-            "host": host,
-            "service": service,
-            "prefix": "/",
-        }
-    })
-}
+    // Everything following what happens to previews subscribes before the
+    // first preview is handled, so nothing is missed
+    tokio::spawn(metrics::count(resources.bus.subscribe()));
+    if let Some(path) = &config.audit_log {
+        tokio::spawn(audit::run(path.clone(), resources.bus.subscribe()));
+    }
+    if let Some(url) = &config.notify_url {
+        tokio::spawn(notifier::run(url.clone(), config.notify_reasons.clone(), resources.bus.subscribe()));
+    }
+    if let Some(events_addr) = config.events_addr {
+        tokio::spawn(sse::serve(events_addr, resources.bus.clone()));
+    }
 
-async fn create_deployment(deployments: &Api<Deployment>, deploy_json: &JsonValue) {
-    let pp = PostParams::default();
-    let data = serde_json::to_vec(&deploy_json).expect("Failed to serialize Deployment json");
-    deployments.create(&pp, data).await.expect("Failed to create deployment");
-}
+    // Teams' profiles have to be read before any of their previews are handled
+    profiles::load(&resources).await;
 
-async fn create_service(services: &Api<Service>, service_json: &JsonValue) {
-    let pp = PostParams::default();
-    let data = serde_json::to_vec(&service_json).expect("Failed to serialize Service json");
-    services.create(&pp, data).await.expect("Failed to create service");
-}
+    // Watch the previews themselves
+    let informer = watch::start(&resources, &api_client).await?;
+    let pod_metrics = RawApi::customResource("pods")
+        .group("metrics.k8s.io")
+        .version("v1beta1")
+        .within(namespace);
 
-async fn create_mapping(resources: &ApiResources, mapping_json: &JsonValue) {
-    let pp = PostParams::default();
-    let data = serde_json::to_vec(&mapping_json).expect("Failed to serialize Mapping json");
-    let request = resources.mappings.create(&pp, data).expect("Failed to create mapping");
-    resources.client.request::<Void>(request).await.unwrap();
-}
+    // Set up the previews' PriorityClass if we've been asked to manage it,
+    // which waits until we're out of maintenance like everything else
+    let managed = config.priority_class_value.filter(|_| !config.maintenance);
+    if let (Some(priority_class), Some(value)) = (&config.priority_class, managed) {
+        let priority_classes = RawApi::customResource("priorityclasses").group("scheduling.k8s.io").version("v1");
+        let data = serde_json::to_vec(&scheduling::priority_class_json(priority_class, value))
+            .expect("Failed to serialize PriorityClass json");
+        match client.request::<Void>(priority_classes.create(&PostParams::default(), data)?).await {
+            Ok(_) => println!("Created PriorityClass {}", priority_class),
+            Err(Error::Api(ae)) if ae.code == 409 => {}
+            Err(err) => println!("Failed to create PriorityClass {}: {:?}", priority_class, err),
+        }
+    }
 
-async fn delete_mapping(resources: &ApiResources, name: &str) {
-    let request = resources.mappings.delete(name, &DeleteParams::default()).unwrap();
-    resources.client.request::<Void>(request).await.unwrap();
-}
+    // Keep copied secrets in step with the secrets they were copied from
+    if let Some(source_secrets) = resources.source_secrets.as_ref().filter(|_| !config.maintenance) {
+        let sync = secrets::sync(client.clone(), source_secrets.clone(), resources.secrets.clone(), config.secret_sync_interval);
+        tokio::spawn(sync);
+    }
 
-async fn handle(resources: &ApiResources, event: WatchEvent<KubePreviewEnvironment>) {
-    match event {
-        WatchEvent::Added(pe) => {
-            println!("Add PreviewEnvironment name: {}", pe.metadata.name);
+    // Keep Vault leases alive for as long as their previews exist
+    #[cfg(feature = "vault")]
+    if let Some(vault) = &resources.vault {
+        let secrets = resources.secrets.clone();
+        let renew = rust_k8s_starter::vault::renew_leases(vault.clone(), client.clone(), secrets, config.vault_renew_interval);
+        tokio::spawn(renew);
+    }
 
-            let deploy_name = format!("{}-deployment", pe.metadata.name);
-            let service_name = format!("{}-service", pe.metadata.name);
-            let mapping_name = format!("{}-mapping", pe.metadata.name);
-            let host = format!("{}.volgenic.com", pe.metadata.name);
+    // Serve the gRPC control API and metrics alongside the controller loop.
+    tokio::spawn(grpc::serve(config.grpc_addr, api_client.clone(), namespace.to_string()));
+    tokio::spawn(metrics::serve(config.metrics_addr));
+    tokio::spawn(usage::refresh(resources.clone(), pod_metrics, config.usage_interval));
+    tokio::spawn(inventory::refresh(resources.clone(), config.child_status_interval));
+    tokio::spawn(profiles::refresh(resources.clone(), config.profile_refresh_interval));
+    tokio::spawn(rollouts::refresh(resources.clone(), config.rollout_status_interval));
+    tokio::spawn(sweeper::run(resources.clone(), config.sweep_interval));
+    tokio::spawn(requeue::run(resources.clone()));
+    tokio::spawn(debounce::run(resources.clone()));
+    tokio::spawn(reload::run(resources.clone(), args));
+    if let Some(admission_addr) = config.admission_addr {
+        let naming = config.loaded.naming_script.clone();
+        let (cert, key) = (config.admission_tls_cert.clone(), config.admission_tls_key.clone());
+        let serve = admission::serve(admission_addr, cert, key, naming, resources.clone());
+        tokio::spawn(serve);
+    }
+    if let Some(webhook_addr) = config.webhook_addr {
+        tokio::spawn(webhook::serve(webhook_addr, resources.clone()));
+    }
 
-            // Create a deployment
-            let test_deploy = json_for_deployment(deploy_name.as_str());
-            create_deployment(&resources.deployments, &test_deploy).await;
+    // The watch only reports what changes from here on, so catch up on
+    // anything created or changed while the controller was down first
+    if resources.config.maintenance {
+        println!("Maintenance mode: watching previews but not changing anything");
+    } else {
+        reconcile_existing(&resources).await;
+    }
 
-            // Create a service
-            let test_service = json_for_service(service_name.as_str());
-            create_service(&resources.services, &test_service).await;
+    println!("Controller initialized and waiting for changes...");
 
-            // Create a mapping
-            let test_mapping = json_for_mapping(mapping_name.as_str(), host.as_str(), service_name.as_str());
-            create_mapping(&resources, &test_mapping).await;
-        }
-        WatchEvent::Deleted(pe) => {
-            println!("Deleted PreviewEnvironment name: {}", pe.metadata.name);
-            resources.services.delete(format!("{}-service", pe.metadata.name).as_str(), &DeleteParams::default()).await.unwrap();
-            resources.deployments.delete(format!("{}-deployment", pe.metadata.name).as_str(), &DeleteParams::default()).await.unwrap();
-            delete_mapping(&resources, "test-mapping").await;
-        },
-
-        WatchEvent::Modified(pe) => println!("Modified PreviewEnvironment name: {}", pe.metadata.name),
-        WatchEvent::Error(err) => println!("{:?}", err),
-    }
+    watch::run(resources, api_client, informer).await;
+    Ok(())
 }
@@ -0,0 +1,74 @@
+//! Service mesh participation.  The controller can put every preview in a
+//! Linkerd or Istio mesh (`MESH`), and a preview can override that with
+//! `spec.mesh`.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::str::FromStr;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mesh {
+    None,
+    Linkerd,
+    Istio,
+}
+
+impl FromStr for Mesh {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(Mesh::None),
+            "linkerd" => Ok(Mesh::Linkerd),
+            "istio" => Ok(Mesh::Istio),
+            _ => Err(format!("unknown mesh {:?}", value)),
+        }
+    }
+}
+
+/// Ask the mesh to inject its sidecar into the deployment's pods.
+pub fn inject(deployment: &mut JsonValue, mesh: Mesh) {
+    let template = &mut deployment["spec"]["template"]["metadata"];
+    match mesh {
+        Mesh::None => {}
+        Mesh::Linkerd => template["annotations"]["linkerd.io/inject"] = json!("enabled"),
+        Mesh::Istio => template["labels"]["sidecar.istio.io/inject"] = json!("true"),
+    }
+}
+
+/// Adjust the Mapping for the mesh.  Linkerd routes on the `l5d-dst-override`
+/// header, which Ambassador only adds when asked.
+pub fn route(mapping: &mut JsonValue, mesh: Mesh) {
+    if mesh == Mesh::Linkerd {
+        mapping["spec"]["add_linkerd_headers"] = json!(true);
+    }
+}
+
+/// Istio clusters commonly enforce strict mTLS, which would shut out
+/// Ambassador when it isn't in the mesh itself.  Accepting plain text as
+/// well keeps the preview reachable.
+pub fn peer_authentication_json(name: &str, preview: &str, deployment: &str) -> JsonValue {
+    json!({
+        "apiVersion": "security.istio.io/v1beta1",
+        "kind": "PeerAuthentication",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "spec": {
+            "selector": {
+                "matchLabels": {
+                    "app": deployment,
+                }
+            },
+            "mtls": {
+                "mode": "PERMISSIVE",
+            }
+        }
+    })
+}
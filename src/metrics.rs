@@ -0,0 +1,85 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_counter, register_gauge, register_histogram, register_int_counter, register_int_counter_vec, Counter, Encoder, Gauge,
+    Histogram, IntCounter, IntCounterVec, TextEncoder,
+};
+use std::net::SocketAddr;
+use tokio::sync::broadcast::Receiver;
+use warp::Filter;
+
+use crate::bus::{self, Event};
+
+lazy_static! {
+    pub static ref THROTTLED_REQUESTS: IntCounter = register_int_counter!(
+        "preview_controller_throttled_requests_total",
+        "API requests delayed by the client-side rate limiter"
+    )
+    .unwrap();
+    pub static ref THROTTLED_SECONDS: Counter = register_counter!(
+        "preview_controller_throttled_seconds_total",
+        "Time spent waiting on the client-side rate limiter"
+    )
+    .unwrap();
+    pub static ref RETRIED_REQUESTS: IntCounter = register_int_counter!(
+        "preview_controller_retried_requests_total",
+        "API requests retried after a 429 or 503 response"
+    )
+    .unwrap();
+    pub static ref DEBOUNCED_EVENTS: IntCounter = register_int_counter!(
+        "preview_controller_debounced_events_total",
+        "Preview changes superseded by a later change before they were reconciled"
+    )
+    .unwrap();
+    pub static ref WATCH_DISCONNECTS: IntCounter = register_int_counter!(
+        "preview_controller_watch_disconnects_total",
+        "Times the watch on previews failed with an error"
+    )
+    .unwrap();
+    pub static ref WATCH_RESTARTS: IntCounter = register_int_counter!(
+        "preview_controller_watch_restarts_total",
+        "Times the watch on previews was started again after failing or stalling"
+    )
+    .unwrap();
+    pub static ref WATCH_POLL_SECONDS: Histogram = register_histogram!(
+        "preview_controller_watch_poll_seconds",
+        "Time taken for the API server to answer each poll of the watch on previews"
+    )
+    .unwrap();
+    pub static ref WATCH_EVENT_LAG_SECONDS: Histogram = register_histogram!(
+        "preview_controller_watch_event_lag_seconds",
+        "Time each preview event waited between its poll returning and being handled"
+    )
+    .unwrap();
+    pub static ref WATCH_LAST_SEEN: Gauge = register_gauge!(
+        "preview_controller_watch_last_seen_timestamp_seconds",
+        "When the watch on previews last delivered an event or finished a poll"
+    )
+    .unwrap();
+    pub static ref PREVIEW_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "preview_controller_preview_events_total",
+        "Things that happened to previews, by type and reason",
+        &["type", "reason"]
+    )
+    .unwrap();
+}
+
+/// Counts everything published on the bus in `PREVIEW_EVENTS`.
+pub async fn count(mut events: Receiver<Event>) {
+    while let Some(event) = bus::next(&mut events, "metrics").await {
+        PREVIEW_EVENTS.with_label_values(&[&event.event_type, &event.reason]).inc();
+    }
+}
+
+/// Serves everything in the default registry at `/metrics` for Prometheus.
+pub async fn serve(addr: SocketAddr) {
+    let route = warp::path("metrics").map(|| {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+        String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+    });
+
+    println!("Metrics listening on {}", addr);
+    warp::serve(route).run(addr).await;
+}
@@ -0,0 +1,66 @@
+//! Prometheus Operator scraping for previews that expose metrics.  We
+//! generate a PodMonitor rather than a ServiceMonitor so the metrics port
+//! doesn't have to be published on the preview's Service.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSpec {
+    /// Container port the metrics are served on.
+    pub port: u16,
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Scrape interval, e.g. `30s`.  Prometheus' default when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+}
+
+fn default_path() -> String {
+    "/metrics".to_string()
+}
+
+const PORT_NAME: &str = "metrics";
+
+/// Name the metrics port on the deployment's container so the PodMonitor
+/// can refer to it.
+pub fn expose_port(deployment: &mut JsonValue, metrics: &MetricsSpec) {
+    let container = &mut deployment["spec"]["template"]["spec"]["containers"][0];
+    let port = json!({ "name": PORT_NAME, "containerPort": metrics.port, "protocol": "TCP" });
+    match container["ports"].as_array_mut() {
+        Some(ports) => ports.push(port),
+        None => container["ports"] = json!([port]),
+    }
+}
+
+pub fn pod_monitor_json(name: &str, preview: &str, deployment: &str, metrics: &MetricsSpec) -> JsonValue {
+    let mut endpoint = json!({
+        "port": PORT_NAME,
+        "path": metrics.path,
+    });
+    if let Some(interval) = &metrics.interval {
+        endpoint["interval"] = json!(interval);
+    }
+
+    json!({
+        "apiVersion": "monitoring.coreos.com/v1",
+        "kind": "PodMonitor",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "spec": {
+            "selector": {
+                "matchLabels": {
+                    "app": deployment,
+                }
+            },
+            "podMetricsEndpoints": [endpoint],
+        }
+    })
+}
@@ -0,0 +1,107 @@
+//! Names fit for Kubernetes.  Child resource names, label values and the
+//! labels of a host name are all capped at 63 characters, and most of them
+//! only take lowercase letters, digits and dashes, while previews are often
+//! named after long branches.  Everything the controller names after a
+//! preview goes through here so a long name is shortened the same way
+//! everywhere.
+//!
+//! A name that's too long is cut short and ends with a hash of the whole
+//! name instead, so two long names that start the same way still come out
+//! different, and the same name always comes out the same.
+
+/// Longest a DNS label, and so most names and label values, can be.
+pub const MAX_LABEL: usize = 63;
+
+// Room for a dash and eight hex digits of hash.
+const HASH_LEN: usize = 9;
+
+/// `name` lowercased, with anything other than letters and digits squashed
+/// into single dashes, and no longer than `max`.
+pub fn slug(name: &str, max: usize) -> String {
+    let mut slug = String::new();
+    for c in name.to_lowercase().chars() {
+        let c = if c.is_ascii_alphanumeric() { c } else { '-' };
+        if !(c == '-' && (slug.is_empty() || slug.ends_with('-'))) {
+            slug.push(c);
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.len() <= max {
+        return slug.to_string();
+    }
+
+    let hash = format!("{:08x}", fnv1a(name));
+    let kept = slug[..max.saturating_sub(HASH_LEN)].trim_end_matches('-');
+    match kept {
+        "" => hash[..max.min(hash.len())].to_string(),
+        kept => format!("{}-{}", kept, hash),
+    }
+}
+
+/// The name of the preview's child that ends in `suffix`, e.g.
+/// `child("my-branch", "service")` is `my-branch-service`.  The suffix is
+/// kept whole unless it's very long itself, so children of the same preview
+/// stay told apart.
+pub fn child(preview: &str, suffix: &str) -> String {
+    let suffix = slug(suffix, MAX_LABEL / 2);
+    format!("{}-{}", slug(preview, MAX_LABEL - suffix.len() - 1), suffix)
+}
+
+// FNV-1a, which is simple enough to be sure it won't change between
+// releases, unlike std's hasher.
+fn fnv1a(value: &str) -> u32 {
+    value.bytes().fold(0x811c_9dc5, |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_lowercased_and_squashed_into_dashes() {
+        let cases = vec![
+            ("web", "web"),
+            ("Feature/My_Branch", "feature-my-branch"),
+            ("--fix--the  thing--", "fix-the-thing"),
+            ("release/1.2.3", "release-1-2-3"),
+            ("", ""),
+        ];
+
+        for (name, expected) in cases {
+            assert_eq!(slug(name, MAX_LABEL), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn long_names_are_cut_short_and_end_in_a_hash() {
+        let name = format!("feature/{}", "very-long-branch-name-".repeat(5));
+        let other = format!("{}-again", name);
+
+        let short = slug(&name, MAX_LABEL);
+        assert_eq!(short.len(), MAX_LABEL);
+        assert!(short.starts_with("feature-very-long-branch-name-"));
+        assert!(short.ends_with(&format!("-{:08x}", fnv1a(&name))));
+        assert_eq!(short, slug(&name, MAX_LABEL));
+        assert_ne!(short, slug(&other, MAX_LABEL));
+        // Too short for any of the name, so just the hash
+        assert_eq!(slug(&name, 5), format!("{:08x}", fnv1a(&name))[..5]);
+    }
+
+    #[test]
+    fn the_hash_doesnt_change_between_releases() {
+        assert_eq!(fnv1a(""), 0x811c_9dc5);
+        assert_eq!(fnv1a("a"), 0xe40c_292c);
+        assert_eq!(fnv1a("foobar"), 0xbf9c_f968);
+    }
+
+    #[test]
+    fn children_keep_their_suffix() {
+        assert_eq!(child("my-branch", "service"), "my-branch-service");
+
+        let long = child(&"x".repeat(100), "service");
+        assert_eq!(long.len(), MAX_LABEL);
+        assert!(long.ends_with("-service"));
+        assert_ne!(long, child(&"x".repeat(100), "ingress"));
+        assert!(child("web", &"s".repeat(100)).len() <= MAX_LABEL);
+    }
+}
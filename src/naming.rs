@@ -0,0 +1,96 @@
+//! Bespoke naming conventions.  Organizations that name things their own
+//! way can have a small Rhai script, usually mounted from a ConfigMap and
+//! named by `NAMING_SCRIPT`, decide a new preview's FQDN, extra labels and
+//! what its child resources are called.
+//!
+//! The script runs in the admission webhook when a preview is created, so
+//! what it decides is written into the preview itself and never changes
+//! afterwards, even if the script does.  It sees the preview's `name`,
+//! `namespace`, `labels` and `spec`, and returns a map with any of:
+//!
+//! - `fqdn`, used when the preview doesn't set one.
+//! - `labels`, a map added to `spec.labels` for every child to carry.
+//! - `prefix`, which child names start with in place of the preview's name,
+//!   so `prefix-deployment` rather than `name-deployment`.
+//!
+//! ```rhai
+//! let team = if "team" in labels { labels.team } else { "shared" };
+//! #{ fqdn: name + "." + team + ".previews.example.com", labels: #{ team: team } }
+//! ```
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde_json::json;
+
+type JsonValue = serde_json::value::Value;
+
+/// Where the child name prefix the script chose is kept.
+pub const PREFIX_ANNOTATION: &str = "preview.platform9.com/child-prefix";
+
+// Enough for any naming convention, but not for a runaway loop
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub struct NamingScript {
+    engine: Engine,
+    ast: AST,
+}
+
+impl NamingScript {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("Failed to read naming script {}: {}", path, err))?;
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(&source).map_err(|err| format!("Failed to compile naming script {}: {}", path, err))?;
+        Ok(NamingScript { engine, ast })
+    }
+
+    /// JSON patch operations applying what the script decides for the
+    /// preview `object` being created.
+    pub fn patch(&self, object: &JsonValue) -> Result<Vec<JsonValue>, String> {
+        let metadata = &object["metadata"];
+        let mut scope = Scope::new();
+        scope.push("name", metadata["name"].as_str().unwrap_or_default().to_string());
+        scope.push("namespace", metadata["namespace"].as_str().unwrap_or_default().to_string());
+        scope.push("labels", self.map(&metadata["labels"])?);
+        scope.push("spec", self.map(&object["spec"])?);
+
+        let result: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &self.ast).map_err(|err| err.to_string())?;
+        let result: Map = result.try_cast().ok_or("the naming script must return a map")?;
+
+        let mut patch = Vec::new();
+        if let Some(fqdn) = string(&result, "fqdn")? {
+            if object["spec"]["fqdn"].as_str().map(str::is_empty).unwrap_or(true) {
+                patch.push(json!({ "op": "add", "path": "/spec/fqdn", "value": fqdn }));
+            }
+        }
+        if let Some(labels) = result.get("labels") {
+            let labels: Map = labels.clone().try_cast().ok_or("labels must be a map")?;
+            let mut merged = object["spec"]["labels"].as_object().cloned().unwrap_or_default();
+            for (key, value) in labels {
+                let value: String = value.try_cast().ok_or_else(|| format!("label {} must be a string", key))?;
+                merged.insert(key.to_string(), json!(value));
+            }
+            patch.push(json!({ "op": "add", "path": "/spec/labels", "value": merged }));
+        }
+        if let Some(prefix) = string(&result, "prefix")? {
+            let mut annotations = metadata["annotations"].as_object().cloned().unwrap_or_default();
+            annotations.insert(PREFIX_ANNOTATION.to_string(), json!(prefix));
+            patch.push(json!({ "op": "add", "path": "/metadata/annotations", "value": annotations }));
+        }
+        Ok(patch)
+    }
+
+    // Hand JSON to the script as a Rhai object map.
+    fn map(&self, value: &JsonValue) -> Result<Map, String> {
+        if value.is_null() {
+            return Ok(Map::new());
+        }
+        self.engine.parse_json(&value.to_string(), true).map_err(|err| err.to_string())
+    }
+}
+
+fn string(result: &Map, key: &str) -> Result<Option<String>, String> {
+    match result.get(key) {
+        Some(value) if value.is::<()>() => Ok(None),
+        Some(value) => value.clone().try_cast().map(Some).ok_or_else(|| format!("{} must be a string", key)),
+        None => Ok(None),
+    }
+}
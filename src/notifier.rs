@@ -0,0 +1,46 @@
+//! Notifications about previews, posted as JSON to `NOTIFY_URL`.  The body
+//! has a `text` summary, which is all a Slack or Mattermost incoming webhook
+//! needs, alongside the event itself for anything that wants more.
+//!
+//! Every Warning is sent, and any other reasons listed in `NOTIFY_REASONS`,
+//! e.g. `Added,Deleted,Promoted`.  Events are sent one at a time in the
+//! order they happened, off the event bus, so a slow or failing endpoint
+//! only delays notifications.  A notification that can't be sent is logged
+//! and dropped.
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+
+use crate::bus::{self, Event};
+
+// How long the endpoint gets to answer each notification.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `event` is one to notify about.
+pub fn wanted(event: &Event, reasons: &[String]) -> bool {
+    event.event_type == "Warning" || reasons.contains(&event.reason)
+}
+
+/// What's posted for `event`.
+pub fn body(event: &Event) -> serde_json::Value {
+    let text = match event.message.as_str() {
+        "" => format!("{} {}: {}", event.event_type, event.preview, event.reason),
+        message => format!("{} {}: {} {}", event.event_type, event.preview, event.reason, message),
+    };
+    json!({ "text": text, "event": event })
+}
+
+/// Posts the wanted events published on the bus to `url` until the bus goes
+/// away.
+pub async fn run(url: String, reasons: Vec<String>, mut events: Receiver<Event>) {
+    let http = reqwest::Client::builder().timeout(TIMEOUT).build().expect("Failed to build notifier HTTP client");
+    while let Some(event) = bus::next(&mut events, "notifier").await {
+        if !wanted(&event, &reasons) {
+            continue;
+        }
+        let result = http.post(&url).json(&body(&event)).send().await.and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            println!("Failed to notify about {} for {}: {}", event.reason, event.preview, err);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Pausing a preview so it can be tuned by hand.  While a preview has the
+//! `preview.platform9.com/paused: "true"` annotation (or after
+//! `kubectl preview pause`) the controller leaves its resources alone, so
+//! changes made directly to them aren't reverted.  It can still be deleted.
+//! Anything that changed in the spec meanwhile is rolled out when the
+//! annotation is removed.
+use crate::{set_status, ApiResources, KubePreviewEnvironment};
+
+pub const PAUSED_ANNOTATION: &str = "preview.platform9.com/paused";
+
+/// Phase of a preview that was paused before it was ever created.
+pub const PAUSED: &str = "Paused";
+
+pub fn is_paused(pe: &KubePreviewEnvironment) -> bool {
+    pe.metadata.annotations.get(PAUSED_ANNOTATION).map(String::as_str) == Some("true")
+}
+
+/// Whether the preview is waiting to be created now it's no longer paused.
+pub fn is_held(pe: &KubePreviewEnvironment) -> bool {
+    pe.status.as_ref().and_then(|status| status.phase.as_deref()) == Some(PAUSED)
+}
+
+/// Put off creating the preview until it's unpaused.
+pub async fn hold(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    println!("{} is paused, not creating it", pe.metadata.name);
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some(PAUSED.to_string());
+        status.message = Some("Paused; remove the preview.platform9.com/paused annotation to create it".to_string());
+    })
+    .await;
+}
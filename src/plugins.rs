@@ -0,0 +1,120 @@
+//! WebAssembly plugins that change the manifests the controller renders, for
+//! platform teams that need something the controller doesn't do -- an
+//! extra sidecar, an org-wide annotation, a naming rule -- without building
+//! their own controller.  Plugins are listed in `PLUGINS` and need the
+//! `wasm` cargo feature.
+//!
+//! A plugin is a module exporting its `memory` and two functions:
+//!
+//! - `alloc(len: i32) -> i32` returns space for `len` bytes of input.
+//! - `mutate(ptr: i32, len: i32) -> i64` is handed a JSON object with the
+//!   preview's `manifests` and the `preview` itself, and returns where to
+//!   find its output in memory, as `ptr << 32 | len`.  The output is the
+//!   JSON array of manifests.
+//!
+//! Plugins run in the order they're listed, each seeing what the one before
+//! it returned, on the preview's own manifests before anything else is
+//! derived from them.  They can change manifests as they like but not add
+//! or remove them, since the controller still decides what to create.  Each
+//! call gets a fresh instance, so nothing carries over between previews.
+#[cfg(feature = "wasm")]
+use wasmtime::{Engine, Instance, Module, Store};
+
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Default)]
+pub struct Plugins {
+    #[cfg(feature = "wasm")]
+    engine: Engine,
+    #[cfg(feature = "wasm")]
+    modules: Vec<(String, Module)>,
+}
+
+impl Plugins {
+    /// Compiles every plugin up front so a broken one fails at startup
+    /// rather than on the first preview.
+    #[cfg(feature = "wasm")]
+    pub fn load(paths: &[String]) -> Result<Self, String> {
+        let engine = Engine::default();
+        let modules = paths
+            .iter()
+            .map(|path| {
+                let module = Module::from_file(&engine, path).map_err(|err| format!("Failed to load plugin {}: {}", path, err))?;
+                Ok((path.clone(), module))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Plugins { engine, modules })
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn load(paths: &[String]) -> Result<Self, String> {
+        if !paths.is_empty() {
+            return Err("PLUGINS is set but the controller was built without the wasm feature".to_string());
+        }
+        Ok(Plugins::default())
+    }
+
+    /// Runs every plugin over `manifests`, replacing them with the result.
+    /// Nothing is changed unless every plugin succeeds.
+    #[cfg(feature = "wasm")]
+    pub fn mutate(&self, manifests: &mut [&mut JsonValue], pe: &KubePreviewEnvironment) -> Result<(), String> {
+        if self.modules.is_empty() {
+            return Ok(());
+        }
+        let mut current: Vec<JsonValue> = manifests.iter().map(|manifest| (**manifest).clone()).collect();
+        for (path, module) in &self.modules {
+            let mutated = self.call(module, &current, pe).map_err(|err| format!("plugin {}: {}", path, err))?;
+            if mutated.len() != current.len() {
+                let message = format!("returned {} manifests for {}, but plugins can't add or remove them", mutated.len(), current.len());
+                return Err(format!("plugin {}: {}", path, message));
+            }
+            current = mutated;
+        }
+        for (manifest, mutated) in manifests.iter_mut().zip(current) {
+            **manifest = mutated;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn mutate(&self, _manifests: &mut [&mut JsonValue], _pe: &KubePreviewEnvironment) -> Result<(), String> {
+        Ok(())
+    }
+
+    #[cfg(feature = "wasm")]
+    fn call(&self, module: &Module, manifests: &[JsonValue], pe: &KubePreviewEnvironment) -> Result<Vec<JsonValue>, String> {
+        let input = serde_json::to_vec(&serde_json::json!({ "manifests": manifests, "preview": pe }))
+            .expect("Failed to serialize plugin input");
+
+        let store = Store::new(&self.engine);
+        let instance = Instance::new(&store, module, &[]).map_err(|err| err.to_string())?;
+        let memory = instance.get_memory("memory").ok_or("doesn't export its memory")?;
+        let alloc = instance
+            .get_func("alloc")
+            .ok_or("doesn't export alloc")?
+            .get1::<i32, i32>()
+            .map_err(|err| err.to_string())?;
+        let mutate = instance
+            .get_func("mutate")
+            .ok_or("doesn't export mutate")?
+            .get2::<i32, i32, i64>()
+            .map_err(|err| err.to_string())?;
+
+        let ptr = alloc(input.len() as i32).map_err(|err| err.to_string())? as usize;
+        // Safe as long as nothing runs in the instance while the slices are
+        // held, and nothing does until the next call
+        unsafe {
+            let data = memory.data_unchecked_mut();
+            data.get_mut(ptr..ptr + input.len()).ok_or("alloc returned memory out of bounds")?.copy_from_slice(&input);
+        }
+        let packed = mutate(ptr as i32, input.len() as i32).map_err(|err| err.to_string())? as u64;
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let output = unsafe {
+            let data = memory.data_unchecked();
+            data.get(out_ptr..out_ptr + out_len).ok_or("mutate returned memory out of bounds")?.to_vec()
+        };
+        serde_json::from_slice(&output).map_err(|err| format!("returned invalid manifests: {}", err))
+    }
+}
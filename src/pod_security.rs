@@ -0,0 +1,164 @@
+//! Checks the pods a preview would create against a Pod Security Standard
+//! (`POD_SECURITY_LEVEL`) before anything is applied.  Admission would
+//! reject them anyway, but only when the Deployment tries to create pods,
+//! leaving a preview that never comes up and no clear reason why.
+//!
+//! The controller doesn't create namespaces, so the namespace it watches
+//! should carry the matching `pod-security.kubernetes.io/enforce` label.
+//! Only the checks that generated pods could plausibly trip are made here;
+//! admission remains the final word.
+use std::fmt;
+use std::str::FromStr;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Privileged,
+    Baseline,
+    Restricted,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "privileged" => Ok(Level::Privileged),
+            "baseline" => Ok(Level::Baseline),
+            "restricted" => Ok(Level::Restricted),
+            _ => Err(format!("unknown Pod Security level {:?}", value)),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Privileged => "privileged",
+            Level::Baseline => "baseline",
+            Level::Restricted => "restricted",
+        };
+        f.write_str(name)
+    }
+}
+
+// Capabilities the baseline level lets containers add
+const BASELINE_CAPABILITIES: &[&str] = &[
+    "AUDIT_WRITE", "CHOWN", "DAC_OVERRIDE", "FOWNER", "FSETID", "KILL", "MKNOD", "NET_BIND_SERVICE", "SETFCAP", "SETGID",
+    "SETPCAP", "SETUID", "SYS_CHROOT",
+];
+
+// Volume types allowed at the restricted level
+const RESTRICTED_VOLUMES: &[&str] = &[
+    "configMap", "csi", "downwardAPI", "emptyDir", "ephemeral", "persistentVolumeClaim", "projected", "secret",
+];
+
+fn pod_spec(manifest: &JsonValue) -> Option<&JsonValue> {
+    let spec = &manifest["spec"];
+    let pod_spec = match manifest["kind"].as_str()? {
+        "CronJob" => &spec["jobTemplate"]["spec"]["template"]["spec"],
+        "Deployment" | "StatefulSet" | "Job" | "Rollout" => &spec["template"]["spec"],
+        _ => return None,
+    };
+    Some(pod_spec).filter(|pod_spec| pod_spec.is_object())
+}
+
+/// Everything about `manifests` that breaks `level`, as messages naming
+/// the offending manifest.
+pub fn violations(level: Level, manifests: &[JsonValue]) -> Vec<String> {
+    manifests
+        .iter()
+        .filter_map(|manifest| {
+            let name = manifest["metadata"]["name"].as_str().unwrap_or_default();
+            pod_spec(manifest).map(|pod_spec| (name, pod_spec))
+        })
+        .flat_map(|(name, pod_spec)| check_pod(level, pod_spec).into_iter().map(move |problem| format!("{}: {}", name, problem)))
+        .collect()
+}
+
+fn check_pod(level: Level, pod_spec: &JsonValue) -> Vec<String> {
+    let mut problems = Vec::new();
+    if level == Level::Privileged {
+        return problems;
+    }
+
+    for field in &["hostNetwork", "hostPID", "hostIPC"] {
+        if pod_spec[*field] == true {
+            problems.push(format!("{} is not allowed", field));
+        }
+    }
+    for volume in pod_spec["volumes"].as_array().into_iter().flatten() {
+        let volume_type = volume
+            .as_object()
+            .and_then(|volume| volume.keys().find(|key| *key != "name").cloned())
+            .unwrap_or_default();
+        if volume_type == "hostPath" || (level == Level::Restricted && !RESTRICTED_VOLUMES.contains(&volume_type.as_str())) {
+            problems.push(format!("{} volumes are not allowed", volume_type));
+        }
+    }
+
+    let pod_context = &pod_spec["securityContext"];
+    let containers = pod_spec["containers"].as_array().into_iter().chain(pod_spec["initContainers"].as_array()).flatten();
+    for container in containers {
+        let name = container["name"].as_str().unwrap_or_default();
+        for problem in check_container(level, pod_context, container) {
+            problems.push(format!("container {} {}", name, problem));
+        }
+    }
+    problems
+}
+
+// Container settings win over the pod's
+fn effective<'a>(pod_context: &'a JsonValue, context: &'a JsonValue, field: &str) -> &'a JsonValue {
+    if context[field].is_null() {
+        &pod_context[field]
+    } else {
+        &context[field]
+    }
+}
+
+fn check_container(level: Level, pod_context: &JsonValue, container: &JsonValue) -> Vec<String> {
+    let mut problems = Vec::new();
+    let context = &container["securityContext"];
+    let setting = |field| effective(pod_context, context, field);
+
+    if context["privileged"] == true {
+        problems.push("must not be privileged".to_string());
+    }
+    if container["ports"].as_array().into_iter().flatten().any(|port| port["hostPort"].as_i64().unwrap_or(0) != 0) {
+        problems.push("must not use host ports".to_string());
+    }
+    if setting("seccompProfile")["type"] == "Unconfined" {
+        problems.push("must not run with an Unconfined seccomp profile".to_string());
+    }
+    let allowed_capabilities = if level == Level::Restricted { &["NET_BIND_SERVICE"][..] } else { BASELINE_CAPABILITIES };
+    for capability in context["capabilities"]["add"].as_array().into_iter().flatten() {
+        let capability = capability.as_str().unwrap_or_default();
+        if !allowed_capabilities.contains(&capability) {
+            problems.push(format!("must not add the {} capability", capability));
+        }
+    }
+    if level == Level::Baseline {
+        return problems;
+    }
+
+    if context["allowPrivilegeEscalation"] != false {
+        problems.push("must set allowPrivilegeEscalation to false".to_string());
+    }
+    if setting("runAsNonRoot") != true {
+        problems.push("must set runAsNonRoot to true".to_string());
+    }
+    if setting("runAsUser") == 0 {
+        problems.push("must not run as root".to_string());
+    }
+    let seccomp = setting("seccompProfile")["type"].as_str();
+    if seccomp != Some("RuntimeDefault") && seccomp != Some("Localhost") {
+        problems.push("must use the RuntimeDefault or Localhost seccomp profile".to_string());
+    }
+    let drops_all = context["capabilities"]["drop"].as_array().into_iter().flatten().any(|capability| capability == "ALL");
+    if !drops_all {
+        problems.push("must drop ALL capabilities".to_string());
+    }
+    problems
+}
@@ -0,0 +1,81 @@
+//! Checks rendered children against policy before anything is created, so
+//! platform teams can enforce rules like "no `:latest` tags" or "set
+//! resource limits" in one place rather than in every preview.
+//!
+//! Policies live in an external OPA.  The controller queries
+//! `OPA_POLICY_PATH` (`preview/deny` by default) with the preview and its
+//! manifests as input, and expects back a list of reasons to refuse it:
+//!
+//! ```rego
+//! package preview
+//!
+//! deny[msg] {
+//!     manifest := input.manifests[_]
+//!     manifest.kind == "Deployment"
+//!     container := manifest.spec.template.spec.containers[_]
+//!     endswith(container.image, ":latest")
+//!     msg := sprintf("%s uses a :latest tag", [container.name])
+//! }
+//! ```
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::config::Config;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Error, Debug)]
+pub enum PolicyError {
+    #[error("OPA request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("OPA returned {0}: {1}")]
+    Opa(u16, String),
+}
+
+pub struct Opa {
+    http: reqwest::Client,
+    url: String,
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct Decision {
+    // Missing when the policy isn't loaded or has no opinion.
+    #[serde(default)]
+    result: Vec<String>,
+}
+
+impl Opa {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.opa_url.clone()?;
+        Some(Opa {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            path: config.opa_policy_path.trim_matches('/').to_string(),
+        })
+    }
+
+    /// Returns the reasons the policy gives for refusing the preview, which
+    /// is empty when it's allowed.
+    pub async fn evaluate(&self, preview: &JsonValue, manifests: &[JsonValue]) -> Result<Vec<String>, PolicyError> {
+        let input = json!({
+            "input": {
+                "preview": preview,
+                "manifests": manifests,
+            }
+        });
+        let response = self
+            .http
+            .post(&format!("{}/v1/data/{}", self.url, self.path))
+            .json(&input)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PolicyError::Opa(status.as_u16(), response.text().await?));
+        }
+        let decision: Decision = response.json().await?;
+        Ok(decision.result)
+    }
+}
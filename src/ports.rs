@@ -0,0 +1,130 @@
+//! Named ports and the routes to them.  A preview with no `ports` gets the
+//! original single port 80 and a Mapping for `/`.  Otherwise each port is
+//! declared on the container and the Service, and each of `routes` gets
+//! its own Mapping (`/api` to `http`, `/ws` to `websocket` and so on).  With
+//! no `routes`, `/` goes to the first port.  `protocol: grpc` has the
+//! routes speak HTTP/2 to the app.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortSpec {
+    pub name: String,
+    /// Port the container listens on.  The Service exposes it on the same
+    /// number.
+    pub port: u16,
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+}
+
+fn default_protocol() -> String {
+    "TCP".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteSpec {
+    pub prefix: String,
+    /// Name of the port in `ports` to send the traffic to.
+    pub port: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Http,
+    Grpc,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::Http
+    }
+}
+
+/// A route with its port resolved to a number.
+pub struct Route {
+    pub prefix: String,
+    pub port: Option<u16>,
+}
+
+/// The preview's routes, or why they don't make sense.
+pub fn routes(ports: &[PortSpec], routes: &[RouteSpec]) -> Result<Vec<Route>, String> {
+    if routes.is_empty() {
+        let port = ports.first().map(|port| port.port);
+        return Ok(vec![Route { prefix: "/".to_string(), port }]);
+    }
+    routes
+        .iter()
+        .map(|route| match ports.iter().find(|port| port.name == route.port) {
+            Some(port) => Ok(Route { prefix: route.prefix.clone(), port: Some(port.port) }),
+            None => Err(format!("route {} refers to unknown port {}", route.prefix, route.port)),
+        })
+        .collect()
+}
+
+/// Name of the Mapping for the route at `index`.  The first keeps the
+/// preview's original Mapping name.
+pub fn mapping_name(preview: &str, index: usize) -> String {
+    match index {
+        0 => names::child(preview, "mapping"),
+        _ => names::child(preview, &format!("mapping-{}", index)),
+    }
+}
+
+/// Declare the ports on the deployment's container.
+pub fn expose(deployment: &mut JsonValue, ports: &[PortSpec]) {
+    if ports.is_empty() {
+        return;
+    }
+    let declared: Vec<JsonValue> = ports
+        .iter()
+        .map(|port| json!({ "name": port.name, "containerPort": port.port, "protocol": port.protocol }))
+        .collect();
+    let container = &mut deployment["spec"]["template"]["spec"]["containers"][0];
+    match container["ports"].as_array_mut() {
+        Some(existing) => existing.extend(declared),
+        None => container["ports"] = json!(declared),
+    }
+}
+
+/// Replace the Service's default port 80 with the named ports.
+pub fn publish(service: &mut JsonValue, ports: &[PortSpec]) {
+    if ports.is_empty() {
+        return;
+    }
+    service["spec"]["ports"] = json!(ports
+        .iter()
+        .map(|port| json!({ "name": port.name, "port": port.port, "targetPort": port.name, "protocol": port.protocol }))
+        .collect::<Vec<_>>());
+}
+
+/// Point a Mapping at `route`.
+pub fn route(mapping: &mut JsonValue, service: &str, route: &Route) {
+    mapping["spec"]["prefix"] = json!(route.prefix);
+    mapping["spec"]["service"] = match route.port {
+        Some(port) => json!(format!("{}:{}", service, port)),
+        None => json!(service),
+    };
+}
+
+/// Set up a Mapping and the Service behind it for the app's protocol.
+pub fn apply_protocol(mapping: &mut JsonValue, service: &mut JsonValue, protocol: Protocol) {
+    if protocol != Protocol::Grpc {
+        return;
+    }
+    // Talk HTTP/2 to the app, and don't cut off long-lived streams
+    mapping["spec"]["grpc"] = json!(true);
+    mapping["spec"]["timeout_ms"] = json!(0);
+    // Meshes pick the protocol from the port name
+    for port in service["spec"]["ports"].as_array_mut().into_iter().flatten() {
+        if port["name"].is_null() {
+            port["name"] = json!("grpc");
+        }
+    }
+}
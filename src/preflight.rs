@@ -0,0 +1,163 @@
+//! Checks made once at startup that the cluster has everything the
+//! controller needs: the PreviewEnvironment CRD, the API groups it creates
+//! things in, the permissions to create them, and DNS for previews' FQDNs.
+//! Without them the controller would start happily and then fail on the
+//! first preview, with an error that only makes sense to someone who knows
+//! the code.  Instead every check is made, everything wrong is reported at
+//! once with what to do about it, and the controller exits.
+//!
+//! Optional API groups, like Argo Rollouts or the External Secrets
+//! Operator, are only checked for when the config turns on something that
+//! needs them.  `SKIP_PREFLIGHT` skips the checks altogether.
+use kube::{
+    api::{ListParams, PostParams, RawApi},
+    Error,
+};
+use serde_json::json;
+
+use crate::config::{Config, TlsMode};
+use crate::egress::EgressPolicy;
+use crate::mesh::Mesh;
+use crate::ApiResources;
+
+type JsonValue = serde_json::value::Value;
+
+/// Everything that's wrong, each with what to do about it.  Empty when the
+/// controller is good to go.
+pub async fn run(resources: &ApiResources) -> Vec<String> {
+    let mut problems = vec![];
+    for (api, what, fix) in served(resources) {
+        if let Some(problem) = check_served(resources, api, what, fix).await {
+            problems.push(problem);
+        }
+    }
+    problems.extend(check_permissions(resources).await);
+    problems.extend(check_dns(&resources.config).await);
+    problems
+}
+
+// The resources the controller reads and writes, which API group each is
+// in, and how to get it served if it isn't.
+fn served(resources: &ApiResources) -> Vec<(&RawApi, &'static str, &'static str)> {
+    let config = &resources.config;
+    let crd = "install the CRD with kubectl apply -f preview-environment-crd.yaml";
+    let mut served = vec![
+        (&resources.previews, "PreviewEnvironments (platform9.com)", crd),
+        (&resources.deployments, "Deployments (apps/v1)", "upgrade the cluster to Kubernetes 1.9 or later"),
+        (&resources.mappings, "Mappings (getambassador.io/v2)", "install Ambassador, or its CRDs"),
+    ];
+    if config.tls_mode != TlsMode::None {
+        served.push((&resources.hosts, "Hosts (getambassador.io/v2)", "install Ambassador 1.x, or set TLS_MODE=none"));
+    }
+    if config.external_secret_template.is_some() {
+        let fix = "install the External Secrets Operator";
+        served.push((&resources.external_secrets, "ExternalSecrets (external-secrets.io)", fix));
+    }
+    if config.rollout_strategy_template.is_some() {
+        served.push((&resources.rollouts, "Rollouts (argoproj.io)", "install Argo Rollouts"));
+    }
+    if config.mesh == Mesh::Istio {
+        let fix = "install Istio, or set MESH=none";
+        served.push((&resources.peer_authentications, "PeerAuthentications (security.istio.io)", fix));
+    }
+    if config.egress_policy == EgressPolicy::Cilium {
+        let fix = "install Cilium, or use EGRESS_POLICY=networkpolicy";
+        served.push((&resources.egress_policies, "CiliumNetworkPolicies (cilium.io)", fix));
+    }
+    served
+}
+
+// A list the API server doesn't know the path of means the group, version
+// or resource isn't served.  Anything else, a 403 included, is someone
+// else's problem: the permission checks cover those.
+async fn check_served(resources: &ApiResources, api: &RawApi, what: &str, fix: &str) -> Option<String> {
+    let result: Result<JsonValue, Error> = match api.list(&ListParams::default()) {
+        Ok(request) => resources.client.request(request).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(Error::Api(ae)) if ae.code == 404 => Some(format!("{} aren't served by the API server: {}", what, fix)),
+        Err(Error::Api(_)) | Ok(_) => None,
+        Err(err) => Some(format!("Failed to reach the API server to check for {}: {}", what, err)),
+    }
+}
+
+// What the controller does to which resources.  Checked with a
+// SelfSubjectAccessReview each, which needs no permissions of its own.
+const PERMISSIONS: &[(&str, &str, &[&str])] = &[
+    ("platform9.com", "previewenvironments", &["get", "list", "watch", "update"]),
+    ("platform9.com", "previewenvironments/status", &["update"]),
+    ("apps", "deployments", &["get", "list", "create", "update", "delete"]),
+    ("", "services", &["get", "list", "create", "update", "delete"]),
+    ("getambassador.io", "mappings", &["get", "list", "create", "update", "delete"]),
+    ("", "events", &["create"]),
+    ("batch", "jobs", &["get", "list", "create", "delete"]),
+];
+
+async fn check_permissions(resources: &ApiResources) -> Vec<String> {
+    let reviews = RawApi::customResource("selfsubjectaccessreviews").group("authorization.k8s.io").version("v1");
+    let namespace = resources.config.namespace.as_str();
+    let mut missing = vec![];
+    for (group, resource, verbs) in PERMISSIONS {
+        for verb in verbs.iter() {
+            let (resource, subresource) = match resource.find('/') {
+                Some(slash) => (&resource[..slash], &resource[slash + 1..]),
+                None => (*resource, ""),
+            };
+            let review = json!({
+                "apiVersion": "authorization.k8s.io/v1",
+                "kind": "SelfSubjectAccessReview",
+                "spec": { "resourceAttributes": {
+                    "namespace": namespace,
+                    "group": group,
+                    "resource": resource,
+                    "subresource": subresource,
+                    "verb": verb,
+                } },
+            });
+            let data = serde_json::to_vec(&review).expect("Failed to serialize SelfSubjectAccessReview json");
+            let result: Result<JsonValue, Error> = match reviews.create(&PostParams::default(), data) {
+                Ok(request) => resources.client.request(request).await,
+                Err(err) => Err(err),
+            };
+            let what = if subresource.is_empty() { resource.to_string() } else { format!("{}/{}", resource, subresource) };
+            match result {
+                Ok(review) if review["status"]["allowed"] == true => {}
+                Ok(_) => missing.push(format!("{} {}", verb, what)),
+                Err(err) => return vec![format!("Failed to check the controller's permissions: {}", err)],
+            }
+        }
+    }
+    if missing.is_empty() {
+        return vec![];
+    }
+    let missing = missing.join(", ");
+    vec![format!(
+        "The controller's service account can't {} in namespace {}: apply what `{} generate-rbac` prints",
+        missing,
+        namespace,
+        env!("CARGO_PKG_NAME"),
+    )]
+}
+
+// Previews' FQDNs have to resolve to something.  When the controller
+// manages DNS records they point at `DNS_TARGET`, so that has to resolve;
+// otherwise there has to be a wildcard record under the FQDN template's
+// domain.
+async fn check_dns(config: &Config) -> Option<String> {
+    let (host, fix) = match (&config.dns_provider, &config.dns_target, &config.fqdn_template) {
+        (Some(_), Some(target), _) => (target.clone(), "set DNS_TARGET to the ingress load balancer's address"),
+        (None, _, Some(template)) => {
+            let domain = template.rsplit('}').next().unwrap_or_default().trim_start_matches('.');
+            if domain.is_empty() {
+                return None;
+            }
+            (format!("preflight.{}", domain), "add a wildcard DNS record for the FQDN template's domain")
+        }
+        _ => return None,
+    };
+    match tokio::net::lookup_host((host.as_str(), 0)).await {
+        Ok(_) => None,
+        Err(err) => Some(format!("{} doesn't resolve ({}): {}", host, err, fix)),
+    }
+}
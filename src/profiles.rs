@@ -0,0 +1,133 @@
+//! Per-team settings, so one controller can serve teams with different
+//! policies.  A team's profile is a ConfigMap in the controller's namespace
+//! labelled `preview.platform9.com/profile` with the team's name, and a
+//! preview belongs to the team its `TEAM_LABEL` label (`team` by default)
+//! names.  The ConfigMap's data overrides the controller's settings for the
+//! team's previews, using the same names as the config file:
+//!
+//! - `fqdn_template`, and so the domain, for previews that leave out `fqdn`
+//! - `default_cpu_request`, `default_memory_request`, `default_cpu_limit`
+//!   and `default_memory_limit`, for previews that don't set `resources`
+//! - `preview_ttl_seconds`, how long previews live before they're deleted
+//! - `allowed_registries`, where their images may come from (see
+//!   `registries`)
+//!
+//! Anything a profile leaves out, and every setting for previews without a
+//! team or whose team has no profile, is the controller's.  Profiles are
+//! read at startup and every `PROFILE_REFRESH_INTERVAL_SECONDS`.  One that
+//! doesn't parse is reported and ignored, and the team keeps the profile it
+//! had.
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use kube::api::ListParams;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config;
+use crate::fqdn;
+use crate::{ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// Label on a ConfigMap holding a team's profile, naming the team.
+pub const PROFILE_LABEL: &str = "preview.platform9.com/profile";
+
+/// The settings a team has of its own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub fqdn_template: Option<String>,
+    pub default_resources: Option<ResourceRequirements>,
+    pub ttl: Option<Duration>,
+    pub allowed_registries: Option<Vec<String>>,
+}
+
+impl Profile {
+    /// The profile in a ConfigMap's `data`.
+    pub fn parse(data: &JsonValue) -> Result<Self, String> {
+        let setting = |key: &str| data[key.to_lowercase()].as_str().filter(|value| !value.is_empty()).map(String::from);
+        let fqdn_template = setting("FQDN_TEMPLATE");
+        if let Some(template) = &fqdn_template {
+            fqdn::check_template(template)?;
+        }
+        let ttl = match setting("PREVIEW_TTL_SECONDS") {
+            Some(ttl) => Some(Duration::from_secs(ttl.parse().map_err(|err| format!("invalid preview_ttl_seconds: {}", err))?)),
+            None => None,
+        };
+        let allowed_registries = setting("ALLOWED_REGISTRIES")
+            .map(|registries| registries.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect());
+        Ok(Profile {
+            fqdn_template,
+            default_resources: config::default_resources(setting),
+            ttl,
+            allowed_registries,
+        })
+    }
+}
+
+/// Every team's profile, as last read.
+#[derive(Default)]
+pub struct Profiles {
+    by_team: RwLock<BTreeMap<String, Profile>>,
+}
+
+impl Profiles {
+    /// The profile of `team`, or an empty one when it has none.
+    pub fn get(&self, team: Option<&str>) -> Profile {
+        let by_team = self.by_team.read().unwrap();
+        team.and_then(|team| by_team.get(team)).cloned().unwrap_or_default()
+    }
+}
+
+/// The profile of `pe`'s team.
+pub fn of(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Profile {
+    resources.profiles.get(pe.metadata.labels.get(&resources.config.team_label).map(String::as_str))
+}
+
+/// Reads every team's profile again.
+pub async fn load(resources: &ApiResources) {
+    let lp = ListParams {
+        label_selector: Some(PROFILE_LABEL.to_string()),
+        ..ListParams::default()
+    };
+    let list: Result<JsonValue, _> = match resources.config_maps.list(&lp) {
+        Ok(request) => resources.client.request(request).await,
+        Err(err) => Err(err),
+    };
+    let list = match list {
+        Ok(list) => list,
+        Err(err) => {
+            println!("Failed to list profiles: {:?}", err);
+            return;
+        }
+    };
+
+    let current = resources.profiles.by_team.read().unwrap().clone();
+    let mut profiles = BTreeMap::new();
+    for item in list["items"].as_array().into_iter().flatten() {
+        let team = item["metadata"]["labels"][PROFILE_LABEL].as_str().unwrap_or_default();
+        let name = item["metadata"]["name"].as_str().unwrap_or_default();
+        match Profile::parse(&item["data"]) {
+            Ok(profile) => {
+                if current.get(team) != Some(&profile) {
+                    println!("Loaded profile for team {} from ConfigMap {}", team, name);
+                }
+                profiles.insert(team.to_string(), profile);
+            }
+            Err(err) => {
+                println!("Ignoring profile for team {} in ConfigMap {}: {}", team, name, err);
+                if let Some(profile) = current.get(team) {
+                    profiles.insert(team.to_string(), profile.clone());
+                }
+            }
+        }
+    }
+    *resources.profiles.by_team.write().unwrap() = profiles;
+}
+
+/// Periodically reads every team's profile again.
+pub async fn refresh(resources: Arc<ApiResources>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        load(&resources).await;
+    }
+}
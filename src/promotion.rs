@@ -0,0 +1,64 @@
+//! Promoting a preview to a long-lived environment such as staging.
+//!
+//! Setting `preview.platform9.com/promote` to a namespace (or running
+//! `kubectl preview promote`) has the controller create a copy of the
+//! preview there, under the FQDN in `preview.platform9.com/promote-fqdn`.
+//! The copy gets production-grade settings -- at least `PROMOTE_REPLICAS`
+//! replicas spread across nodes, and blue-green updates -- and is labelled
+//! long-lived so it's never reaped for its age.  The target namespace needs
+//! a controller of its own watching it, which renders the copy's resources
+//! there the same way as any other preview.  The original is left alone.
+use serde_json::json;
+
+use crate::bluegreen::UpdateStrategy;
+use crate::config::Config;
+use crate::scheduling::Spread;
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+pub const PROMOTE_ANNOTATION: &str = "preview.platform9.com/promote";
+pub const FQDN_ANNOTATION: &str = "preview.platform9.com/promote-fqdn";
+/// Records where a promoted environment came from, as `namespace/name`.
+pub const PROMOTED_FROM_ANNOTATION: &str = "preview.platform9.com/promoted-from";
+
+/// Set on environments that stay until they're deleted by hand.
+pub const LONG_LIVED_LABEL: &str = "preview.platform9.com/long-lived";
+
+/// The namespace and FQDN the preview has been asked to be promoted to.
+pub fn requested(pe: &KubePreviewEnvironment) -> Option<(&str, Option<&str>)> {
+    let annotations = &pe.metadata.annotations;
+    let namespace = annotations.get(PROMOTE_ANNOTATION).map(String::as_str).filter(|ns| !ns.is_empty())?;
+    Some((namespace, annotations.get(FQDN_ANNOTATION).map(String::as_str)))
+}
+
+/// The promoted copy of `pe`.
+pub fn promoted_json(pe: &KubePreviewEnvironment, fqdn: &str, config: &Config) -> JsonValue {
+    let mut spec = pe.spec.clone();
+    spec.fqdn = fqdn.to_string();
+    spec.replicas = spec.replicas.max(config.promote_replicas);
+    if spec.spread.unwrap_or(config.spread) == Spread::None {
+        spec.spread = Some(Spread::TopologySpread);
+    }
+    if spec.strategy == UpdateStrategy::Rolling {
+        spec.strategy = UpdateStrategy::BlueGreen;
+    }
+    // It's a fresh environment, not a clone of the preview's data
+    spec.clone_from = None;
+
+    let source = format!("{}/{}", pe.metadata.namespace.as_deref().unwrap_or_default(), pe.metadata.name);
+    let mut labels = pe.metadata.labels.clone();
+    labels.insert(LONG_LIVED_LABEL.to_string(), "true".to_string());
+    json!({
+        "apiVersion": "platform9.com/v1",
+        "kind": "PreviewEnvironment",
+        "metadata": {
+            "name": pe.metadata.name,
+            "labels": labels,
+            "annotations": {
+                PROMOTED_FROM_ANNOTATION: source,
+            }
+        },
+        "spec": spec,
+    })
+}
@@ -0,0 +1,124 @@
+//! Caps on how many previews can run at once, per owner and across the
+//! namespace.  Previews over a cap are queued rather than rejected, and
+//! start as soon as a slot frees up: when another preview is deleted, or
+//! failing that when they're next looked at, every
+//! `QUOTA_RETRY_INTERVAL_SECONDS`.
+use crate::config::Config;
+use crate::labels::OWNER_LABEL;
+use crate::KubePreviewEnvironment;
+
+/// Phase of a preview waiting on quota.
+pub const QUEUED: &str = "Queued";
+
+/// Who a preview belongs to: `spec.owner`, falling back to the owner label.
+pub fn owner(pe: &KubePreviewEnvironment) -> Option<&str> {
+    pe.spec
+        .owner
+        .as_deref()
+        .or_else(|| pe.metadata.labels.get(OWNER_LABEL).map(String::as_str))
+        .filter(|owner| !owner.is_empty())
+}
+
+pub fn is_queued(pe: &KubePreviewEnvironment) -> bool {
+    pe.status.as_ref().and_then(|status| status.phase.as_deref()) == Some(QUEUED)
+}
+
+/// Why `pe` can't start yet given the other previews in the namespace, or
+/// `None` if it's within quota.  Queued previews don't count against
+/// anyone's quota.
+pub fn exceeded(config: &Config, pe: &KubePreviewEnvironment, previews: &[KubePreviewEnvironment]) -> Option<String> {
+    let running: Vec<&KubePreviewEnvironment> = previews
+        .iter()
+        .filter(|other| other.metadata.name != pe.metadata.name && !is_queued(other))
+        .collect();
+
+    if let Some(max) = config.max_previews_per_namespace {
+        if running.len() >= max {
+            return Some(format!("Waiting for one of the {} previews allowed in the namespace to be deleted", max));
+        }
+    }
+
+    if let (Some(max), Some(owner)) = (config.max_previews_per_owner, owner(pe)) {
+        let owned = running.iter().filter(|other| self::owner(other) == Some(owner)).count();
+        if owned >= max {
+            return Some(format!("Waiting for one of the {} previews allowed for {} to be deleted", max, owner));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn preview(name: &str, owner: Option<&str>, phase: Option<&str>) -> KubePreviewEnvironment {
+        let mut pe = json!({
+            "apiVersion": "platform9.com/v1",
+            "kind": "PreviewEnvironment",
+            "metadata": { "name": name, "labels": { "preview": "true" } },
+            "spec": { "image": "nginx:1.19", "owner": owner },
+        });
+        if let Some(phase) = phase {
+            pe["status"] = json!({ "phase": phase });
+        }
+        serde_json::from_value(pe).unwrap()
+    }
+
+    fn config(flags: &[&str]) -> Config {
+        Config::load(flags.iter().map(|flag| flag.to_string())).unwrap()
+    }
+
+    #[test]
+    fn previews_over_a_cap_are_held_back() {
+        let previews = vec![
+            preview("a", Some("alice"), Some("Ready")),
+            preview("b", Some("bob"), Some("Ready")),
+            preview("c", Some("alice"), None),
+        ];
+        let cases = vec![
+            // (flags, preview, held back for)
+            (vec![], "c", None),
+            (vec!["--max-previews-per-namespace=3"], "c", None),
+            (vec!["--max-previews-per-namespace=2"], "c", Some("the 2 previews allowed in the namespace")),
+            (vec!["--max-previews-per-owner=1"], "c", Some("the 1 previews allowed for alice")),
+            (vec!["--max-previews-per-owner=1"], "b", None),
+            (vec!["--max-previews-per-owner=2"], "c", None),
+        ];
+
+        for (flags, name, expected) in cases {
+            let pe = previews.iter().find(|pe| pe.metadata.name == name).unwrap();
+            let reason = exceeded(&config(&flags), pe, &previews);
+            match expected {
+                Some(expected) => assert!(reason.as_deref().unwrap_or_default().contains(expected), "{:?}: {:?}", flags, reason),
+                None => assert_eq!(reason, None, "{:?}", flags),
+            }
+        }
+    }
+
+    #[test]
+    fn queued_previews_and_ownerless_ones_dont_count() {
+        let previews = vec![
+            preview("a", Some("alice"), Some(QUEUED)),
+            preview("b", None, Some("Ready")),
+            preview("c", None, None),
+        ];
+
+        assert_eq!(exceeded(&config(&["--max-previews-per-namespace=2"]), &previews[2], &previews), None);
+        assert_eq!(exceeded(&config(&["--max-previews-per-owner=1"]), &previews[2], &previews), None);
+        assert!(exceeded(&config(&["--max-previews-per-namespace=1"]), &previews[2], &previews).is_some());
+    }
+
+    #[test]
+    fn the_owner_label_stands_in_for_spec_owner() {
+        let mut pe = preview("a", None, None);
+        pe.metadata.labels.insert(OWNER_LABEL.to_string(), "alice".to_string());
+
+        assert_eq!(owner(&pe), Some("alice"));
+        pe.spec.owner = Some("bob".to_string());
+        assert_eq!(owner(&pe), Some("bob"));
+        pe.spec.owner = Some(String::new());
+        assert_eq!(owner(&pe), None);
+    }
+}
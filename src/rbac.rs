@@ -0,0 +1,198 @@
+//! The RBAC the controller needs, printed by `generate-rbac` as YAML ready
+//! for `kubectl apply -f -`: a ServiceAccount for the controller to run as,
+//! and the roles and bindings that let it do what its config has it do.
+//! It takes the same flags, environment and config file as the controller
+//! itself, so what it prints matches the controller it's run alongside.
+//!
+//! Every kind of child is granted whatever the config says, since cleaning
+//! up a preview deletes every kind it could have.  The rest depends on the
+//! config: secrets copied from `SECRET_SOURCE_NAMESPACE`, ArgoCD
+//! Applications in `ARGOCD_NAMESPACE`, Flux's resources, the previews'
+//! PriorityClass, impersonating creators, and reading nodes and pods for
+//! `CHECK_CAPACITY`.
+//!
+//! By default the controller gets a Role in its own namespace, Roles in
+//! the other namespaces it reads or writes, and a ClusterRole only for what
+//! isn't namespaced.  With `--cluster-wide` it's all one ClusterRole bound
+//! across the cluster instead, which is what promoting previews to other
+//! namespaces needs.
+use serde_json::json;
+
+use crate::config::{Config, ConfigError};
+use crate::delivery;
+use crate::egress::EgressPolicy;
+
+type JsonValue = serde_json::value::Value;
+
+/// The subcommand that prints the RBAC rather than running the controller.
+pub const COMMAND: &str = "generate-rbac";
+
+/// What the ServiceAccount, roles and bindings are called.
+pub const NAME: &str = "preview-controller";
+
+/// Where the controller's permissions apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// Only in the namespaces the config names.
+    Namespaced,
+    /// In every namespace.
+    ClusterWide,
+}
+
+// An API group, its resources, and what may be done to them
+type Rule = (&'static str, &'static [&'static str], &'static [&'static str]);
+
+const ALL: &[&str] = &["get", "list", "watch", "create", "update", "patch", "delete"];
+const READ: &[&str] = &["get", "list", "watch"];
+
+/// The YAML for `generate-rbac`'s arguments: the controller's own flags,
+/// plus `--cluster-wide`.
+pub fn generate(args: &[String]) -> Result<String, ConfigError> {
+    let scope = if args.iter().any(|arg| arg == "--cluster-wide") { Scope::ClusterWide } else { Scope::Namespaced };
+    let config = Config::load(args.iter().filter(|arg| *arg != "--cluster-wide").cloned())?;
+    let documents: Vec<String> = manifests(&config, scope)
+        .iter()
+        .map(|manifest| serde_yaml::to_string(manifest).expect("Failed to serialize RBAC yaml"))
+        .collect();
+    Ok(documents.join("\n"))
+}
+
+// What the controller does in its own namespace, whatever the config
+const NAMESPACED: &[Rule] = &[
+    ("platform9.com", &["previewenvironments"], ALL),
+    ("platform9.com", &["previewenvironments/status"], &["get", "update", "patch"]),
+    ("", &["services", "configmaps", "secrets", "persistentvolumeclaims"], ALL),
+    ("", &["pods"], READ),
+    ("", &["pods/log"], &["get"]),
+    ("", &["events"], &["create", "patch"]),
+    ("apps", &["deployments", "statefulsets"], ALL),
+    ("batch", &["jobs", "cronjobs"], ALL),
+    ("getambassador.io", &["mappings", "tcpmappings", "hosts"], ALL),
+    ("argoproj.io", &["rollouts"], ALL),
+    ("tekton.dev", &["pipelineruns"], ALL),
+    ("monitoring.coreos.com", &["podmonitors"], ALL),
+    ("security.istio.io", &["peerauthentications"], ALL),
+    ("external-secrets.io", &["externalsecrets"], ALL),
+    ("metrics.k8s.io", &["pods"], &["get", "list"]),
+];
+
+fn namespaced(config: &Config) -> Vec<Rule> {
+    let mut rules = NAMESPACED.to_vec();
+    rules.push(match config.egress_policy {
+        EgressPolicy::Cilium => ("cilium.io", &["ciliumnetworkpolicies"], ALL),
+        _ => ("networking.k8s.io", &["networkpolicies"], ALL),
+    });
+    if backend(config).as_deref() == Some("flux") {
+        rules.push(("source.toolkit.fluxcd.io", &["gitrepositories", "helmrepositories"], ALL));
+        rules.push(("helm.toolkit.fluxcd.io", &["helmreleases"], ALL));
+    }
+    rules
+}
+
+// What the controller does in namespaces other than its own, by namespace
+fn elsewhere(config: &Config) -> Vec<(String, Vec<Rule>)> {
+    let mut namespaces = vec![];
+    if let Some(source_namespace) = &config.secret_source_namespace {
+        namespaces.push((source_namespace.clone(), vec![("", &["secrets"][..], READ)]));
+    }
+    if backend(config).as_deref() == Some("argocd") {
+        namespaces.push((config.argocd_namespace.clone(), vec![("argoproj.io", &["applications"][..], ALL)]));
+    }
+    namespaces
+}
+
+// What the controller does to things that aren't in any namespace
+fn cluster(config: &Config) -> Vec<Rule> {
+    let mut rules: Vec<Rule> = vec![];
+    if config.priority_class.is_some() && config.priority_class_value.is_some() {
+        rules.push(("scheduling.k8s.io", &["priorityclasses"], &["get", "create"]));
+    }
+    if config.impersonate_creator {
+        rules.push(("", &["users", "groups"], &["impersonate"]));
+    }
+    // Pods in every namespace take up room on the nodes
+    if config.check_capacity {
+        rules.push(("", &["nodes", "pods"], &["list"]));
+    }
+    rules
+}
+
+// The delivery backend previews with a `source` go through, the way
+// `delivery::from_config` picks it.
+fn backend(config: &Config) -> Option<String> {
+    config.delivery_backend.clone().or_else(|| delivery::backends().first().map(|(name, _)| name.to_string()))
+}
+
+fn rules_json(rules: &[Rule]) -> JsonValue {
+    let rules: Vec<JsonValue> = rules
+        .iter()
+        .map(|(group, resources, verbs)| json!({ "apiGroups": [group], "resources": resources, "verbs": verbs }))
+        .collect();
+    json!(rules)
+}
+
+fn role_json(kind: &str, name: &str, namespace: Option<&str>, rules: &[Rule]) -> JsonValue {
+    let mut role = json!({
+        "apiVersion": "rbac.authorization.k8s.io/v1",
+        "kind": kind,
+        "metadata": { "name": name },
+        "rules": rules_json(rules),
+    });
+    if let Some(namespace) = namespace {
+        role["metadata"]["namespace"] = json!(namespace);
+    }
+    role
+}
+
+fn binding_json(kind: &str, name: &str, namespace: Option<&str>, config: &Config) -> JsonValue {
+    let role_kind = if kind == "ClusterRoleBinding" { "ClusterRole" } else { "Role" };
+    let mut binding = json!({
+        "apiVersion": "rbac.authorization.k8s.io/v1",
+        "kind": kind,
+        "metadata": { "name": name },
+        "roleRef": { "apiGroup": "rbac.authorization.k8s.io", "kind": role_kind, "name": name },
+        "subjects": [{ "kind": "ServiceAccount", "name": NAME, "namespace": config.namespace }],
+    });
+    if let Some(namespace) = namespace {
+        binding["metadata"]["namespace"] = json!(namespace);
+    }
+    binding
+}
+
+/// The ServiceAccount, roles and bindings, in the order they should be
+/// applied.
+pub fn manifests(config: &Config, scope: Scope) -> Vec<JsonValue> {
+    let namespace = config.namespace.as_str();
+    let mut manifests = vec![json!({
+        "apiVersion": "v1",
+        "kind": "ServiceAccount",
+        "metadata": { "name": NAME, "namespace": namespace },
+    })];
+
+    // Anything outside the controller's namespace is named for it, so
+    // controllers in different namespaces don't share roles
+    let cluster_name = format!("{}-{}", NAME, namespace);
+    match scope {
+        Scope::Namespaced => {
+            manifests.push(role_json("Role", NAME, Some(namespace), &namespaced(config)));
+            manifests.push(binding_json("RoleBinding", NAME, Some(namespace), config));
+            for (other, rules) in elsewhere(config).iter().filter(|(other, _)| other != namespace) {
+                manifests.push(role_json("Role", &cluster_name, Some(other), rules));
+                manifests.push(binding_json("RoleBinding", &cluster_name, Some(other), config));
+            }
+            let rules = cluster(config);
+            if !rules.is_empty() {
+                manifests.push(role_json("ClusterRole", &cluster_name, None, &rules));
+                manifests.push(binding_json("ClusterRoleBinding", &cluster_name, None, config));
+            }
+        }
+        Scope::ClusterWide => {
+            let mut rules = namespaced(config);
+            rules.extend(elsewhere(config).into_iter().flat_map(|(_, rules)| rules));
+            rules.extend(cluster(config));
+            manifests.push(role_json("ClusterRole", &cluster_name, None, &rules));
+            manifests.push(binding_json("ClusterRoleBinding", &cluster_name, None, config));
+        }
+    }
+    manifests
+}
@@ -0,0 +1,2042 @@
+//! The reconcile loop: what the controller does when a preview is added,
+//! changed or deleted, from checking it's allowed to run through building,
+//! deploying and routing to it, to cleaning up after it.
+use chrono::Utc;
+use futures::future;
+use kube::{
+    api::{PostParams, RawApi, Void, WatchEvent},
+    Error,
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{field, instrument, Span};
+
+use crate::api::{
+    apply_child, create_child, create_job, delete_child, delete_params, list_previews, record_event, set_status,
+    update_deployment_image, ApiResources,
+};
+use crate::bluegreen::UpdateStrategy;
+use crate::build::{BuildSpec, JobResult};
+use crate::canary::CanarySpec;
+use crate::cloning::CloneSpec;
+use crate::conflicts::{self, Conflict};
+use crate::creation::{self, PartialFailure};
+use crate::delivery::{Release, SourceSpec};
+use crate::jsonnet::{self, JsonnetSpec};
+use crate::mesh::Mesh;
+use crate::ports::PortSpec;
+use crate::resources::{canary_json, deployment_manifest, service_manifest, to_json, Children};
+use crate::routing::{allocate_tcp_port, create_dns_record, json_for_host, mapping_manifest};
+use crate::statefulsets::WorkloadType;
+use crate::tekton::{PipelineRunStatus, RunResult};
+use crate::{
+    bluegreen, build, bus, capacity, cloning, conditions, cost, credentials, cronjobs, dependencies, egress, fqdn, freeze,
+    grafana, impersonation, inventory, jobs, labels, mesh, monitoring, pause, pod_security, ports, profiles, promotion, quota,
+    registries, retry, rollouts, scale, scan, scheduling, secrets, security, services, shared, snapshot, statefulsets, tcp,
+    tekton, validation, KubePreviewEnvironment,
+};
+#[cfg(feature = "vault")]
+use crate::vault;
+
+type JsonValue = serde_json::value::Value;
+
+// Tear down everything we created for an environment.  Every child is
+// attempted even if an earlier one fails, and the failures are reported
+// together as a Kubernetes Event rather than crashing the controller, and
+// returned so a preview that's still around can say so in its status.
+//
+// Once the preview itself is gone there's no status to write to, so the
+// Event is all that's left on the cluster, for as long as the API server
+// keeps Events (an hour by default).  It's also published on the bus, so
+// the notifier, the audit log and the CleanupFailed count in the metrics
+// keep a record of it.  Children left behind still carry the preview's
+// name label, and those of the kinds the sweeper knows, like Deployments
+// and Mappings, go on its next pass.  Anything else, such as DNS records,
+// Vault leases, copied Secrets or GitOps releases, has to be removed by
+// hand.
+#[instrument(skip(resources, pe))]
+async fn cleanup(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Vec<String> {
+    let children = Children::of(pe);
+    let targets = [
+        ("service", &resources.services, &children.service),
+        ("deployment", &resources.deployments, &children.deployment),
+        ("mapping", &resources.mappings, &children.mapping),
+        ("tcpmapping", &resources.tcp_mappings, &children.tcp_mapping),
+        ("service", &resources.services, &children.canary_service),
+        ("deployment", &resources.deployments, &children.canary_deployment),
+        ("mapping", &resources.mappings, &children.canary_mapping),
+        ("deployment", &resources.deployments, &children.green_deployment),
+        ("rollout", &resources.rollouts, &children.deployment),
+        ("statefulset", &resources.stateful_sets, &children.deployment),
+        ("service", &resources.services, &children.headless_service),
+        ("host", &resources.hosts, &children.host),
+        ("externalsecret", &resources.external_secrets, &children.external_secret),
+        ("job", &resources.jobs, &children.build_job),
+        ("job", &resources.jobs, &children.scan_job),
+        ("job", &resources.jobs, &children.snapshot_job),
+        ("pipelinerun", &resources.pipeline_runs, &children.pipeline_run),
+        ("configmap", &resources.config_maps, &children.dashboard),
+        ("podmonitor", &resources.pod_monitors, &children.monitor),
+        ("peerauthentication", &resources.peer_authentications, &children.peer_authentication),
+        ("networkpolicy", &resources.egress_policies, &children.egress_policy),
+    ];
+
+    let mut failures = vec![];
+    for (kind, api, name) in targets.iter() {
+        let dp = delete_params(resources, pe, kind);
+        if let Err(err) = delete_child(resources, kind, api, name, &dp).await {
+            failures.push(format!("{} {}: {}", kind, name, err));
+        }
+    }
+
+    if let (Some(_), Some(gitops)) = (&pe.spec.source, &resources.gitops) {
+        let namespace = resources.config.namespace.as_str();
+        if let Err(err) = gitops.delete(&resources.client, &pe.metadata.name, namespace).await {
+            failures.push(format!("gitops release: {}", err));
+        }
+    }
+
+    if pe.spec.jsonnet.is_some() {
+        failures.extend(jsonnet::remove(resources, pe).await);
+    }
+
+    if let Err(err) = revoke_database_credentials(resources, pe).await {
+        failures.push(format!("vault lease: {}", err));
+    }
+
+    for index in 1..pe.spec.routes.len() {
+        let name = ports::mapping_name(&pe.metadata.name, index);
+        let dp = delete_params(resources, pe, "mapping");
+        if let Err(err) = delete_child(resources, "mapping", &resources.mappings, &name, &dp).await {
+            failures.push(format!("mapping {}: {}", name, err));
+        }
+    }
+
+    // Shared services go with the last preview using them
+    if !pe.spec.shared_services.is_empty() {
+        match list_previews(resources).await {
+            Ok(previews) => {
+                for service in &pe.spec.shared_services {
+                    let key = shared::key(pe, service);
+                    if shared::in_use(&key, pe, &previews) {
+                        continue;
+                    }
+                    for (kind, api) in [("deployment", &resources.deployments), ("service", &resources.services)].iter() {
+                        let dp = delete_params(resources, pe, kind);
+                        if let Err(err) = delete_child(resources, kind, api, &key, &dp).await {
+                            failures.push(format!("shared {} {}: {}", kind, key, err));
+                        }
+                    }
+                }
+            }
+            Err(err) => failures.push(format!("shared services: {}", err)),
+        }
+    }
+
+    for job in &jobs::for_preview(pe) {
+        let name = jobs::name(&pe.metadata.name, job);
+        let dp = delete_params(resources, pe, "job");
+        if let Err(err) = delete_child(resources, "job", &resources.jobs, &name, &dp).await {
+            failures.push(format!("job {}: {}", name, err));
+        }
+    }
+
+    if pe.spec.workload_type == WorkloadType::StatefulSet {
+        let claims = &resources.persistent_volume_claims;
+        if let Err(err) = statefulsets::remove_claims(&resources.client, claims, &pe.metadata.name).await {
+            failures.push(format!("volume claims: {}", err));
+        }
+    }
+
+    if let Err(err) = cronjobs::remove(&resources.client, &resources.cron_jobs, &pe.metadata.name).await {
+        failures.push(format!("cronjobs: {}", err));
+    }
+
+    if let Err(err) = secrets::remove(&resources.client, &resources.secrets, &pe.metadata.name).await {
+        failures.push(format!("copied secrets: {}", err));
+    }
+
+    if let Some(dns) = &resources.dns {
+        if let Err(err) = dns.delete_record(&pe.spec.fqdn).await {
+            failures.push(format!("dns record {}: {}", pe.spec.fqdn, err));
+        }
+    }
+
+    if !failures.is_empty() {
+        let message = format!("Failed to clean up: {}", failures.join("; "));
+        println!("{} {}", pe.metadata.name, message);
+        record_event(resources, pe, "Warning", "CleanupFailed", &message).await;
+    }
+    failures
+}
+
+// Copy the secrets listed in `copySecrets`.  Problems are reported as an
+// event and the preview carries on without them.
+#[instrument(skip(resources, pe))]
+async fn copy_secrets(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Vec<secrets::CopiedSecret> {
+    if pe.spec.copy_secrets.is_empty() {
+        return vec![];
+    }
+    let source = match &resources.source_secrets {
+        Some(source) => source,
+        None => {
+            let message = "copySecrets is set but the controller has no SECRET_SOURCE_NAMESPACE";
+            record_event(resources, pe, "Warning", "SecretCopyFailed", message).await;
+            return vec![];
+        }
+    };
+
+    let result = secrets::replicate(&resources.client, source, &resources.secrets, &pe.metadata.name, &pe.spec.copy_secrets).await;
+    match result {
+        Ok(copied) => copied,
+        Err(err) => {
+            let message = format!("Failed to copy secrets: {}", err);
+            println!("{} {}", pe.metadata.name, message);
+            record_event(resources, pe, "Warning", "SecretCopyFailed", &message).await;
+            vec![]
+        }
+    }
+}
+
+// Mint database credentials from Vault and store them in a Secret for the
+// preview, unless that's been done already.  Returns the Secret to mount,
+// if any.
+#[cfg(feature = "vault")]
+#[instrument(skip(resources, pe))]
+async fn mint_database_credentials(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Option<secrets::CopiedSecret> {
+    let vault = resources.vault.as_ref()?;
+    let name = &pe.metadata.name;
+    let minted = secrets::CopiedSecret {
+        name: vault::secret_name(name),
+        secret_type: "Opaque".to_string(),
+    };
+
+    // The preview keeps the credentials it was created with when it's
+    // rendered again
+    if let Ok(Some(_)) = vault::lease_id(&resources.client, &resources.secrets, name).await {
+        return Some(minted);
+    }
+
+    let lease = match vault.database_credentials(&vault.database_role).await {
+        Ok(lease) => lease,
+        Err(err) => {
+            let message = format!("Failed to get database credentials from Vault: {}", err);
+            println!("{} {}", name, message);
+            record_event(resources, pe, "Warning", "VaultFailed", &message).await;
+            return None;
+        }
+    };
+
+    if let Err(err) = vault::store_credentials(&resources.client, &resources.secrets, name, &lease).await {
+        // Don't leave credentials nobody knows about lying around in Vault
+        let _ = vault.revoke(&lease.lease_id).await;
+        let message = format!("Failed to store database credentials: {}", err);
+        println!("{} {}", name, message);
+        record_event(resources, pe, "Warning", "VaultFailed", &message).await;
+        return None;
+    }
+    Some(minted)
+}
+
+#[cfg(feature = "vault")]
+async fn revoke_database_credentials(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Result<(), String> {
+    let vault = match &resources.vault {
+        Some(vault) => vault,
+        None => return Ok(()),
+    };
+    let lease_id = vault::lease_id(&resources.client, &resources.secrets, &pe.metadata.name)
+        .await
+        .map_err(|err| err.to_string())?;
+    match lease_id {
+        Some(lease_id) => vault.revoke(&lease_id).await.map_err(|err| err.to_string()),
+        None => Ok(()),
+    }
+}
+
+// Without Vault there are never any credentials to mint or revoke
+#[cfg(not(feature = "vault"))]
+async fn mint_database_credentials(_resources: &ApiResources, _pe: &KubePreviewEnvironment) -> Option<secrets::CopiedSecret> {
+    None
+}
+
+#[cfg(not(feature = "vault"))]
+async fn revoke_database_credentials(_resources: &ApiResources, _pe: &KubePreviewEnvironment) -> Result<(), String> {
+    Ok(())
+}
+
+#[instrument(skip(resources, pe))]
+async fn generate_secrets(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Option<secrets::CopiedSecret> {
+    if pe.spec.generated_secrets.is_empty() {
+        return None;
+    }
+    let name = &pe.metadata.name;
+    if let Err(err) = credentials::create(&resources.client, &resources.secrets, name, &pe.spec.generated_secrets).await {
+        let message = format!("Failed to generate secrets: {}", err);
+        println!("{} {}", name, message);
+        record_event(resources, pe, "Warning", "SecretGenerationFailed", &message).await;
+        return None;
+    }
+    Some(secrets::CopiedSecret {
+        name: credentials::secret_name(name),
+        secret_type: "Opaque".to_string(),
+    })
+}
+
+// Check the rendered children against Pod Security Standards, then ask OPA
+// whether they're allowed.  If OPA can't be reached the preview is refused
+// rather than let through unchecked.
+#[instrument(skip(resources, pe, manifests))]
+async fn check_policy(resources: &ApiResources, pe: &KubePreviewEnvironment, manifests: &[JsonValue]) -> bool {
+    let pod_security = resources
+        .config
+        .pod_security_level
+        .map(|level| (level, pod_security::violations(level, manifests)))
+        .filter(|(_, violations)| !violations.is_empty());
+
+    let message = match (pod_security, &resources.policy) {
+        (Some((level, violations)), _) => {
+            format!("Refused by the {} Pod Security Standard: {}", level, violations.join("; "))
+        }
+        (None, Some(opa)) => {
+            let preview = serde_json::to_value(pe).expect("Failed to serialize PreviewEnvironment json");
+            match opa.evaluate(&preview, manifests).await {
+                Ok(violations) if violations.is_empty() => return true,
+                Ok(violations) => format!("Refused by policy: {}", violations.join("; ")),
+                Err(err) => format!("Failed to evaluate policy: {}", err),
+            }
+        }
+        (None, None) => return true,
+    };
+
+    fail(resources, pe, "PolicyDenied", &message).await;
+    false
+}
+
+// Bring the canary in line with the spec after the preview is deployed:
+// create it, move it to a new image or weight, or remove it.  Returns the
+// fields someone else had changed too, or `None` if there was nothing to
+// do or it couldn't be done.
+#[instrument(skip(resources, pe))]
+async fn sync_canary(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Option<Vec<Conflict>> {
+    let status = pe.status.clone().unwrap_or_default();
+    if status.image.is_none() || status.canary == pe.spec.canary {
+        return None;
+    }
+
+    let children = Children::of(pe);
+    let result = match &pe.spec.canary {
+        Some(canary) => apply_canary(resources, &children, canary, &pe.spec.ports).await,
+        None => remove_canary(resources, pe, &children).await.map(|()| vec![]),
+    };
+    match result {
+        Ok(found) => {
+            set_status(resources, &pe.metadata.name, |status| {
+                status.canary = pe.spec.canary.clone();
+            })
+            .await;
+            Some(found)
+        }
+        Err(err) => {
+            let message = format!("Failed to update canary: {}", err);
+            println!("{} {}", pe.metadata.name, message);
+            record_event(resources, pe, "Warning", "CanaryFailed", &message).await;
+            None
+        }
+    }
+}
+
+async fn apply_canary(
+    resources: &ApiResources,
+    children: &Children,
+    canary: &CanarySpec,
+    ports: &[PortSpec],
+) -> Result<Vec<Conflict>, Error> {
+    let deployment: JsonValue = resources.client.request(resources.deployments.get(&children.deployment)?).await?;
+    let mapping: JsonValue = resources.client.request(resources.mappings.get(&children.mapping)?).await?;
+    let [canary_deploy, canary_service, canary_mapping] = canary_json(children, &deployment, &mapping, canary, ports);
+
+    // Existing canary children are updated in place rather than recreated,
+    // and only when the image or weight has changed what's rendered
+    let mut found = vec![];
+    found.extend_from_slice(apply_child(resources, &resources.deployments, &canary_deploy).await?.conflicts());
+    found.extend_from_slice(apply_child(resources, &resources.services, &canary_service).await?.conflicts());
+    found.extend_from_slice(apply_child(resources, &resources.mappings, &canary_mapping).await?.conflicts());
+    Ok(found)
+}
+
+async fn remove_canary(resources: &ApiResources, pe: &KubePreviewEnvironment, children: &Children) -> Result<(), Error> {
+    // The mapping goes first so no traffic is sent to a canary that's on its way out
+    let targets = [
+        ("mapping", &resources.mappings, &children.canary_mapping),
+        ("service", &resources.services, &children.canary_service),
+        ("deployment", &resources.deployments, &children.canary_deployment),
+    ];
+    for (kind, api, name) in targets.iter() {
+        let dp = delete_params(resources, pe, kind);
+        delete_child(resources, kind, api, name, &dp).await?;
+    }
+    Ok(())
+}
+
+// Give the preview the secrets it's to have: those it asked to have copied
+// in, those the External Secrets Operator fetches, database credentials
+// from Vault and any it needs generated.  Returns what its pods are to
+// mount, and the ExternalSecret that does the fetching, if any.  The
+// template rendered at startup, so a preview it can't be rendered for has
+// values that break the YAML, and is failed.
+async fn provide_secrets(
+    resources: &ApiResources,
+    pe: &KubePreviewEnvironment,
+) -> Option<(Vec<secrets::CopiedSecret>, Option<JsonValue>)> {
+    let children = Children::of(pe);
+
+    // Copy in any secrets the preview asked for
+    let mut copied = copy_secrets(resources, pe).await;
+
+    // Have the External Secrets Operator fetch the rest from the secret store
+    let external_secret = match &resources.external_secret_template {
+        Some(template) => {
+            let namespace = resources.config.namespace.as_str();
+            let name = &pe.metadata.name;
+            match template.render(name, namespace, &pe.spec.fqdn, &children.external_secret, &children.external_secret_target) {
+                Ok(external_secret) => Some(external_secret),
+                Err(err) => {
+                    fail(resources, pe, "InvalidSpec", &err).await;
+                    return None;
+                }
+            }
+        }
+        None => None,
+    };
+    if external_secret.is_some() {
+        copied.push(secrets::CopiedSecret {
+            name: children.external_secret_target.clone(),
+            secret_type: "Opaque".to_string(),
+        });
+    }
+
+    // Mint database credentials from Vault
+    if let Some(credentials) = mint_database_credentials(resources, pe).await {
+        copied.push(credentials);
+    }
+
+    // Generate any random passwords or keys the preview needs
+    if let Some(generated) = generate_secrets(resources, pe).await {
+        copied.push(generated);
+    }
+    Some((copied, external_secret))
+}
+
+// Everything rendered for a preview built from the controller's own
+// manifests.
+struct Environment<'a> {
+    // The preview's own children, in the order they're created in
+    children: Vec<(&'a RawApi, JsonValue)>,
+    // The canary's, which `sync_canary` keeps up to date once they're
+    // created
+    canary: Vec<(&'a RawApi, JsonValue)>,
+    // Shared services, in case this is the first preview to need them
+    shared: Vec<JsonValue>,
+    // The one-shot jobs, only started when the preview is created
+    jobs: Vec<JsonValue>,
+    // The Deployment, whether or not it's what runs the pods, for the cost
+    deployment: JsonValue,
+    tcp_port: Option<u16>,
+}
+
+// Render everything a preview needs, running `image`, and check it against
+// policy.  A preview that can't be rendered or isn't allowed is failed, and
+// there's nothing to create.
+async fn render_environment<'a>(
+    resources: &'a ApiResources,
+    pe: &KubePreviewEnvironment,
+    image: &str,
+    copied: &[secrets::CopiedSecret],
+    mut external_secret: Option<JsonValue>,
+    clone_source: Option<&JsonValue>,
+) -> Option<Environment<'a>> {
+    let children = Children::of(pe);
+    let host = pe.spec.fqdn.as_str();
+
+    let routes = match ports::routes(&pe.spec.ports, &pe.spec.routes) {
+        Ok(routes) => routes,
+        Err(message) => {
+            fail(resources, pe, "InvalidSpec", &message).await;
+            return None;
+        }
+    };
+
+    // A TCP service needs a listener port of its own
+    let tcp_port = match &pe.spec.tcp {
+        Some(_) => Some(allocate_tcp_port(resources, pe).await?),
+        None => None,
+    };
+
+    // Render everything up front so policy sees the whole environment
+    let profile = profiles::of(resources, pe);
+    let default_resources = profile.default_resources.or_else(|| resources.reloadable().default_resources);
+    let container_resources = pe.spec.resources.as_ref().or(default_resources.as_ref());
+    let mut test_deploy = to_json(&deployment_manifest(children.deployment.as_str(), image, container_resources));
+    secrets::attach(&mut test_deploy, copied);
+    security::harden(&mut test_deploy, pe.spec.pod_security_context.as_ref(), pe.spec.security_context.as_ref());
+    test_deploy["spec"]["replicas"] = json!(pe.spec.replicas);
+    let spread = pe.spec.spread.unwrap_or(resources.config.spread);
+    scheduling::spread(&mut test_deploy, spread, &resources.config.spread_topology_key);
+    if let Some(priority_class) = &resources.config.priority_class {
+        scheduling::set_priority_class(&mut test_deploy, priority_class);
+    }
+    if let Some(runtime_class) = pe.spec.runtime_class_name.as_ref().or(resources.config.runtime_class.as_ref()) {
+        scheduling::set_runtime_class(&mut test_deploy, runtime_class);
+    }
+    scheduling::target_platform(&mut test_deploy, pe.spec.node_os.as_deref(), pe.spec.arch.as_deref());
+    // Tell the app where the previews it depends on are
+    if !pe.spec.depends_on.is_empty() {
+        match list_previews(resources).await {
+            Ok(previews) => dependencies::inject(&mut test_deploy, pe, &previews),
+            Err(err) => println!("Failed to list previews to find the dependencies of {}: {:?}", pe.metadata.name, err),
+        }
+    }
+    shared::inject(&mut test_deploy, pe);
+    // Let the preview resolve internal test domains
+    if let Some(dns_config) = &pe.spec.dns_config {
+        test_deploy["spec"]["template"]["spec"]["dnsConfig"] = json!(dns_config);
+    }
+    if !pe.spec.host_aliases.is_empty() {
+        test_deploy["spec"]["template"]["spec"]["hostAliases"] = json!(pe.spec.host_aliases);
+    }
+    // Have Prometheus scrape the app if it serves metrics
+    let mut pod_monitor_json = pe.spec.metrics.as_ref().map(|metrics| {
+        monitoring::expose_port(&mut test_deploy, metrics);
+        monitoring::pod_monitor_json(&children.monitor, &pe.metadata.name, &children.deployment, metrics)
+    });
+    let mut test_service = to_json(&service_manifest(
+        children.service.as_str(),
+        children.deployment.as_str(),
+        pe.spec.service_type,
+        pe.spec.session_affinity,
+    ));
+    ports::expose(&mut test_deploy, &pe.spec.ports);
+    ports::publish(&mut test_service, &pe.spec.ports);
+    let routed_service = services::routed(pe, &children);
+    let mut test_mapping = to_json(&mapping_manifest(children.mapping.as_str(), host, routed_service.as_str()));
+    ports::route(&mut test_mapping, &routed_service, &routes[0]);
+    // Join the service mesh, if there is one
+    let mesh = pe.spec.mesh.unwrap_or(resources.config.mesh);
+    mesh::inject(&mut test_deploy, mesh);
+    mesh::route(&mut test_mapping, mesh);
+    ports::apply_protocol(&mut test_mapping, &mut test_service, pe.spec.protocol);
+    let mut peer_authentication_json = match mesh {
+        Mesh::Istio => Some(mesh::peer_authentication_json(&children.peer_authentication, &pe.metadata.name, &children.deployment)),
+        _ => None,
+    };
+    // A host terminates TLS, unless that happens elsewhere
+    let mut host_json = json_for_host(children.host.as_str(), host, &resources.config);
+    // A dashboard for the preview's pods, when there's a Grafana to show it
+    let mut dashboard_json = resources.config.grafana_url.as_ref().map(|_| {
+        let namespace = resources.config.namespace.as_str();
+        grafana::config_map_json(&children.dashboard, &pe.metadata.name, namespace, &children.deployment, &resources.config)
+    });
+    // Scheduled tasks, sharing the app's secrets
+    let mut cron_jobs_json: Vec<JsonValue> = pe
+        .spec
+        .cron_jobs
+        .iter()
+        .map(|cron_job| cronjobs::cron_job_json(&pe.metadata.name, cron_job, &test_deploy))
+        .collect();
+    let preview_jobs = jobs::for_preview(pe);
+    let mut jobs_json: Vec<JsonValue> = preview_jobs
+        .iter()
+        .map(|job| {
+            let mut job_json = jobs::job_json(&pe.metadata.name, job, &test_deploy);
+            // Copying the database needs the source preview's secrets too
+            if let (cloning::DATABASE_JOB, Some(source_deploy)) = (job.name.as_str(), clone_source) {
+                cloning::add_source(&mut job_json, source_deploy);
+            }
+            job_json
+        })
+        .collect();
+    // Shared services, in case this is the first preview to need them
+    let shared_json: Vec<JsonValue> = pe
+        .spec
+        .shared_services
+        .iter()
+        .flat_map(|service| shared::manifests(&shared::key(pe, service), service).to_vec())
+        .collect();
+    // Only let the preview reach approved services outside the cluster
+    let mut egress_json = egress::policy_json(
+        resources.config.egress_policy,
+        &children.egress_policy,
+        &pe.metadata.name,
+        &resources.config.egress_allowlist,
+    )
+    .await;
+
+    // Label everything the same way so it can be found by preview, owner
+    // and commit
+    let standard_labels = labels::for_preview(pe, &resources.config);
+    let mut rendered: Vec<&mut JsonValue> = vec![&mut test_deploy, &mut test_service, &mut test_mapping]
+        .into_iter()
+        .chain(host_json.as_mut())
+        .chain(external_secret.as_mut())
+        .chain(dashboard_json.as_mut())
+        .chain(pod_monitor_json.as_mut())
+        .chain(peer_authentication_json.as_mut())
+        .chain(egress_json.as_mut())
+        .chain(cron_jobs_json.iter_mut())
+        .chain(jobs_json.iter_mut())
+        .collect();
+    for manifest in rendered.iter_mut() {
+        labels::stamp(manifest, &standard_labels);
+        labels::annotate(manifest, &pe.spec.annotations);
+    }
+    // Then let the platform's plugins have their say
+    if let Err(err) = resources.plugins.mutate(&mut rendered, pe) {
+        fail(resources, pe, "PluginFailed", &format!("Failed to render the preview: {}", err)).await;
+        return None;
+    }
+
+    let mut tcp_mapping_json = pe.spec.tcp.as_ref().zip(tcp_port).map(|(tcp, listener_port)| {
+        tcp::tcp_mapping_json(&children.tcp_mapping, &pe.metadata.name, listener_port, &routed_service, tcp.port)
+    });
+    if let Some(tcp_mapping_json) = tcp_mapping_json.as_mut() {
+        labels::stamp(tcp_mapping_json, &standard_labels);
+        labels::annotate(tcp_mapping_json, &pe.spec.annotations);
+    }
+
+    // Every route after the first gets a Mapping of its own
+    let route_mappings: Vec<JsonValue> = routes
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(index, route)| {
+            let mut mapping = test_mapping.clone();
+            mapping["metadata"]["name"] = json!(ports::mapping_name(&pe.metadata.name, index));
+            ports::route(&mut mapping, &routed_service, route);
+            mapping
+        })
+        .collect();
+
+    // Argo Rollouts manages the pods instead of a Deployment if asked
+    let rollout_json = match pe.spec.strategy {
+        UpdateStrategy::ArgoRollout => {
+            let strategy = pe.spec.rollout_strategy.as_ref().unwrap_or(&resources.rollout_strategy);
+            Some(rollouts::rollout_json(&test_deploy, strategy))
+        }
+        _ => None,
+    };
+
+    // Or a StatefulSet
+    let stateful_set_json = match pe.spec.workload_type {
+        WorkloadType::StatefulSet => Some(statefulsets::stateful_set_json(
+            &test_deploy,
+            &children.headless_service,
+            &pe.metadata.name,
+            &pe.spec.volume_claim_templates,
+        )),
+        WorkloadType::Deployment => None,
+    };
+
+    // A headless Service gives each pod its own DNS name
+    let (normal_service, headless_service) = services::wanted(pe);
+    let headless_json = if headless_service {
+        Some(services::headless_json(&test_service, &children.headless_service))
+    } else {
+        None
+    };
+
+    // Send some of the traffic to a canary, when there is one
+    let canary_children = pe
+        .spec
+        .canary
+        .as_ref()
+        .map(|canary| canary_json(&children, &test_deploy, &test_mapping, canary, &pe.spec.ports));
+
+    let workload = match (&stateful_set_json, &rollout_json) {
+        (Some(stateful_set_json), _) => stateful_set_json,
+        (None, Some(rollout_json)) => rollout_json,
+        (None, None) => &test_deploy,
+    };
+    let mut manifests = vec![workload.clone(), test_mapping.clone()];
+    if normal_service {
+        manifests.push(test_service.clone());
+    }
+    manifests.extend(headless_json.clone());
+    manifests.extend(route_mappings.iter().cloned());
+    manifests.extend(tcp_mapping_json.clone());
+    manifests.extend(host_json.clone());
+    manifests.extend(external_secret.clone());
+    manifests.extend(dashboard_json.clone());
+    manifests.extend(pod_monitor_json.clone());
+    manifests.extend(peer_authentication_json.clone());
+    manifests.extend(egress_json.clone());
+    manifests.extend(shared_json.iter().cloned());
+    manifests.extend(cron_jobs_json.iter().cloned());
+    manifests.extend(jobs_json.iter().cloned());
+    if let Some(canary_children) = &canary_children {
+        manifests.extend(canary_children.iter().cloned());
+    }
+    if !check_policy(resources, pe, &manifests).await {
+        return None;
+    }
+
+    // The preview's own children, in the order they're created in
+    let mut owned: Vec<(&RawApi, JsonValue)> = vec![];
+    owned.extend(external_secret.map(|external_secret| (&resources.external_secrets, external_secret)));
+    // Lock down egress before any of the preview's pods start
+    owned.extend(egress_json.map(|egress_json| (&resources.egress_policies, egress_json)));
+    // A deployment, a rollout or a statefulset
+    match (stateful_set_json, rollout_json) {
+        (Some(stateful_set_json), _) => owned.push((&resources.stateful_sets, stateful_set_json)),
+        (None, Some(rollout_json)) => owned.push((&resources.rollouts, rollout_json)),
+        (None, None) => owned.push((&resources.deployments, test_deploy.clone())),
+    }
+    if normal_service {
+        owned.push((&resources.services, test_service));
+    }
+    owned.extend(headless_json.map(|headless_json| (&resources.services, headless_json)));
+    // A mapping for each route
+    owned.push((&resources.mappings, test_mapping));
+    owned.extend(route_mappings.into_iter().map(|mapping| (&resources.mappings, mapping)));
+    owned.extend(tcp_mapping_json.map(|tcp_mapping_json| (&resources.tcp_mappings, tcp_mapping_json)));
+    owned.extend(host_json.map(|host_json| (&resources.hosts, host_json)));
+    owned.extend(dashboard_json.map(|dashboard_json| (&resources.config_maps, dashboard_json)));
+    owned.extend(pod_monitor_json.map(|pod_monitor_json| (&resources.pod_monitors, pod_monitor_json)));
+    // The scheduled tasks
+    owned.extend(cron_jobs_json.into_iter().map(|cron_job_json| (&resources.cron_jobs, cron_job_json)));
+    // Let traffic from outside the mesh in
+    let peer_authentications = &resources.peer_authentications;
+    owned.extend(peer_authentication_json.map(|peer_authentication| (peer_authentications, peer_authentication)));
+
+    let canary = match canary_children {
+        Some([canary_deploy, canary_service, canary_mapping]) => vec![
+            (&resources.deployments, canary_deploy),
+            (&resources.services, canary_service),
+            (&resources.mappings, canary_mapping),
+        ],
+        None => vec![],
+    };
+
+    Some(Environment {
+        children: owned,
+        canary,
+        shared: shared_json,
+        jobs: jobs_json,
+        deployment: test_deploy,
+        tcp_port,
+    })
+}
+
+// Create everything a preview needs, running `image`.
+async fn create_environment(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, image: &str) {
+    let children = Children::of(&pe);
+    let (copied, external_secret) = match provide_secrets(&resources, &pe).await {
+        Some(provided) => provided,
+        None => return,
+    };
+
+    // The GitOps backend deploys the app from its own source when there
+    // is one
+    if let Some(source) = &pe.spec.source {
+        return deliver(&resources, &pe, source, image).await;
+    }
+
+    // A Jsonnet template takes the place of the built-in manifests
+    if let Some(jsonnet) = &pe.spec.jsonnet {
+        return apply_template(&resources, &pe, jsonnet, image).await;
+    }
+
+    // A clone copies its database from the preview it was cloned from
+    let clone_source = match pe.spec.clone_from.as_ref().filter(|clone| clone.database.is_some()) {
+        Some(clone) => match clone_source(&resources, clone).await {
+            Ok(source_deploy) => Some(source_deploy),
+            Err(err) => {
+                let message = format!("Failed to find {} to clone the database from: {}", clone.name, err);
+                fail(&resources, &pe, "CloneFailed", &message).await;
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let environment = match render_environment(&resources, &pe, image, &copied, external_secret, clone_source.as_ref()).await {
+        Some(environment) => environment,
+        None => return,
+    };
+
+    // Bring up the shared services, unless an earlier preview already has
+    for manifest in &environment.shared {
+        let api = if manifest["kind"] == "Service" { &resources.services } else { &resources.deployments };
+        if let Err(err) = create_child(&resources, api, manifest).await {
+            println!("Failed to create shared service for {}: {:?}", pe.metadata.name, err);
+        }
+    }
+
+    // Then the preview's own children, in order, and the canary's.  Every
+    // one is attempted even if an earlier one fails, so it's clear what's
+    // missing.
+    let owned: Vec<(&RawApi, &JsonValue)> = environment
+        .children
+        .iter()
+        .chain(&environment.canary)
+        .map(|(api, manifest)| (*api, manifest))
+        .collect();
+    let outcomes = creation::create_all(&resources, &owned).await;
+    set_status(&resources, &pe.metadata.name, |status| inventory::record(&mut status.resources, &outcomes)).await;
+    if creation::failed(&outcomes) {
+        return partial_failure(&resources, &pe, &outcomes).await;
+    }
+
+    // Start the one-shot jobs
+    for job_json in &environment.jobs {
+        if let Err(err) = create_job(&resources, job_json).await {
+            println!("Failed to create job for {}: {:?}", pe.metadata.name, err);
+        }
+    }
+
+    // Point the FQDN at the cluster when we manage DNS ourselves
+    create_dns_record(&resources, &pe).await;
+
+    let cost = cost::estimate(&environment.deployment, &resources.config);
+    let tcp_port = environment.tcp_port;
+    let preview_jobs = jobs::for_preview(&pe);
+    set_status(&resources, &pe.metadata.name, |status| {
+        status.phase = Some("Ready".to_string());
+        status.message = None;
+        status.image = Some(image.to_string());
+        status.cost = Some(cost.clone());
+        creation::clear(status, "Created");
+        status.canary = pe.spec.canary.clone();
+        status.tcp_address = tcp_port.map(|port| format!("{}:{}", pe.spec.fqdn, port));
+        status.dashboard_url = grafana::url(&resources.config, &pe.metadata.name);
+        status.logs_url = grafana::explore_url(&resources.config, &resources.config.namespace, &children.deployment);
+        if !preview_jobs.is_empty() {
+            let waiting = preview_jobs.iter().map(|job| job.name.as_str()).collect::<Vec<_>>().join(", ");
+            conditions::set(&mut status.conditions, jobs::CONDITION, "Unknown", "Running", Some(format!("Waiting for {}", waiting)));
+        }
+    })
+    .await;
+
+    if !preview_jobs.is_empty() {
+        let resources = resources.clone();
+        let pe = pe.clone();
+        tokio::spawn(async move {
+            wait_for_jobs(&resources, &pe).await;
+        });
+    }
+}
+
+
+// Bring everything a preview that's already been created has in line with
+// its spec, running `image`: the children are rendered again, and each is
+// only written to if what's rendered for it has changed, and then only in
+// what the controller owns of it (see `apply_child`).  Missing ones are
+// created again.  The canary's are left to `sync_canary`, and the one-shot
+// jobs, which have had their run, are left be.  Returns the fields someone
+// else had changed too, or `None` if it couldn't all be done.
+async fn apply_environment(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, image: &str) -> Option<Vec<Conflict>> {
+    let (copied, external_secret) = provide_secrets(resources, pe).await?;
+    let environment = render_environment(resources, pe, image, &copied, external_secret, None).await?;
+
+    // Shared services the spec didn't have before
+    for manifest in &environment.shared {
+        let api = if manifest["kind"] == "Service" { &resources.services } else { &resources.deployments };
+        if let Err(err) = create_child(resources, api, manifest).await {
+            println!("Failed to create shared service for {}: {:?}", pe.metadata.name, err);
+        }
+    }
+
+    // Like creating them, every one is attempted even if an earlier one fails
+    let mut found = vec![];
+    let mut failures = vec![];
+    for (api, manifest) in &environment.children {
+        match apply_child(resources, api, manifest).await {
+            Ok(applied) => found.extend_from_slice(applied.conflicts()),
+            Err(err) => {
+                let kind = manifest["kind"].as_str().unwrap_or_default();
+                let name = manifest["metadata"]["name"].as_str().unwrap_or_default();
+                failures.push(format!("{} {}: {}", kind, name, err));
+            }
+        }
+    }
+    if !failures.is_empty() {
+        fail(resources, pe, "UpdateFailed", &format!("Failed to update {}", failures.join("; "))).await;
+        return None;
+    }
+
+    let cost = cost::estimate(&environment.deployment, &resources.config);
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some("Ready".to_string());
+        status.message = None;
+        status.image = Some(image.to_string());
+        status.cost = Some(cost.clone());
+    })
+    .await;
+    Some(found)
+}
+
+// The Deployment of the preview being cloned, which has the secrets its
+// database is reached with.
+async fn clone_source(resources: &ApiResources, clone: &CloneSpec) -> Result<JsonValue, Error> {
+    let source: KubePreviewEnvironment = resources.client.request(resources.previews.get(&clone.name)?).await?;
+    let (active, _) = bluegreen::slots(&source);
+    resources.client.request(resources.deployments.get(&active)?).await
+}
+
+// Wait for all the preview's one-shot jobs and record how they went in the
+// JobsComplete condition.
+async fn wait_for_jobs(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let timeout = resources.config.job_timeout;
+    let preview_jobs = jobs::for_preview(pe);
+    let results = future::join_all(preview_jobs.iter().map(|job| async move {
+        let name = jobs::name(&pe.metadata.name, job);
+        let result = build::wait_for_job(&resources.client, &resources.jobs, &name, timeout).await;
+        (job.name.as_str(), result)
+    }))
+    .await;
+
+    let failures: Vec<String> = results
+        .into_iter()
+        .filter_map(|(name, result)| match result {
+            Ok(JobResult::Succeeded) => None,
+            Ok(JobResult::Failed(message)) => Some(format!("{}: {}", name, message)),
+            Err(err) => Some(format!("{}: {}", name, err)),
+        })
+        .collect();
+    let (condition_status, reason, message) = if failures.is_empty() {
+        ("True", "Succeeded", None)
+    } else {
+        let message = failures.join("; ");
+        println!("{} jobs failed: {}", pe.metadata.name, message);
+        record_event(resources, pe, "Warning", "JobFailed", &message).await;
+        ("False", "Failed", Some(message))
+    };
+    set_status(resources, &pe.metadata.name, |status| {
+        conditions::set(&mut status.conditions, jobs::CONDITION, condition_status, reason, message.clone());
+    })
+    .await;
+}
+
+fn release<'a>(resources: &'a ApiResources, pe: &'a KubePreviewEnvironment, source: &'a SourceSpec, image: &'a str) -> Release<'a> {
+    Release {
+        preview: &pe.metadata.name,
+        namespace: &resources.config.namespace,
+        source,
+        image,
+        fqdn: &pe.spec.fqdn,
+    }
+}
+
+// GitOps mode: everything the app needs comes from its chart, so all we
+// create is whatever the GitOps backend syncs it with.  Lifecycle stays
+// with us.
+async fn deliver(resources: &ApiResources, pe: &KubePreviewEnvironment, source: &SourceSpec, image: &str) {
+    let gitops = match &resources.gitops {
+        Some(gitops) => gitops,
+        None => {
+            let message = "The controller was built without a GitOps backend, so previews with a source can't be delivered";
+            fail(resources, pe, "CreateFailed", message).await;
+            return;
+        }
+    };
+    let mut manifests = gitops.render(&release(resources, pe, source, image));
+    let standard_labels = labels::for_preview(pe, &resources.config);
+    for manifest in manifests.iter_mut() {
+        labels::stamp(manifest, &standard_labels);
+        labels::annotate(manifest, &pe.spec.annotations);
+    }
+    let mut rendered: Vec<&mut JsonValue> = manifests.iter_mut().collect();
+    if let Err(err) = resources.plugins.mutate(&mut rendered, pe) {
+        fail(resources, pe, "PluginFailed", &format!("Failed to render the preview: {}", err)).await;
+        return;
+    }
+    if !check_policy(resources, pe, &manifests).await {
+        return;
+    }
+
+    if let Err(err) = gitops.apply(&resources.client, &manifests).await {
+        let message = format!("Failed to hand the preview to the GitOps backend: {}", err);
+        fail(resources, pe, "CreateFailed", &message).await;
+        return;
+    }
+
+    create_dns_record(resources, pe).await;
+
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some("Ready".to_string());
+        status.message = None;
+        status.image = Some(image.to_string());
+        status.dashboard_url = grafana::url(&resources.config, &pe.metadata.name);
+    })
+    .await;
+}
+
+// Jsonnet mode: the template decides what the preview is made of.  It's
+// evaluated again with the new image on every rollout, and whatever it
+// produces is applied over what's there.
+async fn apply_template(resources: &ApiResources, pe: &KubePreviewEnvironment, spec: &JsonnetSpec, image: &str) {
+    let rendered = match jsonnet::template(resources, spec).await {
+        Ok(template) => jsonnet::render(&template, pe, &resources.config.namespace, image),
+        Err(err) => Err(err),
+    };
+    let mut manifests = match rendered {
+        Ok(manifests) => manifests,
+        Err(err) => {
+            fail(resources, pe, "TemplateFailed", &format!("Failed to render the Jsonnet template: {}", err)).await;
+            return;
+        }
+    };
+    let standard_labels = labels::for_preview(pe, &resources.config);
+    for manifest in manifests.iter_mut() {
+        labels::stamp(manifest, &standard_labels);
+        labels::annotate(manifest, &pe.spec.annotations);
+    }
+    let mut rendered: Vec<&mut JsonValue> = manifests.iter_mut().collect();
+    if let Err(err) = resources.plugins.mutate(&mut rendered, pe) {
+        fail(resources, pe, "PluginFailed", &format!("Failed to render the preview: {}", err)).await;
+        return;
+    }
+    if !check_policy(resources, pe, &manifests).await {
+        return;
+    }
+
+    for manifest in &manifests {
+        let kind = manifest["kind"].as_str().unwrap_or_default();
+        let name = manifest["metadata"]["name"].as_str().unwrap_or_default();
+        let api = match jsonnet::api_for(resources, kind) {
+            Some(api) => api,
+            None => {
+                fail(resources, pe, "TemplateFailed", &format!("Jsonnet templates can't create a {} ({})", kind, name)).await;
+                return;
+            }
+        };
+        if let Err(err) = jsonnet::apply(resources, api, manifest).await {
+            fail(resources, pe, "CreateFailed", &format!("Failed to apply {} {}: {}", kind, name, err)).await;
+            return;
+        }
+    }
+
+    create_dns_record(resources, pe).await;
+
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some("Ready".to_string());
+        status.message = None;
+        status.image = Some(image.to_string());
+        status.dashboard_url = grafana::url(&resources.config, &pe.metadata.name);
+    })
+    .await;
+}
+
+// Start building the preview's image from source.  The build runs as a Job
+// and we wait for it in the background so other previews aren't held up;
+// when it finishes the new image is either deployed for the first time or
+// rolled out to the existing deployment.
+#[instrument(skip(resources, pe, build))]
+async fn start_build(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, build: &BuildSpec) {
+    let name = pe.metadata.name.clone();
+    let registry = match &resources.config.build_registry {
+        Some(registry) => registry,
+        None => {
+            let message = "build is set but the controller has no BUILD_REGISTRY to push to";
+            fail(resources, pe, "BuildFailed", message).await;
+            return;
+        }
+    };
+
+    let children = Children::of(pe);
+    let image = build::image_name(registry, &name, build);
+    let builder = build::builder_for(build, &resources.config);
+    let mut job = build::job_json(&children.build_job, &name, build, &image, builder.as_ref(), &resources.config);
+    labels::stamp(&mut job, &labels::for_preview(pe, &resources.config));
+
+    // Any previous build's Job has to go before we can reuse the name
+    let dp = delete_params(resources, pe, "job");
+    if let Err(err) = delete_child(resources, "job", &resources.jobs, &children.build_job, &dp).await {
+        println!("Failed to remove previous build of {}: {:?}", name, err);
+    }
+
+    if let Err(err) = create_job(resources, &job).await {
+        let message = format!("Failed to start build: {}", err);
+        println!("{} {}", name, message);
+        record_event(resources, pe, "Warning", "BuildFailed", &message).await;
+        return;
+    }
+
+    let git_ref = build.git_ref.clone();
+    set_status(resources, &name, |status| {
+        status.phase = Some("Building".to_string());
+        status.message = Some(format!("Building {}", git_ref));
+        status.build_ref = Some(git_ref.clone());
+    })
+    .await;
+
+    let resources = resources.clone();
+    let pe = pe.clone();
+    tokio::spawn(async move {
+        impersonation::as_creator(&resources.config, &pe, finish_build(&resources, &pe, &image, &git_ref)).await;
+    });
+}
+
+async fn finish_build(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, image: &str, git_ref: &str) {
+    let name = &pe.metadata.name;
+    let children = Children::of(pe);
+    let result = build::wait_for_job(&resources.client, &resources.jobs, &children.build_job, resources.config.build_timeout).await;
+
+    let failure = match result {
+        Ok(JobResult::Succeeded) => None,
+        Ok(JobResult::Failed(message)) => Some(message),
+        Err(err) => Some(err.to_string()),
+    };
+    if let Some(message) = failure {
+        let message = format!("Build of {} failed: {}", git_ref, message);
+        fail(resources, pe, "BuildFailed", &message).await;
+        return;
+    }
+
+    println!("Built {} for {}", image, name);
+    set_status(resources, name, |status| {
+        status.built_ref = Some(git_ref.to_string());
+    })
+    .await;
+    deploy_image(resources, pe, image).await;
+}
+
+// Run the preview's pipeline against the image it's running, once it's
+// Ready.  Each image gets one run; a failed run isn't retried until the
+// image changes.
+#[instrument(skip(resources, pe))]
+async fn run_pipeline(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment) {
+    let pipeline = match &pe.spec.pipeline {
+        Some(pipeline) => pipeline,
+        None => return,
+    };
+    let status = pe.status.clone().unwrap_or_default();
+    let image = match (&status.phase, &status.image) {
+        (Some(phase), Some(image)) if phase == "Ready" => image.clone(),
+        _ => return,
+    };
+    if status.pipeline_run.as_ref().map(|run| &run.image) == Some(&image) {
+        return;
+    }
+
+    let name = pe.metadata.name.clone();
+    let children = Children::of(pe);
+    set_status(resources, &name, |status| {
+        status.pipeline_run = Some(PipelineRunStatus {
+            name: children.pipeline_run.clone(),
+            image: image.clone(),
+            result: tekton::RUNNING.to_string(),
+            message: None,
+        });
+    })
+    .await;
+
+    // The previous run has to go before we can reuse the name
+    let dp = delete_params(resources, pe, "pipelinerun");
+    if let Err(err) = delete_child(resources, "pipelinerun", &resources.pipeline_runs, &children.pipeline_run, &dp).await {
+        println!("Failed to remove previous pipeline run of {}: {:?}", name, err);
+    }
+
+    let mut run = tekton::pipeline_run_json(&children.pipeline_run, &name, pipeline, &image, &pe.spec.fqdn);
+    labels::stamp(&mut run, &labels::for_preview(pe, &resources.config));
+    if let Err(err) = create_child(resources, &resources.pipeline_runs, &run).await {
+        return finish_pipeline(resources, pe, &image, Err(err)).await;
+    }
+
+    let resources = resources.clone();
+    let pe = pe.clone();
+    tokio::spawn(async move {
+        let result = tekton::wait_for_run(&resources.client, &resources.pipeline_runs, &children.pipeline_run, resources.config.pipeline_timeout).await;
+        finish_pipeline(&resources, &pe, &image, result).await;
+    });
+}
+
+async fn finish_pipeline(resources: &ApiResources, pe: &KubePreviewEnvironment, image: &str, result: Result<RunResult, Error>) {
+    let (outcome, message) = match result {
+        Ok(RunResult::Succeeded) => (tekton::SUCCEEDED, None),
+        Ok(RunResult::Failed(message)) => (tekton::FAILED, Some(message)),
+        Err(err) => (tekton::FAILED, Some(format!("Failed to run pipeline: {}", err))),
+    };
+    match &message {
+        Some(message) => {
+            println!("Pipeline for {} failed against {}: {}", pe.metadata.name, image, message);
+            record_event(resources, pe, "Warning", "PipelineFailed", message).await;
+        }
+        None => println!("Pipeline for {} passed against {}", pe.metadata.name, image),
+    }
+    set_status(resources, &pe.metadata.name, |status| {
+        if let Some(run) = status.pipeline_run.as_mut().filter(|run| run.image == image) {
+            run.result = outcome.to_string();
+            run.message = message.clone();
+        }
+    })
+    .await;
+}
+
+// Scan `image` with Trivy and check it against the configured threshold.
+// Anything that stops the scan from completing counts as a failure, so an
+// unscanned image is never deployed.
+#[instrument(skip(resources, pe))]
+async fn scan_image(resources: &ApiResources, pe: &KubePreviewEnvironment, image: &str) -> bool {
+    let name = &pe.metadata.name;
+    let max = resources.config.scan_max_critical.unwrap_or(0);
+    let children = Children::of(pe);
+
+    set_status(resources, name, |status| {
+        status.phase = Some("Scanning".to_string());
+        status.message = Some(format!("Scanning {}", image));
+        status.scanned_image = Some(image.to_string());
+    })
+    .await;
+
+    let dp = delete_params(resources, pe, "job");
+    if let Err(err) = delete_child(resources, "job", &resources.jobs, &children.scan_job, &dp).await {
+        println!("Failed to remove previous scan of {}: {:?}", name, err);
+    }
+
+    let mut job = scan::job_json(&children.scan_job, name, image, &resources.config);
+    labels::stamp(&mut job, &labels::for_preview(pe, &resources.config));
+    let result = match create_job(resources, &job).await {
+        Ok(()) => build::wait_for_job(&resources.client, &resources.jobs, &children.scan_job, resources.config.scan_timeout).await,
+        Err(err) => Err(err),
+    };
+    let report = match result {
+        Ok(JobResult::Succeeded) => scan::report(&resources.client, &resources.pods, &children.scan_job)
+            .await
+            .map_err(|err| err.to_string()),
+        Ok(JobResult::Failed(message)) => Err(message),
+        Err(err) => Err(err.to_string()),
+    };
+
+    let message = match report {
+        Ok(report) => {
+            let findings = scan::critical(&report);
+            if findings.len() <= max {
+                println!("{} passed its vulnerability scan with {} critical findings", image, findings.len());
+                return true;
+            }
+            scan::summary(image, &findings, max)
+        }
+        Err(err) => format!("Failed to scan {}: {}", image, err),
+    };
+
+    fail(resources, pe, "ScanFailed", &message).await;
+    false
+}
+
+// Deploy `image`, once it's passed the vulnerability scan if one is
+// required.  A preview that hasn't been deployed yet gets all its children
+// created; otherwise just the image is rolled out.
+async fn deploy_image(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, image: &str) {
+    if scan::required(&resources.config, pe) && !scan_image(resources, pe, image).await {
+        return;
+    }
+
+    let deployed = pe.status.as_ref().and_then(|status| status.image.as_ref()).is_some();
+    if !deployed {
+        create_environment(resources, pe, image).await;
+        return;
+    }
+
+    roll_out(resources, pe, image).await;
+}
+
+// Move an already deployed preview to a new image using its update strategy.
+async fn roll_out(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, image: &str) {
+    // The GitOps backend rolls the change out however the chart says to
+    if let Some(source) = &pe.spec.source {
+        // Never delivered without a backend, so there's nothing to update
+        let gitops = match &resources.gitops {
+            Some(gitops) => gitops,
+            None => return,
+        };
+        let release = release(resources, pe, source, image);
+        if let Err(err) = gitops.update_image(&resources.client, &release).await {
+            println!("Failed to update the GitOps release for {}: {:?}", pe.metadata.name, err);
+            return;
+        }
+        set_status(resources, &pe.metadata.name, |status| {
+            status.phase = Some("Ready".to_string());
+            status.message = None;
+            status.image = Some(image.to_string());
+        })
+        .await;
+        return;
+    }
+
+    // So does a Jsonnet template, rendered again with the new image
+    if let Some(jsonnet) = &pe.spec.jsonnet {
+        return apply_template(resources, pe, jsonnet, image).await;
+    }
+
+    if pe.spec.workload_type == WorkloadType::StatefulSet {
+        let name = Children::of(pe).deployment;
+        if let Err(err) = statefulsets::update_image(&resources.client, &resources.stateful_sets, &name, image).await {
+            println!("Failed to update statefulset {}: {:?}", name, err);
+            return;
+        }
+        set_status(resources, &pe.metadata.name, |status| {
+            status.phase = Some("Ready".to_string());
+            status.message = None;
+            status.image = Some(image.to_string());
+        })
+        .await;
+        return;
+    }
+
+    match pe.spec.strategy {
+        UpdateStrategy::BlueGreen => bluegreen::deploy(resources, pe, image).await,
+        UpdateStrategy::ArgoRollout => {
+            let name = Children::of(pe).deployment;
+            if let Err(err) = rollouts::update_image(&resources.client, &resources.rollouts, &name, image).await {
+                println!("Failed to update rollout {}: {:?}", name, err);
+                return;
+            }
+            // Argo reports its own progress through status.rollout
+            set_status(resources, &pe.metadata.name, |status| {
+                status.phase = Some("Ready".to_string());
+                status.message = None;
+                status.image = Some(image.to_string());
+            })
+            .await;
+        }
+        UpdateStrategy::Rolling => {
+            let (active, _) = bluegreen::slots(pe);
+            update_deployment_image(resources, &active, image).await;
+            set_status(resources, &pe.metadata.name, |status| {
+                status.phase = Some("Ready".to_string());
+                status.message = None;
+                status.image = Some(image.to_string());
+            })
+            .await;
+        }
+    }
+}
+
+// Deploy the spec's image in the background when it has to be scanned
+// first, since a scan can take minutes.
+fn deploy_spec_image(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment) {
+    let resources = resources.clone();
+    let pe = pe.clone();
+    tokio::spawn(async move {
+        impersonation::as_creator(&resources.config, &pe, deploy_image(&resources, &pe, &pe.spec.image)).await;
+    });
+}
+
+// Copy the preview into a long-lived namespace, then clear the request so
+// it's only done once.
+async fn promote(resources: &ApiResources, pe: &KubePreviewEnvironment, namespace: &str, fqdn: Option<&str>) {
+    let name = &pe.metadata.name;
+    let result = match fqdn {
+        Some(fqdn) => {
+            let target = RawApi::customResource("previewenvironments")
+                .group("platform9.com")
+                .within(namespace);
+            let promoted = promotion::promoted_json(pe, fqdn, &resources.config);
+            let data = serde_json::to_vec(&promoted).expect("Failed to serialize PreviewEnvironment json");
+            let created = match target.create(&PostParams::default(), data) {
+                Ok(request) => resources.client.request::<Void>(request).await.map(|_| ()),
+                Err(err) => Err(err),
+            };
+            created.map_err(|err| format!("Failed to promote to {}: {}", namespace, err))
+        }
+        None => Err(format!("Set {} to the FQDN to promote under", promotion::FQDN_ANNOTATION)),
+    };
+
+    let cleared = resources
+        .client
+        .update(&resources.previews, name, |current: &mut KubePreviewEnvironment| {
+            current.metadata.annotations.remove(promotion::PROMOTE_ANNOTATION);
+            current.metadata.annotations.remove(promotion::FQDN_ANNOTATION);
+        })
+        .await;
+    if let Err(err) = cleared {
+        println!("Failed to clear promotion request on {}: {:?}", name, err);
+    }
+
+    match result {
+        Ok(()) => {
+            let message = format!("Promoted to {}/{} at {}", namespace, name, fqdn.unwrap_or_default());
+            println!("{} {}", name, message);
+            record_event(resources, pe, "Normal", "Promoted", &message).await;
+            set_status(resources, name, |status| {
+                status.promoted_to = Some(format!("{}/{}", namespace, name));
+            })
+            .await;
+        }
+        Err(message) => {
+            println!("{} {}", name, message);
+            record_event(resources, pe, "Warning", "PromoteFailed", &message).await;
+        }
+    }
+}
+
+// Queue the preview if starting it would take its owner or the namespace
+// over quota, and look again in a while.  Returns whether it can start now.
+async fn check_quota(resources: &ApiResources, pe: &KubePreviewEnvironment) -> bool {
+    let config = &resources.config;
+    if config.max_previews_per_owner.is_none() && config.max_previews_per_namespace.is_none() {
+        return true;
+    }
+
+    let previews = match list_previews(resources).await {
+        Ok(previews) => previews,
+        Err(err) => {
+            println!("Failed to list previews to check quota, starting {} anyway: {:?}", pe.metadata.name, err);
+            return true;
+        }
+    };
+    let message = match quota::exceeded(config, pe, &previews) {
+        Some(message) => message,
+        None => return true,
+    };
+
+    println!("Queueing {}: {}", pe.metadata.name, message);
+    if !quota::is_queued(pe) {
+        record_event(resources, pe, "Normal", "Queued", &message).await;
+    }
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some(quota::QUEUED.to_string());
+        status.message = Some(message.clone());
+    })
+    .await;
+    // Deleting a preview starts the ones queued behind it, but not if the
+    // controller misses the deletion, e.g. while it's restarting
+    resources.requeue.after(&pe.metadata.name, config.quota_retry_interval);
+    false
+}
+
+// Queue the preview if the cluster hasn't room for its pods, and look
+// again in a while.  Returns whether it can start now.
+async fn check_capacity(resources: &ApiResources, pe: &KubePreviewEnvironment) -> bool {
+    if !resources.config.check_capacity {
+        return true;
+    }
+
+    let message = match capacity::shortage(resources, pe).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return true,
+        Err(err) => {
+            println!("Failed to check capacity, starting {} anyway: {:?}", pe.metadata.name, err);
+            return true;
+        }
+    };
+
+    println!("Queueing {}: {}", pe.metadata.name, message);
+    if !quota::is_queued(pe) {
+        record_event(resources, pe, "Normal", "Queued", &message).await;
+    }
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some(quota::QUEUED.to_string());
+        status.message = Some(message.clone());
+    })
+    .await;
+    resources.requeue.after(&pe.metadata.name, resources.config.capacity_retry_interval);
+    false
+}
+
+// Hold the preview back until everything it depends on is Ready.  Returns
+// whether it can start now.
+async fn check_dependencies(resources: &ApiResources, pe: &KubePreviewEnvironment) -> bool {
+    if pe.spec.depends_on.is_empty() {
+        return true;
+    }
+
+    let previews = match list_previews(resources).await {
+        Ok(previews) => previews,
+        Err(err) => {
+            println!("Failed to list previews to check dependencies, starting {} anyway: {:?}", pe.metadata.name, err);
+            return true;
+        }
+    };
+    let unready = dependencies::unready(pe, &previews);
+    if unready.is_empty() {
+        set_status(resources, &pe.metadata.name, |status| {
+            conditions::set(&mut status.conditions, dependencies::CONDITION, "True", "Ready", None);
+        })
+        .await;
+        return true;
+    }
+
+    let message = format!("Waiting for {} to be Ready", unready.join(", "));
+    println!("{} {}", pe.metadata.name, message);
+    if !dependencies::is_waiting(pe) {
+        record_event(resources, pe, "Normal", "WaitingForDependencies", &message).await;
+    }
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some(dependencies::WAITING.to_string());
+        status.message = Some(message.clone());
+        conditions::set(&mut status.conditions, dependencies::CONDITION, "False", "NotReady", Some(message.clone()));
+    })
+    .await;
+    false
+}
+
+// Refuse a spec that can't work, before anything is created for it.  It's
+// marked Failed but not retried, since it will fail the same way until the
+// spec changes.  Returns whether it can go ahead.
+async fn check_spec(resources: &ApiResources, pe: &KubePreviewEnvironment) -> bool {
+    let problems = validation::problems(pe);
+    if problems.is_empty() {
+        let conditions = pe.status.as_ref().map(|status| status.conditions.as_slice()).unwrap_or_default();
+        if conditions::is_true(conditions, validation::CONDITION) {
+            set_status(resources, &pe.metadata.name, |status| {
+                conditions::set(&mut status.conditions, validation::CONDITION, "False", "Valid", None);
+            })
+            .await;
+        }
+        return true;
+    }
+
+    let message = format!("Invalid spec: {}", problems.join("; "));
+    println!("{} {}", pe.metadata.name, message);
+    record_event(resources, pe, "Warning", "InvalidSpec", &message).await;
+    let generation = pe.metadata.generation;
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some("Failed".to_string());
+        status.message = Some(message.clone());
+        status.observed_generation = generation;
+        conditions::set(&mut status.conditions, validation::CONDITION, "True", "InvalidSpec", Some(message.clone()));
+    })
+    .await;
+    false
+}
+
+// Refuse the preview if any of its images come from a registry it isn't
+// allowed to use, the same way as an invalid spec.  Returns whether it can
+// go ahead.
+async fn check_registries(resources: &ApiResources, pe: &KubePreviewEnvironment) -> bool {
+    let allowed = registries::allowed(resources, &profiles::of(resources, pe));
+    let message = match registries::check(&allowed, pe) {
+        Some(message) => message,
+        None => {
+            let conditions = pe.status.as_ref().map(|status| status.conditions.as_slice()).unwrap_or_default();
+            if conditions::is_true(conditions, registries::CONDITION) {
+                set_status(resources, &pe.metadata.name, |status| {
+                    conditions::set(&mut status.conditions, registries::CONDITION, "False", "Allowed", None);
+                })
+                .await;
+            }
+            return true;
+        }
+    };
+
+    let message = format!("Disallowed image: {}", message);
+    println!("{} {}", pe.metadata.name, message);
+    record_event(resources, pe, "Warning", "DisallowedImage", &message).await;
+    let generation = pe.metadata.generation;
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some("Failed".to_string());
+        status.message = Some(message.clone());
+        status.observed_generation = generation;
+        conditions::set(&mut status.conditions, registries::CONDITION, "True", "DisallowedImage", Some(message.clone()));
+    })
+    .await;
+    false
+}
+
+// Refuse the preview if it has no FQDN, or another one already has its
+// FQDN, since two Mappings for the same host would split its traffic
+// between them.  Returns whether it can go ahead.
+async fn check_fqdn(resources: &ApiResources, pe: &KubePreviewEnvironment) -> bool {
+    if pe.spec.fqdn.is_empty() {
+        let message = fqdn::from_template(&resources.config, &profiles::of(resources, pe), pe).err().unwrap_or_default();
+        fail(resources, pe, "InvalidFqdn", &message).await;
+        return false;
+    }
+    let holder = match resources.fqdns.holder(&pe.spec.fqdn, &pe.metadata.name) {
+        Some(holder) => holder,
+        None => {
+            let conditions = pe.status.as_ref().map(|status| status.conditions.as_slice()).unwrap_or_default();
+            if conditions.iter().any(|condition| condition.condition_type == fqdn::CONDITION && condition.status == "False") {
+                set_status(resources, &pe.metadata.name, |status| {
+                    conditions::set(&mut status.conditions, fqdn::CONDITION, "True", "Unique", None);
+                })
+                .await;
+            }
+            return true;
+        }
+    };
+
+    let message = format!("fqdn {} is already used by preview {}", pe.spec.fqdn, holder);
+    set_status(resources, &pe.metadata.name, |status| {
+        conditions::set(&mut status.conditions, fqdn::CONDITION, "False", "Conflict", Some(message.clone()));
+    })
+    .await;
+    fail(resources, pe, "FqdnConflict", &message).await;
+    false
+}
+
+// Start the previews that were waiting on `pe`, if it was the last thing
+// they needed.
+async fn start_dependents(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment) {
+    let previews = match list_previews(resources).await {
+        Ok(previews) => previews,
+        Err(err) => {
+            println!("Failed to list previews waiting on {}: {:?}", pe.metadata.name, err);
+            return;
+        }
+    };
+    let waiting = previews
+        .iter()
+        .filter(|other| dependencies::is_waiting(other) && dependencies::depends_on(other, &pe.metadata.name));
+    for dependent in waiting {
+        impersonation::as_creator(&resources.config, dependent, start_environment(resources, dependent)).await;
+    }
+}
+
+// Let the previews that depend on `pe` know it's gone.  They're left
+// running; what happens to them is up to their owners.
+async fn warn_dependents(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let previews = match list_previews(resources).await {
+        Ok(previews) => previews,
+        Err(err) => {
+            println!("Failed to list previews depending on {}: {:?}", pe.metadata.name, err);
+            return;
+        }
+    };
+    let message = format!("{}, which this preview depends on, was deleted", pe.metadata.name);
+    for dependent in previews.iter().filter(|other| dependencies::depends_on(other, &pe.metadata.name)) {
+        record_event(resources, dependent, "Warning", "DependencyDeleted", &message).await;
+        set_status(resources, &dependent.metadata.name, |status| {
+            conditions::set(&mut status.conditions, dependencies::CONDITION, "False", "DependencyDeleted", Some(message.clone()));
+        })
+        .await;
+    }
+}
+
+// Start a new preview, or queue it if it's over quota, there's no room for
+// it or there's a freeze on.
+async fn start_environment(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment) {
+    if pause::is_paused(pe) {
+        return pause::hold(resources, pe).await;
+    }
+    if !check_spec(resources, pe).await || !check_registries(resources, pe).await || !check_fqdn(resources, pe).await {
+        return;
+    }
+    if let Some(window) = freeze::current(resources) {
+        return freeze::hold(resources, pe, &window).await;
+    }
+    if !check_quota(resources, pe).await || !check_dependencies(resources, pe).await {
+        return;
+    }
+    if !check_capacity(resources, pe).await {
+        return;
+    }
+
+    match &pe.spec.build {
+        Some(build) => start_build(resources, pe, build).await,
+        None if scan::required(&resources.config, pe) => deploy_spec_image(resources, pe),
+        None => create_environment(resources, pe, &pe.spec.image).await,
+    }
+}
+
+// A preview went away, so start whichever queued previews now fit, oldest
+// first.
+async fn admit_queued(resources: &Arc<ApiResources>) {
+    let config = &resources.config;
+    if config.max_previews_per_owner.is_none() && config.max_previews_per_namespace.is_none() && !config.check_capacity {
+        return;
+    }
+
+    let previews = match list_previews(resources).await {
+        Ok(previews) => previews,
+        Err(err) => {
+            println!("Failed to list queued previews: {:?}", err);
+            return;
+        }
+    };
+    for pe in previews.iter().filter(|pe| quota::is_queued(pe)) {
+        impersonation::as_creator(&resources.config, pe, start_environment(resources, pe)).await;
+    }
+}
+
+// Mark the preview Failed, and either schedule another attempt or, once
+// it's out of them, leave it be until it's changed or asked to retry.
+pub async fn fail(resources: &ApiResources, pe: &KubePreviewEnvironment, reason: &str, message: &str) {
+    println!("{} {}", pe.metadata.name, message);
+    record_event(resources, pe, "Warning", reason, message).await;
+
+    let max_failures = resources.reloadable().max_failures;
+    let generation = pe.metadata.generation;
+    let mut failures = 0;
+    set_status(resources, &pe.metadata.name, |status| {
+        failures = status.failures.unwrap_or(0) + 1;
+        status.phase = Some("Failed".to_string());
+        status.message = Some(message.to_string());
+        status.failures = Some(failures);
+        // This spec has had its go
+        status.observed_generation = generation;
+        if failures >= max_failures {
+            conditions::set(&mut status.conditions, retry::CONDITION, "True", "RetriesExhausted", Some(message.to_string()));
+        }
+    })
+    .await;
+
+    if failures >= max_failures {
+        let message = format!("Giving up after {} failed attempts: {}", failures, message);
+        record_event(resources, pe, "Warning", "RetriesExhausted", &message).await;
+    } else {
+        resources.requeue.after(&pe.metadata.name, retry::backoff(failures));
+    }
+}
+
+// Some of the preview's children couldn't be created.  It's tried again
+// like any other failure until it's out of time or retries, and then given
+// up on the way `PARTIAL_FAILURE` says.  See `creation`.
+async fn partial_failure(resources: &ApiResources, pe: &KubePreviewEnvironment, outcomes: &[creation::Outcome]) {
+    let summary = creation::summary(outcomes);
+    let deadline = creation::deadline(pe, &resources.config);
+    let failures = pe.status.as_ref().and_then(|status| status.failures).unwrap_or(0) + 1;
+    if Utc::now() < deadline && failures < resources.reloadable().max_failures {
+        // The first attempt starts the clock
+        let deadline = deadline.to_rfc3339();
+        set_status(resources, &pe.metadata.name, |status| {
+            status.create_deadline.get_or_insert_with(|| deadline.clone());
+        })
+        .await;
+        return fail(resources, pe, "CreateFailed", &format!("Failed to create every child: {}", summary)).await;
+    }
+
+    // Either way it's left be until it's changed or asked to retry
+    let mut cleaned_up = None;
+    let (phase, reason, condition, message) = match resources.config.partial_failure {
+        PartialFailure::Degrade => {
+            let message = format!("Gave up creating every child: {}", summary);
+            ("Degraded", "Degraded", creation::CONDITION, message)
+        }
+        PartialFailure::RollBack => {
+            let failures = cleanup(resources, pe).await;
+            let message = if failures.is_empty() {
+                format!("Gave up creating every child and deleted the rest: {}", summary)
+            } else {
+                format!("Gave up creating every child, and couldn't delete all the rest: {}", summary)
+            };
+            cleaned_up = Some(failures);
+            ("Failed", "RolledBack", retry::CONDITION, message)
+        }
+    };
+    println!("{} {}", pe.metadata.name, message);
+    record_event(resources, pe, "Warning", reason, &message).await;
+    let generation = pe.metadata.generation;
+    set_status(resources, &pe.metadata.name, |status| {
+        status.phase = Some(phase.to_string());
+        status.message = Some(message.clone());
+        status.observed_generation = generation;
+        conditions::set(&mut status.conditions, condition, "True", reason, Some(message.clone()));
+        // What a rollback left behind, until one deletes everything
+        match cleaned_up.as_deref() {
+            Some([]) => conditions::set(&mut status.conditions, creation::CLEANUP_CONDITION, "False", "CleanedUp", None),
+            Some(failures) => {
+                let message = format!("Failed to delete {}", failures.join("; "));
+                conditions::set(&mut status.conditions, creation::CLEANUP_CONDITION, "True", "CleanupFailed", Some(message));
+            }
+            None => {}
+        }
+    })
+    .await;
+}
+
+// Start counting failures afresh.
+async fn clear_failures(resources: &ApiResources, pe: &KubePreviewEnvironment, reason: &str) {
+    if retry::requested(pe) {
+        let result = resources
+            .client
+            .update(&resources.previews, &pe.metadata.name, |current: &mut KubePreviewEnvironment| {
+                current.metadata.annotations.remove(retry::RETRY_ANNOTATION);
+            })
+            .await;
+        if let Err(err) = result {
+            println!("Failed to remove retry annotation from {}: {:?}", pe.metadata.name, err);
+        }
+    }
+    set_status(resources, &pe.metadata.name, |status| {
+        status.failures = None;
+        creation::clear(status, reason);
+        if status.conditions.iter().any(|condition| condition.condition_type == retry::CONDITION) {
+            conditions::set(&mut status.conditions, retry::CONDITION, "False", reason, None);
+        }
+    })
+    .await;
+}
+
+// Whether the spec has changed since it was last acted on.  The API server
+// only bumps the generation for spec changes, since status is a subresource.
+fn spec_changed(pe: &KubePreviewEnvironment) -> bool {
+    let observed = pe.status.as_ref().and_then(|status| status.observed_generation);
+    observed.is_none() || observed != pe.metadata.generation
+}
+
+// Record that the current spec has been acted on.  Until the preview has been
+// created, updates that wait on it (like blue-green ones) still need every
+// event, so nothing is recorded before then.
+async fn observe_generation(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let created = pe.status.as_ref().map_or(false, |status| status.image.is_some());
+    if !created || pe.metadata.generation.is_none() || !spec_changed(pe) {
+        return;
+    }
+    let generation = pe.metadata.generation;
+    set_status(resources, &pe.metadata.name, |status| status.observed_generation = generation).await;
+}
+
+// Reconcile every preview that already exists, as if it had just been seen.
+// Previews the controller has never touched have no status and are started
+// from scratch; the rest are brought up to date with their spec.  Handling
+// the same preview again when the watch reports it is harmless, since
+// children are only written to when what's rendered for them changes.
+pub async fn reconcile_existing(resources: &Arc<ApiResources>) {
+    let previews = match list_previews(resources).await {
+        Ok(previews) => previews,
+        Err(err) => {
+            println!("Failed to list existing previews: {:?}", err);
+            return;
+        }
+    };
+    println!("Reconciling {} existing PreviewEnvironments", previews.len());
+    // Claimed oldest first, so whichever preview had an FQDN before the
+    // restart still has it
+    for pe in &previews {
+        resources.fqdns.claim(pe);
+    }
+    for pe in previews {
+        // Pending retries only survive the restart in the state store, and
+        // whichever is due first is kept
+        if retry::is_failed(&pe) && !retry::exhausted(&pe) {
+            let failures = pe.status.as_ref().and_then(|status| status.failures).unwrap_or(0);
+            resources.requeue.after(&pe.metadata.name, retry::backoff(failures));
+        }
+        let event = match pe.status {
+            None => WatchEvent::Added(pe),
+            Some(_) => WatchEvent::Modified(pe),
+        };
+        handle(resources, event).await;
+    }
+    // Room may have been freed while the controller was down
+    admit_queued(resources).await;
+}
+
+// Bring a preview in line with its spec.  This is what a Modified event
+// does, and what a requeued preview gets when its time comes round.
+pub async fn reconcile(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment, requeued: bool) {
+    // Nothing more to do for a preview on its way out than save its
+    // data
+    if snapshot::is_deleting(pe) {
+        return snapshot::on_delete(resources, pe).await;
+    }
+    snapshot::protect(resources, pe).await;
+
+    // Hands off while it's paused
+    if pause::is_paused(pe) {
+        return;
+    }
+    // Paused before it was created, so it's created now
+    if pause::is_held(pe) {
+        return start_environment(resources, pe).await;
+    }
+    // Held for a freeze, which may be over now
+    if freeze::is_held(pe) {
+        return start_environment(resources, pe).await;
+    }
+
+    // Scale down the old blue-green release once its rollback window is up
+    bluegreen::retire_expired(resources, pe).await;
+
+    // Anything waiting on this preview can start once it's Ready, and any
+    // failures before now no longer count
+    if pe.status.as_ref().and_then(|status| status.phase.as_deref()) == Some("Ready") {
+        start_dependents(resources, pe).await;
+        if pe.status.as_ref().and_then(|status| status.failures).is_some() {
+            clear_failures(resources, pe, "Recovered").await;
+        }
+    }
+
+    // A queued preview that comes back on its own is due to see whether
+    // there's room for it now
+    if quota::is_queued(pe) && requeued {
+        return start_environment(resources, pe).await;
+    }
+    // Nothing exists yet for a queued preview, or one waiting on
+    // its dependencies
+    if quota::is_queued(pe) || dependencies::is_waiting(pe) {
+        return;
+    }
+
+    if pe.metadata.annotations.contains_key(bluegreen::ROLLBACK_ANNOTATION) {
+        bluegreen::rollback(resources, pe).await;
+        return;
+    }
+
+    if let Some((namespace, fqdn)) = promotion::requested(pe) {
+        promote(resources, pe, namespace, fqdn).await;
+        return;
+    }
+
+    // A failed preview goes again when its backoff is up.  Once it's out of
+    // retries it waits for a new spec or the retry annotation.
+    if retry::is_failed(pe) || retry::requested(pe) {
+        if retry::requested(pe) || spec_changed(pe) {
+            clear_failures(resources, pe, "Retrying").await;
+        } else if !requeued || retry::exhausted(pe) {
+            return;
+        }
+        let created = pe.status.as_ref().map_or(false, |status| status.image.is_some());
+        if !created {
+            return start_environment(resources, pe).await;
+        }
+        return update_environment(resources, &retry::forget_attempts(pe)).await;
+    }
+
+    // Status updates, our own included, don't change the spec, so
+    // there's nothing to bring in line with it.  A requeued reconcile
+    // checks everything regardless.
+    if !requeued && !spec_changed(pe) {
+        if let Some(found) = sync_canary(resources, pe).await {
+            conflicts::report(resources, pe, &found).await;
+        }
+        run_pipeline(resources, pe).await;
+        return;
+    }
+    update_environment(resources, pe).await;
+}
+
+// Roll whatever changed in the spec out to the preview's resources.
+async fn update_environment(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment) {
+    if !check_spec(resources, pe).await || !check_registries(resources, pe).await || !check_fqdn(resources, pe).await {
+        return;
+    }
+    // What someone else changed of the children that were brought in line,
+    // if any were
+    let mut found = None;
+    let mut rendered = false;
+    match &pe.spec.build {
+        // Build each ref once.  A failed build only goes again on a
+        // retry or once the ref moves on.
+        Some(build) => {
+            let status = pe.status.clone().unwrap_or_default();
+            if status.build_ref.as_ref() != Some(&build.git_ref) {
+                start_build(resources, pe, build).await;
+            }
+        }
+        // Scan each image once.  An image that fails is only rescanned
+        // on a retry or once the spec points at a different one.
+        None if scan::required(&resources.config, pe) => {
+            let status = pe.status.clone().unwrap_or_default();
+            if status.scanned_image.as_ref() != Some(&pe.spec.image) {
+                deploy_spec_image(resources, pe);
+            }
+        }
+        // Blue-green updates go once per image, so a failed one
+        // isn't retried on every status change
+        None if pe.spec.strategy == UpdateStrategy::BlueGreen => {
+            let status = pe.status.clone().unwrap_or_default();
+            let attempted = status.deploying_image.as_ref() == Some(&pe.spec.image);
+            if status.image.is_some() && status.image.as_ref() != Some(&pe.spec.image) && !attempted {
+                roll_out(resources, pe, &pe.spec.image).await;
+            }
+        }
+        // Argo, the GitOps backend, the Jsonnet template or the
+        // StatefulSet controller takes over once it has the new image
+        None if pe.spec.strategy == UpdateStrategy::ArgoRollout
+            || pe.spec.source.is_some()
+            || pe.spec.jsonnet.is_some()
+            || pe.spec.workload_type == WorkloadType::StatefulSet =>
+        {
+            let status = pe.status.clone().unwrap_or_default();
+            if status.image.is_some() && status.image.as_ref() != Some(&pe.spec.image) {
+                roll_out(resources, pe, &pe.spec.image).await;
+            }
+        }
+        // Everything else is rendered again and brought in line, once
+        // it's been created
+        None => {
+            let created = pe.status.as_ref().map_or(false, |status| status.image.is_some());
+            if created {
+                found = apply_environment(resources, pe, &pe.spec.image).await;
+                rendered = true;
+            }
+        }
+    }
+    scale::sync(resources, pe).await;
+    if let Some(canary) = sync_canary(resources, pe).await {
+        found.get_or_insert_with(Vec::new).extend(canary);
+    }
+    if let Some(found) = found {
+        conflicts::report(resources, pe, &found).await;
+    }
+    // Rendering the children again copies the secrets too
+    if !rendered {
+        copy_secrets(resources, pe).await;
+    }
+    run_pipeline(resources, pe).await;
+    observe_generation(resources, pe).await;
+}
+
+#[instrument(name = "reconcile", skip(resources, event), fields(name = field::Empty))]
+pub async fn handle(resources: &Arc<ApiResources>, mut event: WatchEvent<KubePreviewEnvironment>) {
+    if let WatchEvent::Added(pe) | WatchEvent::Modified(pe) | WatchEvent::Deleted(pe) = &mut event {
+        let profile = profiles::of(resources, pe);
+        fqdn::fill(&resources.config, &profile, pe);
+    }
+    // The index keeps up even in maintenance mode, so the admission webhook
+    // has it to go on
+    match &event {
+        WatchEvent::Added(pe) | WatchEvent::Modified(pe) => resources.fqdns.claim(pe),
+        WatchEvent::Deleted(pe) => {
+            // Whoever was waiting for the FQDN can have it now
+            for name in resources.fqdns.release(&pe.metadata.name) {
+                resources.requeue.after(&name, Duration::from_secs(0));
+            }
+        }
+        WatchEvent::Error(_) => {}
+    }
+    // Whatever's following along hears about it first, maintenance or not
+    match &event {
+        WatchEvent::Added(pe) => resources.bus.publish(bus::Event::new(pe, "Normal", "Added", "")),
+        WatchEvent::Modified(pe) => resources.bus.publish(bus::Event::new(pe, "Normal", "Modified", "")),
+        WatchEvent::Deleted(pe) => resources.bus.publish(bus::Event::new(pe, "Normal", "Deleted", "")),
+        WatchEvent::Error(_) => {}
+    }
+    // Everything a preview's events lead to changes something, so they're
+    // only logged until maintenance is over
+    if resources.config.maintenance {
+        match &event {
+            WatchEvent::Added(pe) | WatchEvent::Modified(pe) | WatchEvent::Deleted(pe) => {
+                println!("Maintenance mode, not reconciling {}", pe.metadata.name)
+            }
+            WatchEvent::Error(err) => println!("{:?}", err),
+        }
+        return;
+    }
+    match event {
+        WatchEvent::Added(pe) => {
+            Span::current().record("name", &pe.metadata.name.as_str());
+            println!("Add PreviewEnvironment name: {}", pe.metadata.name);
+
+            // Seen for the first time on its way out, e.g. after a restart
+            if snapshot::is_deleting(&pe) {
+                return snapshot::on_delete(&resources, &pe).await;
+            }
+            snapshot::protect(&resources, &pe).await;
+            impersonation::as_creator(&resources.config, &pe, start_environment(&resources, &pe)).await;
+        }
+        WatchEvent::Deleted(pe) => {
+            Span::current().record("name", &pe.metadata.name.as_str());
+            println!("Deleted PreviewEnvironment name: {}", pe.metadata.name);
+            resources.debounce.forget(&pe.metadata.name);
+            cleanup(&resources, &pe).await;
+            warn_dependents(&resources, &pe).await;
+            admit_queued(&resources).await;
+        },
+
+        WatchEvent::Modified(pe) => {
+            Span::current().record("name", &pe.metadata.name.as_str());
+            println!("Modified PreviewEnvironment name: {}", pe.metadata.name);
+            // Held until it stops changing, unless debouncing is off
+            if resources.config.debounce.as_secs() > 0 {
+                return resources.debounce.hold(pe);
+            }
+            impersonation::as_creator(&resources.config, &pe, reconcile(&resources, &pe, false)).await;
+        }
+        WatchEvent::Error(err) => println!("{:?}", err),
+    }
+}
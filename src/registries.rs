@@ -0,0 +1,78 @@
+//! Keeps previews to images from registries the cluster trusts.  With
+//! `ALLOWED_REGISTRIES` set, or `allowed_registries` in the team's profile,
+//! every image a preview runs has to come from one of them: its own, its
+//! canary's, its jobs', its cron jobs' and its shared services'.  An entry
+//! is a registry, like `ghcr.io`, or a repository in one, like
+//! `ghcr.io/acme`, which allows everything under it.  Images are compared
+//! the way Docker names them, so `nginx` is `docker.io/library/nginx`.
+//!
+//! The validating webhook refuses a preview that doesn't keep to them, and
+//! the controller marks one that got past it Failed with the
+//! `DisallowedImage` condition, without creating anything, until its spec
+//! changes.  Images the controller picks itself, like a build's, aren't
+//! checked.
+use crate::profiles::Profile;
+use crate::webhook;
+use crate::{ApiResources, KubePreviewEnvironment};
+
+/// Type of the condition that says which images aren't allowed.
+pub const CONDITION: &str = "DisallowedImage";
+
+/// Whether `image` comes from one of the `allowed` registries or
+/// repositories.
+pub fn allows(allowed: &[String], image: &str) -> bool {
+    let (host, repository, _) = webhook::split_image(image);
+    let name = format!("{}/{}/", host, repository);
+    allowed.iter().any(|entry| {
+        // Spelled the way the image's name is, with something under it so
+        // an organization on Docker Hub isn't taken for an official image
+        let entry = entry.trim_end_matches('/');
+        let (entry_host, entry_repository, _) = webhook::split_image(&format!("{}/_", entry));
+        let prefix = if entry.contains('/') {
+            format!("{}/{}", entry_host, entry_repository.trim_end_matches('_'))
+        } else {
+            format!("{}/", entry_host)
+        };
+        name.starts_with(&prefix)
+    })
+}
+
+/// Every image `pe` names, with where in the spec it's named.
+pub fn images(pe: &KubePreviewEnvironment) -> Vec<(String, &str)> {
+    let spec = &pe.spec;
+    let mut images = vec![("image".to_string(), spec.image.as_str())];
+    if let Some(canary) = &spec.canary {
+        images.push(("canary.image".to_string(), &canary.image));
+    }
+    images.extend(spec.jobs.iter().map(|job| (format!("jobs[{}].image", job.name), job.image.as_str())));
+    images.extend(spec.cron_jobs.iter().map(|cron_job| (format!("cronJobs[{}].image", cron_job.name), cron_job.image.as_str())));
+    images.extend(
+        spec.shared_services.iter().map(|shared| (format!("sharedServices[{}].image", shared.name), shared.image.as_str())),
+    );
+    images.retain(|(_, image)| !image.is_empty());
+    images
+}
+
+/// The registries `pe` may use: its team's, or else the controller's.
+pub fn allowed(resources: &ApiResources, profile: &Profile) -> Vec<String> {
+    match &profile.allowed_registries {
+        Some(allowed) => allowed.clone(),
+        None => resources.config.allowed_registries.clone(),
+    }
+}
+
+/// What's wrong with `pe`'s images, if anything is.
+pub fn check(allowed: &[String], pe: &KubePreviewEnvironment) -> Option<String> {
+    if allowed.is_empty() {
+        return None;
+    }
+    let disallowed: Vec<String> = images(pe)
+        .into_iter()
+        .filter(|(_, image)| !allows(allowed, image))
+        .map(|(field, image)| format!("{} {}", field, image))
+        .collect();
+    if disallowed.is_empty() {
+        return None;
+    }
+    Some(format!("{} not from an allowed registry ({})", disallowed.join(", "), allowed.join(", ")))
+}
@@ -0,0 +1,60 @@
+//! Picks up changes to the config file without a restart.  The file is read
+//! again every `RELOAD_INTERVAL_SECONDS`, which also catches a ConfigMap
+//! mounted as the file being updated by the kubelet.  When its contents
+//! change, the settings are loaded and validated the same way as at startup.
+//!
+//! Only the `Reloadable` settings take effect straight away, and what
+//! changed is logged.  Anything else that changed is logged as waiting for a
+//! restart, since the watches, servers and integrations built from it are
+//! already running.  A file that doesn't load is reported and ignored, and
+//! the settings in effect are kept.
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::ApiResources;
+
+/// `args` are the controller's command line, which still takes precedence
+/// over the file.
+pub async fn run(resources: Arc<ApiResources>, args: Vec<String>) {
+    let path = match &resources.config.config_file {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let mut contents = std::fs::read_to_string(&path).ok();
+    loop {
+        tokio::time::delay_for(resources.config.reload_interval).await;
+        let current = match std::fs::read_to_string(&path) {
+            Ok(current) => current,
+            Err(err) => {
+                println!("Failed to read config file {}: {}", path, err);
+                continue;
+            }
+        };
+        if contents.as_ref() == Some(&current) {
+            continue;
+        }
+        contents = Some(current);
+
+        let mut config = match Config::load(args.clone()) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Ignoring changes to config file {}: {}", path, err);
+                continue;
+            }
+        };
+        let changes = resources.reloadable().changes(&config.reloadable);
+        for change in &changes {
+            println!("Reloaded setting: {}", change);
+        }
+        *resources.live.write().unwrap() = config.reloadable.clone();
+
+        // Everything else is compared as a whole, so the loaded settings
+        // are put back first
+        config.reloadable = resources.config.reloadable.clone();
+        if format!("{:?}", config) != format!("{:?}", resources.config) {
+            println!("Config file {} has changes that take effect when the controller restarts", path);
+        } else if changes.is_empty() {
+            println!("Config file {} changed but no settings did", path);
+        }
+    }
+}
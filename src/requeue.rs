@@ -0,0 +1,98 @@
+//! Reconciles that come back on their own.  Most of what the controller does
+//! is driven by watch events, but some things are due at a time rather than
+//! on a change -- the end of a rollback window, say -- and nothing would
+//! happen at that time without something to wake the preview up.
+//!
+//! `Requeue::after` asks for a preview to be reconciled again after a delay,
+//! and `run` does so when the time comes, checking everything whether or not
+//! the spec has changed.  Only the earliest request for each preview is
+//! kept.  The queue is kept in the state store as well, when there is one,
+//! so what's due carries on from where it was after a restart.  Without
+//! one anything queued is lost on restart, and whatever queued it is
+//! expected to queue it again when the preview is reconciled on startup.
+use kube::Error;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::store::{self, Store};
+use crate::{ApiResources, KubePreviewEnvironment};
+
+// Where the queue is kept in the store.
+const PREFIX: &str = "requeue/";
+
+// How often the queue is checked for previews that are due.
+const TICK: Duration = Duration::from_secs(1);
+
+// How long to wait before trying again when a due preview can't be read.
+const RETRY: Duration = Duration::from_secs(30);
+
+pub struct Requeue {
+    due: Mutex<BTreeMap<String, Instant>>,
+    store: Arc<Store>,
+}
+
+impl Requeue {
+    /// The queue as it was left in `store`, if it was left there at all.
+    pub fn new(store: Arc<Store>) -> Self {
+        let now = Instant::now();
+        let due = store
+            .scan::<u64>(PREFIX)
+            .into_iter()
+            .map(|(name, deadline)| (name, now + store::remaining(deadline)))
+            .collect();
+        Requeue { due: Mutex::new(due), store }
+    }
+
+    /// Reconcile the preview again after `delay`, unless it's already due
+    /// sooner.
+    pub fn after(&self, name: &str, delay: Duration) {
+        let at = Instant::now() + delay;
+        let mut due = self.due.lock().unwrap();
+        if due.get(name).map_or(true, |queued| at < *queued) {
+            due.insert(name.to_string(), at);
+            self.store.put(&format!("{}{}", PREFIX, name), &store::deadline(delay));
+        }
+    }
+
+    fn take_due(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut due = self.due.lock().unwrap();
+        let ready: Vec<String> = due.iter().filter(|(_, at)| **at <= now).map(|(name, _)| name.clone()).collect();
+        for name in &ready {
+            due.remove(name);
+            self.store.remove(&format!("{}{}", PREFIX, name));
+        }
+        ready
+    }
+}
+
+pub async fn run(resources: Arc<ApiResources>) {
+    loop {
+        tokio::time::delay_for(TICK).await;
+        if resources.config.maintenance {
+            continue;
+        }
+        for name in resources.requeue.take_due() {
+            let current: Result<KubePreviewEnvironment, Error> = match resources.previews.get(&name) {
+                Ok(request) => resources.client.request(request).await,
+                Err(err) => Err(err),
+            };
+            match current {
+                Ok(mut pe) => {
+                    let profile = crate::profiles::of(&resources, &pe);
+                    crate::fqdn::fill(&resources.config, &profile, &mut pe);
+                    println!("Requeued PreviewEnvironment name: {}", name);
+                    let reconcile = crate::reconcile::reconcile(&resources, &pe, true);
+                    crate::impersonation::as_creator(&resources.config, &pe, reconcile).await;
+                }
+                // Deleted in the meantime
+                Err(Error::Api(ae)) if ae.code == 404 => {}
+                Err(err) => {
+                    println!("Failed to read requeued preview {}: {:?}", name, err);
+                    resources.requeue.after(&name, RETRY);
+                }
+            }
+        }
+    }
+}
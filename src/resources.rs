@@ -0,0 +1,166 @@
+//! The child resources a preview is made of: what they're called, and the
+//! typed Deployment and Service manifests the rest of its resources build
+//! on.
+use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::api::core::v1::{Container, PodSpec, PodTemplateSpec, ResourceRequirements, Service, ServicePort, ServiceSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::canary::CanarySpec;
+use crate::naming::PREFIX_ANNOTATION;
+use crate::ports::PortSpec;
+use crate::services::{ServiceType, SessionAffinity};
+use crate::{canary, names, ports, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// Names of the child resources we create for an environment.  Both the
+/// create and cleanup paths derive them from here so they can't drift apart.
+pub struct Children {
+    pub deployment: String,
+    pub service: String,
+    pub headless_service: String,
+    pub mapping: String,
+    pub tcp_mapping: String,
+    pub host: String,
+    pub external_secret: String,
+    pub external_secret_target: String,
+    pub build_job: String,
+    pub scan_job: String,
+    pub snapshot_job: String,
+    pub dashboard: String,
+    pub monitor: String,
+    pub peer_authentication: String,
+    pub canary_deployment: String,
+    pub canary_service: String,
+    pub canary_mapping: String,
+    pub green_deployment: String,
+    pub pipeline_run: String,
+    pub egress_policy: String,
+}
+
+impl Children {
+    pub fn of(pe: &KubePreviewEnvironment) -> Self {
+        // Named by the naming script when it was created, if there is one
+        let name = pe.metadata.annotations.get(PREFIX_ANNOTATION).unwrap_or(&pe.metadata.name);
+        Children {
+            deployment: names::child(name, "deployment"),
+            service: names::child(name, "service"),
+            headless_service: names::child(name, "headless"),
+            mapping: names::child(name, "mapping"),
+            tcp_mapping: names::child(name, "tcp"),
+            host: names::child(name, "host"),
+            external_secret: names::child(name, "external-secret"),
+            external_secret_target: names::child(name, "external"),
+            build_job: names::child(name, "build"),
+            scan_job: names::child(name, "scan"),
+            snapshot_job: names::child(name, "snapshot"),
+            dashboard: names::child(name, "dashboard"),
+            monitor: names::child(name, "monitor"),
+            peer_authentication: names::child(name, "mesh"),
+            canary_deployment: names::child(name, "canary-deployment"),
+            canary_service: names::child(name, "canary-service"),
+            canary_mapping: names::child(name, "canary-mapping"),
+            green_deployment: names::child(name, "green-deployment"),
+            pipeline_run: names::child(name, "pipeline"),
+            egress_policy: names::child(name, "egress"),
+        }
+    }
+}
+
+// Every child is labelled so the controller can find it again.
+fn metadata(name: &str) -> ObjectMeta {
+    let mut labels = BTreeMap::new();
+    labels.insert("preview".to_string(), "true".to_string());
+    ObjectMeta {
+        name: Some(name.to_string()),
+        labels: Some(labels),
+        ..ObjectMeta::default()
+    }
+}
+
+fn app_labels(app: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert("app".to_string(), app.to_string());
+    labels
+}
+
+/// A single-container Deployment running `image`, which the features the
+/// preview uses are layered onto.
+pub fn deployment_manifest(name: &str, image: &str, resources: Option<&ResourceRequirements>) -> Deployment {
+    Deployment {
+        metadata: Some(metadata(name)),
+        spec: Some(DeploymentSpec {
+            replicas: Some(1),
+            selector: LabelSelector {
+                match_labels: Some(app_labels(name)),
+                ..LabelSelector::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(app_labels(name)),
+                    ..ObjectMeta::default()
+                }),
+                spec: Some(PodSpec {
+                    containers: vec![Container {
+                        name: name.to_string(),
+                        image: Some(image.to_string()),
+                        resources: resources.cloned(),
+                        ..Container::default()
+                    }],
+                    ..PodSpec::default()
+                }),
+            },
+            ..DeploymentSpec::default()
+        }),
+        ..Deployment::default()
+    }
+}
+
+/// A Service sending port 80 to the pods of `deployment`.
+pub fn service_manifest(name: &str, deployment: &str, service_type: ServiceType, session_affinity: SessionAffinity) -> Service {
+    Service {
+        metadata: Some(metadata(name)),
+        spec: Some(ServiceSpec {
+            type_: Some(service_type.as_str().to_string()),
+            session_affinity: Some(session_affinity.as_str().to_string()),
+            selector: Some(app_labels(deployment)),
+            ports: Some(vec![ServicePort {
+                protocol: Some("TCP".to_string()),
+                port: 80,
+                ..ServicePort::default()
+            }]),
+            ..ServiceSpec::default()
+        }),
+        ..Service::default()
+    }
+}
+
+/// The JSON for a manifest, for the features that add to it as they go.
+pub fn to_json<T: Serialize>(manifest: &T) -> JsonValue {
+    serde_json::to_value(manifest).expect("Failed to serialize manifest")
+}
+
+// The canary's Deployment, Service and Mapping, modelled on the main ones.
+pub fn canary_json(children: &Children, deployment: &JsonValue, mapping: &JsonValue, canary: &CanarySpec, ports: &[PortSpec]) -> [JsonValue; 3] {
+    let canary_deploy = canary::deployment_json(deployment, &children.canary_deployment, &canary.image);
+    // The canary is only reached through Ambassador, so it never needs
+    // exposing directly
+    let mut canary_service = to_json(&service_manifest(
+        &children.canary_service,
+        &children.canary_deployment,
+        ServiceType::ClusterIP,
+        SessionAffinity::None,
+    ));
+    canary_service["metadata"]["labels"] = deployment["metadata"]["labels"].clone();
+    ports::publish(&mut canary_service, ports);
+    // The canary gets the same slice of traffic on the same port
+    let main_service = mapping["spec"]["service"].as_str().unwrap_or_default();
+    let target = match main_service.rfind(':') {
+        Some(colon) => format!("{}{}", children.canary_service, &main_service[colon..]),
+        None => children.canary_service.clone(),
+    };
+    let canary_mapping = canary::mapping_json(mapping, &children.canary_mapping, &target, canary.weight);
+    [canary_deploy, canary_service, canary_mapping]
+}
@@ -0,0 +1,54 @@
+//! Trying failed previews again.  A preview that fails is retried with an
+//! exponential backoff, up to `MAX_RETRIES` times in a row.  After that it's
+//! left alone, with a `Failed` condition holding the last error, until its
+//! spec changes or it's given the `preview.platform9.com/retry` annotation
+//! (or `kubectl preview retry`), so a spec that can never work doesn't keep
+//! the controller busy.
+use std::time::Duration;
+
+use crate::{conditions, creation, KubePreviewEnvironment};
+
+pub const RETRY_ANNOTATION: &str = "preview.platform9.com/retry";
+
+/// Type of the condition set once a preview is out of retries.
+pub const CONDITION: &str = "Failed";
+
+const FIRST_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// How long to wait before the next attempt, after `failures` in a row.
+pub fn backoff(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    FIRST_BACKOFF.checked_mul(factor).unwrap_or(MAX_BACKOFF).min(MAX_BACKOFF)
+}
+
+/// Whether the preview failed, or was given up on with some of its
+/// children missing.  See `creation`.
+pub fn is_failed(pe: &KubePreviewEnvironment) -> bool {
+    let phase = pe.status.as_ref().and_then(|status| status.phase.as_deref());
+    phase == Some("Failed") || phase == Some("Degraded")
+}
+
+pub fn requested(pe: &KubePreviewEnvironment) -> bool {
+    pe.metadata.annotations.contains_key(RETRY_ANNOTATION)
+}
+
+/// Whether the preview has run out of retries, or been given up on with
+/// some of its children missing.
+pub fn exhausted(pe: &KubePreviewEnvironment) -> bool {
+    pe.status.as_ref().map_or(false, |status| {
+        conditions::is_true(&status.conditions, CONDITION) || conditions::is_true(&status.conditions, creation::CONDITION)
+    })
+}
+
+/// The preview as if nothing had been attempted for its current spec yet,
+/// so the build, scan or update that failed goes again.
+pub fn forget_attempts(pe: &KubePreviewEnvironment) -> KubePreviewEnvironment {
+    let mut pe = pe.clone();
+    if let Some(status) = pe.status.as_mut() {
+        status.build_ref = None;
+        status.scanned_image = None;
+        status.deploying_image = None;
+    }
+    pe
+}
@@ -0,0 +1,152 @@
+//! Argo Rollouts support.  Previews with `strategy: argoRollout` get a
+//! `Rollout` in place of their Deployment, so image changes go through
+//! Argo's progressive delivery and analysis.  The rollout strategy comes
+//! from `spec.rolloutStrategy`, the YAML file named by
+//! `ROLLOUT_STRATEGY_TEMPLATE`, or a simple two-step canary, in that order.
+use kube::{
+    api::{ListParams, RawApi},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bluegreen::UpdateStrategy;
+use crate::client::Client;
+use crate::{ApiResources, Children, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// Rollout progress as shown in the preview's status.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RolloutStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Status of the analysis run for the current step, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub analysis: Option<String>,
+}
+
+pub fn default_strategy() -> JsonValue {
+    json!({
+        "canary": {
+            "steps": [
+                { "setWeight": 50 },
+                { "pause": { "duration": "1m" } },
+            ]
+        }
+    })
+}
+
+pub fn load_strategy(path: &str) -> Result<JsonValue, String> {
+    let raw =
+        std::fs::read_to_string(path).map_err(|err| format!("Failed to read rollout strategy template {}: {}", path, err))?;
+    serde_yaml::from_str(&raw).map_err(|err| format!("Invalid rollout strategy template {}: {}", path, err))
+}
+
+/// Turn a rendered Deployment into an equivalent Rollout.
+pub fn rollout_json(deployment: &JsonValue, strategy: &JsonValue) -> JsonValue {
+    let mut rollout = deployment.clone();
+    rollout["apiVersion"] = json!("argoproj.io/v1alpha1");
+    rollout["kind"] = json!("Rollout");
+    rollout["spec"]["strategy"] = strategy.clone();
+    rollout
+}
+
+pub fn status_of(rollout: &JsonValue) -> RolloutStatus {
+    let status = &rollout["status"];
+    let analysis = &status["canary"]["currentStepAnalysisRunStatus"];
+    RolloutStatus {
+        phase: status["phase"].as_str().map(str::to_string),
+        message: status["message"].as_str().filter(|message| !message.is_empty()).map(str::to_string),
+        analysis: analysis["status"].as_str().map(|result| match analysis["message"].as_str() {
+            Some(message) if !message.is_empty() => format!("{}: {}", result, message),
+            _ => result.to_string(),
+        }),
+    }
+}
+
+/// Point the Rollout at a new image.  Argo takes it from there.
+pub async fn update_image(client: &Client, rollouts: &RawApi, name: &str, image: &str) -> Result<(), Error> {
+    client
+        .update(rollouts, name, |rollout: &mut JsonValue| {
+            for container in rollout["spec"]["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+                container["image"] = json!(image);
+            }
+        })
+        .await
+        .map(|_: JsonValue| ())
+}
+
+/// Periodically copies each Rollout's progress into its preview's status.
+pub async fn refresh(resources: Arc<ApiResources>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let lp = ListParams {
+            label_selector: Some("preview=true".to_string()),
+            ..ListParams::default()
+        };
+        let list: Result<JsonValue, Error> = match resources.rollouts.list(&lp) {
+            Ok(request) => resources.client.request(request).await,
+            Err(err) => Err(err),
+        };
+        let rollouts: BTreeMap<String, RolloutStatus> = match list {
+            Ok(list) => list["items"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|rollout| Some((rollout["metadata"]["name"].as_str()?.to_string(), status_of(rollout))))
+                .collect(),
+            // Most clusters won't have Argo Rollouts installed at all
+            Err(Error::Api(ae)) if ae.code == 404 => continue,
+            Err(err) => {
+                println!("Failed to list rollouts: {:?}", err);
+                continue;
+            }
+        };
+
+        let previews = match crate::list_previews(&resources).await {
+            Ok(previews) => previews,
+            Err(err) => {
+                println!("Failed to list previews for rollout status: {:?}", err);
+                continue;
+            }
+        };
+        for pe in previews.iter().filter(|pe| pe.spec.strategy == UpdateStrategy::ArgoRollout) {
+            let current = rollouts.get(&Children::of(pe).deployment).cloned();
+            let recorded = pe.status.as_ref().and_then(|status| status.rollout.clone());
+            if current != recorded {
+                crate::set_status(&resources, &pe.metadata.name, |status| {
+                    status.rollout = current.clone();
+                })
+                .await;
+            }
+        }
+    }
+}
+
+/// Rollouts restart their pods through `spec.restartAt` rather than a
+/// template annotation.
+pub async fn restart(resources: &ApiResources, pe: &KubePreviewEnvironment, name: &str, restarted_at: &str) {
+    let result = resources
+        .client
+        .update(&resources.rollouts, name, |rollout: &mut JsonValue| {
+            rollout["spec"]["restartAt"] = json!(restarted_at);
+            for container in rollout["spec"]["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+                container["imagePullPolicy"] = json!("Always");
+            }
+        })
+        .await;
+    match result.map(|_: JsonValue| ()) {
+        Ok(()) => {
+            println!("Restarted rollout {} for a new image push", name);
+            crate::record_event(resources, pe, "Normal", "Redeployed", "Restarted after a new image was pushed").await;
+        }
+        Err(err) => println!("Failed to restart rollout {}: {:?}", name, err),
+    }
+}
@@ -0,0 +1,123 @@
+//! Getting traffic to a preview: the Ambassador Mapping and Host for its
+//! FQDN, a listener port when it serves TCP, and a DNS record when the
+//! controller manages DNS itself.
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tracing::instrument;
+
+use crate::config::{Config, TlsMode};
+use crate::dns::DnsRecord;
+use crate::{list_previews, names, record_event, set_status, tcp, ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// An Ambassador Mapping, which k8s-openapi has no type for.  Only what the
+/// controller sets is modelled; features add the rest to its JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mapping {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+    pub kind: String,
+    pub metadata: ObjectMeta,
+    pub spec: MappingSpec,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MappingSpec {
+    pub host: String,
+    pub service: String,
+    pub prefix: String,
+}
+
+pub fn mapping_manifest(name: &str, host: &str, service: &str) -> Mapping {
+    let mut labels = BTreeMap::new();
+    labels.insert("preview".to_string(), "true".to_string());
+    Mapping {
+        api_version: "getambassador.io/v2".to_string(),
+        kind: "Mapping".to_string(),
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            labels: Some(labels),
+            ..ObjectMeta::default()
+        },
+        spec: MappingSpec {
+            host: host.to_string(),
+            service: service.to_string(),
+            prefix: "/".to_string(),
+        },
+    }
+}
+
+// An Ambassador Host terminates TLS for the preview's FQDN.  Which
+// certificate it uses depends on the controller's TLS mode.
+pub fn json_for_host(name: &str, fqdn: &str, config: &Config) -> Option<JsonValue> {
+    let (acme_provider, tls_secret) = match config.tls_mode {
+        TlsMode::None => return None,
+        TlsMode::Acme => (
+            json!({ "email": config.acme_email }),
+            names::child(name, "tls"),
+        ),
+        TlsMode::Wildcard => (
+            json!({ "authority": "none" }),
+            config.wildcard_tls_secret.clone().expect("WILDCARD_TLS_SECRET is required"),
+        ),
+    };
+
+    Some(json!({
+        "apiVersion": "getambassador.io/v2",
+        "kind": "Host",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+            }
+        },
+        "spec": {
+            "hostname": fqdn,
+            "acmeProvider": acme_provider,
+            "tlsSecret": {
+                "name": tls_secret,
+            }
+        }
+    }))
+}
+
+#[instrument(skip(resources, pe))]
+pub async fn create_dns_record(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let (dns, target) = match (&resources.dns, &resources.config.dns_target) {
+        (Some(dns), Some(target)) => (dns, target),
+        _ => return,
+    };
+    let record = DnsRecord::new(&pe.spec.fqdn, target);
+    if let Err(err) = dns.upsert_record(&record).await {
+        let message = format!("Failed to create DNS record for {}: {}", pe.spec.fqdn, err);
+        println!("{}", message);
+        record_event(resources, pe, "Warning", "DnsFailed", &message).await;
+    }
+}
+
+// Pick the preview's TCP listener port and record it straight away, so the
+// next preview to be created can't be handed the same one.
+pub async fn allocate_tcp_port(resources: &ApiResources, pe: &KubePreviewEnvironment) -> Option<u16> {
+    let range = resources.config.tcp_port_range;
+    let allocated = match list_previews(resources).await {
+        Ok(previews) => tcp::allocate(range, pe, &previews)
+            .ok_or_else(|| format!("No TCP ports left between {} and {}", range.first, range.last)),
+        Err(err) => Err(format!("Failed to list previews to allocate a TCP port: {}", err)),
+    };
+    match allocated {
+        Ok(port) => {
+            set_status(resources, &pe.metadata.name, |status| {
+                status.tcp_port = Some(port);
+            })
+            .await;
+            Some(port)
+        }
+        Err(message) => {
+            fail(resources, pe, "CreateFailed", &message).await;
+            None
+        }
+    }
+}
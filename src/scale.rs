@@ -0,0 +1,84 @@
+//! The preview's `/scale` subresource, so `kubectl scale previewenvironment`
+//! works and an HPA can target the preview itself instead of a Deployment
+//! the controller might replace.  Scaling changes `spec.replicas`, which is
+//! passed on to the preview's workload whenever its spec changes.  The
+//! status reports the replica count the workload was given, and the label
+//! selector for its pods, both of which the subresource reads.
+//!
+//! Previews deployed through a GitOps backend or a Jsonnet template are
+//! left to those, which are handed the spec to do with as they like.
+use kube::api::RawApi;
+use kube::Error;
+use serde_json::json;
+
+use crate::bluegreen::{self, UpdateStrategy};
+use crate::labels::{name_value, NAME_LABEL};
+use crate::statefulsets::WorkloadType;
+use crate::{set_status, ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// Selects the preview's pods, for the HPA to read their metrics.
+pub fn selector(pe: &KubePreviewEnvironment) -> String {
+    format!("{}={}", NAME_LABEL, name_value(&pe.metadata.name))
+}
+
+// The workload that runs the preview's pods.  For blue-green previews only
+// the active release is scaled; the idle one stays at zero.
+fn workload<'a>(resources: &'a ApiResources, pe: &KubePreviewEnvironment) -> (&'static str, &'a RawApi, String) {
+    let (active, _) = bluegreen::slots(pe);
+    if pe.spec.strategy == UpdateStrategy::ArgoRollout {
+        ("rollout", &resources.rollouts, active)
+    } else if pe.spec.workload_type == WorkloadType::StatefulSet {
+        ("statefulset", &resources.stateful_sets, active)
+    } else {
+        ("deployment", &resources.deployments, active)
+    }
+}
+
+/// Give the preview's workload `spec.replicas` pods if it has a different
+/// number, and report it on the status.
+pub async fn sync(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    if pe.spec.source.is_some() || pe.spec.jsonnet.is_some() {
+        return;
+    }
+    let (kind, api, name) = workload(resources, pe);
+    let current: Result<JsonValue, Error> = match api.get(&name) {
+        Ok(request) => resources.client.request(request).await,
+        Err(err) => Err(err),
+    };
+    let current = match current {
+        Ok(current) => current,
+        // Not created yet, and it'll be created with the right count
+        Err(Error::Api(ae)) if ae.code == 404 => return,
+        Err(err) => {
+            println!("Failed to read {} {} to scale it: {:?}", kind, name, err);
+            return;
+        }
+    };
+
+    let replicas = pe.spec.replicas;
+    if current["spec"]["replicas"].as_i64() != Some(i64::from(replicas)) {
+        let result = resources
+            .client
+            .update(api, &name, |workload: &mut JsonValue| workload["spec"]["replicas"] = json!(replicas))
+            .await;
+        match result.map(|_: JsonValue| ()) {
+            Ok(()) => println!("Scaled {} {} to {} replicas", kind, name, replicas),
+            Err(err) => {
+                println!("Failed to scale {} {}: {:?}", kind, name, err);
+                return;
+            }
+        }
+    }
+
+    let selector = selector(pe);
+    let status = pe.status.clone().unwrap_or_default();
+    if status.replicas != Some(replicas) || status.selector.as_ref() != Some(&selector) {
+        set_status(resources, &pe.metadata.name, |status| {
+            status.replicas = Some(replicas);
+            status.selector = Some(selector.clone());
+        })
+        .await;
+    }
+}
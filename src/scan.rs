@@ -0,0 +1,125 @@
+//! Vulnerability scanning.  When `SCAN_MAX_CRITICAL` is set, every image is
+//! scanned with Trivy in a Job before it's deployed, and previews whose
+//! image has more critical CVEs than that are refused.  A preview can opt
+//! out with the `preview.platform9.com/skip-scan: "true"` annotation.
+use kube::{
+    api::{ListParams, LogParams, RawApi},
+    Error,
+};
+use serde_json::json;
+
+use crate::client::Client;
+use crate::config::Config;
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+pub const SKIP_ANNOTATION: &str = "preview.platform9.com/skip-scan";
+
+// How many findings to list in the status message before summarising.
+const MAX_LISTED: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct Vulnerability {
+    pub id: String,
+    pub package: String,
+}
+
+/// Whether this preview's image has to pass a scan before it's deployed.
+pub fn required(config: &Config, pe: &KubePreviewEnvironment) -> bool {
+    let skipped = pe
+        .metadata
+        .annotations
+        .get(SKIP_ANNOTATION)
+        .map(|value| value == "true")
+        .unwrap_or(false);
+    config.scan_max_critical.is_some() && !skipped
+}
+
+pub fn job_json(name: &str, preview: &str, image: &str, config: &Config) -> JsonValue {
+    json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "spec": {
+            "backoffLimit": 0,
+            "template": {
+                "metadata": {
+                    "labels": {
+                        "preview.platform9.com/name": preview,
+                    }
+                },
+                "spec": {
+                    "restartPolicy": "Never",
+                    "containers": [
+                        {
+                            // --quiet keeps the log down to just the JSON
+                            // report so we can parse it straight back out.
+                            "name": "scan",
+                            "image": config.trivy_image,
+                            "args": ["image", "--quiet", "--no-progress", "--format", "json", "--severity", "CRITICAL", image],
+                        }
+                    ],
+                }
+            }
+        }
+    })
+}
+
+/// Read the report a finished scan Job printed.
+pub async fn report(client: &Client, pods: &RawApi, job_name: &str) -> Result<JsonValue, Error> {
+    let lp = ListParams {
+        label_selector: Some(format!("job-name={}", job_name)),
+        ..ListParams::default()
+    };
+    let pod_list: JsonValue = client.request(pods.list(&lp)?).await?;
+    let pod_name = pod_list["items"][0]["metadata"]["name"].as_str().unwrap_or(job_name).to_string();
+    client.request(pods.log(&pod_name, &LogParams::default())?).await
+}
+
+/// The critical vulnerabilities in a Trivy JSON report.  Older Trivy
+/// releases print a bare list of results rather than an object.
+pub fn critical(report: &JsonValue) -> Vec<Vulnerability> {
+    let results = match report {
+        JsonValue::Array(results) => results,
+        report => match report["Results"].as_array() {
+            Some(results) => results,
+            None => return vec![],
+        },
+    };
+    results
+        .iter()
+        .flat_map(|result| result["Vulnerabilities"].as_array().into_iter().flatten())
+        .filter(|vulnerability| vulnerability["Severity"] == "CRITICAL")
+        .map(|vulnerability| Vulnerability {
+            id: vulnerability["VulnerabilityID"].as_str().unwrap_or("unknown").to_string(),
+            package: vulnerability["PkgName"].as_str().unwrap_or("unknown").to_string(),
+        })
+        .collect()
+}
+
+/// A one-line summary of the findings, for the status message and events.
+pub fn summary(image: &str, findings: &[Vulnerability], max: usize) -> String {
+    let listed: Vec<String> = findings
+        .iter()
+        .take(MAX_LISTED)
+        .map(|vulnerability| format!("{} ({})", vulnerability.id, vulnerability.package))
+        .collect();
+    let mut message = format!(
+        "{} has {} critical vulnerabilities, more than the {} allowed: {}",
+        image,
+        findings.len(),
+        max,
+        listed.join(", ")
+    );
+    if findings.len() > MAX_LISTED {
+        message.push_str(&format!(" and {} more", findings.len() - MAX_LISTED));
+    }
+    message
+}
@@ -0,0 +1,109 @@
+//! Where a preview's pods land.  Previews with more than one replica can
+//! be spread across nodes, either with a topology spread constraint or
+//! with pod anti-affinity, so a single node going away doesn't take the
+//! whole preview with it.  `SPREAD` sets the default and `spec.spread`
+//! overrides it.  Both are preferences, so a small cluster can still
+//! schedule everything.
+//!
+//! Preview pods can also be given a low PriorityClass (`PRIORITY_CLASS`) so
+//! they're the first to be evicted when the cluster is under pressure, and
+//! run under a sandboxed RuntimeClass such as gVisor or Kata
+//! (`RUNTIME_CLASS`, or `runtimeClassName` on the preview) when the branch
+//! can't be trusted.  On mixed clusters `nodeOS` and `arch` pin the pods
+//! to nodes that can actually run the image.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::str::FromStr;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Spread {
+    None,
+    TopologySpread,
+    AntiAffinity,
+}
+
+impl FromStr for Spread {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(Spread::None),
+            "topologyspread" => Ok(Spread::TopologySpread),
+            "antiaffinity" => Ok(Spread::AntiAffinity),
+            _ => Err(format!("unknown spread {:?}", value)),
+        }
+    }
+}
+
+/// Spread the deployment's pods over `topology_key`, if it has more than
+/// one of them.
+pub fn spread(deployment: &mut JsonValue, spread: Spread, topology_key: &str) {
+    if deployment["spec"]["replicas"].as_i64().unwrap_or(1) < 2 {
+        return;
+    }
+    let selector = json!({ "matchLabels": deployment["spec"]["selector"]["matchLabels"].clone() });
+    let pod_spec = &mut deployment["spec"]["template"]["spec"];
+    match spread {
+        Spread::None => {}
+        Spread::TopologySpread => {
+            pod_spec["topologySpreadConstraints"] = json!([{
+                "maxSkew": 1,
+                "topologyKey": topology_key,
+                "whenUnsatisfiable": "ScheduleAnyway",
+                "labelSelector": selector,
+            }]);
+        }
+        Spread::AntiAffinity => {
+            pod_spec["affinity"]["podAntiAffinity"] = json!({
+                "preferredDuringSchedulingIgnoredDuringExecution": [{
+                    "weight": 100,
+                    "podAffinityTerm": {
+                        "topologyKey": topology_key,
+                        "labelSelector": selector,
+                    }
+                }]
+            });
+        }
+    }
+}
+
+/// Give the deployment's pods a PriorityClass.
+pub fn set_priority_class(deployment: &mut JsonValue, priority_class: &str) {
+    deployment["spec"]["template"]["spec"]["priorityClassName"] = json!(priority_class);
+}
+
+/// Only schedule the deployment's pods on nodes with the given OS and
+/// architecture, e.g. `windows` or `arm64`.
+pub fn target_platform(deployment: &mut JsonValue, os: Option<&str>, arch: Option<&str>) {
+    let node_selector = &mut deployment["spec"]["template"]["spec"]["nodeSelector"];
+    if let Some(os) = os {
+        node_selector["kubernetes.io/os"] = json!(os);
+    }
+    if let Some(arch) = arch {
+        node_selector["kubernetes.io/arch"] = json!(arch);
+    }
+}
+
+/// Run the deployment's pods under a RuntimeClass.
+pub fn set_runtime_class(deployment: &mut JsonValue, runtime_class: &str) {
+    deployment["spec"]["template"]["spec"]["runtimeClassName"] = json!(runtime_class);
+}
+
+pub fn priority_class_json(name: &str, value: i32) -> JsonValue {
+    json!({
+        "apiVersion": "scheduling.k8s.io/v1",
+        "kind": "PriorityClass",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+            }
+        },
+        "value": value,
+        "globalDefault": false,
+        "description": "Preview environments, evicted before anything else.",
+    })
+}
@@ -0,0 +1,177 @@
+//! Copies Secrets such as registry credentials or shared API keys from a
+//! source namespace into the namespace previews run in.
+//!
+//! Copies are named `<preview>-<secret>` so two previews asking for the same
+//! Secret don't fight over one object, and are labelled with both the
+//! preview and the Secret they came from.  The labels let cleanup find a
+//! preview's copies and let the periodic sync find every copy of a Secret.
+use kube::{
+    api::{ListParams, PostParams, RawApi},
+    Error,
+};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+pub const PREVIEW_LABEL: &str = "preview.platform9.com/name";
+pub const SOURCE_LABEL: &str = "preview.platform9.com/source-secret";
+
+const DOCKER_CONFIG_TYPE: &str = "kubernetes.io/dockerconfigjson";
+
+/// A Secret that has been copied for a preview.
+pub struct CopiedSecret {
+    pub name: String,
+    pub secret_type: String,
+}
+
+pub fn copy_name(preview: &str, secret: &str) -> String {
+    names::child(preview, secret)
+}
+
+/// Copies each of `names` from `source` into `target` for `preview`,
+/// replacing the data of copies that already exist.
+pub async fn replicate(
+    client: &Client,
+    source: &RawApi,
+    target: &RawApi,
+    preview: &str,
+    names: &[String],
+) -> Result<Vec<CopiedSecret>, Error> {
+    let mut copied = vec![];
+    for name in names {
+        let secret: JsonValue = client.request(source.get(name)?).await?;
+        let copy = copy_name(preview, name);
+        let data = json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": {
+                "name": copy,
+                "labels": {
+                    "preview": "true",
+                    "preview.platform9.com/name": preview,
+                    "preview.platform9.com/source-secret": name,
+                }
+            },
+            "type": secret["type"],
+            "data": secret["data"],
+        });
+
+        let request = target.create(&PostParams::default(), serde_json::to_vec(&data)?)?;
+        match client.request::<JsonValue>(request).await {
+            Ok(_) => {}
+            Err(Error::Api(ae)) if ae.code == 409 => {
+                copy_data(client, target, &copy, &secret).await?;
+            }
+            Err(err) => return Err(err),
+        }
+
+        copied.push(CopiedSecret {
+            name: copy,
+            secret_type: secret["type"].as_str().unwrap_or("Opaque").to_string(),
+        });
+    }
+    Ok(copied)
+}
+
+async fn copy_data(client: &Client, target: &RawApi, name: &str, source: &JsonValue) -> Result<(), Error> {
+    client
+        .update(target, name, |copy: &mut JsonValue| {
+            copy["type"] = source["type"].clone();
+            copy["data"] = source["data"].clone();
+        })
+        .await
+        .map(|_: JsonValue| ())
+}
+
+/// Deletes every Secret copied for `preview`.
+pub async fn remove(client: &Client, target: &RawApi, preview: &str) -> Result<(), Error> {
+    let lp = ListParams {
+        label_selector: Some(format!("{}={}", PREVIEW_LABEL, preview)),
+        ..ListParams::default()
+    };
+    client.request::<JsonValue>(target.delete_collection(&lp)?).await?;
+    Ok(())
+}
+
+/// Wires copied Secrets into a rendered Deployment: registry credentials
+/// become `imagePullSecrets` and everything else is exposed to the
+/// containers as environment variables.
+pub fn attach(deployment: &mut JsonValue, copied: &[CopiedSecret]) {
+    if copied.is_empty() {
+        return;
+    }
+    let pod_spec = &mut deployment["spec"]["template"]["spec"];
+
+    let pull_secrets: Vec<JsonValue> = copied
+        .iter()
+        .filter(|secret| secret.secret_type == DOCKER_CONFIG_TYPE)
+        .map(|secret| json!({ "name": secret.name }))
+        .collect();
+    if !pull_secrets.is_empty() {
+        pod_spec["imagePullSecrets"] = json!(pull_secrets);
+    }
+
+    let env_from: Vec<JsonValue> = copied
+        .iter()
+        .filter(|secret| secret.secret_type != DOCKER_CONFIG_TYPE)
+        .map(|secret| json!({ "secretRef": { "name": secret.name } }))
+        .collect();
+    if let Some(containers) = pod_spec["containers"].as_array_mut() {
+        for container in containers {
+            container["envFrom"] = json!(env_from);
+        }
+    }
+}
+
+/// Periodically pushes changes to source Secrets out to all their copies,
+/// so rotating a shared credential doesn't require recreating previews.
+pub async fn sync(client: Client, source: RawApi, target: RawApi, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        if let Err(err) = sync_once(&client, &source, &target).await {
+            println!("Failed to sync copied secrets: {:?}", err);
+        }
+    }
+}
+
+async fn sync_once(client: &Client, source: &RawApi, target: &RawApi) -> Result<(), Error> {
+    let lp = ListParams {
+        label_selector: Some(SOURCE_LABEL.to_string()),
+        ..ListParams::default()
+    };
+    let copies: JsonValue = client.request(target.list(&lp)?).await?;
+
+    // Group the copies by the Secret they came from so each source is only
+    // fetched once.
+    let mut by_source: BTreeMap<String, Vec<&JsonValue>> = BTreeMap::new();
+    for copy in copies["items"].as_array().into_iter().flatten() {
+        if let Some(source_name) = copy["metadata"]["labels"][SOURCE_LABEL].as_str() {
+            by_source.entry(source_name.to_string()).or_default().push(copy);
+        }
+    }
+
+    for (source_name, copies) in by_source {
+        let secret: JsonValue = match client.request(source.get(&source_name)?).await {
+            Ok(secret) => secret,
+            Err(err) => {
+                println!("Failed to read source secret {}: {:?}", source_name, err);
+                continue;
+            }
+        };
+        for copy in copies {
+            if copy["data"] == secret["data"] && copy["type"] == secret["type"] {
+                continue;
+            }
+            if let Some(name) = copy["metadata"]["name"].as_str() {
+                println!("Syncing secret {} from {}", name, source_name);
+                copy_data(client, target, name, &secret).await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Locked-down security contexts for preview pods, so they're admitted in
+//! namespaces enforcing the `restricted` Pod Security Standard.  Previews
+//! run as non-root on a read-only root filesystem with every capability
+//! dropped and the runtime's default seccomp profile.  Images that need
+//! more can override individual fields with `podSecurityContext` and
+//! `securityContext` on the preview.
+use k8s_openapi::api::core::v1::{PodSecurityContext, SecurityContext};
+use serde_json::json;
+
+type JsonValue = serde_json::value::Value;
+
+pub fn default_pod_security_context() -> JsonValue {
+    json!({
+        "runAsNonRoot": true,
+        "seccompProfile": { "type": "RuntimeDefault" },
+    })
+}
+
+pub fn default_container_security_context() -> JsonValue {
+    json!({
+        "allowPrivilegeEscalation": false,
+        "readOnlyRootFilesystem": true,
+        "capabilities": { "drop": ["ALL"] },
+    })
+}
+
+// Fields set in the override replace the default ones; anything left out
+// keeps its default.
+fn merge<T: serde::Serialize>(mut defaults: JsonValue, overrides: Option<&T>) -> JsonValue {
+    if let Some(JsonValue::Object(overrides)) = overrides.map(|overrides| json!(overrides)) {
+        for (key, value) in overrides {
+            defaults[key] = value;
+        }
+    }
+    defaults
+}
+
+/// Set the security contexts on the deployment's pods and every container
+/// in them.
+pub fn harden(deployment: &mut JsonValue, pod: Option<&PodSecurityContext>, container: Option<&SecurityContext>) {
+    let pod_spec = &mut deployment["spec"]["template"]["spec"];
+    pod_spec["securityContext"] = merge(default_pod_security_context(), pod);
+
+    let container_context = merge(default_container_security_context(), container);
+    for container in pod_spec["containers"].as_array_mut().into_iter().flatten() {
+        container["securityContext"] = container_context.clone();
+    }
+
+    // Most apps still want somewhere to write temporary files
+    if container_context["readOnlyRootFilesystem"] == true {
+        writable_tmp(pod_spec);
+    }
+}
+
+fn writable_tmp(pod_spec: &mut JsonValue) {
+    let volume = json!({ "name": "tmp", "emptyDir": {} });
+    match pod_spec["volumes"].as_array_mut() {
+        Some(existing) => existing.push(volume),
+        None => pod_spec["volumes"] = json!([volume]),
+    }
+    for container in pod_spec["containers"].as_array_mut().into_iter().flatten() {
+        let mount = json!({ "name": "tmp", "mountPath": "/tmp" });
+        match container["volumeMounts"].as_array_mut() {
+            Some(existing) => existing.push(mount),
+            None => container["volumeMounts"] = json!([mount]),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Which Services a preview gets.  Most apps want the usual ClusterIP
+//! Service; apps that do client-side load balancing or peer discovery want
+//! a headless one, whose DNS name resolves straight to the pods.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::statefulsets::WorkloadType;
+use crate::{Children, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ServiceMode {
+    /// Just the normal Service.
+    Normal,
+    /// Just a headless Service, which the Mapping routes to instead.
+    Headless,
+    /// Both.
+    Both,
+}
+
+impl Default for ServiceMode {
+    fn default() -> Self {
+        ServiceMode::Normal
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ServiceType {
+    ClusterIP,
+    NodePort,
+    LoadBalancer,
+}
+
+impl ServiceType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServiceType::ClusterIP => "ClusterIP",
+            ServiceType::NodePort => "NodePort",
+            ServiceType::LoadBalancer => "LoadBalancer",
+        }
+    }
+}
+
+impl Default for ServiceType {
+    fn default() -> Self {
+        ServiceType::ClusterIP
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SessionAffinity {
+    None,
+    /// Send each client to the same pod every time.
+    ClientIP,
+}
+
+impl SessionAffinity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SessionAffinity::None => "None",
+            SessionAffinity::ClientIP => "ClientIP",
+        }
+    }
+}
+
+impl Default for SessionAffinity {
+    fn default() -> Self {
+        SessionAffinity::None
+    }
+}
+
+/// Whether the preview has its normal and its headless Service.
+/// StatefulSets always need the headless one.
+pub fn wanted(pe: &KubePreviewEnvironment) -> (bool, bool) {
+    let mode = pe.spec.service_mode;
+    let normal = mode != ServiceMode::Headless;
+    let headless = mode != ServiceMode::Normal || pe.spec.workload_type == WorkloadType::StatefulSet;
+    (normal, headless)
+}
+
+/// The Service the preview's Mapping sends traffic to.
+pub fn routed(pe: &KubePreviewEnvironment, children: &Children) -> String {
+    let (normal, _) = wanted(pe);
+    if normal {
+        children.service.clone()
+    } else {
+        children.headless_service.clone()
+    }
+}
+
+/// Every Service that selects the preview's pods.
+pub fn names(pe: &KubePreviewEnvironment) -> Vec<String> {
+    let children = Children::of(pe);
+    let (normal, headless) = wanted(pe);
+    let mut names = vec![];
+    if normal {
+        names.push(children.service);
+    }
+    if headless {
+        names.push(children.headless_service);
+    }
+    names
+}
+
+/// A headless copy of the preview's Service, giving each pod its own DNS
+/// name.
+pub fn headless_json(service: &JsonValue, name: &str) -> JsonValue {
+    let mut headless = service.clone();
+    headless["metadata"]["name"] = json!(name);
+    headless["spec"]["type"] = json!(ServiceType::ClusterIP);
+    headless["spec"]["clusterIP"] = json!("None");
+    // Peers need to find each other before they're ready
+    headless["spec"]["publishNotReadyAddresses"] = json!(true);
+    headless
+}
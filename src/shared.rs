@@ -0,0 +1,106 @@
+//! Components shared between previews, such as a big database that would be
+//! too expensive to run once per preview.  A shared service is created the
+//! first time a preview asks for it, reused by every later preview in the
+//! same group, and deleted along with the last preview using it.  There's no
+//! counter to drift: the previews whose spec lists the service are its
+//! users.
+//!
+//! The group defaults to the name of the repository the preview is built
+//! from, so previews of the same repo share and other repos don't.  Each
+//! preview is told where the service is and given a prefix of its own to
+//! keep its data apart -- for a service named `db`, `DB_HOST`, `DB_PORT`
+//! and `DB_PREFIX`, which is meant as a schema or table prefix.  Creating
+//! the schema is left to the app's migrations.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use crate::services::{ServiceType, SessionAffinity};
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// Set on shared services, which don't belong to any one preview.
+pub const SHARED_LABEL: &str = "preview.platform9.com/shared";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedService {
+    pub name: String,
+    pub image: String,
+    pub port: u16,
+    /// Previews in the same group share the service.  Defaults to the
+    /// repository the preview is built from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Environment for the shared container itself.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+fn group(pe: &KubePreviewEnvironment, service: &SharedService) -> String {
+    let repo = pe.spec.build.as_ref().and_then(|build| {
+        let name = build.git.trim_end_matches('/').rsplit('/').next()?;
+        Some(name.trim_end_matches(".git").to_string())
+    });
+    service.group.clone().or(repo).unwrap_or_else(|| "default".to_string())
+}
+
+/// Name of the shared Deployment and Service, the same for every preview in
+/// the group.
+pub fn key(pe: &KubePreviewEnvironment, service: &SharedService) -> String {
+    let raw = format!("shared-{}-{}", group(pe, service), service.name).to_lowercase();
+    let name: String = raw.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).take(63).collect();
+    name.trim_end_matches('-').to_string()
+}
+
+/// What keeps this preview's data apart from the others'.
+pub fn prefix(pe: &KubePreviewEnvironment) -> String {
+    pe.metadata.name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+/// Whether any preview other than `pe` still uses the service named `key`.
+pub fn in_use(key: &str, pe: &KubePreviewEnvironment, previews: &[KubePreviewEnvironment]) -> bool {
+    previews
+        .iter()
+        .filter(|other| other.metadata.name != pe.metadata.name)
+        .any(|other| other.spec.shared_services.iter().any(|service| self::key(other, service) == key))
+}
+
+/// The shared Deployment and Service.
+pub fn manifests(key: &str, service: &SharedService) -> [JsonValue; 2] {
+    let mut deployment = crate::to_json(&crate::deployment_manifest(key, &service.image, None));
+    let mut shared_service = crate::to_json(&crate::service_manifest(key, key, ServiceType::ClusterIP, SessionAffinity::None));
+
+    let container = &mut deployment["spec"]["template"]["spec"]["containers"][0];
+    container["ports"] = json!([{ "containerPort": service.port }]);
+    if !service.env.is_empty() {
+        let env: Vec<JsonValue> = service.env.iter().map(|(name, value)| json!({ "name": name, "value": value })).collect();
+        container["env"] = json!(env);
+    }
+    shared_service["spec"]["ports"] = json!([{ "protocol": "TCP", "port": service.port, "targetPort": service.port }]);
+
+    deployment["metadata"]["labels"][SHARED_LABEL] = json!("true");
+    shared_service["metadata"]["labels"][SHARED_LABEL] = json!("true");
+    [deployment, shared_service]
+}
+
+/// Tell the preview's containers where its shared services are.
+pub fn inject(deployment: &mut JsonValue, pe: &KubePreviewEnvironment) {
+    let mut env = Vec::new();
+    for service in &pe.spec.shared_services {
+        let var = service.name.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        env.push(json!({ "name": format!("{}_HOST", var), "value": key(pe, service) }));
+        env.push(json!({ "name": format!("{}_PORT", var), "value": service.port.to_string() }));
+        env.push(json!({ "name": format!("{}_PREFIX", var), "value": prefix(pe) }));
+    }
+    if env.is_empty() {
+        return;
+    }
+    for container in deployment["spec"]["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+        match container["env"].as_array_mut() {
+            Some(existing) => existing.extend(env.iter().cloned()),
+            None => container["env"] = json!(env),
+        }
+    }
+}
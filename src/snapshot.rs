@@ -0,0 +1,234 @@
+//! Saves a preview's database to object storage before it's torn down, so
+//! the data from a useful preview isn't lost with it.
+//!
+//! Previews with `snapshot` in their spec get a finalizer, which holds the
+//! deletion until a Job has dumped the database and uploaded it to
+//! `SNAPSHOT_LOCATION`.  The Job runs `SNAPSHOT_IMAGE` with the preview's own
+//! secrets, the ones in `SNAPSHOT_CREDENTIALS_SECRET`, and `$SNAPSHOT_URL`
+//! set to where the dump should go; by default it runs
+//! `pg_dump "$DATABASE_URL" | gzip | aws s3 cp - "$SNAPSHOT_URL"`.  Where it
+//! ended up is recorded in `status.snapshot` and in an Event, since the
+//! preview itself is gone soon after.  A failed snapshot is reported the
+//! same way but doesn't stop the preview being deleted.
+use chrono::Utc;
+use kube::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::build::{self, JobResult};
+use crate::config::Config;
+use crate::labels::{name_value, NAME_LABEL};
+use crate::{bluegreen, jobs, set_status, ApiResources, Children, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+pub const FINALIZER: &str = "preview.platform9.com/snapshot";
+
+pub const RUNNING: &str = "Running";
+pub const SUCCEEDED: &str = "Succeeded";
+pub const FAILED: &str = "Failed";
+pub const SKIPPED: &str = "Skipped";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SnapshotSpec {
+    /// Overrides `SNAPSHOT_IMAGE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// Replaces the default `pg_dump | gzip | aws s3 cp`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotStatus {
+    pub phase: String,
+    /// Where the dump was uploaded to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<String>,
+}
+
+fn default_command() -> Vec<String> {
+    let script = "pg_dump \"$DATABASE_URL\" | gzip | aws s3 cp - \"$SNAPSHOT_URL\"";
+    vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+}
+
+/// Whether the preview should be snapshotted before it's deleted.
+pub fn enabled(config: &Config, pe: &KubePreviewEnvironment) -> bool {
+    config.snapshot_location.is_some() && pe.spec.snapshot.is_some()
+}
+
+pub fn is_deleting(pe: &KubePreviewEnvironment) -> bool {
+    pe.metadata.deletion_timestamp.is_some()
+}
+
+fn has_finalizer(pe: &KubePreviewEnvironment) -> bool {
+    pe.metadata.finalizers.iter().any(|finalizer| finalizer == FINALIZER)
+}
+
+/// Add the finalizer to a preview that wants a snapshot and doesn't have
+/// it yet.
+pub async fn protect(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    if !enabled(&resources.config, pe) || has_finalizer(pe) || is_deleting(pe) {
+        return;
+    }
+    let result = resources
+        .client
+        .update(&resources.previews, &pe.metadata.name, |current: &mut JsonValue| {
+            let finalizers = &mut current["metadata"]["finalizers"];
+            match finalizers.as_array_mut() {
+                Some(existing) if existing.iter().any(|finalizer| finalizer == FINALIZER) => {}
+                Some(existing) => existing.push(json!(FINALIZER)),
+                None => *finalizers = json!([FINALIZER]),
+            }
+        })
+        .await;
+    if let Err(err) = result.map(|_: JsonValue| ()) {
+        println!("Failed to add snapshot finalizer to {}: {:?}", pe.metadata.name, err);
+    }
+}
+
+// Let the deletion go ahead.
+async fn release(resources: &ApiResources, pe: &KubePreviewEnvironment) {
+    let result = resources
+        .client
+        .update(&resources.previews, &pe.metadata.name, |current: &mut JsonValue| {
+            if let Some(finalizers) = current["metadata"]["finalizers"].as_array_mut() {
+                finalizers.retain(|finalizer| finalizer != FINALIZER);
+            }
+        })
+        .await;
+    match result.map(|_: JsonValue| ()) {
+        Ok(()) => {}
+        Err(Error::Api(ae)) if ae.code == 404 => {}
+        Err(err) => println!("Failed to remove snapshot finalizer from {}: {:?}", pe.metadata.name, err),
+    }
+}
+
+/// Where a snapshot of the preview taken now is stored.
+pub fn location(config: &Config, preview: &str) -> Option<String> {
+    let base = config.snapshot_location.as_ref()?;
+    let taken = Utc::now().format("%Y%m%dT%H%M%SZ");
+    Some(format!("{}/{}/{}/{}.sql.gz", base.trim_end_matches('/'), config.namespace, preview, taken))
+}
+
+pub fn job_json(name: &str, preview: &str, spec: &SnapshotSpec, config: &Config, location: &str, deployment: &JsonValue) -> Option<JsonValue> {
+    let image = spec.image.as_ref().or(config.snapshot_image.as_ref())?;
+    let command = if spec.command.is_empty() {
+        default_command()
+    } else {
+        spec.command.clone()
+    };
+    let mut pod_spec = jobs::task_pod_spec("snapshot", image, &command, "Never", deployment);
+    let container = &mut pod_spec["containers"][0];
+    container["env"] = json!([{ "name": "SNAPSHOT_URL", "value": location }]);
+    if let Some(credentials) = &config.snapshot_credentials_secret {
+        let credentials = json!({ "secretRef": { "name": credentials } });
+        match container["envFrom"].as_array_mut() {
+            Some(existing) => existing.push(credentials),
+            None => container["envFrom"] = json!([credentials]),
+        }
+    }
+
+    Some(json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                NAME_LABEL: name_value(preview),
+            }
+        },
+        "spec": {
+            "backoffLimit": 1,
+            "template": {
+                "spec": pod_spec,
+            }
+        }
+    }))
+}
+
+/// Handle a preview that's being deleted: take its snapshot, once, and then
+/// let it go.
+pub async fn on_delete(resources: &Arc<ApiResources>, pe: &KubePreviewEnvironment) {
+    if !has_finalizer(pe) {
+        return;
+    }
+    let attempted = pe.status.as_ref().and_then(|status| status.snapshot.as_ref()).is_some();
+    if attempted {
+        return;
+    }
+
+    set_status(resources, &pe.metadata.name, |status| {
+        status.snapshot = Some(SnapshotStatus { phase: RUNNING.to_string(), ..SnapshotStatus::default() });
+    })
+    .await;
+
+    let resources = resources.clone();
+    let pe = pe.clone();
+    tokio::spawn(async move {
+        let (phase, location, message) = take(&resources, &pe).await;
+        let (event_type, reason, note) = match phase {
+            SUCCEEDED => ("Normal", "SnapshotSaved", format!("Saved database snapshot to {}", location.as_deref().unwrap_or_default())),
+            SKIPPED => ("Normal", "SnapshotSkipped", message.clone().unwrap_or_default()),
+            _ => ("Warning", "SnapshotFailed", message.clone().unwrap_or_default()),
+        };
+        println!("{} {}", pe.metadata.name, note);
+        crate::record_event(&resources, &pe, event_type, reason, &note).await;
+        set_status(&resources, &pe.metadata.name, |status| {
+            status.snapshot = Some(SnapshotStatus {
+                phase: phase.to_string(),
+                location: location.clone(),
+                message: message.clone(),
+                completed_at: Some(Utc::now().to_rfc3339()),
+            });
+        })
+        .await;
+        release(&resources, &pe).await;
+    });
+}
+
+// Dump the database and wait for the upload to finish.
+async fn take(resources: &ApiResources, pe: &KubePreviewEnvironment) -> (&'static str, Option<String>, Option<String>) {
+    let spec = pe.spec.snapshot.clone().unwrap_or_default();
+    let name = &pe.metadata.name;
+    let location = match location(&resources.config, name) {
+        Some(location) => location,
+        None => return (SKIPPED, None, Some("SNAPSHOT_LOCATION is not set".to_string())),
+    };
+
+    // The live Deployment has the secrets the database is reached with.
+    // Without one there's nothing running to snapshot.
+    let (active, _) = bluegreen::slots(pe);
+    let deployment: JsonValue = match resources.deployments.get(&active) {
+        Ok(request) => match resources.client.request(request).await {
+            Ok(deployment) => deployment,
+            Err(Error::Api(ae)) if ae.code == 404 => {
+                return (SKIPPED, None, Some("Nothing to snapshot, the preview has no Deployment".to_string()))
+            }
+            Err(err) => return (FAILED, None, Some(format!("Failed to read {}: {}", active, err))),
+        },
+        Err(err) => return (FAILED, None, Some(format!("Failed to read {}: {}", active, err))),
+    };
+
+    let job_name = Children::of(pe).snapshot_job;
+    let job_json = match job_json(&job_name, name, &spec, &resources.config, &location, &deployment) {
+        Some(job_json) => job_json,
+        None => return (SKIPPED, None, Some("No image to take the snapshot with; set SNAPSHOT_IMAGE".to_string())),
+    };
+    if let Err(err) = crate::create_child(resources, &resources.jobs, &job_json).await {
+        return (FAILED, None, Some(format!("Failed to start snapshot job: {}", err)));
+    }
+
+    match build::wait_for_job(&resources.client, &resources.jobs, &job_name, resources.config.job_timeout).await {
+        Ok(JobResult::Succeeded) => (SUCCEEDED, Some(location), None),
+        Ok(JobResult::Failed(message)) => (FAILED, None, Some(format!("Snapshot failed: {}", message))),
+        Err(err) => (FAILED, None, Some(format!("Failed to follow snapshot job: {}", err))),
+    }
+}
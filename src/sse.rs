@@ -0,0 +1,48 @@
+//! A live feed of what's happening to previews, as server-sent events on
+//! `GET /events` at `EVENTS_ADDR`, for dashboards and CI jobs waiting on a
+//! preview.  Each event is named after its reason and carries the bus event
+//! as JSON.  `?preview=<name>` follows a single preview.
+//!
+//! Clients only get what happens while they're connected; there's no
+//! replay.  A client that can't keep up misses events rather than slowing
+//! anything else down.
+use futures::{future, StreamExt};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::RecvError;
+use warp::Filter;
+
+use crate::bus::Bus;
+
+// How often idle connections get a comment, so proxies don't close them.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub async fn serve(addr: SocketAddr, bus: Arc<Bus>) {
+    let route = warp::get()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |query: HashMap<String, String>| {
+            let preview = query.get("preview").cloned();
+            let events = bus.subscribe().filter_map(move |received| {
+                let event = match received {
+                    Ok(event) if preview.as_ref().map_or(true, |name| *name == event.preview) => Some(event),
+                    Ok(_) => None,
+                    Err(RecvError::Lagged(missed)) => {
+                        println!("An events API client fell behind and missed {} preview events", missed);
+                        None
+                    }
+                    Err(RecvError::Closed) => None,
+                };
+                let sent = event.map(|event| (warp::sse::event(event.reason.clone()), warp::sse::json(event)));
+                future::ready(sent.map(Ok::<_, Infallible>))
+            });
+            warp::sse::reply(warp::sse::keep_alive().interval(KEEP_ALIVE).stream(events))
+        });
+
+    println!("Events API listening on {}", addr);
+    warp::serve(route).run(addr).await;
+}
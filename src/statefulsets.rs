@@ -0,0 +1,122 @@
+//! StatefulSet previews, for apps like databases or Kafka consumers that
+//! need stable network identities and their own storage.  The rendered
+//! Deployment is turned into a StatefulSet with the same name and pods,
+//! backed by a headless Service (see `services`) and a PersistentVolumeClaim
+//! per pod for each of the preview's `volumeClaimTemplates`.  StatefulSets are always
+//! updated in place, whatever the preview's `strategy`.
+use kube::{
+    api::{ListParams, RawApi},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::client::Client;
+use crate::labels::{name_value, NAME_LABEL};
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WorkloadType {
+    Deployment,
+    StatefulSet,
+}
+
+impl Default for WorkloadType {
+    fn default() -> Self {
+        WorkloadType::Deployment
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeClaimSpec {
+    pub name: String,
+    /// Storage to request, e.g. `1Gi`.
+    pub size: String,
+    /// Where the volume is mounted in the preview's container.
+    pub mount_path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_class_name: Option<String>,
+}
+
+/// Turn a rendered Deployment into a StatefulSet governed by `service`.
+pub fn stateful_set_json(deployment: &JsonValue, service: &str, preview: &str, claims: &[VolumeClaimSpec]) -> JsonValue {
+    let mut stateful_set = deployment.clone();
+    stateful_set["kind"] = json!("StatefulSet");
+    stateful_set["spec"]["serviceName"] = json!(service);
+    // Deployments and StatefulSets spell their update strategies differently
+    if let Some(spec) = stateful_set["spec"].as_object_mut() {
+        spec.remove("strategy");
+    }
+
+    let templates: Vec<JsonValue> = claims
+        .iter()
+        .map(|claim| {
+            let mut template = json!({
+                "metadata": {
+                    "name": claim.name,
+                    // So the claims can be found and removed with the preview
+                    "labels": { "preview": "true", NAME_LABEL: name_value(preview) },
+                },
+                "spec": {
+                    "accessModes": ["ReadWriteOnce"],
+                    "resources": { "requests": { "storage": claim.size } },
+                }
+            });
+            if let Some(storage_class_name) = &claim.storage_class_name {
+                template["spec"]["storageClassName"] = json!(storage_class_name);
+            }
+            template
+        })
+        .collect();
+    if !templates.is_empty() {
+        stateful_set["spec"]["volumeClaimTemplates"] = json!(templates);
+        let mounts: Vec<JsonValue> = claims
+            .iter()
+            .map(|claim| json!({ "name": claim.name, "mountPath": claim.mount_path }))
+            .collect();
+        let container = &mut stateful_set["spec"]["template"]["spec"]["containers"][0];
+        match container["volumeMounts"].as_array_mut() {
+            Some(existing) => existing.extend(mounts),
+            None => container["volumeMounts"] = json!(mounts),
+        }
+    }
+    stateful_set
+}
+
+pub async fn update_image(client: &Client, stateful_sets: &RawApi, name: &str, image: &str) -> Result<(), Error> {
+    client
+        .update(stateful_sets, name, |stateful_set: &mut JsonValue| {
+            for container in stateful_set["spec"]["template"]["spec"]["containers"].as_array_mut().into_iter().flatten() {
+                container["image"] = json!(image);
+            }
+        })
+        .await
+        .map(|_: JsonValue| ())
+}
+
+/// Roll the StatefulSet's pods the same way `kubectl rollout restart` does.
+pub async fn restart(client: &Client, stateful_sets: &RawApi, name: &str, restarted_at: &str) -> Result<(), Error> {
+    client
+        .update(stateful_sets, name, |stateful_set: &mut JsonValue| {
+            let template = &mut stateful_set["spec"]["template"];
+            template["metadata"]["annotations"]["kubectl.kubernetes.io/restartedAt"] = json!(restarted_at);
+            for container in template["spec"]["containers"].as_array_mut().into_iter().flatten() {
+                container["imagePullPolicy"] = json!("Always");
+            }
+        })
+        .await
+        .map(|_: JsonValue| ())
+}
+
+/// StatefulSets leave their claims behind when they're deleted, so they're
+/// removed separately.
+pub async fn remove_claims(client: &Client, claims: &RawApi, preview: &str) -> Result<(), Error> {
+    let lp = ListParams {
+        label_selector: Some(format!("{}={}", NAME_LABEL, name_value(preview))),
+        ..ListParams::default()
+    };
+    client.request::<JsonValue>(claims.delete_collection(&lp)?).await?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Bookkeeping that has to outlive the controller process.  Most of what the
+//! controller knows about a preview lives in the preview's status, but some
+//! of it -- when a failed preview is next due a retry, when a blue-green
+//! rollback window closes -- only lived in memory, so every redeploy of the
+//! controller started those clocks again.  A controller in a crash loop
+//! would never get round to them at all.
+//!
+//! With `STATE_DIR` set that bookkeeping is kept in an embedded sled
+//! database there as well, and picked up again on startup.  The directory
+//! wants to be on a volume that survives the pod, and only one controller
+//! should use it at a time.  Without `STATE_DIR` everything stays in memory
+//! as before.  Needs the `store` cargo feature.
+//!
+//! Entries are JSON, keyed by what they are and the preview they're for,
+//! e.g. `requeue/web`.  Anything that can't be read is treated as missing
+//! rather than failing the reconcile that wanted it.
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+#[derive(Default)]
+pub struct Store {
+    #[cfg(feature = "store")]
+    db: Option<sled::Db>,
+}
+
+impl Store {
+    /// Opens the store in `STATE_DIR`, or an in-memory stand-in when it's
+    /// unset.  A store that can't be opened fails at startup rather than
+    /// silently forgetting everything.
+    #[cfg(feature = "store")]
+    pub fn open(config: &Config) -> Self {
+        let db = config.state_dir.as_ref().map(|dir| {
+            println!("Keeping reconcile state in {}", dir);
+            sled::open(dir).unwrap_or_else(|err| panic!("Failed to open the state store in {}: {}", dir, err))
+        });
+        Store { db }
+    }
+
+    #[cfg(not(feature = "store"))]
+    pub fn open(config: &Config) -> Self {
+        if config.state_dir.is_some() {
+            panic!("STATE_DIR is set but the controller was built without the store feature");
+        }
+        Store::default()
+    }
+
+    #[cfg(feature = "store")]
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = match self.db.as_ref()?.get(key) {
+            Ok(value) => value?,
+            Err(err) => {
+                println!("Failed to read {} from the state store: {}", key, err);
+                return None;
+            }
+        };
+        serde_json::from_slice(&value).ok()
+    }
+
+    #[cfg(not(feature = "store"))]
+    pub fn get<T: DeserializeOwned>(&self, _key: &str) -> Option<T> {
+        None
+    }
+
+    /// Everything kept under `prefix`, with the prefix taken off the keys.
+    #[cfg(feature = "store")]
+    pub fn scan<T: DeserializeOwned>(&self, prefix: &str) -> Vec<(String, T)> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return vec![],
+        };
+        db.scan_prefix(prefix)
+            .filter_map(|entry| {
+                let (key, value) = entry.ok()?;
+                let key = String::from_utf8(key.to_vec()).ok()?;
+                Some((key[prefix.len()..].to_string(), serde_json::from_slice(&value).ok()?))
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "store"))]
+    pub fn scan<T: DeserializeOwned>(&self, _prefix: &str) -> Vec<(String, T)> {
+        vec![]
+    }
+
+    #[cfg(feature = "store")]
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) {
+        if let Some(db) = &self.db {
+            let value = serde_json::to_vec(value).expect("Failed to serialize state");
+            if let Err(err) = db.insert(key, value) {
+                println!("Failed to write {} to the state store: {}", key, err);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "store"))]
+    pub fn put<T: Serialize>(&self, _key: &str, _value: &T) {}
+
+    #[cfg(feature = "store")]
+    pub fn remove(&self, key: &str) {
+        if let Some(db) = &self.db {
+            if let Err(err) = db.remove(key) {
+                println!("Failed to remove {} from the state store: {}", key, err);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "store"))]
+    pub fn remove(&self, _key: &str) {}
+}
+
+/// A deadline as it's kept in the store, in seconds since the epoch, since
+/// an `Instant` means nothing to the next process.
+pub fn deadline(delay: Duration) -> u64 {
+    (SystemTime::now() + delay).duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default()
+}
+
+/// How long until a deadline from the store, or zero if it's passed.
+pub fn remaining(deadline: u64) -> Duration {
+    let at = UNIX_EPOCH + Duration::from_secs(deadline);
+    at.duration_since(SystemTime::now()).unwrap_or_default()
+}
@@ -0,0 +1,145 @@
+//! Cleans up after crashes.  If the controller dies between a preview being
+//! deleted and its children being cleaned up, the children are left behind
+//! with nothing to delete them.  The sweeper runs at startup and then every
+//! `SWEEP_INTERVAL_SECONDS`, looking for resources labelled `preview=true`
+//! whose preview no longer exists, and deletes them.  With `SWEEP_DRY_RUN`
+//! it only reports what it would delete.
+//!
+//! Resources are matched to their preview by the
+//! `preview.platform9.com/name` label, so anything without it, such as
+//! shared services, is left alone.
+//!
+//! It also deletes previews that have outlived their TTL, their team's
+//! `preview_ttl_seconds` or the controller's `PREVIEW_TTL_SECONDS`, counted
+//! from when they were created.  Their children go the way any deleted
+//! preview's do.  None are deleted during a freeze; see `freeze`.
+use chrono::{DateTime, Utc};
+use kube::api::{DeleteParams, ListParams};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::labels::{name_value, NAME_LABEL};
+use crate::{freeze, ApiResources};
+
+type JsonValue = serde_json::value::Value;
+
+pub async fn run(resources: Arc<ApiResources>, interval: Duration) {
+    loop {
+        expire(&resources).await;
+        sweep(&resources).await;
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+/// Deletes previews older than their TTL, unless there's a freeze on.
+pub async fn expire(resources: &ApiResources) {
+    if let Some(window) = freeze::current(resources) {
+        println!("Not deleting previews older than their TTL until the freeze ends at {}", window.end.to_rfc3339());
+        return;
+    }
+    let list: Result<JsonValue, _> = match resources.previews.list(&ListParams::default()) {
+        Ok(request) => resources.client.request(request).await,
+        Err(err) => Err(err),
+    };
+    let list = match list {
+        Ok(list) => list,
+        Err(err) => {
+            println!("Failed to list previews to expire: {:?}", err);
+            return;
+        }
+    };
+
+    let dry_run = resources.reloadable().sweep_dry_run || resources.config.maintenance;
+    for item in list["items"].as_array().into_iter().flatten() {
+        let team = item["metadata"]["labels"][resources.config.team_label.as_str()].as_str();
+        let ttl = match resources.profiles.get(team).ttl.or(resources.config.preview_ttl) {
+            Some(ttl) => ttl,
+            None => continue,
+        };
+        let created = item["metadata"]["creationTimestamp"].as_str().map(DateTime::parse_from_rfc3339);
+        let expired = match (created, chrono::Duration::from_std(ttl)) {
+            (Some(Ok(created)), Ok(ttl)) => Utc::now() - created.with_timezone(&Utc) >= ttl,
+            _ => false,
+        };
+        // Already on its way out
+        if !expired || !item["metadata"]["deletionTimestamp"].is_null() {
+            continue;
+        }
+        let name = item["metadata"]["name"].as_str().unwrap_or_default();
+        if dry_run {
+            println!("Would delete preview {}, older than its TTL of {}s", name, ttl.as_secs());
+            continue;
+        }
+        println!("Deleting preview {}, older than its TTL of {}s", name, ttl.as_secs());
+        let previews = &resources.previews;
+        if let Err(err) = crate::delete_child(resources, "previewenvironment", previews, name, &DeleteParams::default()).await {
+            println!("Failed to delete preview {}: {:?}", name, err);
+        }
+    }
+}
+
+async fn sweep(resources: &ApiResources) {
+    // If we can't tell which previews exist, everything would look orphaned
+    let previews: BTreeSet<String> = match crate::list_previews(resources).await {
+        Ok(previews) => previews.iter().map(|pe| name_value(&pe.metadata.name)).collect(),
+        Err(err) => {
+            println!("Failed to list previews, skipping sweep: {:?}", err);
+            return;
+        }
+    };
+
+    let kinds = [
+        ("deployment", &resources.deployments),
+        ("statefulset", &resources.stateful_sets),
+        ("service", &resources.services),
+        ("mapping", &resources.mappings),
+        ("tcpmapping", &resources.tcp_mappings),
+        ("host", &resources.hosts),
+        ("configmap", &resources.config_maps),
+        ("job", &resources.jobs),
+        ("cronjob", &resources.cron_jobs),
+    ];
+    let lp = ListParams {
+        label_selector: Some("preview=true".to_string()),
+        ..ListParams::default()
+    };
+    // Nothing is deleted during maintenance, but it's still worth knowing
+    let dry_run = resources.reloadable().sweep_dry_run || resources.config.maintenance;
+    let mut orphans = 0;
+    for (kind, api) in kinds.iter() {
+        let list: Result<JsonValue, _> = match api.list(&lp) {
+            Ok(request) => resources.client.request(request).await,
+            Err(err) => Err(err),
+        };
+        let list = match list {
+            Ok(list) => list,
+            Err(err) => {
+                println!("Failed to list {}s to sweep: {:?}", kind, err);
+                continue;
+            }
+        };
+
+        for item in list["items"].as_array().into_iter().flatten() {
+            let preview = match item["metadata"]["labels"][NAME_LABEL].as_str() {
+                Some(preview) if !previews.contains(preview) => preview,
+                _ => continue,
+            };
+            let name = item["metadata"]["name"].as_str().unwrap_or_default();
+            orphans += 1;
+            if dry_run {
+                println!("Would delete {} {}, left over from preview {}", kind, name, preview);
+                continue;
+            }
+            println!("Deleting {} {}, left over from preview {}", kind, name, preview);
+            if let Err(err) = crate::delete_child(resources, kind, api, name, &DeleteParams::default()).await {
+                println!("Failed to delete {} {}: {:?}", kind, name, err);
+            }
+        }
+    }
+
+    if orphans > 0 {
+        let verb = if dry_run { "Found" } else { "Swept" };
+        println!("{} {} orphaned resources", verb, orphans);
+    }
+}
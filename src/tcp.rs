@@ -0,0 +1,76 @@
+//! Raw TCP previews, such as a database proxy.  Ambassador can't route TCP
+//! by hostname, so each TCP preview is given its own listener port from
+//! `TCP_PORT_RANGE` and a TCPMapping from that port to its Service.
+//! Ambassador's own Service has to expose the range for the ports to be
+//! reachable.  The address ends up in `status.tcpAddress`.
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::str::FromStr;
+
+use crate::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TcpSpec {
+    /// Service port to expose, 80 unless `ports` says otherwise.
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 {
+    80
+}
+
+/// An inclusive range of listener ports, written `30000-30999`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut bounds = value.splitn(2, '-').map(|bound| bound.trim().parse::<u16>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(first)), Some(Ok(last))) if first <= last => Ok(PortRange { first, last }),
+            _ => Err(format!("invalid port range {:?}", value)),
+        }
+    }
+}
+
+/// The listener port for `pe`: the one it already has, or the lowest one
+/// no other preview is using.
+pub fn allocate(range: PortRange, pe: &KubePreviewEnvironment, previews: &[KubePreviewEnvironment]) -> Option<u16> {
+    let assigned = |preview: &KubePreviewEnvironment| preview.status.as_ref().and_then(|status| status.tcp_port);
+    if let Some(port) = assigned(pe) {
+        return Some(port);
+    }
+    let taken: Vec<u16> = previews
+        .iter()
+        .filter(|other| other.metadata.name != pe.metadata.name)
+        .filter_map(assigned)
+        .collect();
+    (range.first..=range.last).find(|port| !taken.contains(port))
+}
+
+pub fn tcp_mapping_json(name: &str, preview: &str, listener_port: u16, service: &str, service_port: u16) -> JsonValue {
+    json!({
+        "apiVersion": "getambassador.io/v2",
+        "kind": "TCPMapping",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "spec": {
+            "port": listener_port,
+            "service": format!("{}:{}", service, service_port),
+        }
+    })
+}
@@ -0,0 +1,104 @@
+//! Tekton pipelines run against a preview once it's Ready -- smoke tests,
+//! E2E suites and the like.  Each image the preview runs gets one
+//! PipelineRun, given the preview's name, URL and image as parameters, and
+//! its result is recorded in `status.pipelineRun`.
+use kube::{api::RawApi, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::client::Client;
+
+type JsonValue = serde_json::value::Value;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+pub const RUNNING: &str = "Running";
+pub const SUCCEEDED: &str = "Succeeded";
+pub const FAILED: &str = "Failed";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PipelineSpec {
+    /// Name of a Tekton Pipeline in the preview namespace.
+    pub name: String,
+    /// Parameters passed on top of `preview-name`, `preview-url` and
+    /// `preview-image`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PipelineRunStatus {
+    pub name: String,
+    /// The image the pipeline ran against.
+    pub image: String,
+    /// `Running`, `Succeeded` or `Failed`.
+    pub result: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+pub enum RunResult {
+    Succeeded,
+    Failed(String),
+}
+
+pub fn pipeline_run_json(name: &str, preview: &str, pipeline: &PipelineSpec, image: &str, fqdn: &str) -> JsonValue {
+    let mut params = vec![
+        json!({ "name": "preview-name", "value": preview }),
+        json!({ "name": "preview-url", "value": format!("https://{}", fqdn) }),
+        json!({ "name": "preview-image", "value": image }),
+    ];
+    params.extend(pipeline.params.iter().map(|(name, value)| json!({ "name": name, "value": value })));
+
+    let mut run = json!({
+        "apiVersion": "tekton.dev/v1beta1",
+        "kind": "PipelineRun",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+            }
+        },
+        "spec": {
+            "pipelineRef": { "name": pipeline.name },
+            "params": params,
+        }
+    });
+    if let Some(service_account_name) = &pipeline.service_account_name {
+        run["spec"]["serviceAccountName"] = json!(service_account_name);
+    }
+    run
+}
+
+/// Polls a PipelineRun until Tekton marks it finished or `timeout` passes.
+pub async fn wait_for_run(client: &Client, pipeline_runs: &RawApi, name: &str, timeout: Duration) -> Result<RunResult, Error> {
+    let started = Instant::now();
+    loop {
+        let run: JsonValue = client.request(pipeline_runs.get(name)?).await?;
+        let succeeded = run["status"]["conditions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|condition| condition["type"] == "Succeeded");
+        match succeeded.map(|condition| (condition["status"].as_str(), condition)) {
+            Some((Some("True"), _)) => return Ok(RunResult::Succeeded),
+            Some((Some("False"), condition)) => {
+                let message = condition["message"].as_str().unwrap_or("pipeline failed");
+                return Ok(RunResult::Failed(message.to_string()));
+            }
+            _ => {}
+        }
+
+        if started.elapsed() > timeout {
+            return Ok(RunResult::Failed(format!("pipeline did not finish within {:?}", timeout)));
+        }
+        tokio::time::delay_for(POLL_INTERVAL).await;
+    }
+}
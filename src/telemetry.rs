@@ -0,0 +1,18 @@
+use opentelemetry_otlp::Uninstall;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Installs an OTLP trace exporter when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+///
+/// The returned guard flushes any pending spans when it is dropped, so hold
+/// on to it for as long as the controller is running.
+pub fn init() -> Option<Uninstall> {
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .install()
+        .expect("Failed to install OTLP trace exporter");
+
+    let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
+    Some(uninstall)
+}
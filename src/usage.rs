@@ -0,0 +1,89 @@
+//! Live CPU and memory usage for each preview, read from metrics-server
+//! (`metrics.k8s.io`) and kept in the preview's status so idle but
+//! expensive previews are easy to spot.
+use kube::{
+    api::{ListParams, RawApi},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::cost::parse_quantity;
+use crate::{ApiResources, Children};
+
+type JsonValue = serde_json::value::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    /// CPU in use across all the preview's pods, e.g. `120m`.
+    pub cpu: String,
+    /// Memory in use across all the preview's pods, e.g. `256Mi`.
+    pub memory: String,
+}
+
+/// Usage of every pod in the namespace, summed by the `app` label the
+/// controller puts on each preview's pods.
+pub async fn by_app(client: &Client, pod_metrics: &RawApi) -> Result<BTreeMap<String, Usage>, Error> {
+    let list: JsonValue = client.request(pod_metrics.list(&ListParams::default())?).await?;
+
+    let mut totals: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+    for pod in list["items"].as_array().into_iter().flatten() {
+        let app = match pod["metadata"]["labels"]["app"].as_str() {
+            Some(app) => app,
+            None => continue,
+        };
+        let total = totals.entry(app.to_string()).or_default();
+        for container in pod["containers"].as_array().into_iter().flatten() {
+            total.0 += container["usage"]["cpu"].as_str().and_then(parse_quantity).unwrap_or(0.0);
+            total.1 += container["usage"]["memory"].as_str().and_then(parse_quantity).unwrap_or(0.0);
+        }
+    }
+
+    Ok(totals
+        .into_iter()
+        .map(|(app, (cpu, memory))| {
+            let usage = Usage {
+                cpu: format!("{}m", (cpu * 1000.0).round()),
+                memory: format!("{}Mi", (memory / (1024.0 * 1024.0)).round()),
+            };
+            (app, usage)
+        })
+        .collect())
+}
+
+/// Periodically refreshes every preview's usage.  Status is only written
+/// when the numbers change, to keep the noise down.
+pub async fn refresh(resources: Arc<ApiResources>, pod_metrics: RawApi, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let usage = match by_app(&resources.client, &pod_metrics).await {
+            Ok(usage) => usage,
+            Err(err) => {
+                println!("Failed to read pod metrics: {:?}", err);
+                continue;
+            }
+        };
+        let previews = match crate::list_previews(&resources).await {
+            Ok(previews) => previews,
+            Err(err) => {
+                println!("Failed to list previews for usage: {:?}", err);
+                continue;
+            }
+        };
+
+        for pe in previews {
+            let current = usage.get(&Children::of(&pe).deployment).cloned();
+            let recorded = pe.status.as_ref().and_then(|status| status.usage.clone());
+            if current != recorded {
+                crate::set_status(&resources, &pe.metadata.name, |status| {
+                    status.usage = current.clone();
+                })
+                .await;
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Checks on a preview's spec that the CRD schema can't express.  Without
+//! them a bad spec gets as far as creating its children, and fails with
+//! whatever the API server or the kubelet makes of it, often some time
+//! later.  A preview that fails them isn't created or updated at all; it's
+//! marked Failed with the `InvalidSpec` condition saying what's wrong, and
+//! left alone until its spec changes.
+use std::collections::BTreeSet;
+
+use crate::statefulsets::WorkloadType;
+use crate::{fqdn, KubePreviewEnvironment};
+
+/// Type of the condition that says what's wrong with the spec.
+pub const CONDITION: &str = "InvalidSpec";
+
+/// Everything wrong with `pe`'s spec, if anything is.
+pub fn problems(pe: &KubePreviewEnvironment) -> Vec<String> {
+    let spec = &pe.spec;
+    let mut problems = Vec::new();
+
+    // A build supplies the image, and a Jsonnet template needn't use one
+    if spec.image.is_empty() && spec.build.is_none() && spec.jsonnet.is_none() {
+        problems.push("image is required unless build or jsonnet is set".to_string());
+    }
+    if !spec.image.is_empty() {
+        problems.extend(image_reference(&spec.image).err().map(|err| format!("image: {}", err)));
+    }
+    if let Some(canary) = &spec.canary {
+        problems.extend(image_reference(&canary.image).err().map(|err| format!("canary.image: {}", err)));
+        if canary.weight > 100 {
+            problems.push(format!("canary.weight is {}, but it's a percentage", canary.weight));
+        }
+    }
+    if !spec.fqdn.is_empty() {
+        problems.extend(hostname(&spec.fqdn).err().map(|err| format!("fqdn: {}", err)));
+    }
+    if let Some(template) = &spec.fqdn_template {
+        problems.extend(fqdn::check_template(template).err().map(|err| format!("fqdnTemplate: {}", err)));
+    }
+    if spec.replicas < 0 {
+        problems.push(format!("replicas is {}, but can't be negative", spec.replicas));
+    }
+
+    let mut names = BTreeSet::new();
+    for port in &spec.ports {
+        if port.port == 0 {
+            problems.push(format!("port {} has number 0", port.name));
+        }
+        problems.extend(port_name(&port.name).err().map(|err| format!("port {:?}: {}", port.name, err)));
+        if !names.insert(port.name.as_str()) {
+            problems.push(format!("port {} is listed more than once", port.name));
+        }
+        if !["TCP", "UDP", "SCTP"].contains(&port.protocol.as_str()) {
+            problems.push(format!("port {} has protocol {:?}, which isn't TCP, UDP or SCTP", port.name, port.protocol));
+        }
+    }
+    for route in &spec.routes {
+        if !route.prefix.starts_with('/') {
+            problems.push(format!("route {:?} must start with /", route.prefix));
+        }
+        if !names.contains(route.port.as_str()) {
+            problems.push(format!("route {} goes to port {}, which isn't in ports", route.prefix, route.port));
+        }
+    }
+    if let Some(tcp) = &spec.tcp {
+        if tcp.port == 0 {
+            problems.push("tcp.port can't be 0".to_string());
+        } else if !spec.ports.is_empty() && !spec.ports.iter().any(|port| port.port == tcp.port) {
+            problems.push(format!("tcp.port {} isn't one of ports", tcp.port));
+        }
+    }
+
+    if spec.source.is_some() && spec.jsonnet.is_some() {
+        problems.push("source and jsonnet can't both be set, since each replaces the preview's manifests".to_string());
+    }
+    if !spec.volume_claim_templates.is_empty() && spec.workload_type != WorkloadType::StatefulSet {
+        problems.push("volumeClaimTemplates need workloadType StatefulSet".to_string());
+    }
+    problems
+}
+
+/// Checks `image` looks like `[registry/]repository[:tag][@digest]`.
+pub fn image_reference(image: &str) -> Result<(), String> {
+    if image.chars().any(char::is_whitespace) {
+        return Err(format!("{:?} contains whitespace", image));
+    }
+    let (rest, digest) = match image.find('@') {
+        Some(at) => (&image[..at], Some(&image[at + 1..])),
+        None => (image, None),
+    };
+    if let Some(digest) = digest {
+        let valid = match digest.find(':') {
+            Some(colon) => {
+                let hex = &digest[colon + 1..];
+                colon > 0 && hex.len() >= 32 && hex.chars().all(|c| c.is_ascii_hexdigit())
+            }
+            None => false,
+        };
+        if !valid {
+            return Err(format!("{:?} has an invalid digest", image));
+        }
+    }
+    // A colon after the last slash starts the tag; before it, it's the
+    // registry's port
+    let (repository, tag) = match rest.rfind(':').filter(|colon| rest[*colon..].find('/').is_none()) {
+        Some(colon) => (&rest[..colon], Some(&rest[colon + 1..])),
+        None => (rest, None),
+    };
+    if let Some(tag) = tag {
+        let valid = !tag.is_empty()
+            && tag.len() <= 128
+            && !tag.starts_with(|c| c == '.' || c == '-')
+            && tag.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        if !valid {
+            return Err(format!("{:?} has an invalid tag", image));
+        }
+    }
+    let mut components: Vec<&str> = repository.split('/').collect();
+    // The registry can have capitals, dots and a port; the path can't
+    if components.len() > 1 && (components[0].contains(|c| c == '.' || c == ':') || components[0] == "localhost") {
+        components.remove(0);
+    }
+    for component in components {
+        let valid = !component.is_empty()
+            && component.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && component.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && component.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c));
+        if !valid {
+            let message = "repositories can only have lowercase letters, digits, ., _ and -";
+            return Err(format!("{:?} isn't a valid image reference; {}", image, message));
+        }
+    }
+    Ok(())
+}
+
+/// Checks `name` is a lowercase DNS name, as Ambassador and DNS providers
+/// need.
+pub fn hostname(name: &str) -> Result<(), String> {
+    if name.len() > 253 {
+        return Err(format!("{} is longer than 253 characters", name));
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("{:?} needs labels of 1 to 63 characters between its dots", name));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(format!("{:?} has a label starting or ending with -", name));
+        }
+        if !label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            return Err(format!("{:?} can only have lowercase letters, digits and -", name));
+        }
+    }
+    Ok(())
+}
+
+// Port names are IANA service names, which Kubernetes holds Services to.
+fn port_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 15 {
+        return Err("names must be 1 to 15 characters".to_string());
+    }
+    if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return Err("names can only have lowercase letters, digits and -".to_string());
+    }
+    if !name.chars().any(|c| c.is_ascii_lowercase()) || name.starts_with('-') || name.ends_with('-') || name.contains("--") {
+        return Err("names need a letter, and can't start or end with - or have -- in them".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn problems_with(spec: serde_json::Value) -> Vec<String> {
+        let pe = serde_json::from_value(json!({
+            "apiVersion": "platform9.com/v1",
+            "kind": "PreviewEnvironment",
+            "metadata": { "name": "web" },
+            "spec": spec,
+        }))
+        .unwrap();
+        problems(&pe)
+    }
+
+    #[test]
+    fn specs_are_checked_for_what_the_schema_cant_say() {
+        let web = json!({ "name": "web", "port": 8080 });
+        let cases = vec![
+            (json!({ "image": "nginx:1.19", "fqdn": "web.previews.example.com" }), vec![]),
+            (json!({}), vec!["image is required unless build or jsonnet is set"]),
+            (json!({ "image": "Nginx" }), vec!["image: \"Nginx\" isn't a valid image reference"]),
+            (json!({ "image": "nginx", "fqdn": "Web.example.com" }), vec!["fqdn: \"Web.example.com\" can only have"]),
+            (json!({ "image": "nginx", "replicas": -1 }), vec!["replicas is -1, but can't be negative"]),
+            (json!({ "image": "nginx", "canary": { "image": "nginx:2", "weight": 150 } }), vec!["canary.weight is 150"]),
+            (json!({ "image": "nginx", "ports": [web.clone(), web.clone()] }), vec!["port web is listed more than once"]),
+            (
+                json!({ "image": "nginx", "ports": [{ "name": "Web", "port": 0 }] }),
+                vec!["port Web has number 0", "port \"Web\": names can only"],
+            ),
+            (
+                json!({ "image": "nginx", "ports": [{ "name": "dns", "port": 53, "protocol": "ICMP" }] }),
+                vec!["port dns has protocol \"ICMP\""],
+            ),
+            (
+                json!({ "image": "nginx", "ports": [web.clone()], "routes": [{ "prefix": "api", "port": "grpc" }] }),
+                vec!["route \"api\" must start with /", "route api goes to port grpc, which isn't in ports"],
+            ),
+            (json!({ "image": "nginx", "ports": [web.clone()], "tcp": { "port": 5432 } }), vec!["tcp.port 5432 isn't"]),
+            (
+                json!({ "image": "nginx", "volumeClaimTemplates": [{ "name": "data", "size": "1Gi", "mountPath": "/data" }] }),
+                vec!["volumeClaimTemplates need workloadType"],
+            ),
+        ];
+
+        for (spec, expected) in cases {
+            let problems = problems_with(spec.clone());
+            assert_eq!(problems.len(), expected.len(), "{}: {:?}", spec, problems);
+            for (problem, expected) in problems.iter().zip(expected) {
+                assert!(problem.contains(expected), "{}: {:?}", spec, problems);
+            }
+        }
+    }
+
+    #[test]
+    fn image_references_are_checked_the_way_docker_reads_them() {
+        let valid = vec![
+            "nginx",
+            "nginx:1.19",
+            "library/nginx:1.19-alpine",
+            "registry.example.com:5000/team/web:v1",
+            "localhost/web",
+            "MyRegistry.example.com/web",
+            "nginx@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+        ];
+        for image in valid {
+            assert_eq!(image_reference(image), Ok(()), "{}", image);
+        }
+
+        let invalid = vec![
+            "nginx :1.19",
+            "Team/web",
+            "nginx:",
+            "nginx:-latest",
+            "nginx@sha256:abc",
+            "nginx@0123456789abcdef0123456789abcdef",
+            "team//web",
+            "web-",
+        ];
+        for image in invalid {
+            assert!(image_reference(image).is_err(), "{}", image);
+        }
+    }
+}
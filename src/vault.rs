@@ -0,0 +1,231 @@
+//! Mints short-lived database credentials for each preview from Vault's
+//! database secrets engine.
+//!
+//! The credentials are written to a `<preview>-vault-db` Secret which is
+//! exposed to the preview as `DB_USERNAME` and `DB_PASSWORD`.  The Secret
+//! carries the lease ID so a background task can keep renewing the lease
+//! while the preview lives, and cleanup can revoke it when the preview goes.
+use kube::{
+    api::{ListParams, PostParams, RawApi},
+    Error,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::config::Config;
+use crate::names;
+
+type JsonValue = serde_json::value::Value;
+
+pub const LEASE_ANNOTATION: &str = "preview.platform9.com/vault-lease-id";
+const CREDENTIALS_LABEL: &str = "preview.platform9.com/vault-credentials";
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+#[derive(Error, Debug)]
+pub enum VaultError {
+    #[error("Vault request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Vault returned {0}: {1}")]
+    Vault(u16, String),
+    #[error("Failed to read service account token: {0}")]
+    Token(#[from] std::io::Error),
+}
+
+/// Either a fixed token or a role to log in with via Vault's Kubernetes
+/// auth method using the controller's service account.
+enum Auth {
+    Token(String),
+    Kubernetes { role: String },
+}
+
+pub struct Vault {
+    http: reqwest::Client,
+    addr: String,
+    auth: Auth,
+    token: Mutex<Option<String>>,
+    pub database_mount: String,
+    pub database_role: String,
+}
+
+#[derive(Deserialize)]
+pub struct Lease {
+    pub lease_id: String,
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
+impl Vault {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let addr = config.vault_addr.clone()?;
+        let database_role = config.vault_database_role.clone()?;
+        let auth = match (&config.vault_token, &config.vault_auth_role) {
+            (Some(token), _) => Auth::Token(token.clone()),
+            (None, Some(role)) => Auth::Kubernetes { role: role.clone() },
+            (None, None) => panic!("VAULT_TOKEN or VAULT_AUTH_ROLE is required when VAULT_ADDR is set"),
+        };
+        Some(Vault {
+            http: reqwest::Client::new(),
+            addr: addr.trim_end_matches('/').to_string(),
+            auth,
+            token: Mutex::new(None),
+            database_mount: config.vault_database_mount.clone(),
+            database_role,
+        })
+    }
+
+    async fn token(&self) -> Result<String, VaultError> {
+        let role = match &self.auth {
+            Auth::Token(token) => return Ok(token.clone()),
+            Auth::Kubernetes { role } => role,
+        };
+
+        let mut cached = self.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            return Ok(token.clone());
+        }
+
+        let jwt = std::fs::read_to_string(SERVICE_ACCOUNT_TOKEN)?;
+        let body = json!({ "role": role, "jwt": jwt });
+        let response = self.http.post(&format!("{}/v1/auth/kubernetes/login", self.addr)).json(&body).send().await?;
+        let login: Login = check(response).await?.json().await?;
+        *cached = Some(login.auth.client_token.clone());
+        Ok(login.auth.client_token)
+    }
+
+    // Sends an authenticated request, logging in again once if our cached
+    // token has expired.
+    async fn send(&self, method: reqwest::Method, path: &str, body: Option<JsonValue>) -> Result<reqwest::Response, VaultError> {
+        let url = format!("{}/v1/{}", self.addr, path);
+        let mut retried = false;
+        loop {
+            let mut request = self.http.request(method.clone(), &url).header("X-Vault-Token", self.token().await?);
+            if let Some(body) = &body {
+                request = request.json(body);
+            }
+            let response = request.send().await?;
+            if response.status() == reqwest::StatusCode::FORBIDDEN && !retried {
+                if let Auth::Kubernetes { .. } = self.auth {
+                    *self.token.lock().await = None;
+                    retried = true;
+                    continue;
+                }
+            }
+            return check(response).await;
+        }
+    }
+
+    pub async fn database_credentials(&self, role: &str) -> Result<Lease, VaultError> {
+        let path = format!("{}/creds/{}", self.database_mount, role);
+        Ok(self.send(reqwest::Method::GET, &path, None).await?.json().await?)
+    }
+
+    pub async fn renew(&self, lease_id: &str) -> Result<(), VaultError> {
+        let body = json!({ "lease_id": lease_id });
+        self.send(reqwest::Method::PUT, "sys/leases/renew", Some(body)).await?;
+        Ok(())
+    }
+
+    pub async fn revoke(&self, lease_id: &str) -> Result<(), VaultError> {
+        let body = json!({ "lease_id": lease_id });
+        self.send(reqwest::Method::PUT, "sys/leases/revoke", Some(body)).await?;
+        Ok(())
+    }
+}
+
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, VaultError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let text = response.text().await.unwrap_or_default();
+    Err(VaultError::Vault(status.as_u16(), text))
+}
+
+pub fn secret_name(preview: &str) -> String {
+    names::child(preview, "vault-db")
+}
+
+/// Writes a freshly minted lease into the preview's credentials Secret.
+pub async fn store_credentials(client: &Client, secrets: &RawApi, preview: &str, lease: &Lease) -> Result<(), Error> {
+    let data = json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": secret_name(preview),
+            "labels": {
+                "preview": "true",
+                "preview.platform9.com/name": preview,
+                "preview.platform9.com/vault-credentials": "true",
+            },
+            "annotations": {
+                "preview.platform9.com/vault-lease-id": lease.lease_id,
+            }
+        },
+        "type": "Opaque",
+        "stringData": {
+            "DB_USERNAME": lease.data.get("username"),
+            "DB_PASSWORD": lease.data.get("password"),
+        }
+    });
+    let request = secrets.create(&PostParams::default(), serde_json::to_vec(&data)?)?;
+    client.request::<JsonValue>(request).await?;
+    Ok(())
+}
+
+/// Looks up the lease backing a preview's credentials, if it has any.
+pub async fn lease_id(client: &Client, secrets: &RawApi, preview: &str) -> Result<Option<String>, Error> {
+    match client.request::<JsonValue>(secrets.get(&secret_name(preview))?).await {
+        Ok(secret) => Ok(secret["metadata"]["annotations"][LEASE_ANNOTATION].as_str().map(String::from)),
+        Err(Error::Api(ae)) if ae.code == 404 => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Renews the lease of every preview's credentials on a fixed interval so
+/// they stay valid for as long as the preview exists.
+pub async fn renew_leases(vault: Arc<Vault>, client: Client, secrets: RawApi, interval: Duration) {
+    let lp = ListParams {
+        label_selector: Some(CREDENTIALS_LABEL.to_string()),
+        ..ListParams::default()
+    };
+    loop {
+        tokio::time::delay_for(interval).await;
+        let request = match secrets.list(&lp) {
+            Ok(request) => request,
+            Err(err) => {
+                println!("Failed to build secret list request: {:?}", err);
+                continue;
+            }
+        };
+        let list: JsonValue = match client.request(request).await {
+            Ok(list) => list,
+            Err(err) => {
+                println!("Failed to list Vault credentials: {:?}", err);
+                continue;
+            }
+        };
+        for secret in list["items"].as_array().into_iter().flatten() {
+            if let Some(lease_id) = secret["metadata"]["annotations"][LEASE_ANNOTATION].as_str() {
+                if let Err(err) = vault.renew(lease_id).await {
+                    println!("Failed to renew Vault lease {}: {}", lease_id, err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Recording the controller's requests to the API server and playing them
+//! back without one, so a tricky sequence seen on a real cluster -- a write
+//! that conflicts, a throttled request, a rollout that takes a few polls --
+//! can be turned into a test that runs the same way every time.
+//!
+//! `Recorder` sits in front of a real transport and writes each request and
+//! its response to a fixture file as it goes.  The controller records when
+//! started with `VCR_RECORD=<file>`.  `Player` reads a fixture back and
+//! answers each request with the next recorded response for the same method
+//! and path, so repeated requests get their responses in the order they
+//! first came.  A request that wasn't recorded gets a `500` saying so.
+//!
+//! Only requests made through `Client` are recorded; the watch on previews
+//! isn't.  Request bodies are kept for reference but aren't matched on,
+//! since they carry resource versions and timestamps.  Responses are saved
+//! as they came, Secrets and all, so check a fixture before committing it.
+use async_trait::async_trait;
+use http::{HeaderMap, HeaderValue, StatusCode};
+use kube::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::client::Transport;
+
+type JsonValue = serde_json::value::Value;
+
+/// One request and the response it got.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Interaction {
+    pub method: String,
+    /// Path and query string.
+    pub path: String,
+    #[serde(default, skip_serializing_if = "JsonValue::is_null")]
+    pub request: JsonValue,
+    pub status: u16,
+    /// Response headers worth keeping, e.g. `Retry-After`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// The response body, as JSON when it was JSON and a string otherwise.
+    pub response: JsonValue,
+}
+
+// The only response headers the controller looks at.
+const KEPT_HEADERS: &[&str] = &["retry-after"];
+
+pub struct Recorder {
+    inner: Arc<dyn Transport>,
+    path: String,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl Recorder {
+    /// Records everything sent over `inner` to the fixture file at `path`,
+    /// replacing whatever was there.
+    pub fn new(inner: Arc<dyn Transport>, path: &str) -> Self {
+        Recorder { inner, path: path.to_string(), interactions: Mutex::new(Vec::new()) }
+    }
+}
+
+#[async_trait]
+impl Transport for Recorder {
+    async fn send(&self, request: http::Request<Vec<u8>>) -> Result<http::Response<String>, Error> {
+        let method = request.method().to_string();
+        let path = target(request.uri());
+        let body = serde_json::from_slice(request.body()).unwrap_or(JsonValue::Null);
+        let response = self.inner.send(request).await?;
+
+        let headers = KEPT_HEADERS
+            .iter()
+            .filter_map(|name| Some((name.to_string(), response.headers().get(*name)?.to_str().ok()?.to_string())))
+            .collect();
+        let interaction = Interaction {
+            method,
+            path,
+            request: body,
+            status: response.status().as_u16(),
+            headers,
+            response: serde_json::from_str(response.body()).unwrap_or_else(|_| json!(response.body())),
+        };
+        // Written out after every request, so nothing is lost when the
+        // controller is stopped
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.push(interaction);
+        let fixture = serde_json::to_string_pretty(&*interactions).expect("Failed to serialize interactions");
+        if let Err(err) = std::fs::write(&self.path, fixture) {
+            println!("Failed to write recorded requests to {}: {}", self.path, err);
+        }
+        Ok(response)
+    }
+}
+
+pub struct Player {
+    // Each interaction, and whether it's been played yet
+    interactions: Mutex<Vec<(Interaction, bool)>>,
+}
+
+impl Player {
+    /// Plays back the fixture file at `path`.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let fixture = std::fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        let interactions: Vec<Interaction> =
+            serde_json::from_str(&fixture).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+        Ok(Player::new(interactions))
+    }
+
+    pub fn new(interactions: Vec<Interaction>) -> Self {
+        Player { interactions: Mutex::new(interactions.into_iter().map(|interaction| (interaction, false)).collect()) }
+    }
+
+    /// The recorded interactions that haven't been played, e.g. to check a
+    /// test made every request it was expected to.
+    pub fn unplayed(&self) -> Vec<Interaction> {
+        let interactions = self.interactions.lock().unwrap();
+        interactions.iter().filter(|(_, played)| !played).map(|(interaction, _)| interaction.clone()).collect()
+    }
+}
+
+#[async_trait]
+impl Transport for Player {
+    async fn send(&self, request: http::Request<Vec<u8>>) -> Result<http::Response<String>, Error> {
+        let method = request.method().to_string();
+        let path = target(request.uri());
+        let next = {
+            let mut interactions = self.interactions.lock().unwrap();
+            let next = interactions.iter_mut().find(|(interaction, played)| {
+                !played && interaction.method == method && interaction.path == path
+            });
+            next.map(|(interaction, played)| {
+                *played = true;
+                interaction.clone()
+            })
+        };
+
+        let interaction = match next {
+            Some(interaction) => interaction,
+            None => {
+                let message = format!("no recorded response for {} {}", method, path);
+                let status = json!({ "status": "Failure", "message": message, "reason": "NotRecorded", "code": 500 });
+                let mut response = http::Response::new(status.to_string());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return Ok(response);
+            }
+        };
+        let body = match &interaction.response {
+            JsonValue::String(text) => text.clone(),
+            body => body.to_string(),
+        };
+        let mut response = http::Response::new(body);
+        *response.status_mut() = StatusCode::from_u16(interaction.status).map_err(http::Error::from)?;
+        *response.headers_mut() = headers(&interaction.headers)?;
+        Ok(response)
+    }
+}
+
+// Where a request went.  kube leaves a `?` on the end of some URIs and not
+// others, which doesn't matter to the API server and shouldn't here.
+fn target(uri: &http::Uri) -> String {
+    uri.to_string().trim_end_matches('?').to_string()
+}
+
+fn headers(recorded: &BTreeMap<String, String>) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in recorded {
+        let name: http::header::HeaderName = name.parse().map_err(http::Error::from)?;
+        headers.insert(name, HeaderValue::from_str(value).map_err(http::Error::from)?);
+    }
+    Ok(headers)
+}
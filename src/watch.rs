@@ -0,0 +1,104 @@
+//! The watch on previews that drives the reconcile loop, and a watchdog for
+//! it.  A watch can stall without failing -- the connection stays open but
+//! nothing more comes down it -- and the controller would sit there
+//! reconciling nothing.  So if the watch has neither delivered an event nor
+//! finished a poll within `WATCH_STALL_SECONDS`, or fails outright, it's
+//! started again from scratch and every preview is reconciled to catch up
+//! on whatever was missed.  This version of kube doesn't ask for bookmarks,
+//! so a poll finishing is the watch's sign of life.
+//!
+//! How the watch is doing is in the `preview_controller_watch_*` metrics:
+//! failures and restarts, how long each poll takes, how long events wait
+//! behind the ones before them, and when the watch was last heard from.
+use chrono::Utc;
+use futures::prelude::*;
+use kube::api::{Informer, WatchEvent};
+use kube::client::APIClient;
+use kube::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{WATCH_DISCONNECTS, WATCH_EVENT_LAG_SECONDS, WATCH_LAST_SEEN, WATCH_POLL_SECONDS, WATCH_RESTARTS};
+use crate::reconcile::{handle, reconcile_existing};
+use crate::{ApiResources, KubePreviewEnvironment};
+
+type PreviewInformer = Informer<KubePreviewEnvironment>;
+
+// How long to wait before trying again when the watch can't be started.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+enum Interrupted {
+    Stalled,
+    Failed(Error),
+}
+
+/// Starts watching previews.  Done before catching up on existing previews,
+/// so nothing that changes in the meantime is missed.
+pub async fn start(resources: &ApiResources, client: &APIClient) -> Result<PreviewInformer, Error> {
+    Informer::raw(client.clone(), resources.previews.clone()).init().await
+}
+
+/// Handles every event on the watch, for as long as the controller runs.
+pub async fn run(resources: Arc<ApiResources>, client: APIClient, mut informer: PreviewInformer) {
+    loop {
+        let err = match poll(&resources, &informer).await {
+            Ok(()) => continue,
+            Err(Interrupted::Stalled) => {
+                let window = resources.config.watch_stall_timeout.as_secs();
+                format!("nothing heard from it in {}s", window)
+            }
+            Err(Interrupted::Failed(err)) => {
+                WATCH_DISCONNECTS.inc();
+                format!("{:?}", err)
+            }
+        };
+        println!("Restarting the watch on previews: {}", err);
+        WATCH_RESTARTS.inc();
+
+        informer = loop {
+            match start(&resources, &client).await {
+                Ok(informer) => break informer,
+                Err(err) => println!("Failed to restart the watch on previews, trying again: {:?}", err),
+            }
+            tokio::time::delay_for(RESTART_DELAY).await;
+        };
+        if !resources.config.maintenance {
+            reconcile_existing(&resources).await;
+        }
+    }
+}
+
+// One poll of the watch, handling each event it brings.
+async fn poll(resources: &Arc<ApiResources>, informer: &PreviewInformer) -> Result<(), Interrupted> {
+    let started = Instant::now();
+    let events = watchdog(resources, informer.poll()).await??;
+    WATCH_POLL_SECONDS.observe(started.elapsed().as_secs_f64());
+    seen();
+
+    let polled = Instant::now();
+    let mut events = events.boxed();
+    while let Some(event) = watchdog(resources, events.next()).await? {
+        let event: WatchEvent<KubePreviewEnvironment> = event?;
+        seen();
+        WATCH_EVENT_LAG_SECONDS.observe(polled.elapsed().as_secs_f64());
+        handle(resources, event).await;
+    }
+    seen();
+    Ok(())
+}
+
+// Wait on the watch, giving up once it's been quiet for too long.
+async fn watchdog<T>(resources: &ApiResources, waiting: impl Future<Output = T>) -> Result<T, Interrupted> {
+    tokio::time::timeout(resources.config.watch_stall_timeout, waiting).await.map_err(|_| Interrupted::Stalled)
+}
+
+impl From<Error> for Interrupted {
+    fn from(err: Error) -> Self {
+        Interrupted::Failed(err)
+    }
+}
+
+fn seen() {
+    WATCH_LAST_SEEN.set(Utc::now().timestamp() as f64);
+}
@@ -0,0 +1,183 @@
+//! Registry webhooks.  When an image is pushed under a tag that a live
+//! preview is running, that preview's deployment is restarted so it pulls
+//! the new image.  Docker Hub, Harbor and ECR (via an EventBridge API
+//! destination) notifications are understood.
+use kube::api::{ListParams, ObjectList};
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::{ApiResources, KubePreviewEnvironment};
+
+type JsonValue = serde_json::value::Value;
+
+/// An image pushed to a registry.  `host` is `None` when the notification
+/// doesn't say which registry it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Push {
+    pub host: Option<String>,
+    pub repository: String,
+    pub tag: String,
+}
+
+/// Pull the pushed images out of a webhook payload.  Anything that isn't a
+/// push notification we recognise yields nothing.
+pub fn parse(body: &JsonValue) -> Vec<Push> {
+    // Docker Hub
+    if let (Some(repository), Some(tag)) = (body["repository"]["repo_name"].as_str(), body["push_data"]["tag"].as_str()) {
+        return vec![Push {
+            host: Some("docker.io".to_string()),
+            repository: normalize_repository("docker.io", repository),
+            tag: tag.to_string(),
+        }];
+    }
+
+    // Harbor
+    if body["type"] == "PUSH_ARTIFACT" {
+        let repository = body["event_data"]["repository"]["repo_full_name"].as_str().unwrap_or_default();
+        return body["event_data"]["resources"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|resource| {
+                let tag = resource["tag"].as_str()?;
+                let host = resource["resource_url"].as_str().and_then(|url| url.split('/').next());
+                Some(Push {
+                    host: host.map(str::to_string),
+                    repository: repository.to_string(),
+                    tag: tag.to_string(),
+                })
+            })
+            .collect();
+    }
+
+    // ECR, forwarded by EventBridge
+    if body["detail-type"] == "ECR Image Action" {
+        let detail = &body["detail"];
+        if detail["action-type"] != "PUSH" || detail["result"] != "SUCCESS" {
+            return vec![];
+        }
+        if let (Some(repository), Some(tag)) = (detail["repository-name"].as_str(), detail["image-tag"].as_str()) {
+            let host = match (body["account"].as_str(), body["region"].as_str()) {
+                (Some(account), Some(region)) => Some(format!("{}.dkr.ecr.{}.amazonaws.com", account, region)),
+                _ => None,
+            };
+            return vec![Push { host, repository: repository.to_string(), tag: tag.to_string() }];
+        }
+    }
+
+    vec![]
+}
+
+/// Whether `image` (as written in a pod spec) refers to the pushed tag.
+pub fn matches(image: &str, push: &Push) -> bool {
+    let (host, repository, tag) = split_image(image);
+    if repository != push.repository || tag != push.tag {
+        return false;
+    }
+    match &push.host {
+        Some(push_host) => normalize_host(push_host) == host,
+        None => true,
+    }
+}
+
+/// Splits an image reference into registry host, repository and tag,
+/// filling in the defaults Docker would use.
+pub fn split_image(image: &str) -> (String, String, String) {
+    let image = image.split('@').next().unwrap_or(image);
+    let (name, tag) = match image.rfind(':') {
+        Some(i) if !image[i..].contains('/') => (&image[..i], &image[i + 1..]),
+        _ => (image, "latest"),
+    };
+
+    let mut parts = name.splitn(2, '/');
+    let first = parts.next().unwrap_or_default();
+    let (host, repository) = match parts.next() {
+        Some(rest) if first.contains('.') || first.contains(':') || first == "localhost" => (normalize_host(first), rest),
+        _ => ("docker.io".to_string(), name),
+    };
+    let repository = normalize_repository(&host, repository);
+    (host, repository, tag.to_string())
+}
+
+fn normalize_host(host: &str) -> String {
+    match host {
+        "index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => "docker.io".to_string(),
+        host => host.to_string(),
+    }
+}
+
+// Official Docker Hub images live under `library/`.
+fn normalize_repository(host: &str, repository: &str) -> String {
+    if host == "docker.io" && !repository.contains('/') {
+        format!("library/{}", repository)
+    } else {
+        repository.to_string()
+    }
+}
+
+/// Serve `POST /webhooks/registry`.  When a token is configured the
+/// registry has to send it as `?token=...`, since not all of them can set
+/// custom headers.
+pub async fn serve(addr: SocketAddr, resources: Arc<ApiResources>) {
+    let route = warp::post()
+        .and(warp::path!("webhooks" / "registry"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::json())
+        .and_then(move |query: HashMap<String, String>, body: JsonValue| {
+            let resources = resources.clone();
+            async move { Ok::<_, Infallible>(receive(&resources, query, body).await) }
+        });
+
+    println!("Registry webhooks listening on {}", addr);
+    warp::serve(route).run(addr).await;
+}
+
+async fn receive(resources: &ApiResources, query: HashMap<String, String>, body: JsonValue) -> impl warp::Reply {
+    if let Some(token) = &resources.config.webhook_token {
+        if query.get("token") != Some(token) {
+            return warp::reply::with_status(warp::reply::json(&json!({ "error": "invalid token" })), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    if resources.config.maintenance {
+        let body = json!({ "error": "the controller is in maintenance mode" });
+        return warp::reply::with_status(warp::reply::json(&body), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let pushes = parse(&body);
+    let mut restarted = vec![];
+    for push in &pushes {
+        println!("Registry push: {:?}", push);
+        match redeploy(resources, push).await {
+            Ok(names) => restarted.extend(names),
+            Err(err) => {
+                println!("Failed to handle registry push {:?}: {:?}", push, err);
+                let body = json!({ "error": err.to_string() });
+                return warp::reply::with_status(warp::reply::json(&body), StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    warp::reply::with_status(warp::reply::json(&json!({ "restarted": restarted })), StatusCode::OK)
+}
+
+// Restart every preview currently running the pushed image.
+async fn redeploy(resources: &ApiResources, push: &Push) -> Result<Vec<String>, kube::Error> {
+    let request = resources.previews.list(&ListParams::default())?;
+    let previews: ObjectList<KubePreviewEnvironment> = resources.client.request(request).await?;
+
+    let mut restarted = vec![];
+    for pe in previews.items {
+        // Previews built from source are deployed from the image they built
+        let image = pe.status.as_ref().and_then(|status| status.image.clone()).unwrap_or_else(|| pe.spec.image.clone());
+        if matches(&image, push) {
+            crate::restart_deployment(resources, &pe).await;
+            restarted.push(pe.metadata.name);
+        }
+    }
+    Ok(restarted)
+}
@@ -0,0 +1,57 @@
+// Queueing previews the cluster hasn't room for.
+use kube::api::{DeleteParams, WatchEvent};
+use serde_json::json;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::{capacity, quota, reconcile};
+
+mod common;
+use common::{node, pod, spec};
+
+const FLAGS: &[&str] = &["--check-capacity", "--default-cpu-request=1", "--default-memory-request=1Gi"];
+
+#[tokio::test]
+async fn previews_wait_in_the_queue_until_there_is_room() {
+    let harness = Harness::new(FLAGS);
+    node(&harness, "node-1", "2");
+    pod(&harness, "batch", "node-1", "1500m");
+    let pe = harness.preview("web", spec());
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert!(harness.api.created("deployments").is_empty());
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some(quota::QUEUED));
+    assert!(status.message.unwrap().starts_with("Waiting for room in the cluster"));
+
+    harness.api.apply(harness.resources.pods.delete("batch", &DeleteParams::default()).unwrap());
+    reconcile::reconcile(&harness.resources, &harness.current("web"), true).await;
+
+    assert_eq!(harness.api.created("deployments").len(), 1);
+    assert_eq!(harness.current("web").status.unwrap().phase.as_deref(), Some("Ready"));
+}
+
+#[tokio::test]
+async fn each_pod_has_to_fit_on_a_single_node() {
+    let harness = Harness::new(FLAGS);
+    node(&harness, "node-1", "1500m");
+    node(&harness, "node-2", "1500m");
+    let two = harness.preview("two", json!({ "image": "nginx:1.19", "replicas": 2 }));
+    let three = harness.preview("three", json!({ "image": "nginx:1.19", "replicas": 3 }));
+
+    assert_eq!(capacity::shortage(&harness.resources, &two).await.unwrap(), None);
+    let shortage = capacity::shortage(&harness.resources, &three).await.unwrap().unwrap();
+    assert!(shortage.contains("1 of its 3 pods, each requesting 1000m CPU and 1024Mi memory"));
+}
+
+#[tokio::test]
+async fn a_canary_needs_room_for_its_pods_too() {
+    let harness = Harness::new(FLAGS);
+    node(&harness, "node-1", "2500m");
+    let canary = json!({ "image": "nginx:1.20", "weight": 10 });
+    let pe = harness.preview("web", json!({ "image": "nginx:1.19", "replicas": 2, "canary": canary }));
+
+    let shortage = capacity::shortage(&harness.resources, &pe).await.unwrap().unwrap();
+    assert!(shortage.contains("2 of its 4 pods"));
+    assert!(shortage.contains("without injected sidecars"));
+}
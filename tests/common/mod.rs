@@ -0,0 +1,83 @@
+// Setting up the fake API server for the tests of the controller's
+// behaviour: previews, teams' profiles, and the nodes and pods already in
+// the cluster.  Each test file only uses some of it.
+#![allow(dead_code)]
+
+use serde_json::json;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::profiles::{self, PROFILE_LABEL};
+use rust_k8s_starter::KubePreviewEnvironment;
+
+type JsonValue = serde_json::value::Value;
+
+/// A preview of nginx with a domain of its own.
+pub fn spec() -> JsonValue {
+    spec_with_image("nginx:1.19")
+}
+
+/// A preview of `image` with a domain of its own.
+pub fn spec_with_image(image: &str) -> JsonValue {
+    json!({ "image": image, "fqdn": "web.previews.example.com" })
+}
+
+/// A harness with `flags`, and a profile for each team in `profiles`,
+/// loaded the way the controller does at startup.
+pub async fn with_profiles(flags: &[&str], profiles: &[(&str, JsonValue)]) -> Harness {
+    let harness = Harness::new(flags);
+    for (team, data) in profiles {
+        let config_map = json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": { "name": format!("{}-profile", team), "labels": { PROFILE_LABEL: team } },
+            "data": data,
+        });
+        harness.insert(&harness.resources.config_maps, config_map);
+    }
+    profiles::load(&harness.resources).await;
+    harness
+}
+
+/// Stores a new preview with `spec` belonging to `team`, if any.
+pub fn team_preview(harness: &Harness, name: &str, team: Option<&str>, spec: JsonValue) -> KubePreviewEnvironment {
+    let mut labels = json!({ "preview": "true" });
+    if let Some(team) = team {
+        labels["team"] = json!(team);
+    }
+    let manifest = json!({
+        "apiVersion": "platform9.com/v1",
+        "kind": "PreviewEnvironment",
+        "metadata": { "name": name, "labels": labels },
+        "spec": spec,
+    });
+    serde_json::from_value(harness.insert(&harness.resources.previews, manifest)).unwrap()
+}
+
+/// A ready node that can allocate `cpu` and 8Gi of memory.
+pub fn node(harness: &Harness, name: &str, cpu: &str) {
+    let node = json!({
+        "apiVersion": "v1",
+        "kind": "Node",
+        "metadata": { "name": name },
+        "status": {
+            "allocatable": { "cpu": cpu, "memory": "8Gi" },
+            "conditions": [{ "type": "Ready", "status": "True" }],
+        },
+    });
+    harness.insert(&harness.resources.nodes, node);
+}
+
+/// A running pod on `node` requesting `cpu`.
+pub fn pod(harness: &Harness, name: &str, node: &str, cpu: &str) {
+    let pod = json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": { "name": name },
+        "spec": {
+            "nodeName": node,
+            "containers": [{ "name": "app", "image": "busybox", "resources": { "requests": { "cpu": cpu } } }],
+        },
+        "status": { "phase": "Running" },
+    });
+    harness.insert(&harness.resources.pods, pod);
+}
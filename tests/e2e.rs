@@ -0,0 +1,213 @@
+// End-to-end tests against a real cluster.  They only build with the `e2e`
+// feature, since they need `kind` and `kubectl` on the PATH:
+//
+//     cargo test --features e2e --test e2e
+//
+// They use a kind cluster named `preview-e2e`, creating it if it doesn't
+// exist yet, or the kubectl context named by `E2E_KUBE_CONTEXT` instead.
+// The CRDs are installed, and each test starts the controller watching a
+// namespace of its own, applies previews and waits for the controller to
+// act on them.  A cluster the tests create is left running for next time;
+// `kind delete cluster --name preview-e2e` gets rid of it.
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_k8s_starter::{validation, Children, KubePreviewEnvironment};
+
+const CLUSTER: &str = "preview-e2e";
+const PREVIEWS: &str = "previewenvironments.platform9.com";
+const MAPPINGS: &str = "mappings.getambassador.io";
+
+// How long the controller gets to do anything asked of it.
+const TIMEOUT: Duration = Duration::from_secs(90);
+const POLL: Duration = Duration::from_millis(500);
+
+static SETUP: Once = Once::new();
+
+fn context() -> String {
+    std::env::var("E2E_KUBE_CONTEXT").unwrap_or_else(|_| format!("kind-{}", CLUSTER))
+}
+
+fn kubectl(args: &[&str]) -> Command {
+    let mut command = Command::new("kubectl");
+    command.arg("--context").arg(context()).args(args);
+    command
+}
+
+// Run a command to completion, failing the test if it fails.
+fn run(command: &mut Command, input: Option<&str>) -> String {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|err| panic!("Failed to run {:?}: {}", command, err));
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    }
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", command, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// Bring up the cluster if there isn't one, and install the CRDs the
+// controller needs, once for the whole run.
+fn setup() {
+    SETUP.call_once(|| {
+        if std::env::var("E2E_KUBE_CONTEXT").is_err() {
+            let clusters = run(Command::new("kind").args(&["get", "clusters"]), None);
+            if !clusters.lines().any(|cluster| cluster == CLUSTER) {
+                run(Command::new("kind").args(&["create", "cluster", "--name", CLUSTER, "--wait", "120s"]), None);
+            }
+        }
+        let root = env!("CARGO_MANIFEST_DIR");
+        run(&mut kubectl(&["apply", "-f", &format!("{}/preview-environment-crd.yaml", root)]), None);
+        run(&mut kubectl(&["apply", "-f", &format!("{}/tests/e2e/ambassador-crds.yaml", root)]), None);
+        let crds = [format!("crd/{}", PREVIEWS), format!("crd/{}", MAPPINGS), "crd/hosts.getambassador.io".to_string()];
+        let mut wait = vec!["wait", "--for=condition=Established"];
+        wait.extend(crds.iter().map(String::as_str));
+        run(&mut kubectl(&wait), None);
+    });
+}
+
+/// The controller, running against a namespace of its own for one test.
+struct Controller {
+    process: Child,
+    namespace: String,
+    kubeconfig: PathBuf,
+}
+
+impl Controller {
+    fn start(test: &str) -> Self {
+        setup();
+        let namespace = format!("e2e-{}-{}", test, std::process::id());
+        run(&mut kubectl(&["create", "namespace", &namespace]), None);
+
+        // The controller always uses the current context, so it gets a
+        // kubeconfig with only the test cluster in it
+        let kubeconfig = std::env::temp_dir().join(format!("{}.kubeconfig", namespace));
+        std::fs::write(&kubeconfig, run(&mut kubectl(&["config", "view", "--minify", "--flatten"]), None)).unwrap();
+        let process = Command::new(env!("CARGO_BIN_EXE_rust-k8s-starter"))
+            .env_remove("CONFIG_FILE")
+            .env("KUBECONFIG", &kubeconfig)
+            .env("WATCH_NAMESPACE", &namespace)
+            .env("GRPC_ADDR", "127.0.0.1:0")
+            .env("METRICS_ADDR", "127.0.0.1:0")
+            .spawn()
+            .expect("Failed to start the controller");
+        Controller { process, namespace, kubeconfig }
+    }
+
+    fn apply(&self, manifest: &Value) {
+        run(&mut kubectl(&["-n", &self.namespace, "apply", "-f", "-"]), Some(&manifest.to_string()));
+    }
+
+    fn delete(&self, kind: &str, name: &str) {
+        run(&mut kubectl(&["-n", &self.namespace, "delete", kind, name, "--wait=false"]), None);
+    }
+
+    fn get(&self, kind: &str, name: &str) -> Option<Value> {
+        let output = run(&mut kubectl(&["-n", &self.namespace, "get", kind, name, "-o", "json", "--ignore-not-found"]), None);
+        Some(output).filter(|output| !output.trim().is_empty()).map(|output| serde_json::from_str(&output).unwrap())
+    }
+
+    // Poll until `check` gives something back, failing the test if it
+    // takes too long.
+    fn wait_for<T>(&self, what: &str, check: impl Fn() -> Option<T>) -> T {
+        let started = Instant::now();
+        loop {
+            if let Some(found) = check() {
+                return found;
+            }
+            assert!(started.elapsed() < TIMEOUT, "Timed out waiting for {} in {}", what, self.namespace);
+            thread::sleep(POLL);
+        }
+    }
+
+    fn wait_for_status(&self, name: &str, what: &str, check: impl Fn(&Value) -> bool) -> Value {
+        self.wait_for(what, || self.get(PREVIEWS, name).map(|pe| pe["status"].clone()).filter(|status| check(status)))
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = kubectl(&["delete", "namespace", &self.namespace, "--wait=false"]).output();
+        let _ = std::fs::remove_file(&self.kubeconfig);
+    }
+}
+
+fn preview(name: &str, image: &str) -> Value {
+    json!({
+        "apiVersion": "platform9.com/v1",
+        "kind": "PreviewEnvironment",
+        "metadata": { "name": name, "labels": { "preview": "true" } },
+        "spec": { "image": image, "fqdn": format!("{}.e2e.example.com", name) },
+    })
+}
+
+fn children(manifest: &Value) -> Children {
+    let pe: KubePreviewEnvironment = serde_json::from_value(manifest.clone()).unwrap();
+    Children::of(&pe)
+}
+
+fn image(deployment: &Value) -> &str {
+    deployment["spec"]["template"]["spec"]["containers"][0]["image"].as_str().unwrap_or_default()
+}
+
+#[test]
+fn preview_lifecycle() {
+    let controller = Controller::start("lifecycle");
+    let manifest = preview("web", "nginx:1.19");
+    let children = children(&manifest);
+
+    controller.apply(&manifest);
+    let deployment = controller.wait_for("the deployment", || controller.get("deployments", &children.deployment));
+    assert_eq!(image(&deployment), "nginx:1.19");
+    controller.wait_for("the service", || controller.get("services", &children.service));
+    let mapping = controller.wait_for("the mapping", || controller.get(MAPPINGS, &children.mapping));
+    assert_eq!(mapping["spec"]["host"], "web.e2e.example.com");
+    controller.wait_for_status("web", "the preview to be Ready", |status| {
+        status["phase"] == "Ready" && status["image"] == "nginx:1.19"
+    });
+
+    // A new image is rolled out to the existing deployment
+    controller.apply(&preview("web", "nginx:1.20"));
+    controller.wait_for("the new image", || {
+        controller.get("deployments", &children.deployment).filter(|deployment| image(deployment) == "nginx:1.20")
+    });
+    controller.wait_for_status("web", "the status to show the new image", |status| status["image"] == "nginx:1.20");
+
+    // And everything goes with the preview
+    controller.delete(PREVIEWS, "web");
+    controller.wait_for("the preview to go", || Some(()).filter(|_| controller.get(PREVIEWS, "web").is_none()));
+    controller.wait_for("the children to go", || {
+        let remaining = [
+            controller.get("deployments", &children.deployment),
+            controller.get("services", &children.service),
+            controller.get(MAPPINGS, &children.mapping),
+        ];
+        Some(()).filter(|_| remaining.iter().all(Option::is_none))
+    });
+}
+
+#[test]
+fn invalid_spec_is_reported() {
+    let controller = Controller::start("invalid");
+    let mut manifest = preview("broken", "");
+    manifest["spec"].as_object_mut().unwrap().remove("image");
+    let children = children(&manifest);
+
+    controller.apply(&manifest);
+    let status = controller.wait_for_status("broken", "the preview to fail", |status| status["phase"] == "Failed");
+    let conditions = status["conditions"].as_array().cloned().unwrap_or_default();
+    let invalid = conditions.iter().find(|condition| condition["type"] == validation::CONDITION).unwrap();
+    assert_eq!(invalid["status"], "True");
+    assert!(controller.get("deployments", &children.deployment).is_none());
+}
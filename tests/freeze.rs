@@ -0,0 +1,57 @@
+// Freeze windows holding up new previews.
+use chrono::{Duration, Utc};
+use kube::api::WatchEvent;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::{freeze, sweeper};
+
+mod common;
+use common::spec;
+
+// A freeze that's on now
+fn freeze_windows() -> String {
+    let (start, end) = (Utc::now() - Duration::hours(1), Utc::now() + Duration::hours(1));
+    format!("--freeze-windows={}/{} release weekend", start.to_rfc3339(), end.to_rfc3339())
+}
+
+#[tokio::test]
+async fn previews_created_during_a_freeze_wait_for_it_to_end() {
+    let harness = Harness::new(&[&freeze_windows()]);
+    let pe = harness.preview("web", spec());
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert!(harness.api.created("deployments").is_empty());
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some(freeze::FROZEN));
+    assert!(status.message.unwrap().contains("(release weekend)"));
+
+    harness.resources.live.write().unwrap().freeze_windows.clear();
+    harness.handle(WatchEvent::Modified(harness.current("web"))).await;
+
+    assert_eq!(harness.api.created("deployments").len(), 1);
+    assert_eq!(harness.current("web").status.unwrap().phase.as_deref(), Some("Ready"));
+}
+
+#[tokio::test]
+async fn previews_created_during_a_blocking_freeze_fail() {
+    let harness = Harness::new(&[&freeze_windows(), "--freeze-mode=block"]);
+    let pe = harness.preview("web", spec());
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert!(harness.api.created("deployments").is_empty());
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Failed"));
+    assert!(status.message.unwrap().starts_with("Creating previews is frozen until"));
+}
+
+#[tokio::test]
+async fn previews_outliving_their_ttl_are_kept_during_a_freeze() {
+    let harness = Harness::new(&[&freeze_windows(), "--preview-ttl-seconds=0"]);
+    harness.preview("web", spec());
+
+    sweeper::expire(&harness.resources).await;
+
+    assert!(harness.api.deleted("previewenvironments").is_empty());
+}
@@ -0,0 +1,105 @@
+// The Deployments, Services and Mappings the controller renders for a range
+// of specs, compared against the files in tests/golden.  A change to what's
+// rendered fails here until the golden files are updated to match:
+//
+//     UPDATE_GOLDEN=1 cargo test --test golden
+//
+// Review the diff of tests/golden before committing it.  A case without a
+// golden file fails too, so a new case needs the same.
+use http::Method;
+use kube::api::WatchEvent;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+use rust_k8s_starter::fake::Harness;
+
+// What each golden file covers.
+const KINDS: &[&str] = &["deployments", "services", "mappings"];
+
+fn cases() -> Vec<(&'static str, Value)> {
+    let base = json!({ "image": "nginx:1.19", "fqdn": "web.previews.example.com" });
+    let with = |extra: Value| {
+        let mut spec = base.clone();
+        for (key, value) in extra.as_object().unwrap() {
+            spec[key] = value.clone();
+        }
+        spec
+    };
+    vec![
+        ("basic", base.clone()),
+        ("replicas", with(json!({ "replicas": 3 }))),
+        (
+            "resources",
+            with(json!({
+                "resources": {
+                    "requests": { "cpu": "250m", "memory": "256Mi" },
+                    "limits": { "memory": "1Gi" },
+                }
+            })),
+        ),
+        (
+            "ports-and-routes",
+            with(json!({
+                "ports": [{ "name": "http", "port": 8080 }, { "name": "admin", "port": 9000 }],
+                "routes": [{ "prefix": "/", "port": "http" }, { "prefix": "/admin/", "port": "admin" }],
+            })),
+        ),
+        ("node-port", with(json!({ "serviceType": "NodePort", "sessionAffinity": "ClientIP" }))),
+        ("canary", with(json!({ "canary": { "image": "nginx:1.20", "weight": 20 } }))),
+    ]
+}
+
+// Everything of `KINDS` the controller asked to create for a preview, as
+// it sent them.
+async fn render(spec: Value) -> Value {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec);
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let mut rendered = json!({});
+    for kind in KINDS {
+        let suffix = format!("/{}", kind);
+        let mut created: Vec<Value> = harness
+            .api
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == Method::POST && request.path.ends_with(&suffix))
+            .map(|request| request.body)
+            .collect();
+        created.sort_by_key(|manifest| manifest["metadata"]["name"].as_str().unwrap_or_default().to_string());
+        rendered[*kind] = json!(created);
+    }
+    rendered
+}
+
+fn golden(case: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.json", case))
+}
+
+#[tokio::test]
+async fn rendered_manifests_match_golden_files() {
+    let update = std::env::var("UPDATE_GOLDEN").as_deref() == Ok("1");
+    let mut mismatched = Vec::new();
+
+    for (case, spec) in cases() {
+        let rendered = render(spec).await;
+        let path = golden(case);
+        let expected: Option<Value> = std::fs::read_to_string(&path).ok().map(|text| serde_json::from_str(&text).unwrap());
+        match expected {
+            Some(expected) if expected == rendered => continue,
+            None if !update => mismatched.push(format!("{} has no golden file", case)),
+            Some(_) if !update => mismatched.push(format!("{} doesn't match {}", case, path.display())),
+            _ => {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, serde_json::to_string_pretty(&rendered).unwrap() + "\n").unwrap();
+                println!("Wrote {}", path.display());
+            }
+        }
+    }
+
+    assert!(
+        mismatched.is_empty(),
+        "Rendered manifests changed: {}.  If that's intended, run UPDATE_GOLDEN=1 cargo test --test golden",
+        mismatched.join(", ")
+    );
+}
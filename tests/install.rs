@@ -0,0 +1,71 @@
+// What `generate-install` prints for different configs.
+use serde_json::json;
+
+use rust_k8s_starter::config::Config;
+use rust_k8s_starter::install::{self, Options};
+use rust_k8s_starter::rbac::Scope;
+
+fn options(args: &[&str]) -> Options {
+    Options {
+        image: "registry.example.com/preview-controller:1.0".to_string(),
+        scope: Scope::Namespaced,
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        config_file: None,
+    }
+}
+
+fn find<'a>(manifests: &'a [serde_json::Value], kind: &str) -> Option<&'a serde_json::Value> {
+    manifests.iter().find(|manifest| manifest["kind"] == kind)
+}
+
+#[test]
+fn the_controller_is_installed_with_its_flags() {
+    let args = ["--watch-namespace=previews", "--metrics-addr=0.0.0.0:9100"];
+    let config = Config::load(args.iter().map(|arg| arg.to_string())).unwrap();
+
+    let manifests = install::manifests(&config, &options(&args));
+
+    assert_eq!(manifests[0]["kind"], "CustomResourceDefinition");
+    assert_eq!(manifests[0]["metadata"]["name"], "previewenvironments.platform9.com");
+    assert!(find(&manifests, "RoleBinding").is_some());
+    let deployment = find(&manifests, "Deployment").unwrap();
+    assert_eq!(deployment["metadata"]["namespace"], "previews");
+    assert_eq!(deployment["spec"]["template"]["spec"]["serviceAccountName"], "preview-controller");
+    let container = &deployment["spec"]["template"]["spec"]["containers"][0];
+    assert_eq!(container["image"], "registry.example.com/preview-controller:1.0");
+    assert_eq!(container["args"], json!(args));
+    let service = find(&manifests, "Service").unwrap();
+    let metrics = json!({ "name": "metrics", "port": 9100, "targetPort": "metrics" });
+    assert!(service["spec"]["ports"].as_array().unwrap().contains(&metrics));
+    assert!(find(&manifests, "MutatingWebhookConfiguration").is_none());
+}
+
+#[test]
+fn webhooks_point_at_the_controller_when_admission_is_on() {
+    let args = ["--watch-namespace=previews", "--admission-addr=0.0.0.0:9443"];
+    let config = Config::load(args.iter().map(|arg| arg.to_string())).unwrap();
+
+    let manifests = install::manifests(&config, &options(&args));
+
+    for kind in &["MutatingWebhookConfiguration", "ValidatingWebhookConfiguration"] {
+        let registration = find(&manifests, kind).unwrap();
+        let service = &registration["webhooks"][0]["clientConfig"]["service"];
+        assert_eq!(service["namespace"], "previews");
+        assert_eq!(service["port"], 9443);
+    }
+    let deployment = find(&manifests, "Deployment").unwrap();
+    assert_eq!(deployment["spec"]["template"]["spec"]["volumes"][0]["secret"]["secretName"], install::TLS_SECRET);
+}
+
+#[test]
+fn the_config_file_is_mounted_from_a_config_map() {
+    let path = std::env::temp_dir().join("generate-install-config.yaml");
+    std::fs::write(&path, "watch_namespace: previews\n").unwrap();
+
+    let yaml = install::generate(&[format!("--config={}", path.display()), "--image=controller:dev".to_string()]).unwrap();
+
+    assert!(yaml.contains("kind: ConfigMap"));
+    assert!(yaml.contains("watch_namespace: previews"));
+    assert!(yaml.contains("--config=/etc/preview-controller/generate-install-config.yaml"));
+    assert!(yaml.contains("controller:dev"));
+}
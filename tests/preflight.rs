@@ -0,0 +1,29 @@
+// The checks the controller makes at startup, against the fake API server.
+use kube::api::{ListParams, PostParams, RawApi};
+use serde_json::json;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::preflight;
+
+#[tokio::test]
+async fn a_ready_cluster_passes() {
+    let harness = Harness::new(&[]);
+    assert_eq!(preflight::run(&harness.resources).await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn missing_api_groups_and_permissions_are_reported() {
+    let harness = Harness::new(&[]);
+    let list = harness.resources.mappings.list(&ListParams::default()).unwrap();
+    harness.api.respond(&list, 404, json!({ "status": "Failure", "message": "not found", "reason": "NotFound", "code": 404 }));
+    // The first review is for getting previews
+    let review = json!({ "status": { "allowed": false } });
+    let reviews = RawApi::customResource("selfsubjectaccessreviews").group("authorization.k8s.io").version("v1");
+    harness.api.respond(&reviews.create(&PostParams::default(), vec![]).unwrap(), 201, review);
+
+    let problems = preflight::run(&harness.resources).await;
+
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].starts_with("Mappings (getambassador.io/v2) aren't served"));
+    assert!(problems[1].contains("can't get previewenvironments in namespace default"));
+}
@@ -0,0 +1,59 @@
+// Teams' profiles overriding the controller's settings.
+use kube::api::WatchEvent;
+use serde_json::json;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::{sweeper, Children, KubePreviewEnvironment};
+
+mod common;
+
+// No domain of its own, so it gets one from the FQDN template
+fn preview(harness: &Harness, name: &str, team: Option<&str>) -> KubePreviewEnvironment {
+    common::team_preview(harness, name, team, json!({ "image": "nginx:1.19" }))
+}
+
+#[tokio::test]
+async fn team_previews_get_their_teams_domain_and_resources() {
+    let flags = ["--fqdn-template={name}.previews.example.com", "--default-cpu-limit=500m"];
+    let data = json!({ "fqdn_template": "{name}.payments.example.com", "default_cpu_limit": "2" });
+    let harness = common::with_profiles(&flags, &[("payments", data)]).await;
+    let pe = preview(&harness, "web", Some("payments"));
+    let children = Children::of(&pe);
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let mapping = harness.get(&harness.resources.mappings, &children.mapping).unwrap();
+    assert_eq!(mapping["spec"]["host"], "web.payments.example.com");
+    let deployment = harness.get(&harness.resources.deployments, &children.deployment).unwrap();
+    assert_eq!(deployment["spec"]["template"]["spec"]["containers"][0]["resources"]["limits"]["cpu"], "2");
+}
+
+#[tokio::test]
+async fn previews_without_a_profile_get_the_controllers_settings() {
+    let profiles = [
+        ("payments", json!({ "fqdn_template": "{name}.payments.example.com" })),
+        // Not a valid template, so the team keeps the profile it had: none
+        ("search", json!({ "fqdn_template": "{name}.{team}.example.com" })),
+    ];
+    let harness = common::with_profiles(&["--fqdn-template={name}.previews.example.com"], &profiles).await;
+
+    for (name, team) in &[("web", None), ("api", Some("search"))] {
+        let pe = preview(&harness, name, *team);
+        let children = Children::of(&pe);
+        harness.handle(WatchEvent::Added(pe)).await;
+        let mapping = harness.get(&harness.resources.mappings, &children.mapping).unwrap();
+        assert_eq!(mapping["spec"]["host"], format!("{}.previews.example.com", name));
+    }
+}
+
+#[tokio::test]
+async fn previews_are_deleted_once_older_than_their_ttl() {
+    let profiles = [("payments", json!({ "preview_ttl_seconds": "0" }))];
+    let harness = common::with_profiles(&["--preview-ttl-seconds=86400"], &profiles).await;
+    preview(&harness, "old", Some("payments"));
+    preview(&harness, "new", None);
+
+    sweeper::expire(&harness.resources).await;
+
+    assert_eq!(harness.api.deleted("previewenvironments"), vec!["old".to_string()]);
+}
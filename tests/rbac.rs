@@ -0,0 +1,64 @@
+// What `generate-rbac` prints for different configs.
+use serde_json::json;
+
+use rust_k8s_starter::config::Config;
+use rust_k8s_starter::rbac::{self, Scope};
+
+fn config(args: &[&str]) -> Config {
+    Config::load(args.iter().map(|arg| arg.to_string())).unwrap()
+}
+
+fn kinds(manifests: &[serde_json::Value]) -> Vec<(&str, &str)> {
+    manifests
+        .iter()
+        .map(|manifest| (manifest["kind"].as_str().unwrap(), manifest["metadata"]["namespace"].as_str().unwrap_or_default()))
+        .collect()
+}
+
+#[test]
+fn namespaced_controllers_get_a_role_in_each_namespace_they_use() {
+    let config = config(&["--watch-namespace=previews", "--secret-source-namespace=shared", "--egress-policy=cilium"]);
+
+    let manifests = rbac::manifests(&config, Scope::Namespaced);
+
+    let expected = vec![
+        ("ServiceAccount", "previews"),
+        ("Role", "previews"),
+        ("RoleBinding", "previews"),
+        ("Role", "shared"),
+        ("RoleBinding", "shared"),
+    ];
+    assert!(kinds(&manifests).starts_with(&expected));
+    let rules = manifests[1]["rules"].as_array().unwrap();
+    assert!(rules.contains(&json!({
+        "apiGroups": ["cilium.io"],
+        "resources": ["ciliumnetworkpolicies"],
+        "verbs": ["get", "list", "watch", "create", "update", "patch", "delete"],
+    })));
+    let secrets = json!([{ "apiGroups": [""], "resources": ["secrets"], "verbs": ["get", "list", "watch"] }]);
+    assert_eq!(manifests[3]["rules"], secrets);
+    let subject = json!({ "kind": "ServiceAccount", "name": "preview-controller", "namespace": "previews" });
+    assert_eq!(manifests[4]["subjects"][0], subject);
+}
+
+#[test]
+fn cluster_scoped_features_need_a_cluster_role() {
+    let plain = rbac::manifests(&config(&[]), Scope::Namespaced);
+    assert!(!plain.iter().any(|manifest| manifest["kind"] == "ClusterRole"));
+
+    let manifests = rbac::manifests(&config(&["--priority-class=previews", "--priority-class-value=-10"]), Scope::Namespaced);
+
+    let cluster_role = manifests.iter().find(|manifest| manifest["kind"] == "ClusterRole").unwrap();
+    assert_eq!(cluster_role["metadata"]["name"], "preview-controller-default");
+    assert_eq!(cluster_role["rules"][0]["resources"], json!(["priorityclasses"]));
+}
+
+#[test]
+fn cluster_wide_controllers_get_one_cluster_role() {
+    let args = ["--cluster-wide", "--impersonate-creator", "--admission-addr=0.0.0.0:8443"];
+    let yaml = rbac::generate(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>()).unwrap();
+
+    assert!(yaml.contains("kind: ClusterRoleBinding"));
+    assert!(!yaml.contains("kind: RoleBinding"));
+    assert!(yaml.contains("impersonate"));
+}
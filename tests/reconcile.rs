@@ -0,0 +1,364 @@
+// How the controller handles preview events.
+use http::Method;
+use kube::api::{DeleteParams, PostParams, WatchEvent};
+use serde_json::json;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::api::Applied;
+use rust_k8s_starter::impersonation::USER_ANNOTATION;
+use rust_k8s_starter::{
+    apply_child, conditions, conflicts, creation, inventory, labels, retry, validation, Children, KubePreviewEnvironment,
+};
+
+mod common;
+use common::spec;
+
+#[tokio::test]
+async fn added_preview_gets_its_children() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert_eq!(harness.api.created("deployments"), vec![children.deployment.clone()]);
+    assert!(harness.api.created("services").contains(&children.service));
+    assert!(harness.api.created("mappings").contains(&children.mapping));
+    let deployment = harness.get(&harness.resources.deployments, &children.deployment).unwrap();
+    assert_eq!(deployment["spec"]["template"]["spec"]["containers"][0]["image"], "nginx:1.19");
+
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Ready"));
+    assert_eq!(status.image.as_deref(), Some("nginx:1.19"));
+}
+
+#[tokio::test]
+async fn invalid_spec_creates_nothing() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", json!({ "fqdn": "web.previews.example.com" }));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert!(harness.api.created("deployments").is_empty());
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Failed"));
+    let condition = status.conditions.iter().find(|condition| condition.condition_type == validation::CONDITION).unwrap();
+    assert_eq!(condition.status, "True");
+}
+
+#[tokio::test]
+async fn deleted_preview_is_cleaned_up() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    harness.handle(WatchEvent::Deleted(harness.current("web"))).await;
+
+    assert!(harness.api.deleted("deployments").contains(&children.deployment));
+    assert!(harness.api.deleted("services").contains(&children.service));
+    assert!(harness.get(&harness.resources.deployments, &children.deployment).is_none());
+}
+
+#[tokio::test]
+async fn modified_image_is_rolled_out() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let mut pe = harness.current("web");
+    pe.spec.image = "nginx:1.20".to_string();
+    let data = serde_json::to_vec(&pe).unwrap();
+    harness.api.apply(harness.resources.previews.replace("web", &PostParams::default(), data).unwrap());
+    harness.handle(WatchEvent::Modified(harness.current("web"))).await;
+
+    let deployment = harness.get(&harness.resources.deployments, &children.deployment).unwrap();
+    assert_eq!(deployment["spec"]["template"]["spec"]["containers"][0]["image"], "nginx:1.20");
+}
+
+#[tokio::test]
+async fn modified_spec_reaches_every_child() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let mut pe = harness.current("web");
+    pe.spec.ports = serde_json::from_value(json!([{ "name": "http", "port": 8080 }])).unwrap();
+    let data = serde_json::to_vec(&pe).unwrap();
+    harness.api.apply(harness.resources.previews.replace("web", &PostParams::default(), data).unwrap());
+    harness.handle(WatchEvent::Modified(harness.current("web"))).await;
+
+    let deployment = harness.get(&harness.resources.deployments, &children.deployment).unwrap();
+    let container = &deployment["spec"]["template"]["spec"]["containers"][0];
+    assert_eq!(container["ports"], json!([{ "name": "http", "containerPort": 8080, "protocol": "TCP" }]));
+    let service = harness.get(&harness.resources.services, &children.service).unwrap();
+    assert_eq!(service["spec"]["ports"][0]["port"], 8080);
+    assert_eq!(harness.current("web").status.unwrap().phase.as_deref(), Some("Ready"));
+}
+
+#[tokio::test]
+async fn children_are_created_as_the_creator() {
+    let harness = Harness::new(&["--impersonate-creator", "--admission-addr=127.0.0.1:8443"]);
+    let manifest = json!({
+        "apiVersion": "platform9.com/v1",
+        "kind": "PreviewEnvironment",
+        "metadata": { "name": "web", "annotations": { USER_ANNOTATION: "alice" } },
+        "spec": spec(),
+    });
+    let pe: KubePreviewEnvironment = serde_json::from_value(harness.insert(&harness.resources.previews, manifest)).unwrap();
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let creates: Vec<_> = harness.api.requests().into_iter().filter(|request| request.method == Method::POST).collect();
+    assert!(!creates.is_empty());
+    for request in creates {
+        let impersonated = request.headers.get("Impersonate-User").map(|user| user.to_str().unwrap().to_string());
+        let expected = if request.path.ends_with("/events") { None } else { Some("alice".to_string()) };
+        assert_eq!(impersonated, expected, "{}", request.path);
+    }
+}
+
+#[tokio::test]
+async fn status_updates_retry_conflicts() {
+    let harness = Harness::new(&[]);
+    harness.preview("web", spec());
+    let status = harness.resources.previews.replace_status("web", &PostParams::default(), vec![]).unwrap();
+    harness.api.respond(&status, 409, json!({ "status": "Failure", "message": "conflict", "reason": "Conflict", "code": 409 }));
+
+    let result = harness
+        .resources
+        .client
+        .update_status(&harness.resources.previews, "web", |pe: &mut KubePreviewEnvironment| {
+            pe.status.get_or_insert_with(Default::default).phase = Some("Ready".to_string());
+        })
+        .await;
+
+    assert!(result.is_ok());
+    let writes = harness.api.requests().into_iter().filter(|request| request.method == Method::PUT).count();
+    assert_eq!(writes, 2);
+    assert_eq!(harness.current("web").status.unwrap().phase.as_deref(), Some("Ready"));
+}
+
+#[tokio::test]
+async fn lifecycle_events_are_published() {
+    let harness = Harness::new(&[]);
+    let mut events = harness.resources.bus.subscribe();
+    let pe = harness.preview("web", json!({ "fqdn": "web.previews.example.com" }));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let added = events.try_recv().unwrap();
+    assert_eq!((added.preview.as_str(), added.reason.as_str()), ("web", "Added"));
+    let invalid = events.try_recv().unwrap();
+    assert_eq!((invalid.event_type.as_str(), invalid.reason.as_str()), ("Warning", "InvalidSpec"));
+}
+
+#[tokio::test]
+async fn unchanged_children_are_not_rewritten() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    harness.handle(WatchEvent::Added(pe)).await;
+    let created = harness.api.requests().into_iter().find(|request| request.path.ends_with("/services")).unwrap();
+    let services = &harness.resources.services;
+    harness.api.clear_requests();
+
+    let mut rendered = created.body;
+    assert_eq!(apply_child(&harness.resources, services, &rendered).await.unwrap(), Applied::Unchanged);
+    assert!(harness.api.requests().iter().all(|request| request.method == Method::GET));
+
+    rendered["spec"]["ports"][0]["port"] = json!(8080);
+    assert_eq!(apply_child(&harness.resources, services, &rendered).await.unwrap(), Applied::Updated);
+    let service = harness.get(services, &children.service).unwrap();
+    assert_eq!(service["spec"]["ports"][0]["port"], 8080);
+    assert_eq!(apply_child(&harness.resources, services, &rendered).await.unwrap(), Applied::Unchanged);
+}
+
+#[tokio::test]
+async fn updates_keep_what_others_changed() {
+    let harness = Harness::new(&[]);
+    let deployments = &harness.resources.deployments;
+    let mut rendered = json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": { "name": "web", "labels": { "app": "web" } },
+        "spec": {
+            "replicas": 1,
+            "template": { "spec": { "containers": [{ "name": "app", "image": "nginx:1.19" }] } },
+        },
+    });
+    apply_child(&harness.resources, deployments, &rendered).await.unwrap();
+
+    // An HPA scales it up and the mesh injects a sidecar
+    let mut live = harness.get(deployments, "web").unwrap();
+    live["spec"]["replicas"] = json!(4);
+    let sidecar = json!({ "name": "istio-proxy", "image": "istio/proxyv2" });
+    live["spec"]["template"]["spec"]["containers"].as_array_mut().unwrap().push(sidecar);
+    live["metadata"]["labels"]["injected"] = json!("true");
+    let data = serde_json::to_vec(&live).unwrap();
+    harness.api.apply(deployments.replace("web", &PostParams::default(), data).unwrap());
+
+    rendered["spec"]["template"]["spec"]["containers"][0]["image"] = json!("nginx:1.20");
+    assert_eq!(apply_child(&harness.resources, deployments, &rendered).await.unwrap(), Applied::Updated);
+
+    let live = harness.get(deployments, "web").unwrap();
+    assert_eq!(live["spec"]["replicas"], 4);
+    let containers = live["spec"]["template"]["spec"]["containers"].as_array().unwrap();
+    assert_eq!(containers.len(), 2);
+    assert_eq!((&containers[0]["name"], &containers[0]["image"]), (&json!("app"), &json!("nginx:1.20")));
+    assert_eq!(containers[1]["name"], "istio-proxy");
+    assert_eq!(live["metadata"]["labels"], json!({ "app": "web", "injected": "true" }));
+}
+
+#[tokio::test]
+async fn updates_report_who_else_changed_the_same_fields() {
+    let harness = Harness::new(&[]);
+    let deployments = &harness.resources.deployments;
+    let mut rendered = json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": { "name": "web" },
+        "spec": { "template": { "spec": { "containers": [{ "name": "app", "image": "nginx:1.19" }] } } },
+    });
+    apply_child(&harness.resources, deployments, &rendered).await.unwrap();
+    assert!(harness.api.requests().iter().all(|request| request.method == Method::GET || request.query.contains("fieldManager")));
+
+    // A GitOps tool pins a different image
+    let mut live = harness.get(deployments, "web").unwrap();
+    live["spec"]["template"]["spec"]["containers"][0]["image"] = json!("nginx:1.19-alpine");
+    let image = json!({ "k:{\"name\":\"app\"}": { "f:image": {} } });
+    let owned = json!({ "f:spec": { "f:template": { "f:spec": { "f:containers": image } } } });
+    live["metadata"]["managedFields"] = json!([{ "manager": "argocd", "operation": "Update", "fieldsV1": owned }]);
+    let data = serde_json::to_vec(&live).unwrap();
+    harness.api.apply(deployments.replace("web", &PostParams::default(), data).unwrap());
+
+    rendered["spec"]["template"]["spec"]["containers"][0]["image"] = json!("nginx:1.20");
+    let applied = apply_child(&harness.resources, deployments, &rendered).await.unwrap();
+    let conflicts = applied.conflicts();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].child, "Deployment web");
+    assert_eq!(conflicts[0].field, "spec.template.spec.containers[app].image");
+    assert_eq!(conflicts[0].manager.as_deref(), Some("argocd"));
+    let live = harness.get(deployments, "web").unwrap();
+    assert_eq!(live["spec"]["template"]["spec"]["containers"][0]["image"], "nginx:1.20");
+}
+
+#[tokio::test]
+async fn edits_to_the_main_deployment_are_reported_as_conflicts() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    // Someone pins a different image with kubectl
+    let deployments = &harness.resources.deployments;
+    let mut live = harness.get(deployments, &children.deployment).unwrap();
+    live["spec"]["template"]["spec"]["containers"][0]["image"] = json!("nginx:1.19-alpine");
+    let container = format!("k:{}", json!({ "name": children.deployment }));
+    let image = json!({ container: { "f:image": {} } });
+    let owned = json!({ "f:spec": { "f:template": { "f:spec": { "f:containers": image } } } });
+    live["metadata"]["managedFields"] = json!([{ "manager": "kubectl-edit", "operation": "Update", "fieldsV1": owned }]);
+    let data = serde_json::to_vec(&live).unwrap();
+    harness.api.apply(deployments.replace(&children.deployment, &PostParams::default(), data).unwrap());
+
+    let mut pe = harness.current("web");
+    pe.spec.image = "nginx:1.20".to_string();
+    let data = serde_json::to_vec(&pe).unwrap();
+    harness.api.apply(harness.resources.previews.replace("web", &PostParams::default(), data).unwrap());
+    harness.handle(WatchEvent::Modified(harness.current("web"))).await;
+
+    let status = harness.current("web").status.unwrap();
+    let condition = status.conditions.iter().find(|condition| condition.condition_type == conflicts::CONDITION).unwrap();
+    assert_eq!((condition.status.as_str(), condition.reason.as_deref()), ("True", Some("Overwritten")));
+    let expected = format!(
+        "kubectl-edit changed spec.template.spec.containers[{}].image of Deployment {}",
+        children.deployment, children.deployment
+    );
+    assert_eq!(condition.message.as_deref(), Some(expected.as_str()));
+    let live = harness.get(deployments, &children.deployment).unwrap();
+    assert_eq!(live["spec"]["template"]["spec"]["containers"][0]["image"], "nginx:1.20");
+}
+
+#[tokio::test]
+async fn partly_created_previews_are_degraded_once_out_of_time() {
+    let harness = Harness::new(&["--creation-timeout-seconds=0"]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    let create = harness.resources.mappings.create(&PostParams::default(), vec![]).unwrap();
+    harness.api.respond(&create, 422, json!({ "status": "Failure", "message": "denied", "reason": "Invalid", "code": 422 }));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Degraded"));
+    let message = status.message.unwrap();
+    assert!(message.contains(&format!("Deployment {} created", children.deployment)));
+    assert!(message.contains(&format!("Mapping {} failed", children.mapping)));
+    assert!(harness.get(&harness.resources.deployments, &children.deployment).is_some());
+    assert!(retry::exhausted(&harness.current("web")));
+    let mapping = status.resources.iter().find(|child| child.kind == "Mapping" && child.name == children.mapping).unwrap();
+    assert!(mapping.created.is_none());
+    assert!(mapping.last_error.is_some());
+}
+
+#[tokio::test]
+async fn partly_created_previews_can_be_rolled_back() {
+    let harness = Harness::new(&["--creation-timeout-seconds=0", "--partial-failure=rollback"]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    let create = harness.resources.mappings.create(&PostParams::default(), vec![]).unwrap();
+    harness.api.respond(&create, 422, json!({ "status": "Failure", "message": "denied", "reason": "Invalid", "code": 422 }));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Failed"));
+    assert!(harness.get(&harness.resources.deployments, &children.deployment).is_none());
+    assert!(harness.get(&harness.resources.services, &children.service).is_none());
+    assert!(!conditions::is_true(&status.conditions, creation::CLEANUP_CONDITION));
+}
+
+#[tokio::test]
+async fn rolled_back_previews_say_what_they_left_behind() {
+    let harness = Harness::new(&["--creation-timeout-seconds=0", "--partial-failure=rollback"]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+    let create = harness.resources.mappings.create(&PostParams::default(), vec![]).unwrap();
+    harness.api.respond(&create, 422, json!({ "status": "Failure", "message": "denied", "reason": "Invalid", "code": 422 }));
+    let delete = harness.resources.deployments.delete(&children.deployment, &DeleteParams::default()).unwrap();
+    harness.api.respond(&delete, 403, json!({ "status": "Failure", "message": "forbidden", "reason": "Forbidden", "code": 403 }));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Failed"));
+    assert!(status.message.unwrap().contains("couldn't delete all the rest"));
+    let condition = status.conditions.iter().find(|condition| condition.condition_type == creation::CLEANUP_CONDITION).unwrap();
+    assert_eq!(condition.status, "True");
+    assert!(condition.message.as_deref().unwrap().contains(&format!("deployment {}", children.deployment)));
+    assert!(harness.get(&harness.resources.deployments, &children.deployment).is_some());
+}
+
+#[tokio::test]
+async fn children_are_listed_in_status() {
+    let harness = Harness::new(&[]);
+    let pe = harness.preview("web", spec());
+    let children = Children::of(&pe);
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    let recorded = harness.current("web").status.unwrap().resources;
+    let deployment = recorded.iter().find(|child| child.kind == "Deployment" && child.name == children.deployment).unwrap();
+    assert!(deployment.created.is_some());
+    assert_eq!(deployment.last_error, None);
+
+    let found = inventory::find(&harness.resources).await;
+    let found = &found[&labels::name_value("web")];
+    let mapping = found.iter().find(|child| child.kind == "Mapping" && child.name == children.mapping).unwrap();
+    assert!(mapping.ready);
+    // Nothing has reported its pods ready
+    let deployment = found.iter().find(|child| child.kind == "Deployment" && child.name == children.deployment).unwrap();
+    assert!(!deployment.ready);
+}
@@ -0,0 +1,46 @@
+// Keeping previews to images from allowed registries.
+use kube::api::WatchEvent;
+use serde_json::json;
+
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::registries;
+
+mod common;
+use common::spec_with_image;
+
+#[tokio::test]
+async fn images_from_elsewhere_fail_without_creating_anything() {
+    let harness = Harness::new(&["--allowed-registries=registry.example.com/team"]);
+    let pe = harness.preview("web", spec_with_image("registry.example.com/other/web:1.0"));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert!(harness.api.created("deployments").is_empty());
+    let status = harness.current("web").status.unwrap();
+    assert_eq!(status.phase.as_deref(), Some("Failed"));
+    let condition = status.conditions.iter().find(|condition| condition.condition_type == registries::CONDITION).unwrap();
+    assert_eq!(condition.status, "True");
+    assert!(condition.message.as_deref().unwrap().contains("registry.example.com/other/web:1.0"));
+}
+
+#[tokio::test]
+async fn a_teams_profile_overrides_the_allowed_registries() {
+    let profiles = [("payments", json!({ "allowed_registries": "ghcr.io/payments, registry.example.com" }))];
+    let harness = common::with_profiles(&["--allowed-registries=registry.example.com"], &profiles).await;
+    let pe = common::team_preview(&harness, "web", Some("payments"), spec_with_image("ghcr.io/payments/web:1.0"));
+
+    harness.handle(WatchEvent::Added(pe)).await;
+
+    assert_eq!(harness.current("web").status.unwrap().phase.as_deref(), Some("Ready"));
+}
+
+#[test]
+fn images_are_compared_the_way_docker_names_them() {
+    let allowed = vec!["docker.io/library".to_string(), "index.docker.io/acme".to_string(), "localhost:5000".to_string()];
+
+    assert!(registries::allows(&allowed, "nginx:1.19"));
+    assert!(registries::allows(&allowed, "acme/web"));
+    assert!(registries::allows(&allowed, "localhost:5000/web@sha256:abc"));
+    assert!(!registries::allows(&allowed, "acmecorp/web"));
+    assert!(!registries::allows(&allowed, "ghcr.io/library/nginx"));
+}
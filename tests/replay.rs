@@ -0,0 +1,94 @@
+// Sequences of API server responses, played back from fixtures in
+// tests/fixtures.
+use kube::api::{PostParams, RawApi};
+use kube::Error;
+use serde_json::json;
+use std::sync::Arc;
+
+use rust_k8s_starter::client::Client;
+use rust_k8s_starter::fake::Harness;
+use rust_k8s_starter::vcr::{Player, Recorder};
+use rust_k8s_starter::KubePreviewEnvironment;
+
+fn player(fixture: &str) -> Arc<Player> {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), fixture);
+    Arc::new(Player::load(&path).unwrap())
+}
+
+fn previews() -> RawApi {
+    RawApi::customResource("previewenvironments").group("platform9.com").within("default")
+}
+
+#[tokio::test]
+async fn status_update_retries_after_a_conflict() {
+    let player = player("status-conflict.json");
+    let client = Client::with_transport(player.clone(), 1e6, 1000);
+
+    let pe: KubePreviewEnvironment = client
+        .update_status(&previews(), "web", |pe: &mut KubePreviewEnvironment| {
+            pe.status.get_or_insert_with(Default::default).phase = Some("Ready".to_string());
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(pe.status.unwrap().phase.as_deref(), Some("Ready"));
+    assert!(player.unplayed().is_empty());
+}
+
+#[tokio::test]
+async fn throttled_request_is_retried() {
+    let player = player("throttled.json");
+    let client = Client::with_transport(player.clone(), 1e6, 1000);
+    let deployments = RawApi::v1Deployment().within("default");
+
+    let deployment: serde_json::Value = client.request(deployments.get("web-deployment").unwrap()).await.unwrap();
+
+    assert_eq!(deployment["metadata"]["resourceVersion"], "2210");
+    assert!(player.unplayed().is_empty());
+}
+
+#[tokio::test]
+async fn unrecorded_request_fails() {
+    let player = player("throttled.json");
+    let client = Client::with_transport(player, 1e6, 1000);
+
+    let result: Result<serde_json::Value, _> = client.request(previews().get("web").unwrap()).await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn recording_plays_back_the_same() {
+    let harness = Harness::new(&[]);
+    harness.preview("web", json!({ "image": "nginx:1.19", "fqdn": "web.previews.example.com" }));
+    let fixture = std::env::temp_dir().join(format!("preview-vcr-{}.json", std::process::id()));
+    let fixture = fixture.to_str().unwrap();
+    let config_maps = RawApi::v1ConfigMap().within("default");
+    let data = serde_json::to_vec(&json!({ "metadata": { "name": "settings" } })).unwrap();
+    let create = || config_maps.create(&PostParams::default(), data.clone()).unwrap();
+
+    // Record against the fake API server, then make the same requests
+    // against the recording alone
+    let recorder = Client::with_transport(Arc::new(Recorder::new(harness.api.clone(), fixture)), 1e6, 1000);
+    let pe: serde_json::Value = recorder.request(previews().get("web").unwrap()).await.unwrap();
+    let created: serde_json::Value = recorder.request(create()).await.unwrap();
+    let conflict: Result<serde_json::Value, _> = recorder.request(create()).await;
+
+    let player = Arc::new(Player::load(fixture).unwrap());
+    let client = Client::with_transport(player.clone(), 1e6, 1000);
+    let replayed: serde_json::Value = client.request(previews().get("web").unwrap()).await.unwrap();
+    assert_eq!(replayed, pe);
+    let replayed: serde_json::Value = client.request(create()).await.unwrap();
+    assert_eq!(replayed, created);
+    let replayed: Result<serde_json::Value, _> = client.request(create()).await;
+    match (conflict, replayed) {
+        (Err(Error::Api(recorded)), Err(Error::Api(replayed))) => {
+            assert_eq!(recorded.code, 409);
+            assert_eq!(replayed.code, 409);
+        }
+        other => panic!("Expected the create to conflict both times, got {:?}", other),
+    }
+    assert!(player.unplayed().is_empty());
+
+    std::fs::remove_file(fixture).unwrap();
+}